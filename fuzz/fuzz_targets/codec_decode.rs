@@ -0,0 +1,34 @@
+//! Feeds arbitrary bytes to the codec in pieces, as they would arrive from the network.
+//!
+//! The first byte picks the stream threshold, `0` disabling streaming, and the second one the
+//! size of the pieces. The rest is the data read off the substream.
+//!
+//! Seeded with the inputs in `seeds/codec_decode`: `cargo fuzz run codec_decode
+//! corpus/codec_decode seeds/codec_decode`.
+
+#![no_main]
+
+use asynchronous_codec::Decoder;
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use libp2p_broadcast::wire::LengthPrefixedCodec;
+
+fuzz_target!(|data: &[u8]| {
+    let [threshold, piece, data @ ..] = data else {
+        return;
+    };
+    let threshold = (*threshold != 0).then(|| *threshold as usize * 16);
+    let mut codec = LengthPrefixedCodec::new(64 * 1024).with_stream_threshold(threshold);
+    let mut src = BytesMut::new();
+    for piece in data.chunks(*piece as usize + 1) {
+        src.extend_from_slice(piece);
+        loop {
+            match codec.decode(&mut src) {
+                Ok(Some(_)) => {}
+                Ok(None) => break,
+                // The substream is closed on the first error.
+                Err(_) => return,
+            }
+        }
+    }
+});
@@ -0,0 +1,20 @@
+//! Decodes arbitrary frames. Frames that decode must encode back into frames decoding into the
+//! same message.
+//!
+//! Seeded with the inputs in `seeds/from_bytes`, e.g. deeply nested batches, which must be
+//! rejected rather than overflow the stack: `cargo fuzz run from_bytes corpus/from_bytes
+//! seeds/from_bytes`.
+
+#![no_main]
+
+use bytes::Bytes;
+use libfuzzer_sys::fuzz_target;
+use libp2p_broadcast::wire::Message;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(message) = Message::from_bytes(Bytes::copy_from_slice(data)) {
+        let encoded = Bytes::from(message.to_bytes());
+        assert_eq!(encoded.len(), message.len());
+        assert_eq!(Message::from_bytes(encoded).unwrap(), message);
+    }
+});
@@ -0,0 +1,163 @@
+//! Joins a network over TCP, noise and yamux, publishes the lines read from stdin and dumps
+//! everything the behaviour reports, to debug topic connectivity between nodes.
+//!
+//! ```sh
+//! cargo run --manifest-path interop/Cargo.toml --example broadcast-cli -- \
+//!     --topic orders --topic audit --dial /ip4/10.0.0.7/tcp/4001
+//! ```
+//!
+//! Each line of stdin is broadcast on the `--publish` topic, which defaults to the first
+//! `--topic`. Received broadcasts are printed with the time since startup and, as timestamps are
+//! enabled, the transit delay skewed by the offset between the clocks of the peers.
+
+use std::{error::Error, time::Instant};
+
+use bytes::Bytes;
+use futures::StreamExt;
+use libp2p::{noise, swarm::SwarmEvent, tcp, yamux, Multiaddr, SwarmBuilder};
+use libp2p_broadcast::{Behaviour, Config, Event, Topic};
+use tokio::io::{AsyncBufReadExt, BufReader};
+
+const USAGE: &str = "usage: broadcast-cli --topic <topic>... [--publish <topic>] \
+                     [--listen <multiaddr>] [--dial <multiaddr>]...";
+
+/// Number of payload bytes printed for each received broadcast.
+const PREVIEW_LEN: usize = 64;
+
+struct Args {
+    topics: Vec<Topic>,
+    publish: Option<Topic>,
+    listen: Multiaddr,
+    dial: Vec<Multiaddr>,
+}
+
+impl Args {
+    fn parse() -> Result<Self, Box<dyn Error>> {
+        let mut args = std::env::args().skip(1);
+        let mut parsed = Self {
+            topics: Vec::new(),
+            publish: None,
+            listen: "/ip4/0.0.0.0/tcp/0".parse()?,
+            dial: Vec::new(),
+        };
+        while let Some(flag) = args.next() {
+            let value = args.next().ok_or(USAGE)?;
+            match flag.as_str() {
+                "--topic" => parsed.topics.push(topic(&value)?),
+                "--publish" => parsed.publish = Some(topic(&value)?),
+                "--listen" => parsed.listen = value.parse()?,
+                "--dial" => parsed.dial.push(value.parse()?),
+                _ => return Err(USAGE.into()),
+            }
+        }
+        if parsed.topics.is_empty() {
+            return Err(USAGE.into());
+        }
+        Ok(parsed)
+    }
+}
+
+fn topic(name: &str) -> Result<Topic, String> {
+    if name.len() > Topic::MAX_TOPIC_LENGTH {
+        return Err(format!(
+            "topic {name} is longer than {} bytes",
+            Topic::MAX_TOPIC_LENGTH
+        ));
+    }
+    Ok(Topic::new(name.as_bytes()))
+}
+
+fn name(topic: &Topic) -> String {
+    String::from_utf8_lossy(topic).into_owned()
+}
+
+fn preview(payload: &[u8]) -> String {
+    let preview = String::from_utf8_lossy(&payload[..payload.len().min(PREVIEW_LEN)]);
+    if payload.len() > PREVIEW_LEN {
+        format!("{preview:?}...")
+    } else {
+        format!("{preview:?}")
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let args = Args::parse()?;
+    let publish = args.publish.unwrap_or(args.topics[0]);
+    let started = Instant::now();
+
+    let mut swarm = SwarmBuilder::with_new_identity()
+        .with_tokio()
+        .with_tcp(
+            tcp::Config::default(),
+            noise::Config::new,
+            yamux::Config::default,
+        )?
+        .with_behaviour(|_| Behaviour::new(Config::default().with_timestamps()))?
+        .build();
+    swarm
+        .behaviour_mut()
+        .subscribe_many(args.topics.iter().copied());
+    swarm.listen_on(args.listen)?;
+    for addr in args.dial {
+        swarm.dial(addr)?;
+    }
+
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    let mut stdin_open = true;
+    loop {
+        tokio::select! {
+            line = lines.next_line(), if stdin_open => match line? {
+                Some(line) => {
+                    let report = swarm.behaviour_mut().broadcast(&publish, Bytes::from(line));
+                    let peers = report.queued().count();
+                    println!(
+                        "[{:>9.3}s] published on {} to {peers} peers",
+                        started.elapsed().as_secs_f64(),
+                        name(&publish),
+                    );
+                }
+                None => stdin_open = false,
+            },
+            event = swarm.select_next_some() => {
+                let elapsed = started.elapsed().as_secs_f64();
+                match event {
+                    SwarmEvent::NewListenAddr { address, .. } => {
+                        let peer = swarm.local_peer_id();
+                        println!("[{elapsed:>9.3}s] listening on {address}/p2p/{peer}");
+                    }
+                    SwarmEvent::ConnectionEstablished { peer_id, endpoint, .. } => {
+                        let addr = endpoint.get_remote_address();
+                        println!("[{elapsed:>9.3}s] connected to {peer_id} at {addr}");
+                    }
+                    SwarmEvent::ConnectionClosed { peer_id, cause, .. } => {
+                        println!("[{elapsed:>9.3}s] disconnected from {peer_id}: {cause:?}");
+                    }
+                    SwarmEvent::OutgoingConnectionError { peer_id, error, .. } => {
+                        println!("[{elapsed:>9.3}s] failed to dial {peer_id:?}: {error}");
+                    }
+                    SwarmEvent::Behaviour(Event::Received(peer, topic, payload, metadata)) => {
+                        let skew = metadata
+                            .skew
+                            .map(|skew| format!(" after {skew}ms"))
+                            .unwrap_or_default();
+                        println!(
+                            "[{elapsed:>9.3}s] {peer} on {}{skew}: {} bytes {}",
+                            name(&topic),
+                            payload.len(),
+                            preview(&payload),
+                        );
+                    }
+                    SwarmEvent::Behaviour(Event::Subscribed(peer, topic)) => {
+                        println!("[{elapsed:>9.3}s] {peer} subscribed to {}", name(&topic));
+                    }
+                    SwarmEvent::Behaviour(Event::Unsubscribed(peer, topic)) => {
+                        println!("[{elapsed:>9.3}s] {peer} unsubscribed from {}", name(&topic));
+                    }
+                    SwarmEvent::Behaviour(event) => println!("[{elapsed:>9.3}s] {event:?}"),
+                    _ => {}
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,73 @@
+//! Runs a node broadcasting on the topic `metrics` every second and serves its metrics at
+//! `http://127.0.0.1:9090/metrics`.
+//!
+//! Start a second node dialing the address printed by the first one to see the counters move:
+//!
+//! ```sh
+//! cargo run --manifest-path interop/Cargo.toml --example metrics
+//! cargo run --manifest-path interop/Cargo.toml --example metrics -- <address> 127.0.0.1:9091
+//! ```
+
+use std::{
+    error::Error,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use bytes::Bytes;
+use futures::StreamExt;
+use libp2p::{noise, swarm::SwarmEvent, tcp, yamux, Multiaddr, SwarmBuilder};
+use libp2p_broadcast::{Behaviour, Config, MetricsExporter, Topic};
+use prometheus_client::registry::Registry;
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let mut args = std::env::args().skip(1);
+    let dial: Option<Multiaddr> = args.next().map(|addr| addr.parse()).transpose()?;
+    let metrics_addr = args.next().unwrap_or_else(|| "127.0.0.1:9090".to_owned());
+
+    let mut registry = Registry::with_prefix("broadcast");
+    let behaviour = Behaviour::new_with_metrics(Config::default(), &mut registry);
+    let exporter = MetricsExporter::spawn(metrics_addr, Arc::new(Mutex::new(registry)))?;
+    println!(
+        "serving metrics at http://{}/metrics",
+        exporter.local_addr()
+    );
+
+    let mut swarm = SwarmBuilder::with_new_identity()
+        .with_tokio()
+        .with_tcp(
+            tcp::Config::default(),
+            noise::Config::new,
+            yamux::Config::default,
+        )?
+        .with_behaviour(|_| behaviour)?
+        .with_swarm_config(|config| config.with_idle_connection_timeout(Duration::from_secs(60)))
+        .build();
+    let topic = Topic::new(b"metrics");
+    swarm.behaviour_mut().subscribe(topic);
+    swarm.listen_on("/ip4/127.0.0.1/tcp/0".parse()?)?;
+    if let Some(addr) = dial {
+        swarm.dial(addr)?;
+    }
+
+    let mut ticks = tokio::time::interval(Duration::from_secs(1));
+    let mut seq = 0u64;
+    loop {
+        tokio::select! {
+            _ = ticks.tick() => {
+                seq += 1;
+                swarm
+                    .behaviour_mut()
+                    .broadcast(&topic, Bytes::copy_from_slice(&seq.to_be_bytes()));
+            }
+            event = swarm.select_next_some() => match event {
+                SwarmEvent::NewListenAddr { address, .. } => {
+                    println!("listening on {address}/p2p/{}", swarm.local_peer_id());
+                }
+                SwarmEvent::Behaviour(event) => println!("{event:?}"),
+                _ => {}
+            },
+        }
+    }
+}
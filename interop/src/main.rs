@@ -0,0 +1,211 @@
+//! Scripted scenarios to check that another implementation of the broadcast protocol
+//! interoperates with this crate over TCP, noise and yamux.
+//!
+//! Run one side with `--listen`, point the implementation under test at the printed address, or
+//! dial it with `--dial`. Both sides use the topic `interop`. The process prints `PASS` and exits
+//! with 0 once the scenario succeeded, and prints `FAIL` and exits with 1 otherwise.
+//!
+//! - `subscribe`: both sides subscribe, passes once the remote's subscription is seen.
+//! - `flood`: once subscribed to each other, both sides broadcast `--count` messages whose
+//!   payload is their sequence number as a big-endian `u64`. Passes once all of the remote's
+//!   messages were received, in order.
+//! - `oversize`: once the remote subscribed, broadcasts a payload of `--size` bytes, which should
+//!   exceed the largest frame the remote accepts. Passes once the frame was reported as too
+//!   large, whether dropped after the remote's handshake, rejected by the remote, or received.
+
+use std::{convert::TryFrom, error::Error, process, str::FromStr, time::Duration};
+
+use bytes::Bytes;
+use futures::StreamExt;
+use libp2p::{noise, swarm::SwarmEvent, tcp, yamux, Multiaddr, SwarmBuilder};
+use libp2p_broadcast::{Behaviour, Config, Event, Topic};
+
+const USAGE: &str = "usage: libp2p-broadcast-interop <subscribe|flood|oversize> \
+                     [--listen <multiaddr>] [--dial <multiaddr>] [--count <n>] \
+                     [--size <bytes>] [--timeout <secs>]";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Scenario {
+    Subscribe,
+    Flood,
+    Oversize,
+}
+
+impl FromStr for Scenario {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "subscribe" => Ok(Self::Subscribe),
+            "flood" => Ok(Self::Flood),
+            "oversize" => Ok(Self::Oversize),
+            _ => Err(format!("unknown scenario {s}")),
+        }
+    }
+}
+
+struct Args {
+    scenario: Scenario,
+    listen: Option<Multiaddr>,
+    dial: Option<Multiaddr>,
+    count: u64,
+    size: usize,
+    timeout: Duration,
+}
+
+impl Args {
+    fn parse() -> Result<Self, Box<dyn Error>> {
+        let mut args = std::env::args().skip(1);
+        let scenario = args.next().ok_or(USAGE)?.parse()?;
+        let mut parsed = Self {
+            scenario,
+            listen: None,
+            dial: None,
+            count: 1000,
+            size: 8 * 1024 * 1024,
+            timeout: Duration::from_secs(30),
+        };
+        while let Some(flag) = args.next() {
+            let value = args.next().ok_or(USAGE)?;
+            match flag.as_str() {
+                "--listen" => parsed.listen = Some(value.parse()?),
+                "--dial" => parsed.dial = Some(value.parse()?),
+                "--count" => parsed.count = value.parse()?,
+                "--size" => parsed.size = value.parse()?,
+                "--timeout" => parsed.timeout = Duration::from_secs(value.parse()?),
+                _ => return Err(USAGE.into()),
+            }
+        }
+        Ok(parsed)
+    }
+}
+
+/// Progress of the scenario.
+struct Run {
+    scenario: Scenario,
+    topic: Topic,
+    count: u64,
+    size: usize,
+    sent: bool,
+    received: u64,
+}
+
+impl Run {
+    /// Returns `Ok(true)` once the scenario passed.
+    fn on_event(&mut self, behaviour: &mut Behaviour, event: Event) -> Result<bool, String> {
+        match (self.scenario, event) {
+            (scenario, Event::Subscribed(peer, topic)) if topic == self.topic => {
+                println!("{peer} subscribed");
+                match scenario {
+                    Scenario::Subscribe => return Ok(true),
+                    Scenario::Flood if !self.sent => {
+                        for seq in 0..self.count {
+                            let payload = Bytes::copy_from_slice(&seq.to_be_bytes());
+                            behaviour.broadcast(&self.topic, payload);
+                        }
+                        self.sent = true;
+                    }
+                    Scenario::Oversize if !self.sent => {
+                        behaviour.broadcast(&self.topic, Bytes::from(vec![0; self.size]));
+                        self.sent = true;
+                    }
+                    _ => {}
+                }
+            }
+            (Scenario::Flood, Event::Received(peer, topic, payload, _)) if topic == self.topic => {
+                let seq = <[u8; 8]>::try_from(payload.as_ref())
+                    .map(u64::from_be_bytes)
+                    .map_err(|_| format!("{peer} sent a payload of {} bytes", payload.len()))?;
+                if seq != self.received {
+                    return Err(format!("expected message {}, got {seq}", self.received));
+                }
+                self.received += 1;
+            }
+            (Scenario::Oversize, Event::Oversized(peer, count)) => {
+                println!("dropped {count} messages larger than {peer} accepts");
+                return Ok(true);
+            }
+            (Scenario::Oversize, Event::Rejected(peer, size)) => {
+                println!("{peer} rejected a frame of {size} bytes");
+                return Ok(true);
+            }
+            (Scenario::Oversize, Event::ReceivedOversized(peer, size)) => {
+                println!("{peer} sent a frame of {size} bytes");
+                return Ok(true);
+            }
+            _ => {}
+        }
+        Ok(self.scenario == Scenario::Flood && self.sent && self.received == self.count)
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let args = Args::parse()?;
+    let topic = Topic::new(b"interop");
+
+    let mut swarm = SwarmBuilder::with_new_identity()
+        .with_tokio()
+        .with_tcp(
+            tcp::Config::default(),
+            noise::Config::new,
+            yamux::Config::default,
+        )?
+        .with_behaviour(|_| Behaviour::new(Config::default()))?
+        .with_swarm_config(|config| config.with_idle_connection_timeout(args.timeout))
+        .build();
+    swarm.behaviour_mut().subscribe(topic);
+
+    match (&args.listen, &args.dial) {
+        (None, None) => {
+            swarm.listen_on("/ip4/0.0.0.0/tcp/0".parse()?)?;
+        }
+        (listen, dial) => {
+            if let Some(addr) = listen {
+                swarm.listen_on(addr.clone())?;
+            }
+            if let Some(addr) = dial {
+                swarm.dial(addr.clone())?;
+            }
+        }
+    }
+
+    let mut run = Run {
+        scenario: args.scenario,
+        topic,
+        count: args.count,
+        size: args.size,
+        sent: false,
+        received: 0,
+    };
+    let deadline = tokio::time::sleep(args.timeout);
+    tokio::pin!(deadline);
+    loop {
+        tokio::select! {
+            _ = &mut deadline => fail(format!("timed out after {:?}", args.timeout)),
+            event = swarm.select_next_some() => match event {
+                SwarmEvent::NewListenAddr { address, .. } => {
+                    println!("listening on {address}/p2p/{}", swarm.local_peer_id());
+                }
+                SwarmEvent::ConnectionEstablished { peer_id, .. } => {
+                    println!("connected to {peer_id}");
+                }
+                SwarmEvent::OutgoingConnectionError { error, .. } => fail(error.to_string()),
+                SwarmEvent::Behaviour(event) => match run.on_event(swarm.behaviour_mut(), event) {
+                    Ok(true) => {
+                        println!("PASS");
+                        return Ok(());
+                    }
+                    Ok(false) => {}
+                    Err(error) => fail(error),
+                },
+                _ => {}
+            },
+        }
+    }
+}
+
+fn fail(reason: String) -> ! {
+    println!("FAIL: {reason}");
+    process::exit(1)
+}
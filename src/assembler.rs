@@ -0,0 +1,201 @@
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use bytes::{Bytes, BytesMut};
+use fnv::FnvHashMap;
+
+use crate::targets::BEHAVIOUR;
+use crate::types::Chunk;
+
+/// Distinguishes the files of the assemblers sharing a directory.
+static NEXT_ASSEMBLER_ID: AtomicU64 = AtomicU64::new(0);
+
+/// A payload reassembled by a `ChunkAssembler`.
+#[derive(Debug, Eq, PartialEq)]
+pub enum AssembledPayload {
+    Memory(Bytes),
+    /// The payload was larger than the memory threshold and is in this file, which the caller
+    /// owns from now on.
+    File(PathBuf),
+}
+
+#[derive(Debug)]
+enum Partial {
+    Memory(BytesMut),
+    File {
+        file: File,
+        path: PathBuf,
+        written: usize,
+    },
+}
+
+/// Reassembles the chunks of streamed broadcasts, see `Event::ReceivedChunk`. Payloads larger
+/// than the memory threshold are written to a file as their chunks arrive, so that receiving
+/// very large broadcasts does not require as much memory.
+#[derive(Debug)]
+pub struct ChunkAssembler {
+    dir: PathBuf,
+    memory_threshold: usize,
+    id: u64,
+    partial: FnvHashMap<u64, Partial>,
+}
+
+impl ChunkAssembler {
+    /// Default threshold above which payloads are spilled to disk.
+    pub const DEFAULT_MEMORY_THRESHOLD: usize = 16 * 1024 * 1024;
+
+    /// Creates an assembler spilling to files in the directory, which must exist.
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            memory_threshold: Self::DEFAULT_MEMORY_THRESHOLD,
+            id: NEXT_ASSEMBLER_ID.fetch_add(1, Ordering::Relaxed),
+            partial: FnvHashMap::default(),
+        }
+    }
+
+    pub fn with_memory_threshold(mut self, bytes: usize) -> Self {
+        self.memory_threshold = bytes;
+        self
+    }
+
+    /// Adds a chunk, in the order received. Returns the payload once the chunk completes it.
+    pub fn push(&mut self, chunk: &Chunk) -> io::Result<Option<AssembledPayload>> {
+        if !self.partial.contains_key(&chunk.stream) {
+            if chunk.offset != 0 {
+                return Err(invalid_data(
+                    "chunk received without the start of its stream",
+                ));
+            }
+            let partial = if chunk.len > self.memory_threshold {
+                let path = self.dir.join(format!(
+                    "libp2p-broadcast-{}-{}-{}.part",
+                    std::process::id(),
+                    self.id,
+                    chunk.stream
+                ));
+                let file = File::create(&path)?;
+                Partial::File {
+                    file,
+                    path,
+                    written: 0,
+                }
+            } else {
+                // Not preallocated, as the length is announced by the remote.
+                Partial::Memory(BytesMut::new())
+            };
+            self.partial.insert(chunk.stream, partial);
+        }
+        if let Err(e) = self.append(chunk) {
+            self.abort(chunk.stream);
+            return Err(e);
+        }
+        if !chunk.is_last() {
+            return Ok(None);
+        }
+        Ok(self
+            .partial
+            .remove(&chunk.stream)
+            .map(|partial| match partial {
+                Partial::Memory(buf) => AssembledPayload::Memory(buf.freeze()),
+                Partial::File { path, .. } => AssembledPayload::File(path),
+            }))
+    }
+
+    fn append(&mut self, chunk: &Chunk) -> io::Result<()> {
+        match self.partial.get_mut(&chunk.stream) {
+            Some(Partial::Memory(buf)) if buf.len() == chunk.offset => {
+                buf.extend_from_slice(&chunk.data);
+                Ok(())
+            }
+            Some(Partial::File { file, written, .. }) if *written == chunk.offset => {
+                file.write_all(&chunk.data)?;
+                *written += chunk.data.len();
+                if chunk.is_last() {
+                    file.flush()?;
+                }
+                Ok(())
+            }
+            _ => Err(invalid_data("chunk received out of order")),
+        }
+    }
+
+    /// Drops the chunks of a stream that will not complete, see `Event::ReceiveAborted`.
+    pub fn abort(&mut self, stream: u64) {
+        discard(self.partial.remove(&stream));
+    }
+}
+
+impl Drop for ChunkAssembler {
+    fn drop(&mut self) {
+        for (_, partial) in self.partial.drain() {
+            discard(Some(partial));
+        }
+    }
+}
+
+fn discard(partial: Option<Partial>) {
+    if let Some(Partial::File { file, path, .. }) = partial {
+        drop(file);
+        if let Err(e) = fs::remove_file(&path) {
+            tracing::warn!(target: BEHAVIOUR, "Failed to remove {}: {e}", path.display());
+        }
+    }
+}
+
+fn invalid_data(error: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunks(stream: u64, payload: &'static [u8], size: usize) -> Vec<Chunk> {
+        let mut offset = 0;
+        payload
+            .chunks(size)
+            .map(|data| {
+                let chunk = Chunk {
+                    stream,
+                    offset,
+                    len: payload.len(),
+                    data: Bytes::from_static(data),
+                };
+                offset += data.len();
+                chunk
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_assembler() {
+        let dir = std::env::temp_dir();
+        let mut assembler = ChunkAssembler::new(&dir).with_memory_threshold(8);
+        let small = chunks(1, b"small", 2);
+        let large = chunks(2, b"larger than eight", 4);
+        for chunk in &small[..small.len() - 1] {
+            assert_eq!(assembler.push(chunk).unwrap(), None);
+        }
+        for chunk in &large[..large.len() - 1] {
+            assert_eq!(assembler.push(chunk).unwrap(), None);
+        }
+        assert_eq!(
+            assembler.push(small.last().unwrap()).unwrap(),
+            Some(AssembledPayload::Memory(Bytes::from_static(b"small")))
+        );
+        let Some(AssembledPayload::File(path)) = assembler.push(large.last().unwrap()).unwrap()
+        else {
+            panic!("expected a spilled payload");
+        };
+        assert_eq!(fs::read(&path).unwrap(), b"larger than eight");
+        fs::remove_file(path).unwrap();
+
+        let aborted = chunks(3, b"aborted halfway", 4);
+        assembler.push(&aborted[0]).unwrap();
+        assert!(assembler.push(&aborted[2]).is_err());
+        assert!(assembler.partial.is_empty());
+    }
+}
@@ -0,0 +1,5745 @@
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::fmt;
+use std::io;
+use std::ops::Range;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use fnv::{FnvHashMap, FnvHashSet};
+use futures::FutureExt;
+use libp2p::core::transport::PortUse;
+use libp2p::core::Endpoint;
+use libp2p::swarm::derive_prelude::FromSwarm;
+use libp2p::swarm::{
+    CloseConnection, ConnectionDenied, ConnectionHandler, ConnectionId, NetworkBehaviour,
+    NotifyHandler, THandler, ToSwarm,
+};
+use libp2p::{Multiaddr, PeerId};
+#[cfg(feature = "prometheus")]
+use prometheus_client::registry::Registry;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::callbacks::Callbacks;
+#[cfg(feature = "chaos")]
+use crate::chaos::Chaos;
+use crate::churn::{Churn, ChurnTracker};
+use crate::clock;
+use crate::config::{
+    CapAction, Config, ConnectionPreference, Reordering, SelfTest, SlowConsumerAction,
+    TransportClass,
+};
+use crate::credit::Grants;
+use crate::dump::{PeerState, StateDump};
+#[cfg(feature = "erasure")]
+use crate::erasure::{ErasureCoding, Reassembler};
+use crate::event_log::EventSink;
+use crate::fan_out::FanOut;
+use crate::group::SharedGroups;
+use crate::handler::{Command, Handler, HandlerEvent::*, Outbound};
+use crate::health::{ErrorKind, ErrorLog, Health};
+use crate::history::History;
+use crate::interceptor::Interceptor;
+use crate::journal::{JournalKind, JournalRecord, JournalSink};
+use crate::membership::Membership;
+#[cfg(feature = "prometheus")]
+use crate::metrics::Metrics;
+use crate::metrics_sink::MetricsSink;
+use crate::outbox::{OutboxEntry, OutboxStore};
+use crate::protocol::Version;
+use crate::queue::QueueStats;
+use crate::quota::{Charge, Quotas};
+use crate::redelivery::{Delivered, Redeliveries};
+use crate::reorder::ReorderBuffers;
+use crate::report::{BroadcastReport, PeerOutcome, SkipReason};
+use crate::reputation::PeerReputations;
+use crate::routing::RoutingTable;
+use crate::seen::{self, DuplicateStore};
+use crate::seen_store::SeenStore;
+use crate::self_test::{ProbeFrame, Prober, SelfTestResult};
+use crate::sequence::DeliveryWindow;
+use crate::snapshot::BehaviourSnapshot;
+use crate::subscription_store::SubscriptionStore;
+use crate::substream::SubstreamEvent;
+use crate::targets::{self, BEHAVIOUR};
+use crate::timer::Sleep;
+use crate::topic_stats::{TopicActivity, TopicStats};
+use crate::types::Message::{self, *};
+#[cfg(feature = "erasure")]
+use crate::types::Shard;
+use crate::types::{Capabilities, Chunk, ContentHasher, MessageId, Metadata, Payload, Topic};
+
+/// Supersede key of the snapshots of our subscriptions. Snapshots name no topic, so it cannot
+/// clash with the keys of broadcasts.
+const SNAPSHOT_KEY: u64 = 0;
+
+// Events are handed over to the application once, boxing the metadata would only make them
+// harder to match on.
+#[allow(clippy::large_enum_variant)]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Event {
+    Subscribed(PeerId, Topic),
+    /// The peer registered our subscription to the topic, and routes its broadcasts on it to us
+    /// from now on. Only reported by peers negotiating `Capabilities::SUBSCRIPTION_ACKS`.
+    SubscriptionConfirmed(PeerId, Topic),
+    /// The topics the peer is subscribed to, sorted, in answer to
+    /// `Behaviour::query_subscriptions`.
+    PeerSubscriptions(PeerId, Vec<Topic>),
+    Unsubscribed(PeerId, Topic),
+    Received(PeerId, Topic, Bytes, Metadata),
+    /// Part of a broadcast larger than `Config::stream_threshold`, passed on as it arrives.
+    ReceivedChunk(PeerId, Topic, Chunk),
+    /// The connection delivering a chunked broadcast failed before the payload was complete, or
+    /// the payload turned out to be a duplicate. Its last chunk is not passed on, and the chunks
+    /// received so far are to be discarded.
+    ReceiveAborted(PeerId, Topic, u64),
+    /// A rate limit engaged for the peer, on the topic unless it limits the peer as a whole: what
+    /// is sent to or received from it is delayed or dropped. Broadcasts shed because the queue
+    /// of the peer is full are reported as `Event::Shed` instead.
+    PeerThrottled(PeerId, Option<Topic>, ThrottleKind),
+    /// This many duplicates from the peer were suppressed since the last event, the latest of
+    /// them with this id. Only reported with `Config::duplicate_events`.
+    DuplicateSuppressed(PeerId, MessageId, usize),
+    /// An application-defined control payload the peer sent with `Behaviour::send_control`.
+    ReceivedControl(PeerId, Topic, Bytes),
+    /// This many broadcasts queued for the peer were discarded by `Config::expiry_predicate`.
+    Expired(PeerId, usize),
+    /// This many messages queued for the peer were dropped because they exceed the largest frame
+    /// it announced to accept. Batches are split rather than dropped.
+    Oversized(PeerId, usize),
+    /// This many broadcasts for the peer were dropped by `Config::drop_policy` because its queue
+    /// held `Config::max_queue_len` messages.
+    Shed(PeerId, usize),
+    /// The peer sent a frame of this size, larger than `Config::max_buf_size`, and the inbound
+    /// substream it was sent over was closed.
+    ReceivedOversized(PeerId, usize),
+    /// The peer reset the substream right after we sent it a frame of this size, larger than any
+    /// it accepted before. It most likely exceeds the largest frame the peer accepts. Only
+    /// reported for peers that did not announce that limit in a handshake.
+    Rejected(PeerId, usize),
+    /// Sending to the peer failed `Config::failure_threshold` times in a row. Broadcasts are not
+    /// routed to it for `Config::failure_cooldown`.
+    PeerFailed(PeerId),
+    /// The oldest message queued for the peer has been waiting for this long, longer than
+    /// `Config::slow_consumer_threshold`. Reported again only once the peer caught up.
+    SlowConsumer(PeerId, Duration),
+    /// We no longer share a subscribed topic with the peer. With `Config::prune_grace` set, the
+    /// connections to the peer are no longer kept alive once idle for the grace period.
+    NoSharedTopics(PeerId),
+    /// The peer finished replaying the history of the topic, the following broadcasts received
+    /// from it on the topic are live. See `Config::with_history`.
+    ReplayCompleted(PeerId, Topic),
+    /// A substream of the connection to the peer was opened, closed or failed. Only reported
+    /// with `Config::substream_events`.
+    Substream(PeerId, ConnectionId, SubstreamEvent),
+    /// The peer did not acknowledge the broadcast on the at-least-once topic with this delivery
+    /// id, after the retries of `Config::with_at_least_once` or before disconnecting.
+    Unacknowledged(PeerId, Topic, u64),
+    /// More broadcasts of the publisher on the topic than `Reordering::max_buffered` were held
+    /// back waiting for a missing one. The missing broadcasts were skipped, see
+    /// `Config::with_reordering`.
+    ReorderOverflow(PeerId, Topic),
+    /// A broadcast of the publisher on the topic was held back for longer than
+    /// `Reordering::timeout` waiting for a missing one. The missing broadcasts were skipped.
+    ReorderTimeout(PeerId, Topic),
+    /// The broadcasts of the publisher on the topic with these sequence numbers never arrived
+    /// within the bounds of `Config::with_reordering`, and were skipped. Reported along with
+    /// `Event::ReorderOverflow` or `Event::ReorderTimeout`, so that the application can recover
+    /// them on its own, e.g. by syncing its state with the publisher.
+    Missing(PeerId, Topic, Range<u64>),
+    /// A probe of `Config::self_test` reached its deadline, with its outcome.
+    SelfTest(SelfTestResult),
+    /// The handshake with the peer completed, with the optional features both sides support.
+    /// Reported again if they change, e.g. after `Behaviour::reconfigure`, so that the
+    /// application can adapt to the peer, e.g. avoid sending control frames to peers without
+    /// `Capabilities::CONTROL`.
+    Negotiated(PeerId, Capabilities),
+}
+
+impl Event {
+    /// The topic the event is about, if it is about a single one.
+    pub fn topic(&self) -> Option<&Topic> {
+        match self {
+            Event::Subscribed(_, topic)
+            | Event::SubscriptionConfirmed(_, topic)
+            | Event::Unsubscribed(_, topic)
+            | Event::Received(_, topic, ..)
+            | Event::ReceivedChunk(_, topic, _)
+            | Event::ReceiveAborted(_, topic, _)
+            | Event::ReceivedControl(_, topic, _)
+            | Event::ReplayCompleted(_, topic)
+            | Event::Unacknowledged(_, topic, _)
+            | Event::ReorderOverflow(_, topic)
+            | Event::ReorderTimeout(_, topic)
+            | Event::Missing(_, topic, _)
+            | Event::PeerThrottled(_, Some(topic), _) => Some(topic),
+            _ => None,
+        }
+    }
+}
+
+/// Which rate limit engaged, see `Event::PeerThrottled`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ThrottleKind {
+    /// The history of the topic is replayed to the peer more slowly than it is available, at
+    /// `Config::history_replay_rate`. Nothing is dropped.
+    HistoryReplay,
+    /// The peer sent more than `BandwidthCap::max_bytes` within an interval of
+    /// `Config::bandwidth_cap`, and was throttled or disconnected. Reported once per interval.
+    Bandwidth(CapAction),
+    /// The peer ran out of the credits it grants on a topic, see `Config::with_flow_control`.
+    /// Broadcasts to it are held back until it grants more. Reported once per connection each
+    /// time it runs out.
+    Credits,
+}
+
+/// What was negotiated with a peer, see `Behaviour::negotiated_protocols`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct NegotiatedProtocol {
+    /// `None` until we opened a substream to the peer.
+    pub version: Option<Version>,
+    /// `None` until the peer sent its handshake, see `Behaviour::capabilities`.
+    pub capabilities: Option<Capabilities>,
+}
+
+/// A chunked broadcast being received over a connection.
+#[derive(Debug)]
+struct InboundStream {
+    id: u64,
+    topic: Topic,
+    offset: usize,
+    len: usize,
+    /// Id of the payload so far, to filter out duplicates once it is complete.
+    content_id: Option<ContentHasher>,
+}
+
+#[derive(Default)]
+pub struct Behaviour {
+    config: Config,
+    subscriptions: FnvHashSet<Topic>,
+    /// Group of each of our subscriptions made as a member of one.
+    shared_subscriptions: FnvHashMap<Topic, Topic>,
+    routes: RoutingTable,
+    /// Groups the peers subscribed as members of.
+    groups: SharedGroups,
+    events: VecDeque<ToSwarm<Event, Command>>,
+    /// Connections to each peer, in the order they were established.
+    connections: FnvHashMap<PeerId, Vec<ConnectionId>>,
+    /// Flush latency of the connections, as reported by their handlers.
+    latencies: FnvHashMap<ConnectionId, Duration>,
+    /// Depth of the queues of the connections, as reported by their handlers.
+    queues: FnvHashMap<ConnectionId, (usize, usize, Option<Instant>)>,
+    /// Broadcasts published or received on each topic, see `topic_stats`.
+    topic_activity: FnvHashMap<Topic, TopicActivity>,
+    /// Errors reported recently, see `health`.
+    errors: ErrorLog,
+    capabilities: FnvHashMap<PeerId, Capabilities>,
+    /// Protocol version last negotiated with each peer.
+    versions: FnvHashMap<PeerId, Version>,
+    /// Peers we share a subscribed topic with, tracked when pruning connections.
+    shared_peers: FnvHashSet<PeerId>,
+    /// Peers skipped by the circuit breaker, until the given time.
+    failed_peers: FnvHashMap<PeerId, Instant>,
+    /// Peers asked for their subscriptions with `query_subscriptions`.
+    subscription_queries: FnvHashSet<PeerId>,
+    /// Topics of the leading parts of the snapshot each peer is sending.
+    snapshot_parts: FnvHashMap<PeerId, Vec<Topic>>,
+    /// When a duplicate from each peer was last reported, and how many were suppressed since.
+    duplicates: FnvHashMap<PeerId, (Option<Instant>, usize)>,
+    /// Subscriptions of peers restored from a snapshot, applied when they connect.
+    restored_peers: FnvHashMap<PeerId, Vec<Topic>>,
+    /// Fires when the subscriptions of the peers are due to be queried.
+    resync_timer: Option<Sleep>,
+    heartbeat_timer: Option<Sleep>,
+    /// Broadcasts published on the topics with history.
+    history: FnvHashMap<Topic, History>,
+    /// Replays of the history in progress, with the sequence number of the next broadcast to
+    /// send and whether the replay rate throttled it. Live broadcasts reach the peer through the
+    /// replay until it caught up.
+    replays: FnvHashMap<(PeerId, Topic), (u64, bool)>,
+    replay_timer: Option<Sleep>,
+    /// Broadcasts of at-least-once topics awaiting their acknowledgment.
+    redeliveries: Redeliveries,
+    /// Delivery ids of the at-least-once broadcasts received lately.
+    delivered: Delivered,
+    redelivery_timer: Option<Sleep>,
+    /// Broadcasts received on flow-controlled topics since their senders were last granted
+    /// credits.
+    grants: Grants,
+    /// Bytes received from each peer in the current interval of `Config::bandwidth_cap`.
+    quotas: Quotas,
+    churn: ChurnTracker,
+    /// Probes of `Config::self_test`, and when the next one is sent or reaches its deadline.
+    prober: Prober,
+    self_test_timer: Option<Sleep>,
+    /// Broadcasts received out of order, held back along with the peer they were received from.
+    reorder: ReorderBuffers<(PeerId, Bytes, Metadata)>,
+    reorder_timer: Option<Sleep>,
+    /// Peers our subscriptions are yet to be announced to, along with the jittered delay.
+    pending_announcements: Vec<(Sleep, PeerId)>,
+    rng: Option<StdRng>,
+    metrics: Option<Box<dyn MetricsSink>>,
+    membership: Option<Box<dyn Membership>>,
+    fan_out: Option<Box<dyn FanOut>>,
+    outbox: Option<Box<dyn OutboxStore>>,
+    journal: Option<Box<dyn JournalSink>>,
+    event_sink: Option<Box<dyn EventSink>>,
+    interceptors: Vec<Box<dyn Interceptor>>,
+    callbacks: Callbacks,
+    /// Broadcasts kept in the outbox by topic, along with the time they were kept at.
+    outboxed: FnvHashMap<Topic, Vec<(Instant, OutboxEntry)>>,
+    /// Broadcasts written ahead to the outbox by id, along with the message sent for them and
+    /// the peers that did not flush them yet.
+    written_ahead: FnvHashMap<u64, (OutboxEntry, Message, FnvHashSet<PeerId>)>,
+    seen: Option<Box<dyn DuplicateStore>>,
+    /// Sequence numbers recently received from each publisher, see
+    /// `Config::with_delivery_window`.
+    delivery_window: Option<DeliveryWindow>,
+    seen_store: Option<Box<dyn SeenStore>>,
+    subscription_store: Option<Box<dyn SubscriptionStore>>,
+    streams: FnvHashMap<(PeerId, ConnectionId), InboundStream>,
+    /// Connections whose current chunked broadcast is dropped, as its sender exceeded its
+    /// bandwidth cap.
+    skipped_streams: FnvHashSet<(PeerId, ConnectionId)>,
+    next_stream_id: u64,
+    next_correlation_id: u64,
+    next_delivery_id: u64,
+    /// Sequence number of the next broadcast we publish on each topic.
+    next_sequence: FnvHashMap<Topic, u64>,
+    #[cfg(feature = "erasure")]
+    shards: Reassembler,
+    #[cfg(feature = "erasure")]
+    next_shard_id: u64,
+    /// Faults injected into the delivered broadcasts, see `Config::with_delivery_faults`.
+    #[cfg(feature = "chaos")]
+    delivery_chaos: Option<Chaos<Event>>,
+}
+
+impl fmt::Debug for Behaviour {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Behaviour")
+            .field("config", &self.config)
+            .field("subscriptions", &self.subscriptions)
+            .field("routes", &self.routes)
+            .field("membership", &self.membership.is_some())
+            .field("fan_out", &self.fan_out.is_some())
+            .field("outbox", &self.outbox.is_some())
+            .field("journal", &self.journal.is_some())
+            .field("event_sink", &self.event_sink.is_some())
+            .field("interceptors", &self.interceptors.len())
+            .field("callbacks", &self.callbacks.len())
+            .field("seen_store", &self.seen_store.is_some())
+            .field("subscription_store", &self.subscription_store.is_some())
+            .finish()
+    }
+}
+
+impl Behaviour {
+    pub fn new(config: Config) -> Self {
+        let mut rng = match config.rng_seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        let mut subscriptions = FnvHashSet::default();
+        if config.self_test.is_some() {
+            subscriptions.insert(Topic::new(SelfTest::TOPIC));
+        }
+        Self {
+            subscriptions,
+            seen: seen::from_config(&config),
+            next_correlation_id: rng.gen(),
+            next_delivery_id: rng.gen(),
+            delivery_window: config.delivery_window.map(DeliveryWindow::new),
+            #[cfg(feature = "erasure")]
+            next_shard_id: rng.gen(),
+            #[cfg(feature = "chaos")]
+            delivery_chaos: config.delivery_faults.clone().map(|faults| {
+                // Offset so as not to inject the same faults as the handlers.
+                let seed = config.rng_seed.map(|seed| seed.wrapping_add(1));
+                Chaos::new(faults, seed, config.timer.clone())
+            }),
+            rng: Some(rng),
+            routes: RoutingTable::new(config.topic_matcher.clone()),
+            config,
+            ..Default::default()
+        }
+    }
+
+    #[cfg(feature = "prometheus")]
+    pub fn new_with_metrics(config: Config, registry: &mut Registry) -> Self {
+        Self {
+            metrics: Some(Box::new(Metrics::new(registry))),
+            ..Self::new(config)
+        }
+    }
+
+    /// Records the metrics with the sink, in place of the Prometheus metrics if any.
+    pub fn set_metrics_sink(&mut self, sink: impl MetricsSink + 'static) {
+        self.metrics = Some(Box::new(sink));
+    }
+
+    /// Restricts broadcasting to the peers the given membership accepts. Messages are neither
+    /// sent to nor accepted from other peers, though their connections are kept open.
+    pub fn set_membership(&mut self, membership: impl Membership + 'static) {
+        self.membership = Some(Box::new(membership));
+    }
+
+    /// Lifts the restriction installed by `set_membership`.
+    pub fn clear_membership(&mut self) {
+        self.membership = None;
+    }
+
+    /// Narrows down the peers the broadcasts we publish and forward are sent to.
+    pub fn set_fan_out(&mut self, fan_out: impl FanOut + 'static) {
+        self.fan_out = Some(Box::new(fan_out));
+    }
+
+    /// Sends broadcasts to all subscribers again.
+    pub fn clear_fan_out(&mut self) {
+        self.fan_out = None;
+    }
+
+    /// Appends an interceptor to the chain every broadcast sent or received passes through.
+    pub fn add_interceptor(&mut self, interceptor: impl Interceptor + 'static) {
+        self.interceptors.push(Box::new(interceptor));
+    }
+
+    pub fn clear_interceptors(&mut self) {
+        self.interceptors.clear();
+    }
+
+    /// Calls the callback every time we subscribe to a topic. Callbacks run synchronously and
+    /// should be quick, e.g. to log or to account for quotas.
+    pub fn on_subscribe(&mut self, callback: impl FnMut(&Topic) + Send + 'static) {
+        self.callbacks.subscribe.push(Box::new(callback));
+    }
+
+    /// Calls the callback every time we unsubscribe from a topic.
+    pub fn on_unsubscribe(&mut self, callback: impl FnMut(&Topic) + Send + 'static) {
+        self.callbacks.unsubscribe.push(Box::new(callback));
+    }
+
+    /// Calls the callback with every broadcast we publish, before it passes through the
+    /// interceptors.
+    pub fn on_publish(&mut self, callback: impl FnMut(&Topic, &Bytes) + Send + 'static) {
+        self.callbacks.publish.push(Box::new(callback));
+    }
+
+    /// Calls the callback with every broadcast delivered in `Event::Received`.
+    pub fn on_receive(&mut self, callback: impl FnMut(&PeerId, &Topic, &Bytes) + Send + 'static) {
+        self.callbacks.receive.push(Box::new(callback));
+    }
+
+    /// Removes the callbacks registered with `on_subscribe` and friends.
+    pub fn clear_callbacks(&mut self) {
+        self.callbacks = Callbacks::default();
+    }
+
+    /// Keeps the broadcasts published while no subscriber of their topic is connected in the
+    /// outbox, and sends them once a subscriber shows up. The broadcasts left in the outbox are
+    /// replayed right away. Chunked and erasure-coded broadcasts are not kept. The outbox is
+    /// unbounded unless limited by `Config::with_retention`.
+    pub fn set_outbox(&mut self, outbox: impl OutboxStore + 'static) -> io::Result<()> {
+        let mut outbox = Box::new(outbox);
+        let now = self.config.clock.now();
+        self.outboxed.clear();
+        for entry in outbox.replay()? {
+            self.outboxed
+                .entry(entry.topic)
+                .or_default()
+                .push((now, entry));
+        }
+        self.outbox = Some(outbox);
+        let topics: Vec<Topic> = self.outboxed.keys().copied().collect();
+        for topic in topics {
+            self.flush_outbox(&topic);
+        }
+        Ok(())
+    }
+
+    /// Suppresses duplicates with the store instead of the one configured with
+    /// `Config::with_seen_cache`, enabling duplicate suppression if it was not. Ids seen so far
+    /// are forgotten.
+    pub fn set_duplicate_store(&mut self, store: impl DuplicateStore + 'static) {
+        self.seen = Some(Box::new(store));
+    }
+
+    /// Records the ids of the messages seen in the store, so that a restarting node neither
+    /// delivers nor forwards again the messages it handled within `Config::seen_ttl`. The ids
+    /// left in the store are loaded right away, after dropping the expired ones. Has no effect
+    /// without duplicate suppression.
+    pub fn set_seen_store(&mut self, store: impl SeenStore + 'static) -> io::Result<()> {
+        let mut store = Box::new(store);
+        if let (Some(seen), Some(ttl)) = (&mut self.seen, self.config.seen_ttl) {
+            let now = self.config.clock.now();
+            let unix_now = clock::unix_millis(self.config.clock.as_ref());
+            store.prune(unix_now.saturating_sub(ttl.as_millis() as u64))?;
+            let mut ids = store.load()?;
+            // Oldest first, as the cache expects.
+            ids.sort_by_key(|(_, seen_at)| *seen_at);
+            for (id, seen_at) in ids {
+                let age = Duration::from_millis(unix_now.saturating_sub(seen_at));
+                if let Some(seen_at) = now.checked_sub(age) {
+                    seen.insert(id, seen_at);
+                }
+            }
+        }
+        self.seen_store = Some(store);
+        Ok(())
+    }
+
+    /// Saves our subscriptions to the store on every change. The topics left in the store are
+    /// subscribed to right away, and announced to the peers as they connect, which spares the
+    /// application from subscribing again after a restart.
+    pub fn set_subscription_store(
+        &mut self,
+        store: impl SubscriptionStore + 'static,
+    ) -> io::Result<()> {
+        let mut store = Box::new(store);
+        let topics: Vec<Topic> = store
+            .load()?
+            .into_iter()
+            .filter(|topic| !self.subscriptions.contains(topic))
+            .collect();
+        self.subscription_store = Some(store);
+        if topics.is_empty() {
+            self.save_subscriptions();
+        } else {
+            self.subscribe_many(topics);
+        }
+        Ok(())
+    }
+
+    fn save_subscriptions(&mut self) {
+        if let Some(store) = self.subscription_store.as_mut() {
+            let topics: Vec<Topic> = self.subscriptions.iter().copied().collect();
+            if let Err(e) = store.save(&topics) {
+                tracing::warn!(target: BEHAVIOUR, "Failed to save the subscriptions: {e}");
+            }
+        }
+    }
+
+    /// Takes a snapshot of the state worth keeping across restarts.
+    pub fn snapshot(&self) -> BehaviourSnapshot {
+        let now = self.config.clock.now();
+        BehaviourSnapshot {
+            taken_at: clock::unix_millis(self.config.clock.as_ref()),
+            subscriptions: self.subscriptions.iter().copied().collect(),
+            peers: self
+                .routes
+                .peers()
+                .filter_map(|peer| {
+                    let topics = self.routes.topics(peer)?;
+                    Some((*peer, topics.copied().collect::<Vec<_>>()))
+                })
+                .filter(|(_, topics)| !topics.is_empty())
+                .collect(),
+            seen: self
+                .seen
+                .as_ref()
+                .map(|seen| seen.entries(now))
+                .unwrap_or_default(),
+            cooldowns: self.cooldowns(now),
+        }
+    }
+
+    /// Everything known about the connected peers and ourselves, for operators to inspect.
+    pub fn dump_state(&self) -> StateDump {
+        let now = self.config.clock.now();
+        let mut subscriptions: Vec<_> = self.subscriptions.iter().copied().collect();
+        subscriptions.sort();
+        let mut shared_subscriptions: Vec<_> = self
+            .shared_subscriptions
+            .iter()
+            .map(|(topic, group)| (*topic, *group))
+            .collect();
+        shared_subscriptions.sort();
+        let mut peers: Vec<_> = self
+            .routes
+            .peers()
+            .map(|peer| {
+                let mut topics: Vec<_> = self
+                    .routes
+                    .topics(peer)
+                    .into_iter()
+                    .flatten()
+                    .copied()
+                    .collect();
+                topics.sort();
+                let connections = self.connections.get(peer).map_or(&[][..], Vec::as_slice);
+                PeerState {
+                    peer: *peer,
+                    topics,
+                    connections: connections.len(),
+                    protocol: NegotiatedProtocol {
+                        version: self.version(peer),
+                        capabilities: self.capabilities(peer),
+                    },
+                    queue: self.pending(peer),
+                    churn: self.churn(peer),
+                    latency: connections
+                        .iter()
+                        .filter_map(|connection| self.latencies.get(connection))
+                        .min()
+                        .copied(),
+                    cooldown: self
+                        .failed_peers
+                        .get(peer)
+                        .and_then(|until| until.checked_duration_since(now)),
+                }
+            })
+            .collect();
+        peers.sort_by_key(|state| state.peer);
+        StateDump {
+            taken_at: clock::unix_millis(self.config.clock.as_ref()),
+            subscriptions,
+            shared_subscriptions,
+            peers,
+            health: self.health(),
+            config: format!("{:?}", self.config),
+        }
+    }
+
+    /// Exports the peers skipped by the circuit breaker, see `Event::PeerFailed`.
+    pub fn reputations(&self) -> PeerReputations {
+        PeerReputations {
+            taken_at: clock::unix_millis(self.config.clock.as_ref()),
+            cooldowns: self.cooldowns(self.config.clock.now()),
+        }
+    }
+
+    /// Skips the imported peers until their cooldown elapses, less the time elapsed since the
+    /// export. A peer already cooling down keeps the longer of both cooldowns, so that the
+    /// reputations of several nodes can be merged.
+    pub fn import_reputations(&mut self, reputations: PeerReputations) {
+        self.import_cooldowns(reputations.cooldowns, reputations.taken_at);
+    }
+
+    fn cooldowns(&self, now: Instant) -> Vec<(PeerId, Duration)> {
+        self.failed_peers
+            .iter()
+            .filter_map(|(peer, until)| Some((*peer, until.checked_duration_since(now)?)))
+            .collect()
+    }
+
+    /// Resumes cooldowns exported at `taken_at`, in milliseconds since the Unix epoch.
+    fn import_cooldowns(&mut self, cooldowns: Vec<(PeerId, Duration)>, taken_at: u64) {
+        let now = self.config.clock.now();
+        let elapsed = Duration::from_millis(
+            clock::unix_millis(self.config.clock.as_ref()).saturating_sub(taken_at),
+        );
+        for (peer, remaining) in cooldowns {
+            if let Some(remaining) = remaining.checked_sub(elapsed) {
+                let until = self.failed_peers.entry(peer).or_insert(now);
+                *until = (*until).max(now + remaining);
+            }
+        }
+    }
+
+    /// Restores a snapshot, typically at startup. Subscribes to its topics, suppresses the
+    /// duplicates of the messages seen before, and resumes the cooldowns that did not elapse
+    /// since.
+    ///
+    /// The subscriptions of the peers are applied when they connect, so that broadcasts reach
+    /// them before they announce their subscriptions again. They are asked for their current
+    /// subscriptions at the same time, which drops the topics they left in the meantime.
+    pub fn restore(&mut self, snapshot: BehaviourSnapshot) {
+        let now = self.config.clock.now();
+        let downtime = Duration::from_millis(
+            clock::unix_millis(self.config.clock.as_ref()).saturating_sub(snapshot.taken_at),
+        );
+        self.subscribe_many(snapshot.subscriptions);
+        for (peer, topics) in snapshot.peers {
+            if !self.routes.is_connected(&peer) {
+                self.restored_peers.insert(peer, topics);
+            }
+        }
+        if let Some(seen) = &mut self.seen {
+            let mut entries = snapshot.seen;
+            // Oldest first, as the cache expects.
+            entries.sort_by_key(|(_, age)| std::cmp::Reverse(*age));
+            for (id, age) in entries {
+                if let Some(seen_at) = now.checked_sub(age + downtime) {
+                    seen.insert(id, seen_at);
+                }
+            }
+        }
+        self.import_cooldowns(snapshot.cooldowns, snapshot.taken_at);
+    }
+
+    /// Passes every broadcast published or delivered from now on to the journal.
+    pub fn set_journal(&mut self, journal: impl JournalSink + 'static) {
+        self.journal = Some(Box::new(journal));
+    }
+
+    pub fn clear_journal(&mut self) {
+        self.journal = None;
+    }
+
+    /// Passes every event handed to the application from now on to the sink as well, e.g. a
+    /// `JsonEventWriter`.
+    pub fn set_event_sink(&mut self, sink: impl EventSink + 'static) {
+        self.event_sink = Some(Box::new(sink));
+    }
+
+    pub fn clear_event_sink(&mut self) {
+        self.event_sink = None;
+    }
+
+    /// Re-injects journaled broadcasts: published ones are published again, and delivered ones
+    /// are handled as if just received from their peer, subject to the same checks, e.g.
+    /// membership, staleness and duplicate suppression. Useful to reproduce a bug from a
+    /// journal; with a journal installed, the replayed broadcasts are journaled again.
+    pub fn replay(&mut self, records: impl IntoIterator<Item = JournalRecord>) {
+        for record in records {
+            match record.kind {
+                JournalKind::Published => {
+                    self.publish(&record.topic, record.payload, record.metadata, None, None);
+                }
+                JournalKind::Delivered(peer) => {
+                    let message = Broadcast {
+                        topic: record.topic,
+                        payload: record.payload.into(),
+                        metadata: Arc::new(record.metadata),
+                    };
+                    if let Some(event) = self.on_message(peer, None, message) {
+                        self.events.push_back(ToSwarm::GenerateEvent(event));
+                    }
+                }
+            }
+        }
+    }
+
+    fn journal(&mut self, kind: JournalKind, topic: Topic, payload: &Bytes, metadata: &Metadata) {
+        if let Some(journal) = self.journal.as_mut() {
+            journal.record(&JournalRecord {
+                kind,
+                at: clock::unix_millis(self.config.clock.as_ref()),
+                topic,
+                payload: payload.clone(),
+                metadata: metadata.clone(),
+            });
+        }
+    }
+
+    /// Sends the broadcasts of the topic kept in the outbox, if a subscriber is connected.
+    fn flush_outbox(&mut self, topic: &Topic) {
+        if !self.has_routable_peers(topic) {
+            return;
+        }
+        for (_, entry) in self.outboxed.remove(topic).unwrap_or_default() {
+            self.publish(topic, entry.payload, entry.metadata, None, None);
+            self.ack_outbox(entry.id);
+        }
+    }
+
+    fn ack_outbox(&mut self, id: u64) {
+        if let Some(outbox) = self.outbox.as_mut() {
+            if let Err(e) = outbox.ack(id) {
+                tracing::warn!(
+                    target: BEHAVIOUR,
+                    "Failed to acknowledge a broadcast in the outbox: {e}"
+                );
+            }
+        }
+    }
+
+    /// Writes a broadcast of a write-ahead topic to the outbox. Returns the id it is
+    /// acknowledged with once flushed to every subscriber.
+    fn write_ahead(&mut self, topic: &Topic, msg: &Bytes, metadata: &Metadata) -> Option<u64> {
+        if !self.config.write_ahead.contains(topic) {
+            return None;
+        }
+        let Some(outbox) = self.outbox.as_mut() else {
+            tracing::debug!(
+                target: BEHAVIOUR,
+                "Publishing on a write-ahead topic without an outbox"
+            );
+            return None;
+        };
+        match outbox.append(topic, msg, metadata) {
+            Ok(id) => Some(id),
+            Err(e) => {
+                tracing::warn!(
+                    target: BEHAVIOUR,
+                    "Failed to write a broadcast ahead to the outbox: {e}"
+                );
+                None
+            }
+        }
+    }
+
+    /// Records that the peer flushed the broadcasts written ahead with these ids, acknowledging
+    /// the ones flushed to every subscriber.
+    fn on_flushed(&mut self, peer: &PeerId, ids: Vec<u64>) {
+        for id in ids {
+            let Some((_, _, pending)) = self.written_ahead.get_mut(&id) else {
+                continue;
+            };
+            pending.remove(peer);
+            if pending.is_empty() {
+                self.written_ahead.remove(&id);
+                self.ack_outbox(id);
+            }
+        }
+    }
+
+    /// Sends the broadcasts written ahead with these ids again, as the peer lost them along with
+    /// its outbound substream before they were flushed.
+    fn on_unflushed(&mut self, peer: &PeerId, ids: Vec<u64>) {
+        for id in ids {
+            let Some((_, message, pending)) = self.written_ahead.get(&id) else {
+                continue;
+            };
+            if !pending.contains(peer) {
+                continue;
+            }
+            self.events.push_back(ToSwarm::NotifyHandler {
+                peer_id: *peer,
+                event: Outbound {
+                    ack: Some(id),
+                    ..message.clone().into()
+                }
+                .into(),
+                handler: self.handler_for(peer),
+            });
+        }
+    }
+
+    /// Drops the oldest broadcasts kept in the outbox for the topics whose `Retention` they
+    /// exceed.
+    fn enforce_retention(&mut self) {
+        let now = self.config.clock.now();
+        for (topic, entries) in &mut self.outboxed {
+            let Some(retention) = self.config.retention.get(topic) else {
+                continue;
+            };
+            let mut len = entries.len();
+            let mut bytes: usize = entries.iter().map(|(_, entry)| entry.payload.len()).sum();
+            let expired = entries
+                .iter()
+                .take_while(|(kept_at, entry)| {
+                    let exceeded = retention.max_messages.is_some_and(|max| len > max)
+                        || retention.max_bytes.is_some_and(|max| bytes > max)
+                        || retention
+                            .max_age
+                            .is_some_and(|max| now.saturating_duration_since(*kept_at) > max);
+                    if exceeded {
+                        len -= 1;
+                        bytes -= entry.payload.len();
+                    }
+                    exceeded
+                })
+                .count();
+            if expired == 0 {
+                continue;
+            }
+            tracing::debug!(
+                target: BEHAVIOUR,
+                "Dropping {expired} broadcasts kept in the outbox beyond retention"
+            );
+            for (_, entry) in entries.drain(..expired) {
+                if let Some(outbox) = self.outbox.as_mut() {
+                    if let Err(e) = outbox.ack(entry.id) {
+                        tracing::warn!(
+                            target: BEHAVIOUR,
+                            "Failed to drop a broadcast from the outbox: {e}"
+                        );
+                    }
+                }
+            }
+        }
+        self.outboxed.retain(|_, entries| !entries.is_empty());
+    }
+
+    /// Starts replaying the history of the topic to a peer that just subscribed to it.
+    fn start_replay(&mut self, peer: PeerId, topic: Topic) {
+        if let Some(history) = self
+            .history
+            .get(&topic)
+            .filter(|history| !history.is_empty())
+        {
+            self.replays
+                .insert((peer, topic), (history.first_seq(), false));
+        }
+    }
+
+    /// Sends the next broadcasts of every replay in progress, within the replay rate.
+    fn replay_history(&mut self) {
+        let ticks_per_second =
+            (Duration::from_secs(1).as_millis() / History::REPLAY_TICK.as_millis()) as u32;
+        let budget = u64::from((self.config.history_replay_rate / ticks_per_second).max(1));
+        let replays: Vec<_> = self
+            .replays
+            .iter()
+            .map(|(key, replay)| (*key, *replay))
+            .collect();
+        for ((peer, topic), (next, throttled)) in replays {
+            let Some(history) = self.history.get(&topic) else {
+                self.replays.remove(&(peer, topic));
+                continue;
+            };
+            // Broadcasts dropped by the retention in the meantime are skipped.
+            let start = next.max(history.first_seq());
+            let end = history.end_seq().min(start + budget);
+            let mut messages = Vec::new();
+            for seq in start..end {
+                let Some((payload, metadata)) = history.get(seq) else {
+                    break;
+                };
+                let remaining = history.end_seq() - seq - 1;
+                let metadata = Metadata {
+                    replay_remaining: Some(remaining.min(u32::MAX.into()) as u32),
+                    delivery_id: None,
+                    ..metadata.clone()
+                };
+                messages.push(Broadcast {
+                    topic,
+                    payload: Payload::compact(payload.clone()),
+                    metadata: Arc::new(metadata),
+                });
+            }
+            if end == history.end_seq() {
+                self.replays.remove(&(peer, topic));
+            } else {
+                self.replays.insert((peer, topic), (end, true));
+                if !throttled {
+                    let event =
+                        Event::PeerThrottled(peer, Some(topic), ThrottleKind::HistoryReplay);
+                    self.events.push_back(ToSwarm::GenerateEvent(event));
+                }
+            }
+            for message in messages {
+                self.events.push_back(ToSwarm::NotifyHandler {
+                    peer_id: peer,
+                    event: message.into(),
+                    handler: self.handler_for(&peer),
+                });
+            }
+        }
+    }
+
+    /// Drops the ids that expired from the seen store.
+    fn prune_seen_store(&mut self) {
+        let (Some(store), Some(ttl)) = (self.seen_store.as_mut(), self.config.seen_ttl) else {
+            return;
+        };
+        let now = clock::unix_millis(self.config.clock.as_ref());
+        if let Err(e) = store.prune(now.saturating_sub(ttl.as_millis() as u64)) {
+            tracing::warn!(target: BEHAVIOUR, "Failed to prune the seen store: {e}");
+        }
+    }
+
+    fn has_routable_peers(&self, topic: &Topic) -> bool {
+        self.routes
+            .route(topic)
+            .is_some_and(|peers| peers.iter().any(|peer| self.is_routable(peer)))
+    }
+
+    /// Returns `true` if one of our subscriptions matches the topic.
+    fn is_subscribed(&self, topic: &Topic) -> bool {
+        self.subscriptions.contains(topic)
+            || !self.config.topic_matcher.is_exact()
+                && self
+                    .subscriptions
+                    .iter()
+                    .any(|subscription| self.routes.matches(subscription, topic))
+    }
+
+    fn is_member(&self, peer: &PeerId) -> bool {
+        self.membership
+            .as_ref()
+            .is_none_or(|membership| membership.is_member(peer))
+    }
+
+    /// Returns `true` if messages may be routed to the peer, i.e. it is a member that is not
+    /// cooling down after repeated failures.
+    fn is_routable(&self, peer: &PeerId) -> bool {
+        self.skip_reason(peer).is_none()
+    }
+
+    /// Returns why messages are not routed to the peer, if they are not.
+    fn skip_reason(&self, peer: &PeerId) -> Option<SkipReason> {
+        if !self.is_member(peer) {
+            return Some(SkipReason::Denied);
+        }
+        self.failed_peers
+            .get(peer)
+            .filter(|until| self.config.clock.now() < **until)
+            .map(|_| SkipReason::CoolingDown)
+    }
+
+    /// Returns the routable subscribers of the topic, reporting the other ones as skipped.
+    fn routable_subscribers(&self, topic: &Topic, report: &mut BroadcastReport) -> Vec<PeerId> {
+        let mut routable = Vec::new();
+        for peer in self
+            .routes
+            .route(topic)
+            .iter()
+            .flat_map(|peers| peers.iter())
+        {
+            match self.skip_reason(peer) {
+                Some(reason) => report.peers.push((*peer, PeerOutcome::Skipped(reason))),
+                None => routable.push(*peer),
+            }
+        }
+        routable
+    }
+
+    /// Picks the connection a message to the peer is sent over, according to
+    /// `Config::connection_preference`.
+    fn handler_for(&self, peer: &PeerId) -> NotifyHandler {
+        let connections = self.connections.get(peer).map(Vec::as_slice);
+        let connection = match (self.config.connection_preference, connections) {
+            (ConnectionPreference::Newest, Some(connections)) => connections.last(),
+            (ConnectionPreference::LowestLatency, Some(connections)) => connections
+                .iter()
+                .min_by_key(|connection| self.latencies.get(connection)),
+            _ => None,
+        };
+        connection.map_or(NotifyHandler::Any, |connection| {
+            NotifyHandler::One(*connection)
+        })
+    }
+
+    pub fn subscribed(&self) -> impl Iterator<Item = &Topic> + '_ {
+        self.subscriptions.iter()
+    }
+
+    pub fn peers(&self, topic: &Topic) -> Option<impl Iterator<Item = &PeerId> + '_> {
+        self.routes.subscribers(topic).map(|peers| peers.iter())
+    }
+
+    pub fn topics(&self, peer: &PeerId) -> Option<impl Iterator<Item = &Topic> + '_> {
+        self.routes.topics(peer)
+    }
+
+    /// Optional features used with the peer, i.e. the ones both sides announced. `None` until
+    /// the peer sent its handshake, which peers speaking version 1 of the protocol never do.
+    pub fn capabilities(&self, peer: &PeerId) -> Option<Capabilities> {
+        self.capabilities.get(peer).copied()
+    }
+
+    /// Version of the protocol we send to the peer in, once we opened a substream to it.
+    pub fn version(&self, peer: &PeerId) -> Option<Version> {
+        self.versions.get(peer).copied()
+    }
+
+    /// Subscribers and recent activity of the topic, e.g. for dashboards. Kept for the topics
+    /// broadcasts were published on or received on while subscribed, until they are idle for a
+    /// few `TopicStats::WINDOW`s.
+    pub fn topic_stats(&self, topic: &Topic) -> TopicStats {
+        let subscribers = self.routes.route(topic).map_or(0, |peers| peers.len());
+        let now = self.config.clock.now();
+        match self.topic_activity.get(topic) {
+            Some(activity) => activity.stats(subscribers, now),
+            None => TopicStats {
+                subscribers,
+                ..TopicStats::default()
+            },
+        }
+    }
+
+    fn record_activity(&mut self, topic: &Topic, bytes: usize) {
+        let now = self.config.clock.now();
+        self.topic_activity
+            .entry(*topic)
+            .or_insert_with(|| TopicActivity::new(now))
+            .record(bytes, now);
+    }
+
+    /// Records a broadcast received on the topic, unless we are not subscribed to it: peers
+    /// could otherwise grow the activity without bound by naming new topics.
+    fn record_received(&mut self, topic: &Topic, bytes: usize) {
+        if self.is_subscribed(topic) {
+            self.record_activity(topic, bytes);
+        }
+    }
+
+    /// Forgets the activity of the topics idle for a few `TopicStats::WINDOW`s.
+    fn evict_idle_topics(&mut self) {
+        let now = self.config.clock.now();
+        self.topic_activity
+            .retain(|_, activity| !activity.is_idle(now));
+    }
+
+    /// Aggregate state of the behaviour, e.g. to decide whether a node is ready.
+    pub fn health(&self) -> Health {
+        let now = self.config.clock.now();
+        let stuck_after = self
+            .config
+            .slow_consumer_threshold
+            .unwrap_or(Health::STUCK_AFTER);
+        let mut health = Health {
+            recent_errors: self.errors.counts(now),
+            ..Health::default()
+        };
+        for peer in self.routes.peers() {
+            health.connected_peers += 1;
+            if self
+                .routes
+                .topics(peer)
+                .is_some_and(|mut topics| topics.next().is_some())
+            {
+                health.subscribers += 1;
+            }
+            if self
+                .pending(peer)
+                .oldest_age
+                .is_some_and(|age| age > stuck_after)
+            {
+                health.stuck_queues.push(*peer);
+            }
+        }
+        for (peer, until) in &self.failed_peers {
+            if now < *until {
+                health.cooling_down.push((*peer, *until - now));
+            }
+        }
+        health.self_test = self.prober.last();
+        health
+    }
+
+    fn record_error(&mut self, kind: ErrorKind, count: usize) {
+        self.errors.record(kind, count, self.config.clock.now());
+    }
+
+    /// How often the peer reconnected and restarted substreams, e.g. to spot flaky links. `None`
+    /// for peers not connected within `Churn::MEMORY`.
+    pub fn churn(&self, peer: &PeerId) -> Option<Churn> {
+        self.churn.get(peer)
+    }
+
+    /// Messages waiting to be sent to the peer, over all connections to it, e.g. to apply
+    /// backpressure before publishing more. Empty if the peer is not connected.
+    pub fn pending(&self, peer: &PeerId) -> QueueStats {
+        let now = self.config.clock.now();
+        let connections = self.connections.get(peer).into_iter().flatten();
+        connections
+            .filter_map(|connection| self.queues.get(connection))
+            .fold(QueueStats::default(), |stats, (messages, bytes, oldest)| {
+                let age = oldest.map(|oldest| now.saturating_duration_since(oldest));
+                QueueStats {
+                    messages: stats.messages + messages,
+                    bytes: stats.bytes + bytes,
+                    oldest_age: stats.oldest_age.max(age),
+                }
+            })
+    }
+
+    /// Protocol version and optional features negotiated with each connected peer, e.g. to
+    /// track the rollout of a new version across the network.
+    pub fn negotiated_protocols(&self) -> impl Iterator<Item = (&PeerId, NegotiatedProtocol)> + '_ {
+        self.routes.peers().map(move |peer| {
+            let negotiated = NegotiatedProtocol {
+                version: self.version(peer),
+                capabilities: self.capabilities(peer),
+            };
+            (peer, negotiated)
+        })
+    }
+
+    pub fn subscribe(&mut self, topic: Topic) {
+        self.subscribe_many(std::iter::once(topic))
+    }
+
+    /// Subscribes to several topics at once. Peers learn about all of them in a single frame.
+    pub fn subscribe_many(&mut self, topics: impl IntoIterator<Item = Topic>) {
+        let mut messages = Vec::new();
+        for topic in topics {
+            if self.subscriptions.insert(topic) {
+                if let Some(metrics) = &mut self.metrics {
+                    metrics.subscribe(&topic);
+                }
+            }
+            self.callbacks.subscribed(&topic);
+            self.shared_subscriptions.remove(&topic);
+            messages.push(Message::Subscribe { topic });
+        }
+        self.save_subscriptions();
+        self.announce(messages);
+        self.update_all_shared();
+    }
+
+    /// Subscribes to the topic as a member of the named group. The peers publishing on the
+    /// topic send each broadcast to a single member of the group, picked according to their
+    /// `Config::group_selection`, so that the members share the load of the topic.
+    ///
+    /// Peers that did not negotiate `Capabilities::SHARED_SUBSCRIPTIONS` are told about a plain
+    /// subscription instead, and keep sending every broadcast to each member.
+    pub fn subscribe_shared(&mut self, topic: Topic, group: Topic) {
+        if self.subscriptions.insert(topic) {
+            if let Some(metrics) = &mut self.metrics {
+                metrics.subscribe(&topic);
+            }
+        }
+        self.callbacks.subscribed(&topic);
+        self.shared_subscriptions.insert(topic, group);
+        self.save_subscriptions();
+        self.announce(vec![Message::SubscribeShared { topic, group }]);
+        self.update_all_shared();
+    }
+
+    /// Group of our subscription to the topic, if we subscribed as a member of one.
+    pub fn shared_group(&self, topic: &Topic) -> Option<&Topic> {
+        self.shared_subscriptions.get(topic)
+    }
+
+    pub fn unsubscribe(&mut self, topic: &Topic) {
+        self.unsubscribe_many(std::iter::once(*topic))
+    }
+
+    /// Unsubscribes from several topics at once. Peers learn about all of them in a single frame.
+    pub fn unsubscribe_many(&mut self, topics: impl IntoIterator<Item = Topic>) {
+        let mut messages = Vec::new();
+        for topic in topics {
+            if self.subscriptions.remove(&topic) {
+                if let Some(metrics) = &mut self.metrics {
+                    metrics.unsubscribe(&topic);
+                }
+            }
+            self.callbacks.unsubscribed(&topic);
+            self.shared_subscriptions.remove(&topic);
+            messages.push(Message::Unsubscribe { topic });
+        }
+        self.save_subscriptions();
+        self.announce(messages);
+        self.update_all_shared();
+    }
+
+    fn update_all_shared(&mut self) {
+        if self.config.prune_grace.is_none() {
+            return;
+        }
+        let peers: Vec<PeerId> = self.routes.peers().copied().collect();
+        for peer in peers {
+            self.update_shared(&peer);
+        }
+    }
+
+    /// Tells the handlers of the peer whether we share a subscribed topic with it, when that
+    /// changed.
+    fn update_shared(&mut self, peer: &PeerId) {
+        if self.config.prune_grace.is_none() {
+            return;
+        }
+        let shared = self
+            .routes
+            .topics(peer)
+            .is_some_and(|mut topics| topics.any(|t| self.subscriptions.contains(t)));
+        let changed = if shared {
+            self.shared_peers.insert(*peer)
+        } else {
+            self.shared_peers.remove(peer)
+        };
+        if !changed {
+            return;
+        }
+        if !shared {
+            self.events
+                .push_back(ToSwarm::GenerateEvent(Event::NoSharedTopics(*peer)));
+        }
+        for connection in self.connections.get(peer).into_iter().flatten() {
+            self.events.push_back(ToSwarm::NotifyHandler {
+                peer_id: *peer,
+                event: Command::SharesTopics(shared),
+                handler: NotifyHandler::One(*connection),
+            });
+        }
+    }
+
+    /// Sends the subscription changes to all connected peers, batched into as few frames as
+    /// possible.
+    fn announce(&mut self, messages: Vec<Message>) {
+        let plain = messages.iter().cloned().map(Self::without_group).collect();
+        let credits: Vec<_> = messages
+            .iter()
+            .filter_map(|message| match message {
+                Subscribe { topic } | SubscribeShared { topic, .. } => {
+                    let window = self.config.flow_control.get(topic)?;
+                    Some(Credit {
+                        topic: *topic,
+                        credits: *window,
+                    })
+                }
+                _ => None,
+            })
+            .collect();
+        let plain = Message::batch(plain, self.config.max_buf_size);
+        let frames = Message::batch(messages, self.config.max_buf_size);
+        for peer in self.routes.peers() {
+            let frames = if self.shares_groups(peer) {
+                &frames
+            } else {
+                &plain
+            };
+            for frame in frames {
+                self.events.push_back(ToSwarm::NotifyHandler {
+                    peer_id: *peer,
+                    event: frame.clone().into(),
+                    handler: self.handler_for(peer),
+                });
+            }
+            if !self.grants_credits(peer) {
+                continue;
+            }
+            // Every handler tracks its own credits.
+            for connection_id in self.connections.get(peer).into_iter().flatten() {
+                for credit in &credits {
+                    self.events.push_back(ToSwarm::NotifyHandler {
+                        peer_id: *peer,
+                        event: credit.clone().into(),
+                        handler: NotifyHandler::One(*connection_id),
+                    });
+                }
+            }
+        }
+    }
+
+    /// Sends all topics we are subscribed to to the peer, which replaces its view of our
+    /// subscriptions. Returns `false` if the peer is not connected.
+    ///
+    /// Peers speaking version 1 of the protocol only learn about the topics missing from their
+    /// view, as they cannot be told about the topics to drop from it.
+    pub fn resync(&mut self, peer: &PeerId) -> bool {
+        if !self.routes.is_connected(peer) {
+            return false;
+        }
+        let topics: Vec<Topic> = self.subscriptions.iter().copied().collect();
+        // At most one snapshot is pending for each peer, however often it asks: one not handed
+        // to the handler yet is brought up to date, and the handler replaces a queued one.
+        let pending = self.events.iter_mut().find_map(|event| match event {
+            ToSwarm::NotifyHandler {
+                peer_id,
+                event:
+                    Command::Send(Outbound {
+                        message: Message::Snapshot { topics },
+                        ..
+                    }),
+                ..
+            } if peer_id == peer => Some(topics),
+            _ => None,
+        });
+        if let Some(pending) = pending {
+            *pending = topics;
+            return true;
+        }
+        let outbound = Outbound {
+            supersede_key: Some(SNAPSHOT_KEY),
+            ..Message::Snapshot { topics }.into()
+        };
+        self.events.push_back(ToSwarm::NotifyHandler {
+            peer_id: *peer,
+            event: outbound.into(),
+            handler: self.handler_for(peer),
+        });
+        true
+    }
+
+    /// Asks the peer for its current subscriptions, reported in `Event::PeerSubscriptions` once
+    /// it answers. Our view of its subscriptions is healed along the way, as with periodic
+    /// resyncs. Returns `false` if the peer is not connected or only speaks version 1 of the
+    /// protocol, which cannot answer.
+    pub fn query_subscriptions(&mut self, peer: &PeerId) -> bool {
+        if !self.routes.is_connected(peer) || self.versions.get(peer) == Some(&Version::V1) {
+            return false;
+        }
+        self.subscription_queries.insert(*peer);
+        self.events.push_back(ToSwarm::NotifyHandler {
+            peer_id: *peer,
+            event: Message::Query.into(),
+            handler: self.handler_for(peer),
+        });
+        true
+    }
+
+    /// Tears down the substreams to the peer and drops the messages queued for it, then announces
+    /// our subscriptions again and heals our view of its subscriptions, as an escape hatch when
+    /// the session with the peer appears wedged. The circuit breaker is reset for the peer too.
+    ///
+    /// Returns `false` if the peer is not connected.
+    pub fn reset_peer(&mut self, peer: &PeerId) -> bool {
+        let Some(connections) = self.connections.get(peer).cloned() else {
+            return false;
+        };
+        self.events.retain(|event| {
+            !matches!(
+                event,
+                ToSwarm::NotifyHandler { peer_id, event: Command::Send(_), .. } if peer_id == peer
+            )
+        });
+        self.failed_peers.remove(peer);
+        for connection in connections {
+            self.abort_stream(*peer, connection);
+            self.events.push_back(ToSwarm::NotifyHandler {
+                peer_id: *peer,
+                event: Command::Reset,
+                handler: NotifyHandler::One(connection),
+            });
+        }
+        self.announce_subscriptions(peer);
+        if self.versions.get(peer) != Some(&Version::V1) {
+            self.events.push_back(ToSwarm::NotifyHandler {
+                peer_id: *peer,
+                event: Message::Query.into(),
+                handler: self.handler_for(peer),
+            });
+        }
+        true
+    }
+
+    /// Rolls out a new config without disconnecting. New connections use it, and the handlers of
+    /// the existing ones apply it in place: messages queued for a peer are kept, the inbound
+    /// substream decodes the following frames with the new limits, and the outbound substream is
+    /// replaced once flushed, so that the peer learns the new limits and capabilities from its
+    /// handshake.
+    ///
+    /// Enabling or disabling the self-test subscribes to or unsubscribes from its topic. A
+    /// changed delivery window or duplicate filter starts out empty, so broadcasts received
+    /// before may be delivered again. The topic matcher is kept, as the subscriptions of the
+    /// peers were routed with it.
+    pub fn reconfigure(&mut self, mut config: Config) {
+        config.topic_matcher = self.config.topic_matcher.clone();
+        let self_test = Topic::new(SelfTest::TOPIC);
+        match (self.config.self_test, config.self_test) {
+            (None, Some(_)) if self.subscriptions.insert(self_test) => {
+                self.announce(vec![Subscribe { topic: self_test }]);
+            }
+            (Some(_), None) if self.subscriptions.remove(&self_test) => {
+                self.announce(vec![Unsubscribe { topic: self_test }]);
+            }
+            _ => {}
+        }
+        if config.self_test != self.config.self_test {
+            self.self_test_timer = None;
+        }
+        if config.delivery_window != self.config.delivery_window {
+            self.delivery_window = config.delivery_window.map(DeliveryWindow::new);
+        }
+        if (
+            config.seen_ttl,
+            config.seen_capacity,
+            config.seen_false_positive_rate,
+        ) != (
+            self.config.seen_ttl,
+            self.config.seen_capacity,
+            self.config.seen_false_positive_rate,
+        ) {
+            self.seen = seen::from_config(&config);
+        }
+        for (peer, connections) in &self.connections {
+            for connection in connections {
+                self.events.push_back(ToSwarm::NotifyHandler {
+                    peer_id: *peer,
+                    event: Command::Reconfigure(Box::new(config.clone())),
+                    handler: NotifyHandler::One(*connection),
+                });
+            }
+        }
+        self.config = config;
+    }
+
+    /// Asks every connected member for a snapshot of its subscriptions.
+    fn query_all_subscriptions(&mut self) {
+        let peers: Vec<PeerId> = self.routes.peers().copied().collect();
+        for peer in peers {
+            if !self.is_member(&peer) {
+                continue;
+            }
+            self.events.push_back(ToSwarm::NotifyHandler {
+                peer_id: peer,
+                event: Message::Query.into(),
+                handler: self.handler_for(&peer),
+            });
+        }
+    }
+
+    /// Replaces our view of the subscriptions of the peer, emitting events for the differences.
+    fn on_snapshot(&mut self, peer: PeerId, topics: Vec<Topic>) {
+        if self.subscription_queries.remove(&peer) {
+            let mut topics = topics.clone();
+            topics.sort();
+            self.events
+                .push_back(ToSwarm::GenerateEvent(Event::PeerSubscriptions(
+                    peer, topics,
+                )));
+        }
+        let topics: FnvHashSet<Topic> = topics.into_iter().collect();
+        let known: FnvHashSet<Topic> = self
+            .routes
+            .topics(&peer)
+            .into_iter()
+            .flatten()
+            .copied()
+            .collect();
+        if known != topics {
+            tracing::debug!(target: BEHAVIOUR, "Healing diverged subscriptions of {peer}");
+        }
+        let stale = known
+            .difference(&topics)
+            .copied()
+            .map(|topic| Unsubscribe { topic });
+        let missing = topics
+            .difference(&known)
+            .copied()
+            .map(|topic| Subscribe { topic });
+        for message in stale.chain(missing).collect::<Vec<_>>() {
+            if let Some(event) = self.on_message(peer, None, message) {
+                self.events.push_back(ToSwarm::GenerateEvent(event));
+            }
+        }
+    }
+
+    /// Broadcasts a message to the subscribers of the topic. The report tells what became of it
+    /// for each of them.
+    pub fn broadcast(&mut self, topic: &Topic, msg: Bytes) -> BroadcastReport {
+        self.publish(topic, msg, Metadata::default(), None, None)
+    }
+
+    /// Broadcasts a message along with metadata, which subscribers receive in `Event::Received`.
+    ///
+    /// Subscribers that only speak version 1 of the protocol receive the payload without the
+    /// metadata, and so do the subscribers of erasure-coded topics.
+    pub fn broadcast_with_metadata(
+        &mut self,
+        topic: &Topic,
+        msg: Bytes,
+        metadata: Metadata,
+    ) -> BroadcastReport {
+        self.publish(topic, msg, metadata, None, None)
+    }
+
+    /// Broadcasts a message that replaces any message of the same topic published with the same
+    /// key that is still queued for a peer. Useful when only the latest message is worth sending,
+    /// e.g. a state snapshot.
+    pub fn broadcast_superseding(
+        &mut self,
+        topic: &Topic,
+        msg: Bytes,
+        key: u64,
+    ) -> BroadcastReport {
+        self.publish(topic, msg, Metadata::default(), Some(key), None)
+    }
+
+    /// Broadcasts a message that is discarded from the queue of each peer it was not sent to by
+    /// the deadline, as measured by `Config::clock`, and reported in `Event::Expired`. Useful on
+    /// latency-critical topics, where a stale message is worse than none.
+    pub fn broadcast_with_deadline(
+        &mut self,
+        topic: &Topic,
+        msg: Bytes,
+        deadline: Instant,
+    ) -> BroadcastReport {
+        self.publish(topic, msg, Metadata::default(), None, Some(deadline))
+    }
+
+    /// Broadcasts a request whose replies are expected on the `reply_to` topic, which the
+    /// caller should be subscribed to. Returns the correlation id that the replies carry in
+    /// their `Metadata`.
+    pub fn publish_request(&mut self, topic: &Topic, msg: Bytes, reply_to: Topic) -> u64 {
+        let correlation_id = self.next_correlation_id;
+        self.next_correlation_id = correlation_id.wrapping_add(1);
+        let metadata = Metadata::default()
+            .with_correlation_id(correlation_id)
+            .with_reply_to(reply_to);
+        self.publish(topic, msg, metadata, None, None);
+        correlation_id
+    }
+
+    /// Broadcasts a reply to a request on the topic the request asked for, given the metadata
+    /// of the request. Returns `false` if the request did not ask for replies.
+    pub fn publish_reply(&mut self, request: &Metadata, msg: Bytes) -> bool {
+        let Some(reply_to) = request.reply_to else {
+            return false;
+        };
+        let metadata = Metadata {
+            correlation_id: request.correlation_id,
+            ..Metadata::default()
+        };
+        self.publish(&reply_to, msg, metadata, None, None);
+        true
+    }
+
+    /// Sends a message on the topic to a single connected peer, whether or not it subscribed to
+    /// the topic. The peer receives it as a regular `Event::Received`, which makes it possible
+    /// to reply to a publisher without a separate protocol.
+    ///
+    /// Returns `false` if the peer is not connected or not a member.
+    pub fn send_to(&mut self, peer: &PeerId, topic: &Topic, msg: Bytes) -> bool {
+        if !self.routes.is_connected(peer) || !self.is_routable(peer) {
+            return false;
+        }
+
+        let metadata = self.outbound_metadata(Metadata::default());
+        self.callbacks.published(topic, &msg);
+        let Some((msg, metadata)) = self.intercept_outbound(topic, msg, metadata) else {
+            return false;
+        };
+        let msg = Message::Broadcast {
+            topic: *topic,
+            payload: Payload::compact(msg),
+            metadata,
+        };
+        if let Some(metrics) = &mut self.metrics {
+            metrics.msg_sent(topic, msg.len());
+        }
+        self.events.push_back(ToSwarm::NotifyHandler {
+            peer_id: *peer,
+            event: msg.into(),
+            handler: self.handler_for(peer),
+        });
+        true
+    }
+
+    /// Sends an application-defined control payload scoped to the topic to a connected peer,
+    /// which receives it as `Event::ReceivedControl`. Control frames share the substreams of
+    /// broadcasts, but skip the interceptors, duplicate suppression and forwarding, which suits
+    /// small coordination messages.
+    ///
+    /// Returns `false` if the peer is not connected, not a member, or did not negotiate
+    /// `Capabilities::CONTROL`.
+    pub fn send_control(&mut self, peer: &PeerId, topic: &Topic, payload: Bytes) -> bool {
+        if !self.routes.is_connected(peer)
+            || !self.is_routable(peer)
+            || !self
+                .capabilities(peer)
+                .is_some_and(|capabilities| capabilities.contains(Capabilities::CONTROL))
+        {
+            return false;
+        }
+        self.events.push_back(ToSwarm::NotifyHandler {
+            peer_id: *peer,
+            event: Message::Control {
+                topic: *topic,
+                payload,
+            }
+            .into(),
+            handler: self.handler_for(peer),
+        });
+        true
+    }
+
+    /// Drops the broadcasts of the topic that are still waiting to be sent to any peer, e.g. once
+    /// the round they belong to is over.
+    pub fn cancel(&mut self, topic: &Topic) {
+        self.events.retain_mut(|event| match event {
+            ToSwarm::NotifyHandler {
+                event: Command::Send(outbound),
+                ..
+            } => {
+                outbound
+                    .message
+                    .discard(|message| message.is_broadcast_on(topic));
+                !outbound.message.is_empty()
+            }
+            _ => true,
+        });
+        for (peer, connections) in &self.connections {
+            for connection in connections {
+                self.events.push_back(ToSwarm::NotifyHandler {
+                    peer_id: *peer,
+                    event: Command::Cancel(*topic),
+                    handler: NotifyHandler::One(*connection),
+                });
+            }
+        }
+    }
+
+    /// Broadcasts several messages at once. Each peer receives the messages of the topics it
+    /// subscribed to in a single frame rather than one frame per message.
+    pub fn broadcast_many(&mut self, messages: &[(Topic, Bytes)]) {
+        let mut frames: FnvHashMap<PeerId, Vec<Message>> = FnvHashMap::default();
+        for (topic, msg) in messages {
+            self.remember_own(topic, msg);
+            self.callbacks.published(topic, msg);
+
+            #[cfg(feature = "erasure")]
+            if let Some(coding) = self.config.erasure_coding.get(topic).copied() {
+                self.broadcast_shards(topic, coding, msg);
+                continue;
+            }
+
+            let metadata = self.outbound_metadata(Metadata::default());
+            let Some((msg, metadata)) = self.intercept_outbound(topic, msg.clone(), metadata)
+            else {
+                continue;
+            };
+            let msg = Message::Broadcast {
+                topic: *topic,
+                payload: Payload::compact(msg),
+                metadata,
+            };
+            if let Some(peers) = self.routes.route(topic) {
+                for peer in peers.iter().filter(|peer| self.is_routable(peer)) {
+                    frames.entry(*peer).or_default().push(msg.clone());
+                }
+            }
+
+            if let Some(metrics) = &mut self.metrics {
+                metrics.msg_sent(topic, msg.len());
+                metrics.register_published_message(topic);
+            }
+            self.record_activity(topic, msg.len());
+        }
+
+        for (peer, messages) in frames {
+            for frame in Message::batch(messages, self.config.max_buf_size) {
+                self.events.push_back(ToSwarm::NotifyHandler {
+                    peer_id: peer,
+                    event: frame.into(),
+                    handler: self.handler_for(&peer),
+                });
+            }
+        }
+    }
+
+    /// Completes the metadata of a broadcast about to be sent.
+    fn outbound_metadata(&self, mut metadata: Metadata) -> Arc<Metadata> {
+        if self.config.timestamps {
+            metadata.timestamp = Some(clock::unix_millis(self.config.clock.as_ref()));
+        }
+        if metadata.trace.is_none() {
+            metadata.trace = self.config.trace_context.and_then(|capture| capture());
+        }
+        metadata.skew = None;
+        Arc::new(metadata)
+    }
+
+    /// Passes a broadcast about to be sent through the interceptors. Returns `None` if one of
+    /// them dropped it.
+    fn intercept_outbound(
+        &mut self,
+        topic: &Topic,
+        mut msg: Bytes,
+        mut metadata: Arc<Metadata>,
+    ) -> Option<(Bytes, Arc<Metadata>)> {
+        for interceptor in &mut self.interceptors {
+            if !interceptor.on_outbound(topic, &mut msg, Arc::make_mut(&mut metadata)) {
+                tracing::trace!(
+                    target: BEHAVIOUR,
+                    "Not sending a message dropped by an interceptor"
+                );
+                return None;
+            }
+        }
+        Some((msg, metadata))
+    }
+
+    /// Remembers our own broadcasts so they are not delivered back to us.
+    fn remember_own(&mut self, topic: &Topic, msg: &[u8]) {
+        if self.seen.is_some() {
+            self.mark_seen((self.config.message_id_fn)(topic, msg));
+        }
+    }
+
+    /// Records the id in the duplicate filter and the seen store. Returns `false` if it is a
+    /// duplicate.
+    fn mark_seen(&mut self, id: MessageId) -> bool {
+        let Some(seen) = &mut self.seen else {
+            return true;
+        };
+        if !seen.insert(id, self.config.clock.now()) {
+            return false;
+        }
+        if let Some(store) = self.seen_store.as_mut() {
+            let now = clock::unix_millis(self.config.clock.as_ref());
+            if let Err(e) = store.insert(id, now) {
+                tracing::warn!(
+                    target: BEHAVIOUR,
+                    "Failed to record message {id:?} in the seen store: {e}"
+                );
+            }
+        }
+        true
+    }
+
+    fn publish(
+        &mut self,
+        topic: &Topic,
+        msg: Bytes,
+        metadata: Metadata,
+        supersede_key: Option<u64>,
+        deadline: Option<Instant>,
+    ) -> BroadcastReport {
+        let _span =
+            tracing::debug_span!(target: BEHAVIOUR, "publish", topic = %targets::topic(topic))
+                .entered();
+        self.remember_own(topic, &msg);
+        let mut report = BroadcastReport::default();
+        let routable = self.routable_subscribers(topic, &mut report);
+
+        #[cfg(feature = "erasure")]
+        if let Some(coding) = self.config.erasure_coding.get(topic).copied() {
+            let sent = self.broadcast_shards(topic, coding, &msg);
+            for peer in routable {
+                let outcome = if sent.contains(&peer) {
+                    PeerOutcome::Queued
+                } else {
+                    PeerOutcome::Skipped(SkipReason::NotSelected)
+                };
+                report.peers.push((peer, outcome));
+            }
+            return report;
+        }
+
+        if routable.is_empty() {
+            if let Some(outbox) = self.outbox.as_mut() {
+                match outbox.append(topic, &msg, &metadata) {
+                    Ok(id) => {
+                        let entry = OutboxEntry {
+                            id,
+                            topic: *topic,
+                            payload: msg,
+                            metadata,
+                        };
+                        let now = self.config.clock.now();
+                        self.outboxed.entry(*topic).or_default().push((now, entry));
+                        report.outboxed = true;
+                    }
+                    Err(e) => {
+                        tracing::warn!(
+                            target: BEHAVIOUR,
+                            "Failed to keep a broadcast in the outbox: {e}"
+                        )
+                    }
+                }
+                return report;
+            }
+        }
+
+        let redelivery = self.config.at_least_once.get(topic).copied();
+        let mut metadata = metadata;
+        // Broadcasts published again from the outbox keep their number.
+        if self.config.sequence_numbers && metadata.sequence.is_none() {
+            let start = clock::unix_millis(self.config.clock.as_ref()).saturating_mul(1000);
+            let next = self.next_sequence.entry(*topic).or_insert(start);
+            metadata.sequence = Some(*next);
+            *next += 1;
+        }
+        if redelivery.is_some() {
+            let id = self.next_delivery_id;
+            self.next_delivery_id = id.wrapping_add(1);
+            metadata.delivery_id = Some(id);
+            report.delivery_id = Some(id);
+        }
+        let metadata = self.outbound_metadata(metadata);
+        self.journal(JournalKind::Published, *topic, &msg, &metadata);
+        self.callbacks.published(topic, &msg);
+        // Written ahead before interception, as it is published again from the outbox.
+        let ack = self.write_ahead(topic, &msg, &metadata);
+        let entry = ack.map(|id| OutboxEntry {
+            id,
+            topic: *topic,
+            payload: msg.clone(),
+            metadata: (*metadata).clone(),
+        });
+        let Some((msg, metadata)) = self.intercept_outbound(topic, msg, metadata) else {
+            if let Some(entry) = entry {
+                self.ack_outbox(entry.id);
+            }
+            let dropped = routable
+                .into_iter()
+                .map(|peer| (peer, PeerOutcome::Dropped));
+            report.peers.extend(dropped);
+            return report;
+        };
+        if let Some(retention) = self.config.history.get(topic) {
+            let now = self.config.clock.now();
+            let history = self.history.entry(*topic).or_default();
+            history.push(now, msg.clone(), metadata.clone());
+            history.enforce(retention, now);
+        }
+        let msg = Message::Broadcast {
+            topic: *topic,
+            payload: Payload::compact(msg),
+            metadata,
+        };
+        let mut pending = FnvHashSet::default();
+        // Peers catching up with the history get the broadcast through the replay.
+        let (replaying, peers): (Vec<_>, Vec<_>) = routable
+            .into_iter()
+            .partition(|peer| self.replays.contains_key(&(*peer, *topic)));
+        let deferred = replaying
+            .into_iter()
+            .map(|peer| (peer, PeerOutcome::Deferred));
+        report.peers.extend(deferred);
+        for peer in self.fan_out(topic, peers.clone()) {
+            self.events.push_back(ToSwarm::NotifyHandler {
+                peer_id: peer,
+                event: Outbound {
+                    message: msg.clone(),
+                    supersede_key,
+                    ack,
+                    deadline,
+                }
+                .into(),
+                handler: self.handler_for(&peer),
+            });
+            report.peers.push((peer, PeerOutcome::Queued));
+            pending.insert(peer);
+            if let (Some(redelivery), Some(id)) = (redelivery, report.delivery_id) {
+                if self.acks(&peer) {
+                    let now = self.config.clock.now();
+                    self.redeliveries
+                        .track(peer, id, *topic, msg.clone(), redelivery, now);
+                }
+            }
+        }
+        let not_selected = peers.into_iter().filter(|peer| !pending.contains(peer));
+        report
+            .peers
+            .extend(not_selected.map(|peer| (peer, PeerOutcome::Skipped(SkipReason::NotSelected))));
+        if let Some(entry) = entry {
+            if pending.is_empty() {
+                self.ack_outbox(entry.id);
+            } else {
+                self.written_ahead
+                    .insert(entry.id, (entry, msg.clone(), pending));
+            }
+        }
+
+        if let Some(metrics) = &mut self.metrics {
+            metrics.msg_sent(topic, msg.len());
+            metrics.register_published_message(topic);
+        }
+        self.record_activity(topic, msg.len());
+        report
+    }
+
+    /// Spreads the erasure-coded shards of the payload over the subscribers of the topic, which
+    /// relay them to each other. Returns the peers that were sent a shard.
+    #[cfg(feature = "erasure")]
+    fn broadcast_shards(
+        &mut self,
+        topic: &Topic,
+        coding: ErasureCoding,
+        payload: &[u8],
+    ) -> FnvHashSet<PeerId> {
+        let peers: Vec<PeerId> = match self.routes.route(topic) {
+            Some(peers) => peers
+                .iter()
+                .filter(|peer| self.is_routable(peer))
+                .copied()
+                .collect(),
+            None => Vec::new(),
+        };
+        let id = self.next_shard_id;
+        self.next_shard_id = id.wrapping_add(1);
+
+        let mut sent = 0;
+        let mut recipients = FnvHashSet::default();
+        if !peers.is_empty() {
+            for (i, shard) in coding.encode(id, payload).into_iter().enumerate() {
+                let msg = Message::Shard {
+                    topic: *topic,
+                    shard: Box::new(shard),
+                };
+                sent += msg.len();
+                let peer = peers[i % peers.len()];
+                self.events.push_back(ToSwarm::NotifyHandler {
+                    peer_id: peer,
+                    event: msg.into(),
+                    handler: self.handler_for(&peer),
+                });
+                recipients.insert(peer);
+            }
+        }
+
+        if let Some(metrics) = &mut self.metrics {
+            metrics.msg_sent(topic, sent);
+            metrics.register_published_message(topic);
+        }
+        self.record_activity(topic, sent);
+        recipients
+    }
+
+    #[cfg(feature = "erasure")]
+    fn on_shard(&mut self, peer: PeerId, topic: Topic, shard: Shard) -> Option<Event> {
+        if shard.origin.is_none() {
+            // The shard comes from the publisher itself, pass it on to the other subscribers so
+            // that everyone collects enough shards to reconstruct the payload.
+            if let Some(peers) = self.routes.route(&topic) {
+                let relayed = Message::Shard {
+                    topic,
+                    shard: Box::new(Shard {
+                        origin: Some(peer),
+                        ..shard.clone()
+                    }),
+                };
+                for other in peers.iter().filter(|other| **other != peer) {
+                    if !self.is_routable(other) {
+                        continue;
+                    }
+                    self.events.push_back(ToSwarm::NotifyHandler {
+                        peer_id: *other,
+                        event: relayed.clone().into(),
+                        handler: self.handler_for(other),
+                    });
+                }
+            }
+        }
+
+        let origin = shard.origin.unwrap_or(peer);
+        let payload = self.shards.insert(origin, shard, self.config.clock.now())?;
+        self.deliver(origin, topic, payload, Metadata::default())
+    }
+
+    /// Handles a message received from the peer, on the connection unless it is replayed or
+    /// synthesized locally.
+    fn on_message(
+        &mut self,
+        peer: PeerId,
+        connection_id: Option<ConnectionId>,
+        message: Message,
+    ) -> Option<Event> {
+        match message {
+            // Relaying the shards of a non-member would spread its payload anyway.
+            #[cfg(feature = "erasure")]
+            Message::Shard { .. } if !self.is_member(&peer) => {
+                tracing::trace!(target: BEHAVIOUR, "Dropping shard from non-member {peer}");
+                None
+            }
+
+            Subscribe { topic } => {
+                self.groups.leave(&peer, &topic);
+                if self.routes.subscribe(peer, topic) {
+                    if let Some(metrics) = self.metrics.as_mut() {
+                        metrics.inc_topic_peers(&topic);
+                    }
+                    self.update_shared(&peer);
+                    // Started first, so that the broadcasts flushed from the outbox are replayed
+                    // in order after the history.
+                    self.start_replay(peer, topic);
+                    self.flush_outbox(&topic);
+                }
+                if self.capabilities(&peer).is_some_and(|capabilities| {
+                    capabilities.contains(Capabilities::SUBSCRIPTION_ACKS)
+                }) {
+                    self.events.push_back(ToSwarm::NotifyHandler {
+                        peer_id: peer,
+                        event: Message::SubscribeAck { topic }.into(),
+                        handler: self.handler_for(&peer),
+                    });
+                }
+                Some(Event::Subscribed(peer, topic))
+            }
+
+            Broadcast {
+                topic,
+                payload: msg,
+                metadata,
+            } => {
+                // Duplicates count too, as they took a credit of the sender.
+                let window = self.config.flow_control.get(&topic).copied();
+                if let Some(window) = window
+                    .filter(|_| self.subscriptions.contains(&topic) && self.grants_credits(&peer))
+                {
+                    // Replayed broadcasts did not take a credit of any handler.
+                    let granted = connection_id.and_then(|connection_id| {
+                        let credits = self.grants.consume(connection_id, topic, window)?;
+                        Some((connection_id, credits))
+                    });
+                    if let Some((connection_id, credits)) = granted {
+                        self.events.push_back(ToSwarm::NotifyHandler {
+                            peer_id: peer,
+                            event: Credit { topic, credits }.into(),
+                            handler: NotifyHandler::One(connection_id),
+                        });
+                    }
+                }
+                // Acknowledged even if it is a duplicate, as the previous ack may have been lost.
+                if let Some(id) = metadata.delivery_id.filter(|_| self.acks(&peer)) {
+                    self.events.push_back(ToSwarm::NotifyHandler {
+                        peer_id: peer,
+                        event: Message::Ack { delivery_id: id }.into(),
+                        handler: self.handler_for(&peer),
+                    });
+                }
+                if let Some(id) = metadata.delivery_id {
+                    if !self.delivered.insert(peer, id) {
+                        tracing::trace!(
+                            target: BEHAVIOUR,
+                            "Dropping broadcast {id} redelivered by {peer}"
+                        );
+                        return None;
+                    }
+                }
+                let replay_completed = metadata.replay_remaining == Some(0);
+                let event = self.deliver(peer, topic, msg.into(), Arc::unwrap_or_clone(metadata));
+                if !replay_completed {
+                    return event;
+                }
+                if let Some(event) = event {
+                    self.events.push_back(ToSwarm::GenerateEvent(event));
+                }
+                Some(Event::ReplayCompleted(peer, topic))
+            }
+
+            #[cfg(feature = "erasure")]
+            Message::Shard { topic, shard } => self.on_shard(peer, topic, *shard),
+
+            #[cfg(not(feature = "erasure"))]
+            Message::Shard { .. } => {
+                tracing::debug!(
+                    target: BEHAVIOUR,
+                    "Ignoring shard from {peer}, erasure coding support is disabled"
+                );
+                None
+            }
+
+            SubscribeShared { topic, group } => {
+                let event = self.on_message(peer, connection_id, Subscribe { topic });
+                self.groups.join(peer, topic, group);
+                event
+            }
+
+            Unsubscribe { topic } => {
+                self.groups.leave(&peer, &topic);
+                if self.routes.unsubscribe(&peer, &topic) {
+                    if let Some(metrics) = self.metrics.as_mut() {
+                        metrics.dec_topic_peers(&topic);
+                    }
+                    self.update_shared(&peer);
+                }
+                self.replays.remove(&(peer, topic));
+                Some(Event::Unsubscribed(peer, topic))
+            }
+
+            Batch { messages } => {
+                for message in messages {
+                    if let Some(event) = self.on_message(peer, connection_id, message) {
+                        self.events.push_back(ToSwarm::GenerateEvent(event));
+                    }
+                }
+                None
+            }
+
+            // Consumed by the handlers.
+            Handshake { .. } | Credit { .. } => None,
+
+            SnapshotPart { topics } => {
+                self.snapshot_parts.entry(peer).or_default().extend(topics);
+                None
+            }
+
+            Snapshot { topics } => {
+                let topics = match self.snapshot_parts.remove(&peer) {
+                    Some(mut parts) => {
+                        parts.extend(topics);
+                        parts
+                    }
+                    None => topics,
+                };
+                self.on_snapshot(peer, topics);
+                None
+            }
+
+            Query => {
+                self.resync(&peer);
+                None
+            }
+
+            Control { .. } if !self.is_member(&peer) => {
+                tracing::trace!(target: BEHAVIOUR, "Dropping control frame from non-member {peer}");
+                None
+            }
+
+            Control { topic, payload } => Some(Event::ReceivedControl(peer, topic, payload)),
+
+            // Acks of subscriptions we dropped since are stale.
+            SubscribeAck { topic } if !self.subscriptions.contains(&topic) => None,
+
+            SubscribeAck { topic } => Some(Event::SubscriptionConfirmed(peer, topic)),
+
+            Ack { delivery_id: id } => {
+                self.redeliveries.ack(&peer, id);
+                None
+            }
+        }
+    }
+
+    /// Filters out duplicates of a received broadcast and records it in the metrics.
+    fn deliver(
+        &mut self,
+        peer: PeerId,
+        topic: Topic,
+        mut msg: Bytes,
+        mut metadata: Metadata,
+    ) -> Option<Event> {
+        let _span =
+            tracing::debug_span!(target: BEHAVIOUR, "deliver", topic = %targets::topic(&topic))
+                .entered();
+        if !self.is_member(&peer) {
+            tracing::trace!(target: BEHAVIOUR, "Dropping message from non-member {peer}");
+            return None;
+        }
+        if self.config.self_test.is_some() && topic.as_ref() == SelfTest::TOPIC {
+            self.on_probe(peer, topic, &msg);
+            return None;
+        }
+        for interceptor in &mut self.interceptors {
+            if !interceptor.on_inbound(&peer, &topic, &mut msg, &mut metadata) {
+                tracing::trace!(
+                    target: BEHAVIOUR,
+                    "Dropping message from {peer} rejected by an interceptor"
+                );
+                return None;
+            }
+        }
+        if let Some(timestamp) = metadata.timestamp {
+            let now = clock::unix_millis(self.config.clock.as_ref());
+            // Timestamps too far off for the skew to be represented are stale either way.
+            let Ok(skew) = i64::try_from(now as i128 - timestamp as i128) else {
+                tracing::trace!(
+                    target: BEHAVIOUR,
+                    "Dropping message from {peer} with timestamp {timestamp}"
+                );
+                return None;
+            };
+            if self.config.is_stale(skew) {
+                tracing::trace!(
+                    target: BEHAVIOUR,
+                    "Dropping message from {peer} received {skew}ms after its timestamp"
+                );
+                return None;
+            }
+            metadata.skew = Some(skew);
+        }
+        if self.seen.is_some() {
+            let id = (self.config.message_id_fn)(&topic, &msg);
+            if !self.mark_seen(id) {
+                tracing::trace!(target: BEHAVIOUR, "Dropping duplicate message {id:?} from {peer}");
+                return self.on_duplicate(peer, id);
+            }
+        }
+        if let (Some(window), Some(seq)) = (self.delivery_window.as_mut(), metadata.sequence) {
+            let publisher = metadata.origin.unwrap_or(peer);
+            if !window.insert(publisher, topic, seq) {
+                tracing::trace!(
+                    target: BEHAVIOUR,
+                    "Dropping broadcast {seq} of {publisher} received from {peer}"
+                );
+                let id = (self.config.message_id_fn)(&topic, &msg);
+                return self.on_duplicate(peer, id);
+            }
+        }
+        if let Some(metrics) = self.metrics.as_mut() {
+            metrics.msg_received(&topic, msg.len());
+        }
+        self.record_received(&topic, msg.len());
+        if self.config.forwarding && self.is_subscribed(&topic) {
+            self.forward(peer, topic, &msg, &metadata);
+        }
+        let reordering = self.config.reordering.get(&topic).copied();
+        if let (Some(reordering), Some(seq)) = (reordering, metadata.sequence) {
+            let publisher = metadata.origin.unwrap_or(peer);
+            let now = self.config.clock.now();
+            let item = (peer, msg, metadata);
+            let (released, missing) = self
+                .reorder
+                .push(publisher, topic, seq, item, reordering, now);
+            if let Some(missing) = missing {
+                let events = [
+                    Event::ReorderOverflow(publisher, topic),
+                    Event::Missing(publisher, topic, missing),
+                ];
+                self.events
+                    .extend(IntoIterator::into_iter(events).map(ToSwarm::GenerateEvent));
+            }
+            self.surface_all(topic, released);
+            return None;
+        }
+        Some(self.surface(peer, topic, msg, metadata))
+    }
+
+    /// Publishes the next probe of the self-test.
+    fn send_probe(&mut self) {
+        let topic = Topic::new(SelfTest::TOPIC);
+        let probe = self.prober.start(self.config.clock.now());
+        let report = self.publish(&topic, probe, Metadata::default(), None, None);
+        self.prober.sent(report.queued().count());
+    }
+
+    /// Reports the outcome of the probe whose deadline passed.
+    fn finish_probe(&mut self) {
+        let Some(result) = self.prober.finish() else {
+            return;
+        };
+        if !result.passed {
+            tracing::debug!(
+                target: BEHAVIOUR,
+                "Self-test failed, {} of {} peers echoed the probe",
+                result.echoes,
+                result.probed
+            );
+        }
+        if let Some(metrics) = self.metrics.as_mut() {
+            metrics.self_test(result.passed);
+        }
+        self.events
+            .push_back(ToSwarm::GenerateEvent(Event::SelfTest(result)));
+    }
+
+    /// Echoes the probes of the peer, and records its echoes of ours.
+    fn on_probe(&mut self, peer: PeerId, topic: Topic, msg: &[u8]) {
+        match ProbeFrame::decode(msg) {
+            Some(ProbeFrame::Probe(id)) => {
+                self.send_to(&peer, &topic, ProbeFrame::Echo(id).encode());
+            }
+            Some(ProbeFrame::Echo(id)) => {
+                self.prober.echo(peer, id, self.config.clock.now());
+            }
+            None => tracing::debug!(target: BEHAVIOUR, "Ignoring a malformed probe from {peer}"),
+        }
+    }
+
+    /// Takes the next event, passing the broadcasts delivered through
+    /// `Config::delivery_faults`.
+    #[cfg(feature = "chaos")]
+    fn next_event_with_faults(&mut self, cx: &mut Context<'_>) -> Option<ToSwarm<Event, Command>> {
+        let Some(chaos) = self.delivery_chaos.as_mut() else {
+            return self.events.pop_front();
+        };
+        loop {
+            if let Some(event) = chaos.pop_released() {
+                return Some(ToSwarm::GenerateEvent(event));
+            }
+            match self.events.pop_front() {
+                Some(ToSwarm::GenerateEvent(event @ Event::Received(..))) => {
+                    if let Some(event) = chaos.inject(event) {
+                        return Some(ToSwarm::GenerateEvent(event));
+                    }
+                }
+                Some(event) => return Some(event),
+                None => {
+                    chaos.poll_delayed(cx);
+                    return chaos.pop_released().map(ToSwarm::GenerateEvent);
+                }
+            }
+        }
+    }
+
+    /// Hands a received broadcast over to the application.
+    fn surface(&mut self, peer: PeerId, topic: Topic, msg: Bytes, metadata: Metadata) -> Event {
+        if let Some(trace) = &metadata.trace {
+            tracing::debug!(
+                target: BEHAVIOUR,
+                trace_id = %trace.trace_id_hex(),
+                span_id = %trace.span_id_hex(),
+                "Delivering a broadcast from {peer}"
+            );
+        }
+        if let (Some(metrics), Some(skew)) = (self.metrics.as_mut(), metadata.skew) {
+            metrics.delivery_delay(&topic, skew, metadata.trace.as_ref());
+        }
+        self.journal(JournalKind::Delivered(peer), topic, &msg, &metadata);
+        self.callbacks.received(&peer, &topic, &msg);
+        Event::Received(peer, topic, msg, metadata)
+    }
+
+    fn surface_all(&mut self, topic: Topic, released: Vec<(PeerId, Bytes, Metadata)>) {
+        for (peer, msg, metadata) in released {
+            let event = self.surface(peer, topic, msg, metadata);
+            self.events.push_back(ToSwarm::GenerateEvent(event));
+        }
+    }
+
+    /// Releases the broadcasts held back for too long, skipping the missing ones.
+    fn expire_reordering(&mut self) {
+        let now = self.config.clock.now();
+        let reordering = &self.config.reordering;
+        let timeout = |topic: &Topic| reordering.get(topic).map(|reordering| reordering.timeout);
+        for expired in self.reorder.expire(now, timeout) {
+            let (publisher, topic) = (expired.publisher, expired.topic);
+            let events = [
+                Event::ReorderTimeout(publisher, topic),
+                Event::Missing(publisher, topic, expired.missing),
+            ];
+            self.events
+                .extend(IntoIterator::into_iter(events).map(ToSwarm::GenerateEvent));
+            self.surface_all(topic, expired.released);
+        }
+    }
+
+    /// Reports a suppressed duplicate, unless one from the peer was reported less than
+    /// `Config::duplicate_events` ago.
+    fn on_duplicate(&mut self, peer: PeerId, id: MessageId) -> Option<Event> {
+        let interval = self.config.duplicate_events?;
+        let now = self.config.clock.now();
+        let (reported_at, suppressed) = self.duplicates.entry(peer).or_insert((None, 0));
+        *suppressed += 1;
+        if reported_at.is_some_and(|at| now < at + interval) {
+            return None;
+        }
+        *reported_at = Some(now);
+        Some(Event::DuplicateSuppressed(
+            peer,
+            id,
+            std::mem::take(suppressed),
+        ))
+    }
+
+    /// Passes a broadcast received from the peer on to the other subscribers of the topic.
+    fn forward(&mut self, peer: PeerId, topic: Topic, msg: &Bytes, metadata: &Metadata) {
+        if metadata.is_exhausted(clock::unix_millis(self.config.clock.as_ref())) {
+            tracing::trace!(
+                target: BEHAVIOUR,
+                "Not forwarding message from {peer}, its ttl is exhausted"
+            );
+            return;
+        }
+        let Some(peers) = self.routes.route(&topic) else {
+            return;
+        };
+        let origin = metadata.origin.unwrap_or(peer);
+        let metadata = Arc::new(Metadata {
+            origin: Some(origin),
+            hops: Some(metadata.hops.unwrap_or(0).saturating_add(1)),
+            skew: None,
+            replay_remaining: None,
+            delivery_id: None,
+            ..metadata.clone()
+        });
+        let Some((msg, metadata)) = self.intercept_outbound(&topic, msg.clone(), metadata) else {
+            return;
+        };
+        let forwarded = Message::Broadcast {
+            topic,
+            payload: Payload::compact(msg),
+            metadata,
+        };
+        let others = peers
+            .iter()
+            .filter(|other| **other != peer && **other != origin && self.is_routable(other))
+            .copied()
+            .collect();
+        for other in self.fan_out(&topic, others) {
+            self.events.push_back(ToSwarm::NotifyHandler {
+                peer_id: other,
+                event: forwarded.clone().into(),
+                handler: self.handler_for(&other),
+            });
+        }
+    }
+
+    /// Narrows down the peers to send a broadcast on the topic to, first to a single member of
+    /// each group, then with the fan-out strategy.
+    fn fan_out(&mut self, topic: &Topic, mut peers: Vec<PeerId>) -> Vec<PeerId> {
+        if !self.groups.is_empty() {
+            let (routes, connections, queues) = (&self.routes, &self.connections, &self.queues);
+            let queued = |peer: &PeerId| {
+                let connections = connections.get(peer).into_iter().flatten();
+                connections
+                    .filter_map(|connection| queues.get(connection))
+                    .map(|(messages, ..)| messages)
+                    .sum()
+            };
+            let rng = self.rng.get_or_insert_with(StdRng::from_entropy);
+            self.groups.select(
+                &mut peers,
+                self.config.group_selection,
+                |subscription| routes.matches(subscription, topic),
+                queued,
+                rng,
+            );
+        }
+        if let Some(fan_out) = self.fan_out.as_mut() {
+            let rng = self.rng.get_or_insert_with(StdRng::from_entropy);
+            fan_out.select(topic, &mut peers, rng);
+        }
+        peers
+    }
+
+    fn on_connection_established(
+        &mut self,
+        peer: PeerId,
+        connection_id: ConnectionId,
+        other_established: usize,
+    ) {
+        self.connections
+            .entry(peer)
+            .or_default()
+            .push(connection_id);
+        if other_established == 0 {
+            let now = self.config.clock.now();
+            if self.churn.connected(peer, now) {
+                if let Some(metrics) = self.metrics.as_mut() {
+                    metrics.reconnect();
+                }
+            }
+            self.inject_connected(&peer);
+        } else if self.shared_peers.contains(&peer) {
+            // New handlers assume no shared topics, and `update_shared` only sends changes.
+            self.events.push_back(ToSwarm::NotifyHandler {
+                peer_id: peer,
+                event: Command::SharesTopics(true),
+                handler: NotifyHandler::One(connection_id),
+            });
+        }
+    }
+
+    fn inject_connected(&mut self, peer: &PeerId) {
+        self.routes.add_peer(*peer);
+        if let Some(topics) = self.restored_peers.remove(peer) {
+            for topic in topics {
+                if let Some(event) = self.on_message(*peer, None, Subscribe { topic }) {
+                    self.events.push_back(ToSwarm::GenerateEvent(event));
+                }
+            }
+            self.events.push_back(ToSwarm::NotifyHandler {
+                peer_id: *peer,
+                event: Message::Query.into(),
+                handler: self.handler_for(peer),
+            });
+        }
+        match self.config.resubscribe_jitter {
+            Some(jitter) => {
+                let rng = self.rng.get_or_insert_with(StdRng::from_entropy);
+                let delay = rng.gen_range(Duration::ZERO..=jitter);
+                let sleep = self.config.timer.sleep(delay);
+                self.pending_announcements.push((sleep, *peer));
+            }
+            None => self.announce_subscriptions(peer),
+        }
+    }
+
+    fn announce_subscriptions(&mut self, peer: &PeerId) {
+        let shares_groups = self.shares_groups(peer);
+        let messages = self
+            .subscriptions
+            .iter()
+            .map(|topic| match self.shared_subscriptions.get(topic) {
+                Some(group) if shares_groups => SubscribeShared {
+                    topic: *topic,
+                    group: *group,
+                },
+                _ => Subscribe { topic: *topic },
+            })
+            .collect();
+        for frame in Message::batch(messages, self.config.max_buf_size) {
+            self.events.push_back(ToSwarm::NotifyHandler {
+                peer_id: *peer,
+                event: frame.into(),
+                handler: self.handler_for(peer),
+            });
+        }
+    }
+
+    /// Returns `true` if the peer acknowledges the broadcasts of at-least-once topics.
+    fn acks(&self, peer: &PeerId) -> bool {
+        self.capabilities(peer)
+            .is_some_and(|capabilities| capabilities.contains(Capabilities::ACKS))
+    }
+
+    /// Sends again the broadcasts whose acknowledgment is overdue, giving up on the ones out of
+    /// retries.
+    fn redeliver(&mut self) {
+        let now = self.config.clock.now();
+        let due = self.redeliveries.due(now);
+        for (peer, message) in due.resend {
+            self.events.push_back(ToSwarm::NotifyHandler {
+                peer_id: peer,
+                event: message.into(),
+                handler: self.handler_for(&peer),
+            });
+        }
+        for (peer, topic, id) in due.exhausted {
+            self.events
+                .push_back(ToSwarm::GenerateEvent(Event::Unacknowledged(
+                    peer, topic, id,
+                )));
+        }
+    }
+
+    /// Returns `true` if the peer holds back broadcasts on flow-controlled topics until we grant
+    /// credits for them.
+    fn grants_credits(&self, peer: &PeerId) -> bool {
+        self.capabilities(peer)
+            .is_some_and(|capabilities| capabilities.contains(Capabilities::CREDITS))
+    }
+
+    /// Returns `true` if the peer understands subscriptions as a member of a group.
+    fn shares_groups(&self, peer: &PeerId) -> bool {
+        self.capabilities(peer)
+            .is_some_and(|capabilities| capabilities.contains(Capabilities::SHARED_SUBSCRIPTIONS))
+    }
+
+    /// Turns a subscription as a member of a group into a plain one.
+    fn without_group(message: Message) -> Message {
+        match message {
+            SubscribeShared { topic, .. } => Subscribe { topic },
+            message => message,
+        }
+    }
+
+    fn abort_stream(&mut self, peer: PeerId, connection_id: ConnectionId) {
+        self.skipped_streams.remove(&(peer, connection_id));
+        if let Some(stream) = self.streams.remove(&(peer, connection_id)) {
+            self.events
+                .push_back(ToSwarm::GenerateEvent(Event::ReceiveAborted(
+                    peer,
+                    stream.topic,
+                    stream.id,
+                )));
+        }
+    }
+
+    /// Forgets the subscriptions of a peer that stays connected, e.g. because it stopped
+    /// supporting the protocol. Returns the topics it was subscribed to.
+    fn forget_subscriptions(&mut self, peer: &PeerId) -> FnvHashSet<Topic> {
+        let topics = self.routes.remove_peer(peer);
+        self.groups.remove_peer(peer);
+        self.routes.add_peer(*peer);
+        self.replays.retain(|(other, _), _| other != peer);
+        for topic in &topics {
+            if let Some(metrics) = self.metrics.as_mut() {
+                metrics.dec_topic_peers(topic);
+            }
+            self.events
+                .push_back(ToSwarm::GenerateEvent(Event::Unsubscribed(*peer, *topic)));
+        }
+        topics
+    }
+
+    /// Charges the bytes received from the peer against `Config::bandwidth_cap`. Returns `false`
+    /// if the peer exceeded it, enforcing the cap the first time it does in the interval.
+    fn charge_bandwidth(&mut self, peer: PeerId, bytes: usize) -> bool {
+        let Some(cap) = self.config.bandwidth_cap else {
+            return true;
+        };
+        match self
+            .quotas
+            .charge(peer, bytes as u64, &cap, self.config.clock.now())
+        {
+            Charge::Within => return true,
+            Charge::Throttled => {}
+            Charge::Exceeded => {
+                tracing::debug!(target: BEHAVIOUR, "{peer} exceeded its bandwidth cap");
+                if let Some(metrics) = self.metrics.as_mut() {
+                    metrics.bandwidth_exceeded(cap.action);
+                }
+                self.events
+                    .push_back(ToSwarm::GenerateEvent(Event::PeerThrottled(
+                        peer,
+                        None,
+                        ThrottleKind::Bandwidth(cap.action),
+                    )));
+                if cap.action == CapAction::Disconnect {
+                    self.events.push_back(ToSwarm::CloseConnection {
+                        peer_id: peer,
+                        connection: CloseConnection::All,
+                    });
+                }
+            }
+        }
+        false
+    }
+
+    fn on_slow_consumer(&mut self, peer: PeerId, connection_id: ConnectionId) {
+        match self.config.slow_consumer_action {
+            SlowConsumerAction::Report => {}
+            SlowConsumerAction::Unsubscribe => {
+                for topic in self.forget_subscriptions(&peer) {
+                    self.events.push_back(ToSwarm::NotifyHandler {
+                        peer_id: peer,
+                        event: Command::Cancel(topic),
+                        handler: NotifyHandler::One(connection_id),
+                    });
+                }
+            }
+            SlowConsumerAction::Disconnect => {
+                self.events.push_back(ToSwarm::CloseConnection {
+                    peer_id: peer,
+                    connection: CloseConnection::All,
+                });
+            }
+        }
+    }
+
+    fn inject_disconnected(&mut self, peer: &PeerId) {
+        self.replays.retain(|(other, _), _| other != peer);
+        // Broadcasts written ahead that the peer did not flush are kept in the outbox, and sent
+        // again once a subscriber shows up.
+        let now = self.config.clock.now();
+        let unflushed: Vec<u64> = self
+            .written_ahead
+            .iter()
+            .filter(|(_, (.., pending))| pending.contains(peer))
+            .map(|(id, _)| *id)
+            .collect();
+        for id in unflushed {
+            if let Some((entry, ..)) = self.written_ahead.remove(&id) {
+                self.outboxed
+                    .entry(entry.topic)
+                    .or_default()
+                    .push((now, entry));
+            }
+        }
+        self.capabilities.remove(peer);
+        self.versions.remove(peer);
+        self.failed_peers.remove(peer);
+        self.subscription_queries.remove(peer);
+        self.snapshot_parts.remove(peer);
+        self.duplicates.remove(peer);
+        self.shared_peers.remove(peer);
+        self.groups.remove_peer(peer);
+        if let Some(cap) = &self.config.bandwidth_cap {
+            self.quotas.prune(cap, self.config.clock.now());
+        }
+        for (topic, id) in self.redeliveries.remove_peer(peer) {
+            self.events
+                .push_back(ToSwarm::GenerateEvent(Event::Unacknowledged(
+                    *peer, topic, id,
+                )));
+        }
+        let topics = self.routes.remove_peer(peer);
+        if let Some(metrics) = self.metrics.as_mut() {
+            for topic in &topics {
+                metrics.dec_topic_peers(topic);
+            }
+        }
+    }
+}
+
+impl NetworkBehaviour for Behaviour {
+    type ConnectionHandler = Handler;
+    type ToSwarm = Event;
+
+    fn handle_established_inbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        peer: PeerId,
+        local_addr: &Multiaddr,
+        remote_addr: &Multiaddr,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        // Relayed connections are only recognisable by the address we listen on.
+        let transport = match TransportClass::from_addr(local_addr) {
+            TransportClass::Relay => TransportClass::Relay,
+            _ => TransportClass::from_addr(remote_addr),
+        };
+        Ok(Handler::new(self.config.clone())
+            .with_transport(transport)
+            .with_peer(peer, connection_id))
+    }
+
+    fn handle_established_outbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        peer: PeerId,
+        addr: &Multiaddr,
+        _role_override: Endpoint,
+        _port_use: PortUse,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        let transport = TransportClass::from_addr(addr);
+        Ok(Handler::new(self.config.clone())
+            .with_transport(transport)
+            .with_peer(peer, connection_id))
+    }
+
+    fn on_swarm_event(&mut self, event: FromSwarm<'_>) {
+        match event {
+            FromSwarm::ConnectionEstablished(c) => {
+                self.on_connection_established(c.peer_id, c.connection_id, c.other_established)
+            }
+            FromSwarm::ConnectionClosed(c) => {
+                if let Some(connections) = self.connections.get_mut(&c.peer_id) {
+                    connections.retain(|connection| *connection != c.connection_id);
+                    self.latencies.remove(&c.connection_id);
+                    self.queues.remove(&c.connection_id);
+                    self.grants.remove_connection(c.connection_id);
+                    if connections.is_empty() {
+                        self.connections.remove(&c.peer_id);
+                    }
+                }
+                self.abort_stream(c.peer_id, c.connection_id);
+                if c.remaining_established == 0 {
+                    self.churn.disconnected(&c.peer_id, self.config.clock.now());
+                    self.inject_disconnected(&c.peer_id);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn on_connection_handler_event(
+        &mut self,
+        peer: PeerId,
+        connection_id: ConnectionId,
+        event: <Self::ConnectionHandler as ConnectionHandler>::ToBehaviour,
+    ) {
+        let _span = tracing::debug_span!(target: BEHAVIOUR, "handler_event", %peer).entered();
+        let ev = match event {
+            Rx(message) if message.longest_topic() > self.config.max_topic_length => {
+                tracing::debug!(
+                    target: BEHAVIOUR,
+                    "Dropping a message from {peer} naming a topic too long to accept"
+                );
+                return;
+            }
+
+            Rx(mut message) => {
+                let len = message.len();
+                if !self.charge_bandwidth(peer, len) {
+                    message.discard(|message| {
+                        matches!(
+                            message,
+                            Broadcast { .. } | Message::Shard { .. } | Control { .. }
+                        )
+                    });
+                    if let Some(metrics) = self.metrics.as_mut() {
+                        metrics.bandwidth_dropped(len.saturating_sub(message.len()));
+                    }
+                    if message.is_empty() {
+                        return;
+                    }
+                }
+                match self.on_message(peer, Some(connection_id), message) {
+                    Some(event) => event,
+                    None => return,
+                }
+            }
+
+            RxStreamStart(_, _) if !self.is_member(&peer) => {
+                tracing::trace!(
+                    target: BEHAVIOUR,
+                    "Dropping chunked message from non-member {peer}"
+                );
+                return;
+            }
+
+            RxStreamStart(topic, len) => {
+                let key = (peer, connection_id);
+                if !self.charge_bandwidth(peer, len) {
+                    tracing::trace!(
+                        target: BEHAVIOUR,
+                        "Skipping chunked message from {peer}, it exceeded its bandwidth cap"
+                    );
+                    if let Some(metrics) = self.metrics.as_mut() {
+                        metrics.bandwidth_dropped(len);
+                    }
+                    self.streams.remove(&key);
+                    self.skipped_streams.insert(key);
+                    return;
+                }
+                self.skipped_streams.remove(&key);
+                if let Some(metrics) = self.metrics.as_mut() {
+                    metrics.msg_received(&topic, len);
+                }
+                self.record_received(&topic, len);
+                let id = self.next_stream_id;
+                self.next_stream_id += 1;
+                self.streams.insert(
+                    key,
+                    InboundStream {
+                        id,
+                        topic,
+                        offset: 0,
+                        len,
+                        content_id: self.seen.is_some().then(|| ContentHasher::new(&topic)),
+                    },
+                );
+                return;
+            }
+
+            RxStreamChunk(data, last) => {
+                let key = (peer, connection_id);
+                if self.skipped_streams.contains(&key) {
+                    if last {
+                        self.skipped_streams.remove(&key);
+                    }
+                    return;
+                }
+                let Some(stream) = self.streams.get_mut(&key) else {
+                    if self.is_member(&peer) {
+                        tracing::warn!(
+                            target: BEHAVIOUR,
+                            "Received a chunk from {peer} without a stream start"
+                        );
+                    }
+                    return;
+                };
+                if let Some(content_id) = stream.content_id.as_mut() {
+                    content_id.update(&data);
+                }
+                let chunk = Chunk {
+                    stream: stream.id,
+                    offset: stream.offset,
+                    len: stream.len,
+                    data,
+                };
+                stream.offset += chunk.data.len();
+                let topic = stream.topic;
+                if !last {
+                    Event::ReceivedChunk(peer, topic, chunk)
+                } else {
+                    let stream = self.streams.remove(&key).unwrap();
+                    match stream.content_id.map(ContentHasher::finish) {
+                        Some(id) if !self.mark_seen(id) => {
+                            tracing::trace!(
+                                target: BEHAVIOUR,
+                                "Dropping duplicate chunked message {id:?} from {peer}"
+                            );
+                            self.events
+                                .push_back(ToSwarm::GenerateEvent(Event::ReceiveAborted(
+                                    peer, topic, stream.id,
+                                )));
+                            match self.on_duplicate(peer, id) {
+                                Some(event) => event,
+                                None => return,
+                            }
+                        }
+                        _ => Event::ReceivedChunk(peer, topic, chunk),
+                    }
+                }
+            }
+
+            RxStreamAborted => {
+                self.abort_stream(peer, connection_id);
+                return;
+            }
+
+            Expired(count) => {
+                if let Some(metrics) = self.metrics.as_mut() {
+                    metrics.msg_expired(count);
+                }
+                self.record_error(ErrorKind::Expired, count);
+                Event::Expired(peer, count)
+            }
+
+            Oversized(count) => Event::Oversized(peer, count),
+
+            Shed(count) => {
+                self.record_error(ErrorKind::Shed, count);
+                Event::Shed(peer, count)
+            }
+
+            Stalled(topic) => Event::PeerThrottled(peer, Some(topic), ThrottleKind::Credits),
+
+            RxOversized(size) => {
+                tracing::debug!(
+                    target: BEHAVIOUR,
+                    "{peer} sent a frame of {size} bytes, exceeding the maximum of {} bytes",
+                    self.config.max_buf_size
+                );
+                self.record_error(ErrorKind::ReceivedOversized, 1);
+                Event::ReceivedOversized(peer, size)
+            }
+
+            TxRejected(size) => {
+                self.record_error(ErrorKind::Rejected, 1);
+                Event::Rejected(peer, size)
+            }
+
+            Negotiated(capabilities) => {
+                if self.capabilities.insert(peer, capabilities) != Some(capabilities) {
+                    self.events
+                        .push_back(ToSwarm::GenerateEvent(Event::Negotiated(
+                            peer,
+                            capabilities,
+                        )));
+                }
+                // Subscriptions were announced as plain ones before the handshake, and the ones to
+                // topics too long for plain frames not at all.
+                let shares_groups = capabilities.contains(Capabilities::SHARED_SUBSCRIPTIONS);
+                let long_topics = capabilities.contains(Capabilities::LONG_TOPICS);
+                let messages = self
+                    .subscriptions
+                    .iter()
+                    .filter_map(|topic| match self.shared_subscriptions.get(topic) {
+                        Some(group) if shares_groups => Some(SubscribeShared {
+                            topic: *topic,
+                            group: *group,
+                        }),
+                        _ if long_topics && topic.len() > Topic::MAX_PLAIN_LENGTH => {
+                            Some(Subscribe { topic: *topic })
+                        }
+                        _ => None,
+                    })
+                    .collect();
+                for frame in Message::batch(messages, self.config.max_buf_size) {
+                    self.events.push_back(ToSwarm::NotifyHandler {
+                        peer_id: peer,
+                        event: frame.into(),
+                        handler: NotifyHandler::One(connection_id),
+                    });
+                }
+                // The handler starts out without credits, grant it whole windows.
+                if capabilities.contains(Capabilities::CREDITS) {
+                    self.grants.remove_connection(connection_id);
+                    let mut windows: Vec<_> = self
+                        .config
+                        .flow_control
+                        .iter()
+                        .filter(|(topic, _)| self.subscriptions.contains(topic))
+                        .map(|(topic, window)| (*topic, *window))
+                        .collect();
+                    // In a stable order, so that runs with a seed are reproducible.
+                    windows.sort_unstable();
+                    for (topic, credits) in windows {
+                        self.events.push_back(ToSwarm::NotifyHandler {
+                            peer_id: peer,
+                            event: Credit { topic, credits }.into(),
+                            handler: NotifyHandler::One(connection_id),
+                        });
+                    }
+                }
+                return;
+            }
+
+            NegotiatedVersion(version) => {
+                self.versions.insert(peer, version);
+                return;
+            }
+
+            Unsupported => {
+                self.abort_stream(peer, connection_id);
+                self.capabilities.remove(&peer);
+                self.versions.remove(&peer);
+                self.forget_subscriptions(&peer);
+                return;
+            }
+
+            // Remote subscriptions were forgotten along with the support, but ours need to be
+            // announced again.
+            Supported => {
+                self.inject_connected(&peer);
+                return;
+            }
+
+            Latency(latency) => {
+                if let Some(metrics) = self.metrics.as_mut() {
+                    metrics.flush_latency(latency);
+                }
+                self.latencies.insert(connection_id, latency);
+                return;
+            }
+
+            Queue(messages, bytes, oldest) => {
+                self.queues.insert(connection_id, (messages, bytes, oldest));
+                return;
+            }
+
+            Slow(wait) => {
+                tracing::debug!(
+                    target: BEHAVIOUR,
+                    "{peer} is a slow consumer, its queue is {wait:?} behind"
+                );
+                self.on_slow_consumer(peer, connection_id);
+                Event::SlowConsumer(peer, wait)
+            }
+
+            Failed => {
+                let now = self.config.clock.now();
+                self.failed_peers.retain(|_, until| now < *until);
+                self.failed_peers
+                    .insert(peer, now + self.config.failure_cooldown);
+                self.record_error(ErrorKind::PeerFailure, 1);
+                Event::PeerFailed(peer)
+            }
+
+            Substream(event) => {
+                if let SubstreamEvent::Reopened(_) = event {
+                    self.churn.substream_restarted(peer);
+                    if let Some(metrics) = self.metrics.as_mut() {
+                        metrics.substream_restart();
+                    }
+                }
+                if !self.config.substream_events {
+                    return;
+                }
+                Event::Substream(peer, connection_id, event)
+            }
+
+            QueueDelays(delays) => {
+                if let Some(metrics) = self.metrics.as_mut() {
+                    for delay in delays {
+                        metrics.queue_delay(delay);
+                    }
+                }
+                return;
+            }
+
+            Tx => {
+                return;
+            }
+
+            Flushed(ids) => {
+                self.on_flushed(&peer, ids);
+                return;
+            }
+
+            Unflushed(ids) => {
+                self.on_unflushed(&peer, ids);
+                return;
+            }
+        };
+        self.events.push_back(ToSwarm::GenerateEvent(ev));
+    }
+
+    fn poll(&mut self, cx: &mut Context) -> Poll<ToSwarm<Event, Command>> {
+        if let Some(interval) = self.config.resync_interval {
+            let timer = &self.config.timer;
+            let sleep = self
+                .resync_timer
+                .get_or_insert_with(|| timer.sleep(interval));
+            if sleep.poll_unpin(cx).is_ready() {
+                *sleep = timer.sleep(interval);
+                let _ = sleep.poll_unpin(cx);
+                self.query_all_subscriptions();
+            }
+        }
+        if !self.replays.is_empty() {
+            let timer = &self.config.timer;
+            let sleep = self
+                .replay_timer
+                .get_or_insert_with(|| timer.sleep(History::REPLAY_TICK));
+            if sleep.poll_unpin(cx).is_ready() {
+                *sleep = timer.sleep(History::REPLAY_TICK);
+                let _ = sleep.poll_unpin(cx);
+                self.replay_history();
+            }
+        }
+        if !self.reorder.is_empty() {
+            let timer = &self.config.timer;
+            let sleep = self
+                .reorder_timer
+                .get_or_insert_with(|| timer.sleep(Reordering::TICK));
+            if sleep.poll_unpin(cx).is_ready() {
+                *sleep = timer.sleep(Reordering::TICK);
+                let _ = sleep.poll_unpin(cx);
+                self.expire_reordering();
+            }
+        }
+        if !self.redeliveries.is_empty() {
+            let timer = &self.config.timer;
+            let sleep = self
+                .redelivery_timer
+                .get_or_insert_with(|| timer.sleep(Redeliveries::TICK));
+            if sleep.poll_unpin(cx).is_ready() {
+                *sleep = timer.sleep(Redeliveries::TICK);
+                let _ = sleep.poll_unpin(cx);
+                self.redeliver();
+            }
+        }
+        if !self.config.retention.is_empty()
+            || !self.config.history.is_empty()
+            || self.seen_store.is_some()
+            || !self.topic_activity.is_empty()
+        {
+            let interval = self.config.heartbeat_interval;
+            let timer = &self.config.timer;
+            let sleep = self
+                .heartbeat_timer
+                .get_or_insert_with(|| timer.sleep(interval));
+            if sleep.poll_unpin(cx).is_ready() {
+                *sleep = timer.sleep(interval);
+                let _ = sleep.poll_unpin(cx);
+                self.enforce_retention();
+                let now = self.config.clock.now();
+                for (topic, retention) in &self.config.history {
+                    if let Some(history) = self.history.get_mut(topic) {
+                        history.enforce(retention, now);
+                    }
+                }
+                self.prune_seen_store();
+                self.evict_idle_topics();
+            }
+        }
+        if let Some(self_test) = self.config.self_test {
+            let probing = self.prober.is_probing();
+            let timer = &self.config.timer;
+            let sleep = self
+                .self_test_timer
+                .get_or_insert_with(|| timer.sleep(self_test.interval));
+            if sleep.poll_unpin(cx).is_ready() {
+                let wait = if probing {
+                    // The fields may be set without `Config::with_self_test` checking them.
+                    self_test.interval.saturating_sub(self_test.deadline)
+                } else {
+                    self_test.deadline
+                };
+                *sleep = timer.sleep(wait);
+                let _ = sleep.poll_unpin(cx);
+                if probing {
+                    self.finish_probe();
+                } else {
+                    self.send_probe();
+                }
+            }
+        }
+        let mut due = Vec::new();
+        self.pending_announcements
+            .retain_mut(|(delay, peer)| match delay.poll_unpin(cx) {
+                Poll::Ready(()) => {
+                    due.push(*peer);
+                    false
+                }
+                Poll::Pending => true,
+            });
+        for peer in due {
+            // The peer may have disconnected while waiting.
+            if self.routes.is_connected(&peer) {
+                self.announce_subscriptions(&peer);
+            }
+        }
+        #[cfg(feature = "chaos")]
+        let event = self.next_event_with_faults(cx);
+        #[cfg(not(feature = "chaos"))]
+        let event = self.events.pop_front();
+        if let Some(event) = event {
+            if let (ToSwarm::GenerateEvent(event), Some(sink)) = (&event, self.event_sink.as_mut())
+            {
+                sink.export(clock::unix_millis(self.config.clock.as_ref()), event);
+            }
+            Poll::Ready(event)
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+/// Transmission between the `OneShotHandler` and the `BroadcastHandler`.
+// See `handler::HandlerEvent`.
+#[cfg_attr(feature = "long-topics", allow(clippy::large_enum_variant))]
+#[derive(Debug)]
+pub enum HandlerEvent {
+    /// We received a `Message` from a remote.
+    Rx(Message),
+    /// We successfully sent a `Message`.
+    Tx,
+}
+
+impl From<Message> for HandlerEvent {
+    fn from(message: Message) -> Self {
+        Self::Rx(message)
+    }
+}
+
+impl From<()> for HandlerEvent {
+    fn from(_: ()) -> Self {
+        Self::Tx
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant, SystemTime};
+
+    use crate::bloom::RotatingBloomFilter;
+    use crate::config::SelfTest;
+    use crate::config::{BandwidthCap, Redelivery, Retention};
+    use crate::fan_out::RandomSample;
+    use crate::outbox::MemoryOutbox;
+    use crate::seen_store::MemorySeenStore;
+    use crate::subscription_store::MemorySubscriptionStore;
+    use crate::test_support::ManualClock;
+    use crate::timer::Timer;
+    use crate::types::TraceContext;
+    use crate::Clock;
+
+    struct DummySwarm {
+        peer_id: PeerId,
+        behaviour: Arc<Mutex<Behaviour>>,
+        connections: FnvHashMap<PeerId, Arc<Mutex<Behaviour>>>,
+    }
+
+    impl DummySwarm {
+        fn new() -> Self {
+            Self::with_config(Config::default())
+        }
+
+        fn with_config(config: Config) -> Self {
+            Self {
+                peer_id: PeerId::random(),
+                behaviour: Arc::new(Mutex::new(Behaviour::new(config))),
+                connections: Default::default(),
+            }
+        }
+
+        fn peer_id(&self) -> &PeerId {
+            &self.peer_id
+        }
+
+        fn dial(&mut self, other: &mut DummySwarm) {
+            self.behaviour
+                .lock()
+                .unwrap()
+                .inject_connected(other.peer_id());
+            self.connections
+                .insert(*other.peer_id(), other.behaviour.clone());
+            other
+                .behaviour
+                .lock()
+                .unwrap()
+                .inject_connected(self.peer_id());
+            other
+                .connections
+                .insert(*self.peer_id(), self.behaviour.clone());
+        }
+
+        fn next(&self) -> Option<Event> {
+            let waker = futures::task::noop_waker();
+            let mut ctx = Context::from_waker(&waker);
+            let mut me = self.behaviour.lock().unwrap();
+            loop {
+                match me.poll(&mut ctx) {
+                    Poll::Ready(ToSwarm::NotifyHandler {
+                        peer_id,
+                        event: Command::Send(outbound),
+                        ..
+                    }) => {
+                        if let Some(other) = self.connections.get(&peer_id) {
+                            let mut other = other.lock().unwrap();
+                            other.on_connection_handler_event(
+                                *self.peer_id(),
+                                ConnectionId::new_unchecked(0),
+                                Rx(outbound.message),
+                            );
+                        }
+                    }
+                    Poll::Ready(ToSwarm::NotifyHandler { .. }) => {}
+                    Poll::Ready(ToSwarm::GenerateEvent(event)) => {
+                        return Some(event);
+                    }
+                    Poll::Ready(_) => panic!(),
+                    Poll::Pending => {
+                        return None;
+                    }
+                }
+            }
+        }
+
+        fn subscribe(&self, topic: Topic) {
+            let mut me = self.behaviour.lock().unwrap();
+            me.subscribe(topic);
+        }
+
+        fn unsubscribe(&self, topic: &Topic) {
+            let mut me = self.behaviour.lock().unwrap();
+            me.unsubscribe(topic);
+        }
+
+        fn broadcast(&self, topic: &Topic, msg: Bytes) {
+            let mut me = self.behaviour.lock().unwrap();
+            me.broadcast(topic, msg);
+        }
+    }
+
+    /// Polls the swarms in turn, a few rounds for the messages to make their way through all of
+    /// them. Returns the events of each.
+    fn drain(swarms: [&DummySwarm; 3]) -> Vec<Vec<Event>> {
+        let mut events = vec![Vec::new(), Vec::new(), Vec::new()];
+        for _ in 0..3 {
+            for (swarm, events) in swarms.iter().zip(&mut events) {
+                while let Some(event) = swarm.next() {
+                    events.push(event);
+                }
+            }
+        }
+        events
+    }
+
+    #[test]
+    fn test_broadcast() {
+        let topic = Topic::new(b"topic");
+        let msg = Bytes::from_static(b"msg");
+        let mut a = DummySwarm::new();
+        let mut b = DummySwarm::new();
+
+        a.subscribe(topic);
+        a.dial(&mut b);
+        assert!(a.next().is_none());
+        assert_eq!(b.next().unwrap(), Event::Subscribed(*a.peer_id(), topic));
+        b.subscribe(topic);
+        assert!(b.next().is_none());
+        assert_eq!(a.next().unwrap(), Event::Subscribed(*b.peer_id(), topic));
+        b.broadcast(&topic, msg.clone());
+        assert!(b.next().is_none());
+        assert_eq!(
+            a.next().unwrap(),
+            Event::Received(*b.peer_id(), topic, msg, Metadata::default())
+        );
+        a.unsubscribe(&topic);
+        assert!(a.next().is_none());
+        assert_eq!(b.next().unwrap(), Event::Unsubscribed(*a.peer_id(), topic));
+    }
+
+    #[test]
+    fn test_outbox() {
+        struct SharedOutbox(Arc<Mutex<MemoryOutbox>>);
+
+        impl OutboxStore for SharedOutbox {
+            fn append(
+                &mut self,
+                topic: &Topic,
+                payload: &Bytes,
+                metadata: &Metadata,
+            ) -> io::Result<u64> {
+                self.0.lock().unwrap().append(topic, payload, metadata)
+            }
+
+            fn ack(&mut self, id: u64) -> io::Result<()> {
+                self.0.lock().unwrap().ack(id)
+            }
+
+            fn replay(&mut self) -> io::Result<Vec<OutboxEntry>> {
+                self.0.lock().unwrap().replay()
+            }
+        }
+
+        let topic = Topic::new(b"topic");
+        let mut a = DummySwarm::new();
+        let mut b = DummySwarm::new();
+        let outbox = Arc::new(Mutex::new(MemoryOutbox::new()));
+        // Left over from a previous run.
+        outbox
+            .lock()
+            .unwrap()
+            .append(&topic, &Bytes::from_static(b"old"), &Metadata::default())
+            .unwrap();
+        b.behaviour
+            .lock()
+            .unwrap()
+            .set_outbox(SharedOutbox(outbox.clone()))
+            .unwrap();
+
+        b.broadcast(&topic, Bytes::from_static(b"new"));
+        assert!(b.next().is_none());
+        assert_eq!(outbox.lock().unwrap().replay().unwrap().len(), 2);
+
+        a.subscribe(topic);
+        a.dial(&mut b);
+        assert!(a.next().is_none());
+        assert_eq!(b.next().unwrap(), Event::Subscribed(*a.peer_id(), topic));
+        assert!(b.next().is_none());
+        for payload in [&b"old"[..], b"new"] {
+            assert_eq!(
+                a.next().unwrap(),
+                Event::Received(
+                    *b.peer_id(),
+                    topic,
+                    Bytes::from(payload),
+                    Metadata::default()
+                )
+            );
+        }
+        assert!(outbox.lock().unwrap().replay().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_write_ahead() {
+        let topic = Topic::new(b"topic");
+        let mut a = DummySwarm::new();
+        let mut b = DummySwarm::with_config(Config::default().with_write_ahead(topic));
+        b.behaviour
+            .lock()
+            .unwrap()
+            .set_outbox(MemoryOutbox::new())
+            .unwrap();
+        a.subscribe(topic);
+        a.dial(&mut b);
+        assert!(a.next().is_none());
+        assert_eq!(b.next().unwrap(), Event::Subscribed(*a.peer_id(), topic));
+
+        for payload in [&b"first"[..], b"second"] {
+            b.broadcast(&topic, Bytes::from(payload));
+        }
+        let mut behaviour = b.behaviour.lock().unwrap();
+        let stored = behaviour.outbox.as_mut().unwrap().replay().unwrap();
+        assert_eq!(stored.len(), 2);
+
+        behaviour.on_connection_handler_event(
+            *a.peer_id(),
+            ConnectionId::new_unchecked(0),
+            Flushed(vec![stored[0].id]),
+        );
+        let remaining = behaviour.outbox.as_mut().unwrap().replay().unwrap();
+        assert_eq!(remaining, stored[1..]);
+
+        // Lost along with the outbound substream before the flush, sent again.
+        behaviour.events.clear();
+        let id = stored[1].id;
+        behaviour.on_connection_handler_event(
+            *a.peer_id(),
+            ConnectionId::new_unchecked(0),
+            Unflushed(vec![stored[0].id, id]),
+        );
+        assert!(matches!(
+            behaviour.events.pop_front(),
+            Some(ToSwarm::NotifyHandler {
+                event: Command::Send(Outbound {
+                    message: Broadcast { payload, .. },
+                    ack: Some(ack),
+                    ..
+                }),
+                ..
+            }) if ack == id && payload[..] == b"second"[..]
+        ));
+        assert!(behaviour.events.is_empty());
+
+        // Not flushed before the peer disconnected, sent again once a subscriber shows up.
+        behaviour.inject_disconnected(a.peer_id());
+        assert!(behaviour.written_ahead.is_empty());
+        assert_eq!(behaviour.outboxed[&topic].len(), 1);
+    }
+
+    #[test]
+    fn test_retention() {
+        let (orders, quotes) = (Topic::new(b"orders"), Topic::new(b"quotes"));
+        let config = Config::default()
+            .with_retention(orders, Retention::default().with_max_messages(2))
+            .with_retention(quotes, Retention::default().with_max_bytes(5));
+        let mut behaviour = Behaviour::new(config);
+        behaviour.set_outbox(MemoryOutbox::new()).unwrap();
+        for payload in [&b"abc"[..], b"def", b"ghi"] {
+            behaviour.broadcast(&orders, Bytes::from(payload));
+            behaviour.broadcast(&quotes, Bytes::from(payload));
+        }
+
+        behaviour.enforce_retention();
+        let kept = |topic: &Topic| -> Vec<Bytes> {
+            behaviour.outboxed[topic]
+                .iter()
+                .map(|(_, entry)| entry.payload.clone())
+                .collect()
+        };
+        assert_eq!(kept(&orders), [&b"def"[..], b"ghi"]);
+        assert_eq!(kept(&quotes), [&b"ghi"[..]]);
+        let mut outbox = behaviour.outbox.take().unwrap();
+        assert_eq!(outbox.replay().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_replay_throttled() {
+        let topic = Topic::new(b"topic");
+        let peer = PeerId::random();
+        let config = Config::default()
+            .with_history(topic, Retention::default())
+            .with_history_replay_rate(1);
+        let mut behaviour = Behaviour::new(config);
+        for payload in [&b"one"[..], b"two", b"three"] {
+            behaviour.broadcast(&topic, Bytes::from(payload));
+        }
+        behaviour.start_replay(peer, topic);
+
+        let throttled = |behaviour: &mut Behaviour| {
+            behaviour.replay_history();
+            let events = behaviour.events.drain(..);
+            events
+                .filter(|event| {
+                    matches!(
+                        event,
+                        ToSwarm::GenerateEvent(Event::PeerThrottled(
+                            _,
+                            _,
+                            ThrottleKind::HistoryReplay
+                        ))
+                    )
+                })
+                .count()
+        };
+        assert_eq!(throttled(&mut behaviour), 1);
+        assert_eq!(throttled(&mut behaviour), 0);
+        assert_eq!(throttled(&mut behaviour), 0);
+        assert!(behaviour.replays.is_empty());
+    }
+
+    #[test]
+    fn test_history_replay() {
+        let topic = Topic::new(b"topic");
+        let mut a = DummySwarm::new();
+        let config =
+            Config::default().with_history(topic, Retention::default().with_max_messages(2));
+        let mut b = DummySwarm::with_config(config);
+        for payload in [&b"dropped"[..], b"one", b"two"] {
+            b.broadcast(&topic, Bytes::from(payload));
+        }
+
+        a.subscribe(topic);
+        a.dial(&mut b);
+        assert!(a.next().is_none());
+        assert_eq!(b.next().unwrap(), Event::Subscribed(*a.peer_id(), topic));
+        // Published during the replay, sent after the history.
+        b.broadcast(&topic, Bytes::from_static(b"live"));
+        assert!(b.next().is_none());
+        assert!(a.next().is_none());
+
+        b.behaviour.lock().unwrap().replay_history();
+        assert!(b.next().is_none());
+        // Publishing the live broadcast dropped the oldest one of the history.
+        for (payload, remaining) in [(&b"two"[..], 1), (b"live", 0)] {
+            let metadata = Metadata {
+                replay_remaining: Some(remaining),
+                ..Metadata::default()
+            };
+            assert_eq!(
+                a.next().unwrap(),
+                Event::Received(*b.peer_id(), topic, Bytes::from(payload), metadata)
+            );
+        }
+        assert_eq!(
+            a.next().unwrap(),
+            Event::ReplayCompleted(*b.peer_id(), topic)
+        );
+        assert!(b.behaviour.lock().unwrap().replays.is_empty());
+
+        b.broadcast(&topic, Bytes::from_static(b"after"));
+        assert!(b.next().is_none());
+        assert_eq!(
+            a.next().unwrap(),
+            Event::Received(
+                *b.peer_id(),
+                topic,
+                Bytes::from_static(b"after"),
+                Metadata::default()
+            )
+        );
+    }
+
+    #[test]
+    fn test_duplicate_store() {
+        let topic = Topic::new(b"topic");
+        let peer = PeerId::random();
+        let mut behaviour = Behaviour::new(Config::default());
+        let receive = |behaviour: &mut Behaviour, msg: &'static [u8]| {
+            let message = Broadcast {
+                topic,
+                payload: Bytes::from_static(msg).into(),
+                metadata: Default::default(),
+            };
+            behaviour.on_message(peer, None, message).is_some()
+        };
+        assert!(receive(&mut behaviour, b"msg"));
+        assert!(receive(&mut behaviour, b"msg"));
+
+        behaviour.set_duplicate_store(RotatingBloomFilter::new(Duration::from_secs(60), 16, 0.01));
+        assert!(receive(&mut behaviour, b"msg"));
+        assert!(!receive(&mut behaviour, b"msg"));
+    }
+
+    #[test]
+    fn test_duplicate_events() {
+        let topic = Topic::new(b"topic");
+        let msg = Bytes::from_static(b"msg");
+        let peer = PeerId::random();
+        let config = Config::default()
+            .with_seen_cache(Duration::from_secs(60), 16)
+            .with_duplicate_events(Duration::from_secs(60));
+        let mut behaviour = Behaviour::new(config);
+        let mut receive = || {
+            behaviour.on_message(
+                peer,
+                None,
+                Broadcast {
+                    topic,
+                    payload: msg.clone().into(),
+                    metadata: Default::default(),
+                },
+            )
+        };
+
+        assert!(matches!(receive(), Some(Event::Received(..))));
+        let id = MessageId::from_content(&topic, &msg);
+        assert_eq!(receive(), Some(Event::DuplicateSuppressed(peer, id, 1)));
+        assert_eq!(receive(), None);
+    }
+
+    #[test]
+    fn test_seen_store() {
+        let topic = Topic::new(b"topic");
+        let peer = PeerId::random();
+        let config = Config::default().with_seen_cache(Duration::from_secs(60), 1024);
+        let id = |msg: &[u8]| (config.message_id_fn)(&topic, msg);
+        let now = clock::unix_millis(config.clock.as_ref());
+        // Left over from before a restart.
+        let mut store = MemorySeenStore::new();
+        store.insert(id(b"recent"), now - 1_000).unwrap();
+        store.insert(id(b"expired"), now - 120_000).unwrap();
+
+        let mut behaviour = Behaviour::new(config.clone());
+        behaviour.set_seen_store(store).unwrap();
+        let mut receive = |msg: &'static [u8]| {
+            let message = Broadcast {
+                topic,
+                payload: Bytes::from_static(msg).into(),
+                metadata: Default::default(),
+            };
+            behaviour.on_message(peer, None, message).is_some()
+        };
+        assert!(!receive(b"recent"));
+        assert!(receive(b"expired"));
+        assert!(receive(b"new"));
+
+        let mut ids: Vec<_> = behaviour
+            .seen_store
+            .as_mut()
+            .unwrap()
+            .load()
+            .unwrap()
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect();
+        ids.sort();
+        let mut expected = vec![id(b"recent"), id(b"expired"), id(b"new")];
+        expected.sort();
+        assert_eq!(ids, expected);
+    }
+
+    #[test]
+    fn test_subscription_store() {
+        let (kept, added) = (Topic::new(b"kept"), Topic::new(b"added"));
+        let mut store = MemorySubscriptionStore::new();
+        store.save(&[kept]).unwrap();
+        let mut a = DummySwarm::new();
+        let mut b = DummySwarm::new();
+        a.behaviour
+            .lock()
+            .unwrap()
+            .set_subscription_store(store)
+            .unwrap();
+        a.dial(&mut b);
+        assert!(a.next().is_none());
+        assert_eq!(b.next().unwrap(), Event::Subscribed(*a.peer_id(), kept));
+
+        a.subscribe(added);
+        a.unsubscribe(&kept);
+        let mut behaviour = a.behaviour.lock().unwrap();
+        let store = behaviour.subscription_store.as_mut().unwrap();
+        assert_eq!(store.load().unwrap(), [added]);
+    }
+
+    #[test]
+    fn test_interceptors() {
+        /// Reverses outbound payloads and reverses them back inbound, dropping empty ones.
+        struct Reverse;
+
+        impl Interceptor for Reverse {
+            fn on_outbound(&mut self, _: &Topic, payload: &mut Bytes, _: &mut Metadata) -> bool {
+                *payload = payload.iter().rev().copied().collect();
+                !payload.is_empty()
+            }
+
+            fn on_inbound(
+                &mut self,
+                _: &PeerId,
+                _: &Topic,
+                payload: &mut Bytes,
+                metadata: &mut Metadata,
+            ) -> bool {
+                *payload = payload.iter().rev().copied().collect();
+                metadata.content_type = Some("text/plain".into());
+                true
+            }
+        }
+
+        /// Records the outbound payloads.
+        struct Record(Arc<Mutex<Vec<Bytes>>>);
+
+        impl Interceptor for Record {
+            fn on_outbound(&mut self, _: &Topic, payload: &mut Bytes, _: &mut Metadata) -> bool {
+                self.0.lock().unwrap().push(payload.clone());
+                true
+            }
+        }
+
+        let topic = Topic::new(b"topic");
+        let mut a = DummySwarm::new();
+        let mut b = DummySwarm::new();
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        {
+            let mut behaviour = b.behaviour.lock().unwrap();
+            behaviour.add_interceptor(Reverse);
+            behaviour.add_interceptor(Record(sent.clone()));
+        }
+        a.behaviour.lock().unwrap().add_interceptor(Reverse);
+        a.subscribe(topic);
+        a.dial(&mut b);
+        assert!(a.next().is_none());
+        assert_eq!(b.next().unwrap(), Event::Subscribed(*a.peer_id(), topic));
+
+        b.broadcast(&topic, Bytes::new());
+        b.broadcast(&topic, Bytes::from_static(b"abc"));
+        assert!(b.next().is_none());
+        assert_eq!(*sent.lock().unwrap(), [Bytes::from_static(b"cba")]);
+        assert_eq!(
+            a.next().unwrap(),
+            Event::Received(
+                *b.peer_id(),
+                topic,
+                Bytes::from_static(b"abc"),
+                Metadata::default().with_content_type("text/plain")
+            )
+        );
+    }
+
+    #[test]
+    fn test_callbacks() {
+        let topic = Topic::new(b"topic");
+        let mut a = DummySwarm::new();
+        let mut b = DummySwarm::new();
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        for swarm in [&a, &b] {
+            let mut behaviour = swarm.behaviour.lock().unwrap();
+            let record = |call: &'static str| {
+                let calls = calls.clone();
+                move || calls.lock().unwrap().push(call)
+            };
+            let subscribed = record("subscribe");
+            behaviour.on_subscribe(move |_| subscribed());
+            let unsubscribed = record("unsubscribe");
+            behaviour.on_unsubscribe(move |_| unsubscribed());
+            let published = record("publish");
+            behaviour.on_publish(move |_, _| published());
+            let received = record("receive");
+            behaviour.on_receive(move |_, _, _| received());
+        }
+
+        a.subscribe(topic);
+        a.dial(&mut b);
+        assert!(a.next().is_none());
+        assert_eq!(b.next().unwrap(), Event::Subscribed(*a.peer_id(), topic));
+        b.broadcast(&topic, Bytes::from_static(b"msg"));
+        assert!(b.next().is_none());
+        assert!(matches!(a.next().unwrap(), Event::Received(..)));
+        a.unsubscribe(&topic);
+        assert_eq!(
+            *calls.lock().unwrap(),
+            ["subscribe", "publish", "receive", "unsubscribe"]
+        );
+
+        a.behaviour.lock().unwrap().clear_callbacks();
+        a.subscribe(topic);
+        assert_eq!(calls.lock().unwrap().len(), 4);
+    }
+
+    #[test]
+    fn test_journal() {
+        let topic = Topic::new(b"topic");
+        let msg = Bytes::from_static(b"msg");
+        let mut a = DummySwarm::new();
+        let mut b = DummySwarm::new();
+        let journal = Arc::new(Mutex::new(Vec::new()));
+        for swarm in [&a, &b] {
+            let journal = journal.clone();
+            swarm
+                .behaviour
+                .lock()
+                .unwrap()
+                .set_journal(move |record: &JournalRecord| {
+                    journal.lock().unwrap().push(record.clone())
+                });
+        }
+
+        a.subscribe(topic);
+        a.dial(&mut b);
+        assert!(a.next().is_none());
+        assert_eq!(b.next().unwrap(), Event::Subscribed(*a.peer_id(), topic));
+        b.broadcast(&topic, msg.clone());
+        assert!(b.next().is_none());
+        assert!(a.next().is_some());
+        let records = journal.lock().unwrap().clone();
+        let kinds: Vec<_> = records.iter().map(|record| record.kind).collect();
+        assert_eq!(
+            kinds,
+            [JournalKind::Published, JournalKind::Delivered(*b.peer_id())]
+        );
+
+        // Replaying the journal reproduces the delivery.
+        let c = DummySwarm::new();
+        c.behaviour.lock().unwrap().replay(records);
+        assert_eq!(
+            c.next().unwrap(),
+            Event::Received(*b.peer_id(), topic, msg, Metadata::default())
+        );
+    }
+
+    #[test]
+    fn test_dump_state() {
+        let topic = Topic::new(b"topic");
+        let mut a = DummySwarm::new();
+        let mut b = DummySwarm::new();
+        a.subscribe(topic);
+        b.subscribe(Topic::new(b"other"));
+        a.dial(&mut b);
+        assert!(a.next().is_none());
+        assert_eq!(b.next().unwrap(), Event::Subscribed(*a.peer_id(), topic));
+
+        let dump = b.behaviour.lock().unwrap().dump_state();
+        assert_eq!(dump.subscriptions, [Topic::new(b"other")]);
+        assert_eq!(dump.peers.len(), 1);
+        assert_eq!(dump.peers[0].peer, *a.peer_id());
+        assert_eq!(dump.peers[0].topics, [topic]);
+        assert_eq!(dump.health.subscribers, 1);
+        let json = dump.to_json();
+        assert!(json.starts_with(&format!("{{\"taken_at\":{},", dump.taken_at)));
+        assert!(json.contains(&format!("\"peers\":[{{\"peer\":\"{}\",", a.peer_id())));
+        assert!(json.contains("\"subscriptions\":[\"other\"]"));
+    }
+
+    #[test]
+    fn test_event_sink() {
+        let topic = Topic::new(b"topic");
+        let mut a = DummySwarm::new();
+        let mut b = DummySwarm::new();
+        let exported = Arc::new(Mutex::new(Vec::new()));
+        let sink = exported.clone();
+        b.behaviour
+            .lock()
+            .unwrap()
+            .set_event_sink(move |_, event: &Event| sink.lock().unwrap().push(event.clone()));
+
+        a.subscribe(topic);
+        a.dial(&mut b);
+        assert!(a.next().is_none());
+        let event = b.next().unwrap();
+        assert_eq!(*exported.lock().unwrap(), [event]);
+    }
+
+    #[test]
+    fn test_reputations() {
+        let (failed, other) = (PeerId::random(), PeerId::random());
+        let mut exporter = Behaviour::new(Config::default());
+        exporter.on_connection_handler_event(failed, ConnectionId::new_unchecked(0), Failed);
+        let reputations = PeerReputations::from_bytes(&exporter.reputations().to_bytes()).unwrap();
+        assert_eq!(reputations.cooldowns.len(), 1);
+
+        let mut importer = Behaviour::new(Config::default());
+        let until = importer.config.clock.now() + Duration::from_secs(3600);
+        importer.failed_peers.insert(failed, until);
+        importer.import_reputations(reputations);
+        assert!(!importer.is_routable(&failed));
+        assert!(importer.is_routable(&other));
+        // The longer cooldown is kept.
+        assert_eq!(importer.failed_peers[&failed], until);
+    }
+
+    #[test]
+    fn test_snapshot_restore() {
+        let topic = Topic::new(b"topic");
+        let msg = Bytes::from_static(b"msg");
+        let config = Config::default().with_seen_cache(Duration::from_secs(60), 1024);
+        let drain = |swarm: &DummySwarm| std::iter::from_fn(|| swarm.next()).collect::<Vec<_>>();
+        let mut a = DummySwarm::with_config(config.clone());
+        let mut b = DummySwarm::new();
+        a.subscribe(topic);
+        b.subscribe(topic);
+        a.dial(&mut b);
+        assert!(a.next().is_none());
+        assert_eq!(b.next().unwrap(), Event::Subscribed(*a.peer_id(), topic));
+        assert_eq!(a.next().unwrap(), Event::Subscribed(*b.peer_id(), topic));
+        b.broadcast(&topic, msg.clone());
+        assert!(b.next().is_none());
+        assert!(matches!(a.next().unwrap(), Event::Received(..)));
+        let snapshot = a.behaviour.lock().unwrap().snapshot();
+        let snapshot = BehaviourSnapshot::from_bytes(&snapshot.to_bytes()).unwrap();
+
+        // After a restart, b is known to be subscribed as soon as it connects, and its message
+        // is still recognised as a duplicate.
+        let mut a = DummySwarm {
+            peer_id: *a.peer_id(),
+            ..DummySwarm::with_config(config)
+        };
+        let mut b = DummySwarm {
+            peer_id: *b.peer_id(),
+            ..DummySwarm::new()
+        };
+        a.behaviour.lock().unwrap().restore(snapshot);
+        assert!(a
+            .behaviour
+            .lock()
+            .unwrap()
+            .subscribed()
+            .any(|t| *t == topic));
+        a.dial(&mut b);
+        assert_eq!(a.next().unwrap(), Event::Subscribed(*b.peer_id(), topic));
+        b.subscribe(topic);
+        drain(&a);
+        drain(&b);
+        b.broadcast(&topic, msg);
+        drain(&b);
+        assert!(!drain(&a)
+            .iter()
+            .any(|event| matches!(event, Event::Received(..))));
+    }
+
+    #[test]
+    fn test_duplicate_suppression() {
+        let topic = Topic::new(b"topic");
+        let msg = Bytes::from_static(b"msg");
+        let config = Config::default().with_seen_cache(Duration::from_secs(60), 1024);
+        let mut a = DummySwarm::with_config(config.clone());
+        let mut b = DummySwarm::with_config(config);
+
+        a.subscribe(topic);
+        b.subscribe(topic);
+        a.dial(&mut b);
+        assert!(a.next().is_none());
+        assert_eq!(b.next().unwrap(), Event::Subscribed(*a.peer_id(), topic));
+        assert_eq!(a.next().unwrap(), Event::Subscribed(*b.peer_id(), topic));
+
+        b.broadcast(&topic, msg.clone());
+        b.broadcast(&topic, msg.clone());
+        assert!(b.next().is_none());
+        assert_eq!(
+            a.next().unwrap(),
+            Event::Received(*b.peer_id(), topic, msg, Metadata::default())
+        );
+        assert!(a.next().is_none());
+    }
+
+    #[test]
+    fn test_injected_clock() {
+        let topic = Topic::new(b"topic");
+        let msg = Bytes::from_static(b"msg");
+        let clock = ManualClock::new();
+        let config = Config::default()
+            .with_seen_cache(Duration::from_secs(60), 1024)
+            .with_clock(clock.clone());
+        let mut a = DummySwarm::with_config(config.clone());
+        let mut b = DummySwarm::with_config(config);
+
+        a.subscribe(topic);
+        a.dial(&mut b);
+        assert!(a.next().is_none());
+        assert_eq!(b.next().unwrap(), Event::Subscribed(*a.peer_id(), topic));
+
+        b.broadcast(&topic, msg.clone());
+        assert!(b.next().is_none());
+        assert_eq!(
+            a.next().unwrap(),
+            Event::Received(*b.peer_id(), topic, msg.clone(), Metadata::default())
+        );
+        b.broadcast(&topic, msg.clone());
+        assert!(b.next().is_none());
+        assert!(a.next().is_none());
+
+        // Once the simulated time passes the ttl, the broadcast is no longer a duplicate.
+        clock.advance(Duration::from_secs(120));
+        b.broadcast(&topic, msg.clone());
+        assert!(b.next().is_none());
+        assert_eq!(
+            a.next().unwrap(),
+            Event::Received(*b.peer_id(), topic, msg, Metadata::default())
+        );
+    }
+
+    #[test]
+    fn test_timestamps() {
+        struct WallClock(Mutex<SystemTime>);
+
+        impl Clock for Arc<WallClock> {
+            fn now(&self) -> Instant {
+                Instant::now()
+            }
+
+            fn wall_time(&self) -> SystemTime {
+                *self.0.lock().unwrap()
+            }
+        }
+
+        let topic = Topic::new(b"topic");
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let (clock_a, clock_b) = (
+            Arc::new(WallClock(Mutex::new(start))),
+            Arc::new(WallClock(Mutex::new(start + Duration::from_millis(250)))),
+        );
+        let mut a = DummySwarm::with_config(
+            Config::default()
+                .with_timestamps()
+                .with_clock(clock_a.clone()),
+        );
+        let mut b = DummySwarm::with_config(
+            Config::default()
+                .with_max_message_age(Duration::from_secs(10), Duration::from_secs(1))
+                .with_clock(clock_b),
+        );
+
+        b.subscribe(topic);
+        a.dial(&mut b);
+        assert!(b.next().is_none());
+        assert_eq!(a.next().unwrap(), Event::Subscribed(*b.peer_id(), topic));
+
+        a.broadcast(&topic, Bytes::from_static(b"fresh"));
+        assert!(a.next().is_none());
+        let Some(Event::Received(_, _, _, metadata)) = b.next() else {
+            panic!("expected a broadcast");
+        };
+        assert_eq!(metadata.timestamp, Some(1_700_000_000_000));
+        assert_eq!(metadata.skew, Some(250));
+
+        *clock_a.0.lock().unwrap() -= Duration::from_secs(20);
+        a.broadcast(&topic, Bytes::from_static(b"stale"));
+        assert!(a.next().is_none());
+        assert!(b.next().is_none());
+
+        // Extreme timestamps are dropped as stale instead of overflowing.
+        let mut behaviour = Behaviour::new(
+            Config::default().with_max_message_age(Duration::from_secs(10), Duration::from_secs(1)),
+        );
+        for timestamp in [u64::MAX, i64::MAX as u64 + 1] {
+            let metadata = Metadata {
+                timestamp: Some(timestamp),
+                ..Metadata::default()
+            };
+            let payload = Bytes::from_static(b"far off");
+            assert_eq!(
+                behaviour.deliver(*a.peer_id(), topic, payload, metadata),
+                None
+            );
+        }
+    }
+
+    #[test]
+    fn test_self_test() {
+        let self_test = SelfTest {
+            interval: Duration::from_secs(10),
+            deadline: Duration::from_secs(1),
+        };
+        let config = Config::default().with_self_test(self_test);
+        let mut a = DummySwarm::with_config(config.clone());
+        let mut b = DummySwarm::with_config(config);
+        let c = DummySwarm::new();
+        a.dial(&mut b);
+        let reserved = Topic::new(SelfTest::TOPIC);
+        assert!(a.next().is_none());
+        assert_eq!(b.next().unwrap(), Event::Subscribed(*a.peer_id(), reserved));
+        assert_eq!(a.next().unwrap(), Event::Subscribed(*b.peer_id(), reserved));
+
+        // b echoes the probe, without handing it to the application.
+        a.behaviour.lock().unwrap().send_probe();
+        assert!(a.next().is_none());
+        assert!(b.next().is_none());
+        assert!(a.next().is_none());
+        a.behaviour.lock().unwrap().finish_probe();
+        let Some(Event::SelfTest(result)) = a.next() else {
+            panic!("expected the outcome of the probe");
+        };
+        assert!(result.passed);
+        assert_eq!((result.probed, result.echoes), (1, 1));
+        assert_eq!(a.behaviour.lock().unwrap().health().self_test, Some(result));
+
+        // Without any peer to echo it, the probe fails.
+        let mut alone = c.behaviour.lock().unwrap();
+        alone.config.self_test = Some(self_test);
+        alone.send_probe();
+        alone.finish_probe();
+        assert!(alone
+            .health()
+            .self_test
+            .is_some_and(|result| !result.passed));
+    }
+
+    #[test]
+    fn test_self_test_deadline_past_interval() {
+        let clock = ManualClock::new();
+        let mut config = Config::default()
+            .with_clock(clock.clone())
+            .with_timer(clock.clone());
+        config.self_test = Some(SelfTest {
+            interval: Duration::from_secs(1),
+            deadline: Duration::from_secs(2),
+        });
+        let mut behaviour = Behaviour::new(config);
+        let mut cx = Context::from_waker(futures::task::noop_waker_ref());
+        let _ = behaviour.poll(&mut cx);
+        for _ in 0..3 {
+            clock.advance(Duration::from_secs(2));
+            while behaviour.poll(&mut cx).is_ready() {}
+        }
+        assert!(behaviour
+            .health()
+            .self_test
+            .is_some_and(|result| !result.passed));
+    }
+
+    #[cfg(feature = "chaos")]
+    #[test]
+    fn test_delivery_faults() {
+        use crate::chaos::FaultInjection;
+
+        let topic = Topic::new(b"topic");
+        let faults = FaultInjection::default().with_duplicate_rate(1.0);
+        let mut a = DummySwarm::new();
+        let mut b = DummySwarm::with_config(Config::default().with_delivery_faults(faults));
+        b.subscribe(topic);
+        a.dial(&mut b);
+        assert!(b.next().is_none());
+        assert_eq!(a.next().unwrap(), Event::Subscribed(*b.peer_id(), topic));
+
+        // Other events pass untouched, deliveries are duplicated.
+        a.broadcast(&topic, Bytes::from_static(b"twice"));
+        assert!(a.next().is_none());
+        let received = Event::Received(
+            *a.peer_id(),
+            topic,
+            Bytes::from_static(b"twice"),
+            Metadata::default(),
+        );
+        assert_eq!(b.next(), Some(received.clone()));
+        assert_eq!(b.next(), Some(received));
+        assert!(b.next().is_none());
+
+        b.behaviour.lock().unwrap().delivery_chaos = Some(Chaos::new(
+            FaultInjection::default().with_drop_rate(1.0),
+            None,
+            Arc::new(crate::timer::FuturesTimer),
+        ));
+        a.broadcast(&topic, Bytes::from_static(b"lost"));
+        assert!(a.next().is_none());
+        assert!(b.next().is_none());
+    }
+
+    #[test]
+    fn test_trace_context() {
+        let topic = Topic::new(b"topic");
+        let config = Config::default().with_trace_context(|| Some(TraceContext::new(7, 8)));
+        let mut a = DummySwarm::with_config(config);
+        let mut b = DummySwarm::new();
+        b.subscribe(topic);
+        a.dial(&mut b);
+        assert!(b.next().is_none());
+        assert_eq!(a.next().unwrap(), Event::Subscribed(*b.peer_id(), topic));
+
+        a.broadcast(&topic, Bytes::from_static(b"traced"));
+        assert!(a.next().is_none());
+        let Some(Event::Received(_, _, _, metadata)) = b.next() else {
+            panic!("expected a broadcast");
+        };
+        assert_eq!(metadata.trace, Some(TraceContext::new(7, 8)));
+        assert_eq!(
+            metadata.trace.unwrap().trace_id_hex(),
+            "00000000000000000000000000000007"
+        );
+    }
+
+    #[test]
+    fn test_forwarding() {
+        let topic = Topic::new(b"topic");
+        let msg = Bytes::from_static(b"msg");
+        let config = Config::default().with_forwarding();
+        let mut a = DummySwarm::with_config(config.clone());
+        let mut b = DummySwarm::with_config(config.clone());
+        let mut c = DummySwarm::with_config(config);
+
+        // a and c are only connected through b.
+        for swarm in [&a, &b, &c] {
+            swarm.subscribe(topic);
+        }
+        a.dial(&mut b);
+        b.dial(&mut c);
+        drain([&a, &b, &c]);
+
+        a.broadcast(&topic, msg.clone());
+        let events = drain([&a, &b, &c]);
+        assert!(events[0].is_empty());
+        assert_eq!(
+            events[1],
+            [Event::Received(
+                *a.peer_id(),
+                topic,
+                msg.clone(),
+                Metadata::default()
+            )]
+        );
+        assert_eq!(
+            events[2],
+            [Event::Received(
+                *b.peer_id(),
+                topic,
+                msg,
+                Metadata {
+                    origin: Some(*a.peer_id()),
+                    hops: Some(1),
+                    ..Metadata::default()
+                }
+            )]
+        );
+
+        // Broadcasts whose ttl is exhausted only reach the publisher's own neighbours.
+        let msg = Bytes::from_static(b"local");
+        let metadata = Metadata::default().with_hop_limit(0);
+        a.behaviour
+            .lock()
+            .unwrap()
+            .broadcast_with_metadata(&topic, msg.clone(), metadata.clone());
+        let events = drain([&a, &b, &c]);
+        assert_eq!(
+            events[1],
+            [Event::Received(*a.peer_id(), topic, msg, metadata)]
+        );
+        assert!(events[2].is_empty());
+    }
+
+    #[test]
+    fn test_unsupported_peer() {
+        let topic = Topic::new(b"topic");
+        let mut a = DummySwarm::new();
+        let mut b = DummySwarm::new();
+
+        a.subscribe(topic);
+        a.dial(&mut b);
+        assert!(a.next().is_none());
+        assert_eq!(b.next().unwrap(), Event::Subscribed(*a.peer_id(), topic));
+
+        let connection = ConnectionId::new_unchecked(0);
+        let mut behaviour = b.behaviour.lock().unwrap();
+        behaviour.on_connection_handler_event(*a.peer_id(), connection, Unsupported);
+        assert_eq!(behaviour.peers(&topic).map(Iterator::count), None);
+        drop(behaviour);
+        assert_eq!(b.next().unwrap(), Event::Unsubscribed(*a.peer_id(), topic));
+    }
+
+    #[test]
+    fn test_negotiated_protocols() {
+        let peer = PeerId::random();
+        let connection = ConnectionId::new_unchecked(0);
+        let mut behaviour = Behaviour::default();
+        behaviour.inject_connected(&peer);
+        let unknown = NegotiatedProtocol {
+            version: None,
+            capabilities: None,
+        };
+        assert_eq!(
+            behaviour.negotiated_protocols().collect::<Vec<_>>(),
+            [(&peer, unknown)]
+        );
+
+        behaviour.on_connection_handler_event(peer, connection, NegotiatedVersion(Version::V2));
+        behaviour.on_connection_handler_event(peer, connection, Negotiated(Capabilities::ACKS));
+        let negotiated = NegotiatedProtocol {
+            version: Some(Version::V2),
+            capabilities: Some(Capabilities::ACKS),
+        };
+        assert_eq!(
+            behaviour.negotiated_protocols().collect::<Vec<_>>(),
+            [(&peer, negotiated)]
+        );
+
+        behaviour.on_connection_handler_event(peer, connection, Unsupported);
+        assert_eq!(behaviour.version(&peer), None);
+    }
+
+    #[test]
+    fn test_broadcast_report() {
+        let topic = Topic::new(b"topic");
+        let msg = Bytes::from_static(b"msg");
+        let (healthy, failed) = (PeerId::random(), PeerId::random());
+        let mut behaviour = Behaviour::new(Config::default());
+        for peer in [healthy, failed] {
+            behaviour.inject_connected(&peer);
+            behaviour.on_message(peer, None, Subscribe { topic });
+        }
+        let until = Instant::now() + Duration::from_secs(10);
+        behaviour.failed_peers.insert(failed, until);
+
+        let report = behaviour.broadcast(&topic, msg.clone());
+        assert_eq!(report.peers.len(), 2);
+        let cooling_down = PeerOutcome::Skipped(SkipReason::CoolingDown);
+        assert!(report.peers.contains(&(failed, cooling_down)));
+        assert_eq!(report.queued().collect::<Vec<_>>(), [&healthy]);
+
+        behaviour.set_fan_out(RandomSample(0));
+        let report = behaviour.broadcast(&topic, msg);
+        let not_selected = PeerOutcome::Skipped(SkipReason::NotSelected);
+        assert!(report.peers.contains(&(healthy, not_selected)));
+        assert!(!report.outboxed);
+    }
+
+    #[test]
+    fn test_circuit_breaker() {
+        let topic = Topic::new(b"topic");
+        let msg = Bytes::from_static(b"msg");
+        let clock = ManualClock::new();
+        let config = Config::default()
+            .with_circuit_breaker(3, Duration::from_secs(10))
+            .with_clock(clock.clone());
+        let mut a = DummySwarm::new();
+        let mut b = DummySwarm::with_config(config);
+
+        a.subscribe(topic);
+        a.dial(&mut b);
+        assert!(a.next().is_none());
+        assert_eq!(b.next().unwrap(), Event::Subscribed(*a.peer_id(), topic));
+
+        let connection = ConnectionId::new_unchecked(0);
+        b.behaviour
+            .lock()
+            .unwrap()
+            .on_connection_handler_event(*a.peer_id(), connection, Failed);
+        assert_eq!(b.next().unwrap(), Event::PeerFailed(*a.peer_id()));
+        b.broadcast(&topic, msg.clone());
+        assert!(b.next().is_none());
+        assert!(a.next().is_none());
+
+        clock.advance(Duration::from_secs(10));
+        b.broadcast(&topic, msg.clone());
+        assert!(b.next().is_none());
+        assert_eq!(
+            a.next().unwrap(),
+            Event::Received(*b.peer_id(), topic, msg, Metadata::default())
+        );
+    }
+
+    #[test]
+    fn test_slow_consumer_unsubscribed() {
+        let topic = Topic::new(b"topic");
+        let config = Config::default()
+            .with_slow_consumer_detection(Duration::from_secs(1), SlowConsumerAction::Unsubscribe);
+        let mut a = DummySwarm::new();
+        let mut b = DummySwarm::with_config(config);
+
+        a.subscribe(topic);
+        a.dial(&mut b);
+        assert!(a.next().is_none());
+        assert_eq!(b.next().unwrap(), Event::Subscribed(*a.peer_id(), topic));
+
+        let wait = Duration::from_secs(2);
+        b.behaviour.lock().unwrap().on_connection_handler_event(
+            *a.peer_id(),
+            ConnectionId::new_unchecked(0),
+            Slow(wait),
+        );
+        assert_eq!(b.next().unwrap(), Event::Unsubscribed(*a.peer_id(), topic));
+        assert_eq!(b.next().unwrap(), Event::SlowConsumer(*a.peer_id(), wait));
+        b.broadcast(&topic, Bytes::from_static(b"msg"));
+        assert!(b.next().is_none());
+        assert!(a.next().is_none());
+    }
+
+    #[test]
+    fn test_bandwidth_cap() {
+        let topic = Topic::new(b"topic");
+        let msg = Bytes::from(vec![0; 1000]);
+        // Room for two broadcasts, and the handshake and subscriptions sent along.
+        let config = Config::default().with_bandwidth_cap(BandwidthCap {
+            max_bytes: 2500,
+            interval: Duration::from_secs(60),
+            action: CapAction::Throttle,
+        });
+        let mut a = DummySwarm::with_config(config);
+        let mut b = DummySwarm::new();
+
+        a.subscribe(topic);
+        a.dial(&mut b);
+        assert!(a.next().is_none());
+        assert_eq!(b.next().unwrap(), Event::Subscribed(*a.peer_id(), topic));
+        for _ in 0..3 {
+            b.broadcast(&topic, msg.clone());
+        }
+        assert!(b.next().is_none());
+        let received = Event::Received(*b.peer_id(), topic, msg, Metadata::default());
+        assert_eq!(a.next().unwrap(), received);
+        assert_eq!(a.next().unwrap(), received);
+        assert_eq!(
+            a.next().unwrap(),
+            Event::PeerThrottled(
+                *b.peer_id(),
+                None,
+                ThrottleKind::Bandwidth(CapAction::Throttle)
+            )
+        );
+        assert!(a.next().is_none());
+
+        // Subscriptions are still accepted from a throttled peer.
+        b.subscribe(topic);
+        assert!(b.next().is_none());
+        assert_eq!(a.next().unwrap(), Event::Subscribed(*b.peer_id(), topic));
+    }
+
+    #[test]
+    fn test_bandwidth_cap_chunked() {
+        let topic = Topic::new(b"topic");
+        let peer = PeerId::random();
+        let connection = ConnectionId::new_unchecked(0);
+        let config = Config::default().with_bandwidth_cap(BandwidthCap {
+            max_bytes: 8,
+            interval: Duration::from_secs(60),
+            action: CapAction::Throttle,
+        });
+        let mut behaviour = Behaviour::new(config);
+
+        behaviour.on_connection_handler_event(peer, connection, RxStreamStart(topic, 10));
+        for (data, last) in [(&b"abcde"[..], false), (b"fghij", true)] {
+            behaviour.on_connection_handler_event(
+                peer,
+                connection,
+                RxStreamChunk(Bytes::from(data), last),
+            );
+        }
+        let events: Vec<_> = behaviour
+            .events
+            .drain(..)
+            .map(|event| match event {
+                ToSwarm::GenerateEvent(event) => event,
+                _ => panic!(),
+            })
+            .collect();
+        assert_eq!(
+            events,
+            [Event::PeerThrottled(
+                peer,
+                None,
+                ThrottleKind::Bandwidth(CapAction::Throttle)
+            )]
+        );
+        assert!(behaviour.skipped_streams.is_empty());
+    }
+
+    #[test]
+    fn test_connection_preference() {
+        let peer = PeerId::random();
+        let (old, new) = (
+            ConnectionId::new_unchecked(0),
+            ConnectionId::new_unchecked(1),
+        );
+        let behaviour = |preference| {
+            let mut behaviour =
+                Behaviour::new(Config::default().with_connection_preference(preference));
+            behaviour.connections.insert(peer, vec![old, new]);
+            behaviour
+        };
+
+        assert!(matches!(
+            behaviour(ConnectionPreference::Any).handler_for(&peer),
+            NotifyHandler::Any
+        ));
+        assert!(matches!(
+            behaviour(ConnectionPreference::Newest).handler_for(&peer),
+            NotifyHandler::One(c) if c == new
+        ));
+
+        let mut fastest = behaviour(ConnectionPreference::LowestLatency);
+        fastest.on_connection_handler_event(peer, old, Latency(Duration::from_millis(5)));
+        assert!(matches!(fastest.handler_for(&peer), NotifyHandler::One(c) if c == new));
+        fastest.on_connection_handler_event(peer, new, Latency(Duration::from_millis(50)));
+        assert!(matches!(fastest.handler_for(&peer), NotifyHandler::One(c) if c == old));
+    }
+
+    #[test]
+    fn test_pending() {
+        let peer = PeerId::random();
+        let (first, second) = (
+            ConnectionId::new_unchecked(0),
+            ConnectionId::new_unchecked(1),
+        );
+        let mut behaviour = Behaviour::new(Config::default());
+        behaviour.connections.insert(peer, vec![first, second]);
+        assert_eq!(behaviour.pending(&peer), QueueStats::default());
+
+        let oldest = Instant::now() - Duration::from_secs(5);
+        behaviour.on_connection_handler_event(peer, first, Queue(2, 100, Some(oldest)));
+        behaviour.on_connection_handler_event(peer, second, Queue(1, 20, None));
+        let stats = behaviour.pending(&peer);
+        assert_eq!((stats.messages, stats.bytes), (3, 120));
+        assert!(stats.oldest_age >= Some(Duration::from_secs(5)));
+
+        behaviour.on_connection_handler_event(peer, first, Queue(0, 0, None));
+        assert_eq!(behaviour.pending(&peer).oldest_age, None);
+    }
+
+    #[test]
+    fn test_reset_peer() {
+        let topic = Topic::new(b"topic");
+        let peer = PeerId::random();
+        let connection = ConnectionId::new_unchecked(0);
+        let mut behaviour = Behaviour::new(Config::default());
+        assert!(!behaviour.reset_peer(&peer));
+
+        behaviour.inject_connected(&peer);
+        behaviour.connections.insert(peer, vec![connection]);
+        behaviour.on_message(peer, None, Subscribe { topic });
+        behaviour.subscribe(topic);
+        behaviour.events.clear();
+        behaviour.broadcast(&topic, Bytes::from_static(b"wedged"));
+
+        assert!(behaviour.reset_peer(&peer));
+        let commands: Vec<_> = behaviour
+            .events
+            .drain(..)
+            .filter_map(|event| match event {
+                ToSwarm::NotifyHandler { event, .. } => Some(event),
+                _ => None,
+            })
+            .collect();
+        assert!(matches!(
+            commands.as_slice(),
+            [
+                Command::Reset,
+                Command::Send(Outbound { message: Subscribe { topic: t }, .. }),
+                Command::Send(Outbound { message: Query, .. }),
+            ] if *t == topic
+        ));
+    }
+
+    #[test]
+    fn test_reconfigure() {
+        let peer = PeerId::random();
+        let connections = [
+            ConnectionId::new_unchecked(0),
+            ConnectionId::new_unchecked(1),
+        ];
+        let mut behaviour = Behaviour::new(Config::default());
+        behaviour.inject_connected(&peer);
+        behaviour.connections.insert(peer, connections.to_vec());
+        behaviour.events.clear();
+
+        behaviour.reconfigure(Config::default().with_max_buf_size(4096));
+        assert_eq!(behaviour.config.max_buf_size, 4096);
+        let reconfigured: Vec<_> = behaviour
+            .events
+            .drain(..)
+            .filter_map(|event| match event {
+                ToSwarm::NotifyHandler {
+                    event: Command::Reconfigure(config),
+                    handler: NotifyHandler::One(connection),
+                    ..
+                } if config.max_buf_size == 4096 => Some(connection),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(reconfigured, connections);
+    }
+
+    #[test]
+    fn test_reconfigure_set_up() {
+        let self_test = Topic::new(SelfTest::TOPIC);
+        let peer = PeerId::random();
+        let mut behaviour = Behaviour::new(Config::default());
+        behaviour.inject_connected(&peer);
+        behaviour.events.clear();
+        let announced = |behaviour: &mut Behaviour| -> Vec<Message> {
+            behaviour
+                .events
+                .drain(..)
+                .filter_map(|event| match event {
+                    ToSwarm::NotifyHandler {
+                        event: Command::Send(Outbound { message, .. }),
+                        ..
+                    } => Some(message),
+                    _ => None,
+                })
+                .collect()
+        };
+
+        let config = Config::default()
+            .with_self_test(SelfTest {
+                interval: Duration::from_secs(10),
+                deadline: Duration::from_secs(1),
+            })
+            .with_delivery_window(16)
+            .with_seen_cache(Duration::from_secs(60), 16);
+        behaviour.reconfigure(config);
+        assert!(behaviour.subscriptions.contains(&self_test));
+        assert_eq!(announced(&mut behaviour), [Subscribe { topic: self_test }]);
+        assert!(behaviour.delivery_window.is_some());
+        assert!(behaviour.seen.is_some());
+
+        behaviour.reconfigure(Config::default());
+        assert!(!behaviour.subscriptions.contains(&self_test));
+        assert_eq!(
+            announced(&mut behaviour),
+            [Unsubscribe { topic: self_test }]
+        );
+        assert!(behaviour.delivery_window.is_none());
+        assert!(behaviour.seen.is_none());
+    }
+
+    #[test]
+    #[cfg(feature = "long-topics")]
+    fn test_long_topics() {
+        let (topic, long) = (Topic::new(b"short"), Topic::new(&[b'a'; 100]));
+        let peer = PeerId::random();
+        let connection = ConnectionId::new_unchecked(0);
+        let subscribe = |topic| Rx(Subscribe { topic });
+
+        // Topics longer than the plain frames allow are only accepted if configured.
+        let mut behaviour = Behaviour::default();
+        behaviour.inject_connected(&peer);
+        behaviour.on_connection_handler_event(peer, connection, subscribe(long));
+        assert!(behaviour.peers(&long).is_none());
+
+        let mut behaviour = Behaviour::new(Config::default().with_max_topic_length(200));
+        behaviour.inject_connected(&peer);
+        behaviour.on_connection_handler_event(peer, connection, subscribe(long));
+        assert_eq!(behaviour.peers(&long).unwrap().collect::<Vec<_>>(), [&peer]);
+
+        // Subscriptions to long topics are announced again once the remote negotiated them.
+        behaviour.subscribe_many([topic, long]);
+        behaviour.events.clear();
+        let long_topics = Negotiated(Capabilities::LONG_TOPICS);
+        behaviour.on_connection_handler_event(peer, connection, long_topics);
+        let announced: Vec<_> = behaviour
+            .events
+            .drain(..)
+            .filter_map(|event| match event {
+                ToSwarm::NotifyHandler {
+                    event: Command::Send(Outbound { message, .. }),
+                    ..
+                } => Some(message),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(announced, [Subscribe { topic: long }]);
+    }
+
+    #[test]
+    fn test_shared_subscriptions() {
+        let (topic, group) = (Topic::new(b"jobs"), Topic::new(b"workers"));
+        let (a, b, plain) = (PeerId::random(), PeerId::random(), PeerId::random());
+        let mut behaviour = Behaviour::new(Config::default());
+        for peer in [a, b, plain] {
+            behaviour.inject_connected(&peer);
+        }
+        behaviour.on_message(a, None, SubscribeShared { topic, group });
+        behaviour.on_message(b, None, SubscribeShared { topic, group });
+        behaviour.on_message(plain, None, Subscribe { topic });
+
+        let mut members = Vec::new();
+        for _ in 0..4 {
+            let report = behaviour.broadcast(&topic, Bytes::from_static(b"job"));
+            let queued: Vec<_> = report.queued().copied().collect();
+            assert_eq!(queued.len(), 2);
+            assert!(queued.contains(&plain));
+            members.extend(queued.into_iter().filter(|peer| *peer != plain));
+        }
+        assert_eq!(members.iter().filter(|peer| **peer == a).count(), 2);
+
+        // A plain subscription leaves the group.
+        behaviour.on_message(a, None, Subscribe { topic });
+        let report = behaviour.broadcast(&topic, Bytes::from_static(b"job"));
+        assert_eq!(report.queued().count(), 3);
+
+        behaviour
+            .capabilities
+            .insert(a, Capabilities::SHARED_SUBSCRIPTIONS);
+        behaviour.events.clear();
+        behaviour.subscribe_shared(topic, group);
+        assert_eq!(behaviour.shared_group(&topic), Some(&group));
+        let announced: Vec<_> = behaviour
+            .events
+            .drain(..)
+            .filter_map(|event| match event {
+                ToSwarm::NotifyHandler {
+                    peer_id,
+                    event: Command::Send(Outbound { message, .. }),
+                    ..
+                } => Some((peer_id, message)),
+                _ => None,
+            })
+            .collect();
+        assert!(announced.contains(&(a, SubscribeShared { topic, group })));
+        assert!(announced.contains(&(b, Subscribe { topic })));
+    }
+
+    #[test]
+    fn test_at_least_once() {
+        let topic = Topic::new(b"orders");
+        let (acking, other) = (PeerId::random(), PeerId::random());
+        let redelivery = Redelivery {
+            timeout: Duration::from_secs(1),
+            max_retries: 3,
+        };
+        let clock = ManualClock::new();
+        let config = Config::default()
+            .with_at_least_once(topic, redelivery)
+            .with_clock(clock.clone());
+        let mut behaviour = Behaviour::new(config);
+        for peer in [acking, other] {
+            behaviour.inject_connected(&peer);
+            behaviour.on_message(peer, None, Subscribe { topic });
+        }
+        behaviour.capabilities.insert(acking, Capabilities::ACKS);
+
+        let report = behaviour.broadcast(&topic, Bytes::from_static(b"first"));
+        let id = report.delivery_id.unwrap();
+        assert_eq!(report.queued().count(), 2);
+        behaviour.on_message(acking, None, Ack { delivery_id: id });
+        assert!(behaviour.redeliveries.is_empty());
+
+        // Broadcasts not acknowledged within the timeout are sent again.
+        let report = behaviour.broadcast(&topic, Bytes::from_static(b"second"));
+        let id = report.delivery_id.unwrap();
+        behaviour.events.clear();
+        clock.advance(Duration::from_millis(500));
+        behaviour.redeliver();
+        assert!(behaviour.events.is_empty());
+        clock.advance(Duration::from_millis(500));
+        behaviour.redeliver();
+        assert!(matches!(
+            behaviour.events.pop_front(),
+            Some(ToSwarm::NotifyHandler {
+                peer_id,
+                event: Command::Send(Outbound {
+                    message: Broadcast { metadata, .. },
+                    ..
+                }),
+                ..
+            }) if peer_id == acking && metadata.delivery_id == Some(id)
+        ));
+        assert!(behaviour.events.is_empty());
+
+        behaviour.inject_disconnected(&acking);
+        assert!(behaviour.events.iter().any(|event| matches!(
+            event,
+            ToSwarm::GenerateEvent(Event::Unacknowledged(peer, t, i))
+                if *peer == acking && *t == topic && *i == id
+        )));
+
+        // Receivers acknowledge the broadcasts carrying a delivery id.
+        let metadata = Metadata {
+            delivery_id: Some(7),
+            ..Metadata::default()
+        };
+        behaviour.capabilities.insert(other, Capabilities::ACKS);
+        behaviour.subscribe(topic);
+        behaviour.events.clear();
+        let broadcast = Broadcast {
+            topic,
+            payload: Bytes::from_static(b"order").into(),
+            metadata: Arc::new(metadata),
+        };
+        assert!(matches!(
+            behaviour.on_message(other,None, broadcast.clone()),
+            Some(Event::Received(peer, ..)) if peer == other
+        ));
+        // Redeliveries are acknowledged again, but not delivered twice.
+        assert_eq!(behaviour.on_message(other, None, broadcast), None);
+        let acks = behaviour
+            .events
+            .iter()
+            .filter(|event| {
+                matches!(
+                    event,
+                    ToSwarm::NotifyHandler {
+                        event: Command::Send(Outbound {
+                            message: Ack { delivery_id: 7 },
+                            ..
+                        }),
+                        ..
+                    }
+                )
+            })
+            .count();
+        assert_eq!(acks, 2);
+    }
+
+    #[test]
+    fn test_flow_control() {
+        let topic = Topic::new(b"feed");
+        let peer = PeerId::random();
+        let (first, second) = (
+            ConnectionId::new_unchecked(0),
+            ConnectionId::new_unchecked(1),
+        );
+        let config = Config::default()
+            .with_flow_control(topic, 4)
+            .with_connection_pruning(Duration::ZERO);
+        let mut behaviour = Behaviour::new(config);
+        behaviour.on_connection_established(peer, first, 0);
+        behaviour.on_connection_established(peer, second, 1);
+        behaviour.capabilities.insert(peer, Capabilities::CREDITS);
+        let credits = |behaviour: &mut Behaviour| -> Vec<(ConnectionId, Message)> {
+            behaviour
+                .events
+                .drain(..)
+                .filter_map(|event| match event {
+                    ToSwarm::NotifyHandler {
+                        event: Command::Send(Outbound { message, .. }),
+                        handler,
+                        ..
+                    } if matches!(message, Credit { .. }) => match handler {
+                        NotifyHandler::One(connection) => Some((connection, message)),
+                        NotifyHandler::Any => panic!("credits granted to any handler"),
+                    },
+                    _ => None,
+                })
+                .collect()
+        };
+        let broadcast = || Broadcast {
+            topic,
+            payload: Bytes::from_static(b"item").into(),
+            metadata: Default::default(),
+        };
+
+        // Every handler starts out with a whole window.
+        behaviour.subscribe(topic);
+        assert_eq!(
+            credits(&mut behaviour),
+            [
+                (first, Credit { topic, credits: 4 }),
+                (second, Credit { topic, credits: 4 })
+            ]
+        );
+
+        // Credits are granted back once half of the window was received, to the handler that
+        // consumed them.
+        behaviour.on_message(peer, Some(first), broadcast());
+        behaviour.on_message(peer, Some(second), broadcast());
+        assert!(credits(&mut behaviour).is_empty());
+        behaviour.on_message(peer, Some(second), broadcast());
+        assert_eq!(
+            credits(&mut behaviour),
+            [(second, Credit { topic, credits: 2 })]
+        );
+        behaviour.on_message(peer, Some(first), broadcast());
+        assert_eq!(
+            credits(&mut behaviour),
+            [(first, Credit { topic, credits: 2 })]
+        );
+
+        // Replayed broadcasts took no credit.
+        behaviour.on_message(peer, None, broadcast());
+        behaviour.on_message(peer, None, broadcast());
+        assert!(credits(&mut behaviour).is_empty());
+
+        behaviour.on_connection_handler_event(peer, first, Stalled(topic));
+        assert!(matches!(
+            behaviour.events.pop_front(),
+            Some(ToSwarm::GenerateEvent(Event::PeerThrottled(
+                throttled,
+                Some(stalled),
+                ThrottleKind::Credits
+            ))) if throttled == peer && stalled == topic
+        ));
+    }
+
+    #[test]
+    fn test_delivery_window() {
+        let topic = Topic::new(b"topic");
+        let (publisher, relay) = (PeerId::random(), PeerId::random());
+        let config = Config::default()
+            .with_sequence_numbers()
+            .with_delivery_window(16);
+        let mut behaviour = Behaviour::new(config);
+        behaviour.inject_connected(&relay);
+        behaviour.on_message(relay, None, Subscribe { topic });
+        behaviour.broadcast(&topic, Bytes::from_static(b"numbered"));
+        assert!(behaviour.events.iter().any(|event| matches!(
+            event,
+            ToSwarm::NotifyHandler {
+                event: Command::Send(Outbound { message: Broadcast { metadata, .. }, .. }),
+                ..
+            } if metadata.sequence.is_some()
+        )));
+
+        let numbered = |seq| {
+            Arc::new(Metadata {
+                origin: Some(publisher),
+                sequence: Some(seq),
+                ..Metadata::default()
+            })
+        };
+        let received = |behaviour: &mut Behaviour, msg: &'static [u8], seq| {
+            let msg = Bytes::from_static(msg);
+            behaviour.on_message(
+                relay,
+                None,
+                Broadcast {
+                    topic,
+                    payload: msg.into(),
+                    metadata: numbered(seq),
+                },
+            )
+        };
+        assert!(received(&mut behaviour, b"first", 100).is_some());
+        // The same broadcast, replayed with different content.
+        assert!(received(&mut behaviour, b"first again", 100).is_none());
+        assert!(received(&mut behaviour, b"second", 101).is_some());
+    }
+
+    #[test]
+    fn test_reordering() {
+        let topic = Topic::new(b"topic");
+        let peer = PeerId::random();
+        let reordering = Reordering {
+            max_buffered: 2,
+            timeout: Duration::from_secs(1),
+        };
+        let clock = ManualClock::new();
+        let config = Config::default()
+            .with_reordering(topic, reordering)
+            .with_clock(clock.clone());
+        let mut behaviour = Behaviour::new(config);
+        let events = |behaviour: &mut Behaviour| -> Vec<String> {
+            behaviour
+                .events
+                .drain(..)
+                .filter_map(|event| match event {
+                    ToSwarm::GenerateEvent(Event::Received(_, _, msg, _)) => {
+                        Some(String::from_utf8(msg.to_vec()).unwrap())
+                    }
+                    ToSwarm::GenerateEvent(Event::ReorderOverflow(..)) => Some("overflow".into()),
+                    ToSwarm::GenerateEvent(Event::ReorderTimeout(..)) => Some("timeout".into()),
+                    ToSwarm::GenerateEvent(Event::Missing(publisher, t, range)) => {
+                        assert_eq!((publisher, t), (peer, topic));
+                        Some(format!("missing {range:?}"))
+                    }
+                    _ => None,
+                })
+                .collect()
+        };
+        let receive = |behaviour: &mut Behaviour, seq: u64| {
+            let metadata = Metadata {
+                sequence: Some(seq),
+                ..Metadata::default()
+            };
+            let msg = Bytes::from(seq.to_string());
+            if let Some(event) = behaviour.on_message(
+                peer,
+                None,
+                Broadcast {
+                    topic,
+                    payload: msg.into(),
+                    metadata: metadata.into(),
+                },
+            ) {
+                behaviour.events.push_back(ToSwarm::GenerateEvent(event));
+            }
+            events(behaviour)
+        };
+        assert_eq!(receive(&mut behaviour, 1), ["1"]);
+        assert!(receive(&mut behaviour, 3).is_empty());
+        assert_eq!(receive(&mut behaviour, 2), ["2", "3"]);
+        assert!(receive(&mut behaviour, 5).is_empty());
+        assert!(receive(&mut behaviour, 6).is_empty());
+        let overflow = ["overflow", "missing 4..5", "5", "6", "7"];
+        assert_eq!(receive(&mut behaviour, 7), overflow);
+        assert!(receive(&mut behaviour, 4).is_empty());
+
+        // 8 and 9 go missing until the timeout.
+        assert!(receive(&mut behaviour, 10).is_empty());
+        behaviour.expire_reordering();
+        assert!(events(&mut behaviour).is_empty());
+        clock.advance(reordering.timeout);
+        behaviour.expire_reordering();
+        assert_eq!(events(&mut behaviour), ["timeout", "missing 8..10", "10"]);
+        assert!(receive(&mut behaviour, 9).is_empty());
+        assert_eq!(receive(&mut behaviour, 11), ["11"]);
+    }
+
+    #[test]
+    fn test_health() {
+        let topic = Topic::new(b"topic");
+        let (subscriber, other) = (PeerId::random(), PeerId::random());
+        let connection = ConnectionId::new_unchecked(0);
+        let mut behaviour = Behaviour::new(Config::default());
+        behaviour.inject_connected(&subscriber);
+        behaviour.inject_connected(&other);
+        behaviour.connections.insert(subscriber, vec![connection]);
+        behaviour.on_message(subscriber, None, Subscribe { topic });
+        assert_eq!(
+            behaviour.health(),
+            Health {
+                connected_peers: 2,
+                subscribers: 1,
+                ..Health::default()
+            }
+        );
+
+        let oldest = Instant::now() - Health::STUCK_AFTER * 2;
+        behaviour.on_connection_handler_event(subscriber, connection, Queue(1, 10, Some(oldest)));
+        behaviour.on_connection_handler_event(subscriber, connection, Shed(2));
+        behaviour.on_connection_handler_event(subscriber, connection, Failed);
+        let health = behaviour.health();
+        assert_eq!(health.stuck_queues, [subscriber]);
+        assert_eq!(
+            (
+                health.recent_errors.shed,
+                health.recent_errors.peer_failures
+            ),
+            (2, 1)
+        );
+        assert_eq!(health.cooling_down.len(), 1);
+        assert_eq!(health.cooling_down[0].0, subscriber);
+    }
+
+    #[test]
+    fn test_no_shared_topics() {
+        let topic = Topic::new(b"topic");
+        let mut a = DummySwarm::new();
+        let mut b =
+            DummySwarm::with_config(Config::default().with_connection_pruning(Duration::ZERO));
+
+        a.subscribe(topic);
+        b.subscribe(topic);
+        a.dial(&mut b);
+        assert!(a.next().is_none());
+        assert_eq!(b.next().unwrap(), Event::Subscribed(*a.peer_id(), topic));
+        assert_eq!(a.next().unwrap(), Event::Subscribed(*b.peer_id(), topic));
+
+        a.unsubscribe(&topic);
+        assert!(a.next().is_none());
+        assert_eq!(b.next().unwrap(), Event::NoSharedTopics(*a.peer_id()));
+        assert_eq!(b.next().unwrap(), Event::Unsubscribed(*a.peer_id(), topic));
+    }
+
+    #[test]
+    fn test_shared_topics_on_new_connection() {
+        let topic = Topic::new(b"topic");
+        let peer = PeerId::random();
+        let config = Config::default().with_connection_pruning(Duration::ZERO);
+        let mut behaviour = Behaviour::new(config);
+        behaviour.subscribe(topic);
+        behaviour.on_connection_established(peer, ConnectionId::new_unchecked(0), 0);
+        behaviour.on_message(peer, None, Subscribe { topic });
+        behaviour.events.clear();
+
+        let second = ConnectionId::new_unchecked(1);
+        behaviour.on_connection_established(peer, second, 1);
+        assert!(matches!(
+            behaviour.events.pop_front(),
+            Some(ToSwarm::NotifyHandler {
+                peer_id,
+                event: Command::SharesTopics(true),
+                handler: NotifyHandler::One(connection),
+            }) if peer_id == peer && connection == second
+        ));
+        assert!(behaviour.events.is_empty());
+    }
+
+    #[test]
+    fn test_resync() {
+        let (topic, stale) = (Topic::new(b"topic"), Topic::new(b"stale"));
+        let mut a = DummySwarm::new();
+        let mut b = DummySwarm::new();
+
+        a.subscribe(topic);
+        a.dial(&mut b);
+        assert!(a.next().is_none());
+        assert_eq!(b.next().unwrap(), Event::Subscribed(*a.peer_id(), topic));
+
+        // b missed an unsubscription of a.
+        b.behaviour
+            .lock()
+            .unwrap()
+            .routes
+            .subscribe(*a.peer_id(), stale);
+        b.behaviour.lock().unwrap().query_all_subscriptions();
+        assert!(b.next().is_none());
+        assert!(a.next().is_none());
+        assert_eq!(b.next().unwrap(), Event::Unsubscribed(*a.peer_id(), stale));
+        assert!(b.next().is_none());
+
+        assert!(a.behaviour.lock().unwrap().resync(b.peer_id()));
+        assert!(a.next().is_none());
+        assert!(b.next().is_none());
+        assert!(!a.behaviour.lock().unwrap().resync(&PeerId::random()));
+    }
+
+    #[test]
+    fn test_query_subscriptions() {
+        let (first, second) = (Topic::new(b"first"), Topic::new(b"second"));
+        let mut a = DummySwarm::new();
+        let mut b = DummySwarm::new();
+        a.subscribe(second);
+        a.subscribe(first);
+        a.dial(&mut b);
+        assert!(a.next().is_none());
+        while b.next().is_some() {}
+
+        assert!(b.behaviour.lock().unwrap().query_subscriptions(a.peer_id()));
+        assert!(b.next().is_none());
+        assert!(a.next().is_none());
+        assert_eq!(
+            b.next().unwrap(),
+            Event::PeerSubscriptions(*a.peer_id(), vec![first, second])
+        );
+        assert!(b.next().is_none());
+        assert!(!b
+            .behaviour
+            .lock()
+            .unwrap()
+            .query_subscriptions(&PeerId::random()));
+    }
+
+    #[test]
+    fn test_repeated_queries() {
+        let (first, second) = (Topic::new(b"first"), Topic::new(b"second"));
+        let peer = PeerId::random();
+        let mut behaviour = Behaviour::new(Config::default());
+        behaviour.inject_connected(&peer);
+        behaviour.subscribe(first);
+        behaviour.events.clear();
+
+        for _ in 0..1000 {
+            behaviour.on_message(peer, None, Query);
+        }
+        behaviour.subscribe(second);
+        behaviour.on_message(peer, None, Query);
+        let snapshots: Vec<_> = behaviour
+            .events
+            .drain(..)
+            .filter_map(|event| match event {
+                ToSwarm::NotifyHandler {
+                    event: Command::Send(outbound),
+                    ..
+                } if matches!(outbound.message, Snapshot { .. }) => Some(outbound),
+                _ => None,
+            })
+            .collect();
+        let [Outbound {
+            message: Snapshot { topics },
+            supersede_key: Some(SNAPSHOT_KEY),
+            ..
+        }] = snapshots.as_slice()
+        else {
+            panic!("expected a single snapshot, got {:?}", snapshots);
+        };
+        let mut topics = topics.clone();
+        topics.sort();
+        assert_eq!(topics, [first, second]);
+
+        // The parts of a snapshot add up.
+        behaviour.on_message(
+            peer,
+            None,
+            SnapshotPart {
+                topics: vec![first],
+            },
+        );
+        behaviour.on_message(
+            peer,
+            None,
+            Snapshot {
+                topics: vec![second],
+            },
+        );
+        let mut topics: Vec<_> = behaviour
+            .routes
+            .topics(&peer)
+            .into_iter()
+            .flatten()
+            .collect();
+        topics.sort();
+        assert_eq!(topics, [&first, &second]);
+    }
+
+    #[test]
+    fn test_custom_timer() {
+        #[derive(Clone, Default)]
+        struct RecordingTimer(Arc<Mutex<Vec<Duration>>>);
+
+        impl Timer for RecordingTimer {
+            fn sleep(&self, duration: Duration) -> Sleep {
+                self.0.lock().unwrap().push(duration);
+                Box::pin(futures::future::ready(()))
+            }
+        }
+
+        let topic = Topic::new(b"topic");
+        let peer = PeerId::random();
+        let timer = RecordingTimer::default();
+        let jitter = Duration::from_secs(3600);
+        let config = Config::default()
+            .with_timer(timer.clone())
+            .with_resubscribe_jitter(jitter);
+        let mut behaviour = Behaviour::new(config);
+        behaviour.subscribe(topic);
+        behaviour.events.clear();
+
+        behaviour.inject_connected(&peer);
+        assert!(behaviour.events.is_empty());
+        let delays = timer.0.lock().unwrap().clone();
+        assert!(matches!(&delays[..], [delay] if *delay <= jitter));
+
+        // Announced as soon as the timer fires, however long the jitter.
+        let mut cx = Context::from_waker(futures::task::noop_waker_ref());
+        assert!(matches!(
+            behaviour.poll(&mut cx),
+            Poll::Ready(ToSwarm::NotifyHandler {
+                peer_id,
+                event: Command::Send(Outbound {
+                    message: Subscribe { topic: subscribed },
+                    ..
+                }),
+                ..
+            }) if peer_id == peer && subscribed == topic
+        ));
+    }
+
+    #[test]
+    fn test_resubscribe_jitter() {
+        let topic = Topic::new(b"topic");
+        let jitter = Duration::from_secs(60);
+        let clock = ManualClock::new();
+        let config = Config::default()
+            .with_clock(clock.clone())
+            .with_timer(clock.clone())
+            .with_rng_seed(7)
+            .with_resubscribe_jitter(jitter);
+        let mut a = DummySwarm::with_config(config);
+        let mut b = DummySwarm::new();
+
+        a.subscribe(topic);
+        a.dial(&mut b);
+        assert!(a.next().is_none());
+        assert!(b.next().is_none());
+
+        clock.advance(jitter);
+        assert!(a.next().is_none());
+        assert_eq!(b.next().unwrap(), Event::Subscribed(*a.peer_id(), topic));
+    }
+
+    #[test]
+    fn test_topic_stats() {
+        let (subscribed, other) = (Topic::new(b"subscribed"), Topic::new(b"other"));
+        let peer = PeerId::random();
+        let clock = ManualClock::new();
+        let mut behaviour = Behaviour::new(Config::default().with_clock(clock.clone()));
+        behaviour.subscribe(subscribed);
+        for topic in [subscribed, other] {
+            behaviour.on_message(
+                peer,
+                None,
+                Message::broadcast(topic, Bytes::from_static(b"msg")),
+            );
+        }
+        assert!(behaviour.topic_stats(&subscribed).last_activity.is_some());
+        assert!(behaviour.topic_stats(&other).last_activity.is_none());
+
+        // Published topics are kept alike, until idle.
+        behaviour.broadcast(&other, Bytes::from_static(b"msg"));
+        assert!(behaviour.topic_stats(&other).last_activity.is_some());
+        clock.advance(TopicStats::WINDOW * 3);
+        behaviour.broadcast(&other, Bytes::from_static(b"msg"));
+        clock.advance(TopicStats::WINDOW * 3);
+        behaviour.evict_idle_topics();
+        assert!(behaviour.topic_stats(&subscribed).last_activity.is_none());
+        assert!(behaviour.topic_stats(&other).last_activity.is_some());
+    }
+
+    #[test]
+    fn test_chunked_delivery() {
+        let topic = Topic::new(b"topic");
+        let peer = PeerId::random();
+        let connection = ConnectionId::new_unchecked(0);
+        let mut behaviour = Behaviour::default();
+
+        behaviour.on_connection_handler_event(peer, connection, RxStreamStart(topic, 5));
+        behaviour.on_connection_handler_event(
+            peer,
+            connection,
+            RxStreamChunk(Bytes::from_static(b"abc"), false),
+        );
+        behaviour.on_connection_handler_event(
+            peer,
+            connection,
+            RxStreamChunk(Bytes::from_static(b"de"), true),
+        );
+        behaviour.on_connection_handler_event(peer, connection, RxStreamStart(topic, 5));
+        behaviour.on_connection_handler_event(peer, connection, RxStreamAborted);
+
+        let second = Chunk {
+            stream: 0,
+            offset: 3,
+            len: 5,
+            data: Bytes::from_static(b"de"),
+        };
+        assert!(second.is_last());
+        let events: Vec<_> = behaviour
+            .events
+            .drain(..)
+            .map(|event| match event {
+                ToSwarm::GenerateEvent(event) => event,
+                _ => panic!(),
+            })
+            .collect();
+        assert_eq!(
+            events,
+            [
+                Event::ReceivedChunk(
+                    peer,
+                    topic,
+                    Chunk {
+                        stream: 0,
+                        offset: 0,
+                        len: 5,
+                        data: Bytes::from_static(b"abc"),
+                    }
+                ),
+                Event::ReceivedChunk(peer, topic, second),
+                Event::ReceiveAborted(peer, topic, 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_chunked_duplicates() {
+        let topic = Topic::new(b"topic");
+        let peer = PeerId::random();
+        let connection = ConnectionId::new_unchecked(0);
+        let config = Config::default()
+            .with_seen_cache(Duration::from_secs(60), 16)
+            .with_duplicate_events(Duration::from_secs(60));
+        let mut behaviour = Behaviour::new(config);
+
+        for _ in 0..2 {
+            behaviour.on_connection_handler_event(peer, connection, RxStreamStart(topic, 5));
+            for (data, last) in [(&b"abc"[..], false), (b"de", true)] {
+                behaviour.on_connection_handler_event(
+                    peer,
+                    connection,
+                    RxStreamChunk(Bytes::from(data), last),
+                );
+            }
+        }
+        // Also a duplicate of a broadcast received whole.
+        let id = (behaviour.config.message_id_fn)(&topic, b"abcde");
+        assert!(behaviour
+            .on_message(
+                peer,
+                None,
+                Message::broadcast(topic, Bytes::from_static(b"abcde"))
+            )
+            .is_none());
+
+        let events: Vec<_> = behaviour
+            .events
+            .drain(..)
+            .map(|event| match event {
+                ToSwarm::GenerateEvent(event) => event,
+                _ => panic!(),
+            })
+            .collect();
+        let chunk = |stream, offset, data| {
+            Event::ReceivedChunk(
+                peer,
+                topic,
+                Chunk {
+                    stream,
+                    offset,
+                    len: 5,
+                    data: Bytes::from_static(data),
+                },
+            )
+        };
+        // The duplicate is only recognized once complete, and its chunks are then to be
+        // discarded.
+        assert_eq!(
+            events,
+            [
+                chunk(0, 0, b"abc"),
+                chunk(0, 3, b"de"),
+                chunk(1, 0, b"abc"),
+                Event::ReceiveAborted(peer, topic, 1),
+                Event::DuplicateSuppressed(peer, id, 1),
+            ]
+        );
+    }
+
+    #[cfg(feature = "erasure")]
+    #[test]
+    fn test_erasure_coded_broadcast() {
+        let topic = Topic::new(b"blocks");
+        let payload = Bytes::from((0..1000u32).map(|i| i as u8).collect::<Vec<_>>());
+        let config = Config::default().with_erasure_coding(topic, ErasureCoding::new(2, 1));
+        let mut a = DummySwarm::with_config(config.clone());
+        let mut b = DummySwarm::with_config(config.clone());
+        let mut c = DummySwarm::with_config(config);
+
+        a.dial(&mut b);
+        a.dial(&mut c);
+        b.dial(&mut c);
+        for swarm in [&a, &b, &c] {
+            swarm.subscribe(topic);
+        }
+        drain([&a, &b, &c]);
+
+        a.broadcast(&topic, payload.clone());
+        let events = drain([&a, &b, &c]);
+        let received = Event::Received(*a.peer_id(), topic, payload, Metadata::default());
+        assert!(events[0].is_empty());
+        assert_eq!(events[1..], [vec![received.clone()], vec![received]]);
+    }
+
+    #[test]
+    fn test_send_to() {
+        let topic = Topic::new(b"requests");
+        let msg = Bytes::from_static(b"reply");
+        let mut a = DummySwarm::new();
+        let mut b = DummySwarm::new();
+        let c = DummySwarm::new();
+
+        a.dial(&mut b);
+        assert!(!a
+            .behaviour
+            .lock()
+            .unwrap()
+            .send_to(c.peer_id(), &topic, msg.clone()));
+        assert!(a
+            .behaviour
+            .lock()
+            .unwrap()
+            .send_to(b.peer_id(), &topic, msg.clone()));
+        assert!(a.next().is_none());
+        assert_eq!(
+            b.next().unwrap(),
+            Event::Received(*a.peer_id(), topic, msg, Metadata::default())
+        );
+    }
+
+    #[test]
+    fn test_send_control() {
+        let topic = Topic::new(b"round/7");
+        let payload = Bytes::from_static(b"ready");
+        let mut a = DummySwarm::new();
+        let mut b = DummySwarm::new();
+
+        a.dial(&mut b);
+        assert!(!a
+            .behaviour
+            .lock()
+            .unwrap()
+            .send_control(b.peer_id(), &topic, payload.clone()));
+        let mut behaviour = a.behaviour.lock().unwrap();
+        let connection = ConnectionId::new_unchecked(0);
+        behaviour.on_connection_handler_event(
+            *b.peer_id(),
+            connection,
+            Negotiated(Capabilities::CONTROL),
+        );
+        assert!(behaviour.send_control(b.peer_id(), &topic, payload.clone()));
+        drop(behaviour);
+        assert_eq!(
+            a.next().unwrap(),
+            Event::Negotiated(*b.peer_id(), Capabilities::CONTROL)
+        );
+        assert!(a.next().is_none());
+        assert_eq!(
+            b.next().unwrap(),
+            Event::ReceivedControl(*a.peer_id(), topic, payload)
+        );
+    }
+
+    #[test]
+    fn test_subscription_confirmed() {
+        let topic = Topic::new(b"topic");
+        let mut a = DummySwarm::new();
+        let mut b = DummySwarm::new();
+        a.dial(&mut b);
+        for (swarm, other) in [(&a, &b), (&b, &a)] {
+            swarm.behaviour.lock().unwrap().on_connection_handler_event(
+                *other.peer_id(),
+                ConnectionId::new_unchecked(0),
+                Negotiated(Capabilities::SUBSCRIPTION_ACKS),
+            );
+            // Only reported once per peer.
+            swarm.behaviour.lock().unwrap().on_connection_handler_event(
+                *other.peer_id(),
+                ConnectionId::new_unchecked(1),
+                Negotiated(Capabilities::SUBSCRIPTION_ACKS),
+            );
+        }
+        let (peer_a, peer_b) = (*a.peer_id(), *b.peer_id());
+        let acks = Capabilities::SUBSCRIPTION_ACKS;
+        assert_eq!(a.next().unwrap(), Event::Negotiated(peer_b, acks));
+        assert_eq!(b.next().unwrap(), Event::Negotiated(peer_a, acks));
+
+        a.subscribe(topic);
+        assert!(a.next().is_none());
+        assert_eq!(b.next().unwrap(), Event::Subscribed(*a.peer_id(), topic));
+        assert_eq!(
+            a.next().unwrap(),
+            Event::SubscriptionConfirmed(*b.peer_id(), topic)
+        );
+        assert!(b.next().is_none());
+    }
+
+    #[test]
+    fn test_membership() {
+        let topic = Topic::new(b"votes");
+        let msg = Bytes::from_static(b"vote");
+        let mut a = DummySwarm::new();
+        let mut b = DummySwarm::new();
+        let mut c = DummySwarm::new();
+
+        a.dial(&mut b);
+        a.dial(&mut c);
+        for swarm in [&a, &b, &c] {
+            swarm.subscribe(topic);
+        }
+        drain([&a, &b, &c]);
+
+        let validators = Arc::new(Mutex::new(FnvHashSet::default()));
+        validators.lock().unwrap().insert(*b.peer_id());
+        let members = validators.clone();
+        a.behaviour
+            .lock()
+            .unwrap()
+            .set_membership(move |peer: &PeerId| members.lock().unwrap().contains(peer));
+
+        a.broadcast(&topic, msg.clone());
+        assert!(a.next().is_none());
+        assert_eq!(
+            b.next().unwrap(),
+            Event::Received(*a.peer_id(), topic, msg.clone(), Metadata::default())
+        );
+        assert!(c.next().is_none());
+        c.broadcast(&topic, Bytes::from_static(b"forged"));
+        assert!(c.next().is_none());
+        assert!(a.next().is_none());
+
+        // The new validator takes part without reconnecting.
+        validators.lock().unwrap().insert(*c.peer_id());
+        c.broadcast(&topic, msg.clone());
+        assert!(c.next().is_none());
+        assert_eq!(
+            a.next().unwrap(),
+            Event::Received(*c.peer_id(), topic, msg, Metadata::default())
+        );
+    }
+
+    #[test]
+    fn test_batched_subscriptions() {
+        let topics = [Topic::new(b"a"), Topic::new(b"b"), Topic::new(b"c")];
+        let mut a = DummySwarm::new();
+        let mut b = DummySwarm::new();
+
+        a.dial(&mut b);
+        a.behaviour.lock().unwrap().subscribe_many(topics);
+        assert_eq!(a.behaviour.lock().unwrap().events.len(), 1);
+        assert!(a.next().is_none());
+        for topic in topics {
+            assert_eq!(b.next().unwrap(), Event::Subscribed(*a.peer_id(), topic));
+        }
+
+        a.behaviour.lock().unwrap().unsubscribe_many(topics);
+        assert!(a.next().is_none());
+        for topic in topics {
+            assert_eq!(b.next().unwrap(), Event::Unsubscribed(*a.peer_id(), topic));
+        }
+        assert!(b.next().is_none());
+    }
+
+    #[test]
+    fn test_broadcast_many() {
+        let (x, y, z) = (Topic::new(b"x"), Topic::new(b"y"), Topic::new(b"z"));
+        let mut a = DummySwarm::new();
+        let mut b = DummySwarm::new();
+
+        a.behaviour.lock().unwrap().subscribe_many([x, y]);
+        a.dial(&mut b);
+        assert!(a.next().is_none());
+        assert_eq!(b.next().unwrap(), Event::Subscribed(*a.peer_id(), x));
+        assert_eq!(b.next().unwrap(), Event::Subscribed(*a.peer_id(), y));
+
+        let messages = [
+            (x, Bytes::from_static(b"1")),
+            (y, Bytes::from_static(b"2")),
+            (z, Bytes::from_static(b"3")),
+        ];
+        b.behaviour.lock().unwrap().broadcast_many(&messages);
+        assert_eq!(b.behaviour.lock().unwrap().events.len(), 1);
+        assert!(b.next().is_none());
+        for (topic, msg) in &messages[..2] {
+            assert_eq!(
+                a.next().unwrap(),
+                Event::Received(*b.peer_id(), *topic, msg.clone(), Metadata::default())
+            );
+        }
+        assert!(a.next().is_none());
+    }
+
+    #[test]
+    fn test_metadata() {
+        let topic = Topic::new(b"topic");
+        let msg = Bytes::from_static(b"{}");
+        let metadata = Metadata::default().with_content_type("application/json");
+        let mut a = DummySwarm::new();
+        let mut b = DummySwarm::new();
+
+        a.subscribe(topic);
+        a.dial(&mut b);
+        assert!(a.next().is_none());
+        assert_eq!(b.next().unwrap(), Event::Subscribed(*a.peer_id(), topic));
+
+        b.behaviour
+            .lock()
+            .unwrap()
+            .broadcast_with_metadata(&topic, msg.clone(), metadata.clone());
+        assert!(b.next().is_none());
+        assert_eq!(
+            a.next().unwrap(),
+            Event::Received(*b.peer_id(), topic, msg, metadata)
+        );
+    }
+
+    #[test]
+    fn test_request_reply() {
+        let (requests, replies) = (Topic::new(b"requests"), Topic::new(b"replies/b"));
+        let mut a = DummySwarm::new();
+        let mut b = DummySwarm::new();
+
+        a.subscribe(requests);
+        b.subscribe(replies);
+        a.dial(&mut b);
+        assert!(a.next().is_none());
+        assert_eq!(b.next().unwrap(), Event::Subscribed(*a.peer_id(), requests));
+        assert_eq!(a.next().unwrap(), Event::Subscribed(*b.peer_id(), replies));
+
+        let id = b.behaviour.lock().unwrap().publish_request(
+            &requests,
+            Bytes::from_static(b"ping"),
+            replies,
+        );
+        assert!(b.next().is_none());
+        let Some(Event::Received(_, _, _, request)) = a.next() else {
+            panic!("expected a request");
+        };
+        assert_eq!(request.correlation_id, Some(id));
+
+        assert!(a
+            .behaviour
+            .lock()
+            .unwrap()
+            .publish_reply(&request, Bytes::from_static(b"pong")));
+        assert!(a.next().is_none());
+        assert_eq!(
+            b.next().unwrap(),
+            Event::Received(
+                *a.peer_id(),
+                replies,
+                Bytes::from_static(b"pong"),
+                Metadata::default().with_correlation_id(id)
+            )
+        );
+        assert!(!a
+            .behaviour
+            .lock()
+            .unwrap()
+            .publish_reply(&Metadata::default(), Bytes::new()));
+    }
+
+    #[test]
+    fn test_cancel() {
+        let (old, new) = (Topic::new(b"round/1"), Topic::new(b"round/2"));
+        let mut a = DummySwarm::new();
+        let mut b = DummySwarm::new();
+
+        b.subscribe(old);
+        b.subscribe(new);
+        a.dial(&mut b);
+        while b.next().is_some() {}
+        while a.next().is_some() {}
+
+        a.broadcast(&old, Bytes::from_static(b"late"));
+        a.broadcast(&new, Bytes::from_static(b"early"));
+        a.behaviour.lock().unwrap().cancel(&old);
+        assert!(a.next().is_none());
+        assert_eq!(
+            b.next().unwrap(),
+            Event::Received(
+                *a.peer_id(),
+                new,
+                Bytes::from_static(b"early"),
+                Metadata::default()
+            )
+        );
+        assert!(b.next().is_none());
+    }
+}
@@ -0,0 +1,142 @@
+use std::f64::consts::LN_2;
+use std::time::{Duration, Instant};
+
+use crate::types::MessageId;
+
+/// Fixed-size bloom filter over message ids.
+struct BloomFilter {
+    bits: Vec<u64>,
+    num_bits: u64,
+    num_hashes: u32,
+}
+
+impl BloomFilter {
+    /// Sizes the filter so that `capacity` insertions keep the false positive rate at or below
+    /// `fp_rate`.
+    fn new(capacity: usize, fp_rate: f64) -> Self {
+        let capacity = capacity.max(1) as f64;
+        let num_bits = (-capacity * fp_rate.ln() / (LN_2 * LN_2)).ceil().max(64.0) as u64;
+        let num_hashes = ((num_bits as f64 / capacity) * LN_2).round().max(1.0) as u32;
+        Self {
+            bits: vec![0; num_bits.div_ceil(64) as usize],
+            num_bits,
+            num_hashes,
+        }
+    }
+
+    /// Bit positions of the id, derived by double hashing.
+    fn positions(&self, id: &MessageId) -> impl Iterator<Item = u64> + '_ {
+        let h1 = id.as_u64();
+        let h2 = mix(h1) | 1;
+        (0..self.num_hashes as u64)
+            .map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % self.num_bits)
+    }
+
+    fn contains(&self, id: &MessageId) -> bool {
+        self.positions(id)
+            .all(|bit| self.bits[(bit / 64) as usize] & (1 << (bit % 64)) != 0)
+    }
+
+    fn insert(&mut self, id: &MessageId) {
+        let positions: Vec<u64> = self.positions(id).collect();
+        for bit in positions {
+            self.bits[(bit / 64) as usize] |= 1 << (bit % 64);
+        }
+    }
+
+    fn clear(&mut self) {
+        self.bits.iter_mut().for_each(|word| *word = 0);
+    }
+}
+
+/// Finalizer of splitmix64, used to derive a second independent hash from the id.
+fn mix(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
+/// Probabilistic set of recently seen message ids with a fixed memory footprint.
+///
+/// Two bloom filter generations are kept. New ids go into the current generation, lookups check
+/// both, and the older one is discarded whenever the current generation has been filled with
+/// `capacity` ids or has been active for the ttl. Ids are thus remembered for at least the ttl
+/// unless the insertion rate exceeds `capacity` per ttl, and the false positive rate stays
+/// below twice the configured rate.
+pub struct RotatingBloomFilter {
+    current: BloomFilter,
+    previous: BloomFilter,
+    inserted: usize,
+    capacity: usize,
+    ttl: Duration,
+    rotated_at: Option<Instant>,
+}
+
+impl RotatingBloomFilter {
+    /// Creates a filter sized for `capacity` ids per ttl at the false positive rate.
+    pub fn new(ttl: Duration, capacity: usize, fp_rate: f64) -> Self {
+        Self {
+            current: BloomFilter::new(capacity, fp_rate),
+            previous: BloomFilter::new(capacity, fp_rate),
+            inserted: 0,
+            capacity,
+            ttl,
+            rotated_at: None,
+        }
+    }
+
+    /// Records the id as seen at `now`. Returns `false` if it was (probably) already present.
+    pub fn insert(&mut self, id: MessageId, now: Instant) -> bool {
+        let rotated_at = *self.rotated_at.get_or_insert(now);
+        if self.inserted >= self.capacity || now >= rotated_at + self.ttl {
+            self.rotate(now);
+        }
+
+        if self.current.contains(&id) || self.previous.contains(&id) {
+            return false;
+        }
+        self.current.insert(&id);
+        self.inserted += 1;
+        true
+    }
+
+    fn rotate(&mut self, now: Instant) {
+        std::mem::swap(&mut self.current, &mut self.previous);
+        self.current.clear();
+        self.inserted = 0;
+        self.rotated_at = Some(now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_false_positive_rate() {
+        let mut filter = BloomFilter::new(10_000, 0.01);
+
+        for id in 0..10_000 {
+            filter.insert(&MessageId::new(mix(id)));
+        }
+        for id in 0..10_000 {
+            assert!(filter.contains(&MessageId::new(mix(id))));
+        }
+
+        let false_positives = (10_000..20_000)
+            .filter(|id| filter.contains(&MessageId::new(mix(*id))))
+            .count();
+        assert!(false_positives < 200, "{} false positives", false_positives);
+    }
+
+    #[test]
+    fn test_rotation() {
+        let ttl = Duration::from_secs(10);
+        let mut filter = RotatingBloomFilter::new(ttl, 100, 0.01);
+        let start = Instant::now();
+
+        assert!(filter.insert(MessageId::new(1), start));
+        assert!(!filter.insert(MessageId::new(1), start + ttl));
+        assert!(filter.insert(MessageId::new(1), start + ttl * 2));
+    }
+}
@@ -0,0 +1,47 @@
+use bytes::Bytes;
+use libp2p::PeerId;
+
+use crate::types::Topic;
+
+type TopicCallback = Box<dyn FnMut(&Topic) + Send>;
+type PublishCallback = Box<dyn FnMut(&Topic, &Bytes) + Send>;
+type ReceiveCallback = Box<dyn FnMut(&PeerId, &Topic, &Bytes) + Send>;
+
+/// Callbacks registered with `Behaviour::on_subscribe` and friends.
+#[derive(Default)]
+pub(crate) struct Callbacks {
+    pub subscribe: Vec<TopicCallback>,
+    pub unsubscribe: Vec<TopicCallback>,
+    pub publish: Vec<PublishCallback>,
+    pub receive: Vec<ReceiveCallback>,
+}
+
+impl Callbacks {
+    pub fn subscribed(&mut self, topic: &Topic) {
+        for callback in &mut self.subscribe {
+            callback(topic);
+        }
+    }
+
+    pub fn unsubscribed(&mut self, topic: &Topic) {
+        for callback in &mut self.unsubscribe {
+            callback(topic);
+        }
+    }
+
+    pub fn published(&mut self, topic: &Topic, payload: &Bytes) {
+        for callback in &mut self.publish {
+            callback(topic, payload);
+        }
+    }
+
+    pub fn received(&mut self, peer: &PeerId, topic: &Topic, payload: &Bytes) {
+        for callback in &mut self.receive {
+            callback(peer, topic, payload);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.subscribe.len() + self.unsubscribe.len() + self.publish.len() + self.receive.len()
+    }
+}
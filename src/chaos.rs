@@ -0,0 +1,165 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures::FutureExt;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::targets::CHAOS;
+use crate::timer::{Sleep, Timer};
+use crate::types::Message;
+
+/// Faults injected into the frames sent by the handlers, to test how applications cope with
+/// lossy, slow or duplicating links. Not meant for production use.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FaultInjection {
+    /// Probability of silently dropping a frame.
+    pub drop_rate: f64,
+    /// Probability of sending a frame twice.
+    pub duplicate_rate: f64,
+    /// Frames are held back for a duration drawn uniformly from this range, which reorders
+    /// frames whose delays overlap.
+    pub delay: Option<(Duration, Duration)>,
+}
+
+impl FaultInjection {
+    pub fn with_drop_rate(mut self, drop_rate: f64) -> Self {
+        assert!(
+            (0.0..=1.0).contains(&drop_rate),
+            "drop rate must be in [0, 1]"
+        );
+        self.drop_rate = drop_rate;
+        self
+    }
+
+    pub fn with_duplicate_rate(mut self, duplicate_rate: f64) -> Self {
+        assert!(
+            (0.0..=1.0).contains(&duplicate_rate),
+            "duplicate rate must be in [0, 1]"
+        );
+        self.duplicate_rate = duplicate_rate;
+        self
+    }
+
+    pub fn with_delay(mut self, min: Duration, max: Duration) -> Self {
+        assert!(min <= max, "minimum delay exceeds maximum delay");
+        self.delay = Some((min, max));
+        self
+    }
+}
+
+/// Applies `FaultInjection` to the outbound frames of a handler, or to the broadcasts the
+/// behaviour delivers.
+pub(crate) struct Chaos<T = Message> {
+    faults: FaultInjection,
+    rng: StdRng,
+    timer: Arc<dyn Timer>,
+    /// Frames held back, along with the timer releasing them.
+    delayed: Vec<(Sleep, T)>,
+    /// Frames ready to be sent ahead of the queue.
+    released: VecDeque<T>,
+}
+
+impl<T: Clone> Chaos<T> {
+    pub fn new(faults: FaultInjection, seed: Option<u64>, timer: Arc<dyn Timer>) -> Self {
+        Self {
+            faults,
+            rng: seed.map_or_else(StdRng::from_entropy, StdRng::seed_from_u64),
+            timer,
+            delayed: Vec::new(),
+            released: VecDeque::new(),
+        }
+    }
+
+    /// Passes a frame taken from the queue through the faults. Returns the frame to send right
+    /// away, if any.
+    pub fn inject(&mut self, message: T) -> Option<T> {
+        if self.rng.gen_bool(self.faults.drop_rate) {
+            tracing::trace!(target: CHAOS, "Injecting a drop");
+            return None;
+        }
+        if self.rng.gen_bool(self.faults.duplicate_rate) {
+            tracing::trace!(target: CHAOS, "Injecting a duplicate");
+            self.hold(message.clone());
+        }
+        match self.faults.delay {
+            Some(_) => {
+                self.hold(message);
+                None
+            }
+            None => Some(message),
+        }
+    }
+
+    fn hold(&mut self, message: T) {
+        match self.faults.delay {
+            Some((min, max)) => {
+                let delay = self.rng.gen_range(min..=max);
+                self.delayed.push((self.timer.sleep(delay), message));
+            }
+            None => self.released.push_back(message),
+        }
+    }
+
+    /// Moves the frames whose delay elapsed to the released frames.
+    pub fn poll_delayed(&mut self, cx: &mut Context<'_>) {
+        let released = &mut self.released;
+        self.delayed
+            .retain_mut(|(delay, message)| match delay.poll_unpin(cx) {
+                Poll::Ready(()) => {
+                    released.push_back(message.clone());
+                    false
+                }
+                Poll::Pending => true,
+            });
+    }
+
+    pub fn pop_released(&mut self) -> Option<T> {
+        self.released.pop_front()
+    }
+
+    pub fn has_released(&self) -> bool {
+        !self.released.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bytes::Bytes;
+
+    use crate::timer::FuturesTimer;
+    use crate::types::Topic;
+
+    #[test]
+    fn test_faults() {
+        let message = Message::broadcast(Topic::new(b"topic"), Bytes::from_static(b"msg"));
+
+        let timer: Arc<dyn Timer> = Arc::new(FuturesTimer);
+        let chaos_with = |faults| Chaos::new(faults, Some(0), timer.clone());
+
+        let mut chaos = chaos_with(FaultInjection::default().with_drop_rate(1.0));
+        assert_eq!(chaos.inject(message.clone()), None);
+        assert!(!chaos.has_released());
+
+        let faults = FaultInjection::default().with_duplicate_rate(1.0);
+        let mut chaos = chaos_with(faults);
+        assert_eq!(chaos.inject(message.clone()), Some(message.clone()));
+        assert_eq!(chaos.pop_released(), Some(message.clone()));
+
+        let faults = FaultInjection::default()
+            .with_delay(Duration::from_millis(1), Duration::from_millis(5));
+        let mut chaos = chaos_with(faults);
+        assert_eq!(chaos.inject(message.clone()), None);
+        let released = futures::executor::block_on(futures::future::poll_fn(|cx| {
+            chaos.poll_delayed(cx);
+            match chaos.pop_released() {
+                Some(message) => Poll::Ready(message),
+                None => Poll::Pending,
+            }
+        }));
+        assert_eq!(released, message);
+    }
+}
@@ -0,0 +1,84 @@
+use std::time::{Duration, Instant};
+
+use fnv::FnvHashMap;
+use libp2p::PeerId;
+
+/// Connection churn of a peer, see `Behaviour::churn`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Churn {
+    /// Number of times the peer connected again within `Churn::MEMORY` of its last connection
+    /// closing.
+    pub reconnects: u32,
+    /// Number of substreams reopened after the previous one in the same direction closed or
+    /// failed, over all connections to the peer.
+    pub substream_restarts: u32,
+}
+
+impl Churn {
+    /// How long the churn of a peer is remembered once it disconnected.
+    pub const MEMORY: Duration = Duration::from_secs(300);
+}
+
+/// Churn of the connected peers, and of the ones that disconnected within `Churn::MEMORY`,
+/// along with when they did.
+#[derive(Debug, Default)]
+pub(crate) struct ChurnTracker {
+    peers: FnvHashMap<PeerId, (Churn, Option<Instant>)>,
+}
+
+impl ChurnTracker {
+    /// Records that the peer connected. Returns `true` if it reconnected.
+    pub fn connected(&mut self, peer: PeerId, now: Instant) -> bool {
+        self.peers.retain(|_, (_, since)| {
+            since.is_none_or(|since| now.saturating_duration_since(since) < Churn::MEMORY)
+        });
+        let (churn, disconnected_at) = self.peers.entry(peer).or_default();
+        let reconnected = disconnected_at.take().is_some();
+        if reconnected {
+            churn.reconnects += 1;
+        }
+        reconnected
+    }
+
+    pub fn disconnected(&mut self, peer: &PeerId, now: Instant) {
+        if let Some((_, disconnected_at)) = self.peers.get_mut(peer) {
+            *disconnected_at = Some(now);
+        }
+    }
+
+    pub fn substream_restarted(&mut self, peer: PeerId) {
+        self.peers.entry(peer).or_default().0.substream_restarts += 1;
+    }
+
+    pub fn get(&self, peer: &PeerId) -> Option<Churn> {
+        self.peers.get(peer).map(|(churn, _)| *churn)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_churn() {
+        let (peer, other) = (PeerId::random(), PeerId::random());
+        let start = Instant::now();
+        let mut tracker = ChurnTracker::default();
+        assert!(!tracker.connected(peer, start));
+        tracker.substream_restarted(peer);
+        tracker.disconnected(&peer, start);
+        assert!(tracker.connected(peer, start));
+        assert_eq!(
+            tracker.get(&peer),
+            Some(Churn {
+                reconnects: 1,
+                substream_restarts: 1,
+            })
+        );
+
+        // Peers are forgotten once disconnected for longer than the memory.
+        tracker.disconnected(&peer, start);
+        assert!(!tracker.connected(other, start + Churn::MEMORY));
+        assert_eq!(tracker.get(&peer), None);
+    }
+}
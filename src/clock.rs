@@ -0,0 +1,33 @@
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+/// Source of the current time for the time-dependent state of the behaviour, such as duplicate
+/// suppression and reassembly timeouts.
+///
+/// The default reads the system clock. Simulators running many nodes in one process can supply
+/// their own to control the passage of time and replay runs deterministically.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+
+    /// Wall-clock time, used to timestamp broadcasts.
+    fn wall_time(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// Wall-clock time of the clock in milliseconds since the Unix epoch.
+pub(crate) fn unix_millis(clock: &dyn Clock) -> u64 {
+    clock
+        .wall_time()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since_epoch| since_epoch.as_millis() as u64)
+}
+
+/// Reads the monotonic system clock.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
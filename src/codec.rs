@@ -1,25 +1,172 @@
-use std::io;
+use std::{fmt, io};
 
 use asynchronous_codec::{Decoder, Encoder};
-use bytes::{Buf, BytesMut};
+use bytes::{Buf, Bytes, BytesMut};
 
-use crate::types::Message;
+use crate::protocol::Version;
+use crate::targets::CODEC;
+use crate::types::{Header, Message, Topic};
+
+/// A decoded frame, or a part of one.
+// Long topics make the messages larger, boxing them would cost an allocation per frame.
+#[cfg_attr(feature = "long-topics", allow(clippy::large_enum_variant))]
+#[derive(Debug, PartialEq, Eq)]
+pub enum Frame {
+    /// A complete message.
+    Message(Message),
+    /// Start of a broadcast whose payload exceeds the stream threshold. The payload follows as
+    /// `StreamChunk` frames.
+    StreamStart { topic: Topic, len: usize },
+    /// Part of the payload of the broadcast announced by the last `StreamStart`.
+    StreamChunk { data: Bytes, last: bool },
+}
+
+/// Error decoding a frame whose announced length exceeds the maximum we accept.
+#[derive(Debug)]
+pub struct FrameTooLarge {
+    pub size: usize,
+    pub max: usize,
+}
+
+impl fmt::Display for FrameTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Received data size ({} bytes) exceeds maximum ({} bytes)",
+            self.size, self.max
+        )
+    }
+}
+
+impl std::error::Error for FrameTooLarge {}
+
+impl FrameTooLarge {
+    /// Size of the oversized frame, if this is the error the decoder failed with.
+    pub fn size_of(error: &io::Error) -> Option<usize> {
+        let error = error.get_ref()?.downcast_ref::<Self>()?;
+        Some(error.size)
+    }
+}
 
 pub struct LengthPrefixedCodec {
     max_size: usize,
+    /// Payloads larger than this are decoded incrementally instead of buffered whole.
+    stream_threshold: Option<usize>,
+    /// Payload bytes of the current streamed broadcast that are yet to be decoded.
+    streaming: Option<usize>,
+    /// Protocol version spoken by the remote, which determines the frames it understands.
+    version: Version,
 }
 
 impl LengthPrefixedCodec {
     pub fn new(max_size: usize) -> Self {
-        Self { max_size }
+        Self {
+            max_size,
+            stream_threshold: None,
+            streaming: None,
+            version: Version::V2,
+        }
+    }
+
+    pub fn with_version(mut self, version: Version) -> Self {
+        self.version = version;
+        self
+    }
+
+    /// Encodes the message in a frame understood by peers speaking version 1 of the protocol.
+    fn encode_downgraded(&mut self, item: &Message, dst: &mut BytesMut) {
+        match item.downgrade() {
+            Some(item) => self.encode_frame(&item, dst),
+            None => {
+                tracing::debug!(target: CODEC, "Dropping a message not supported by the remote")
+            }
+        }
+    }
+
+    fn encode_frame(&mut self, item: &Message, dst: &mut BytesMut) {
+        let msg_len = item.len();
+
+        let mut varint_buf = unsigned_varint::encode::usize_buffer();
+        let encoded_len = unsigned_varint::encode::usize(msg_len, &mut varint_buf);
+
+        dst.extend_from_slice(encoded_len);
+        dst.extend_from_slice(&item.to_bytes());
+    }
+
+    pub fn with_stream_threshold(mut self, stream_threshold: Option<usize>) -> Self {
+        self.stream_threshold = stream_threshold;
+        self
+    }
+
+    /// Changes the limits of the following frames. A broadcast being streamed in is streamed to
+    /// its end.
+    pub fn set_limits(&mut self, max_size: usize, stream_threshold: Option<usize>) {
+        self.max_size = max_size;
+        self.stream_threshold = stream_threshold;
+    }
+
+    fn decode_chunk(&mut self, src: &mut BytesMut, remaining: usize) -> Option<Frame> {
+        if src.is_empty() {
+            return None;
+        }
+        let len = remaining.min(src.len());
+        let data = src.split_to(len).freeze();
+        let remaining = remaining - len;
+        self.streaming = (remaining > 0).then_some(remaining);
+        Some(Frame::StreamChunk {
+            data,
+            last: remaining == 0,
+        })
+    }
+
+    /// Starts streaming the broadcast at the front of `src` if its payload exceeds the threshold.
+    /// Returns `None` if the frame should be decoded whole, or if more data is needed to decide.
+    fn decode_stream_start(
+        &mut self,
+        src: &mut BytesMut,
+        varint_len: usize,
+        msg_len: usize,
+    ) -> Result<Option<Frame>, io::Error> {
+        let Some(threshold) = self.stream_threshold else {
+            return Ok(None);
+        };
+        if msg_len <= threshold.saturating_add(1) || src.len() <= varint_len {
+            return Ok(None);
+        }
+        let Header::Broadcast { topic_len } = Header::read(src[varint_len]) else {
+            return Ok(None);
+        };
+        let topic_len = topic_len as usize;
+        if topic_len + 1 > msg_len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "topic length out of range",
+            ));
+        }
+        let payload_len = msg_len - topic_len - 1;
+        if payload_len <= threshold || src.len() < varint_len + 1 + topic_len {
+            return Ok(None);
+        }
+
+        src.advance(varint_len + 1);
+        let topic = Topic::new(&src.split_to(topic_len));
+        self.streaming = Some(payload_len);
+        Ok(Some(Frame::StreamStart {
+            topic,
+            len: payload_len,
+        }))
     }
 }
 
 impl Decoder for LengthPrefixedCodec {
-    type Item = Message;
+    type Item = Frame;
     type Error = io::Error;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if let Some(remaining) = self.streaming {
+            return Ok(self.decode_chunk(src, remaining));
+        }
+
         let (msg_len, remaining) = match unsigned_varint::decode::usize(src) {
             Ok((len, remaining)) => (len, remaining),
             Err(unsigned_varint::decode::Error::Insufficient) => {
@@ -36,15 +183,19 @@ impl Decoder for LengthPrefixedCodec {
         if msg_len > self.max_size {
             return Err(io::Error::new(
                 io::ErrorKind::InvalidData,
-                format!(
-                    "Received data size ({} bytes) exceeds maximum ({} bytes)",
-                    msg_len, self.max_size
-                ),
+                FrameTooLarge {
+                    size: msg_len,
+                    max: self.max_size,
+                },
             ));
         }
 
-        // Ensure we can read an entire message
         let varint_len = src.len() - remaining.len();
+        if let Some(frame) = self.decode_stream_start(src, varint_len, msg_len)? {
+            return Ok(Some(frame));
+        }
+
+        // Ensure we can read an entire message
         if src.len() < varint_len + msg_len {
             return Ok(None);
         }
@@ -54,8 +205,8 @@ impl Decoder for LengthPrefixedCodec {
 
         let msg = src.split_to(msg_len);
 
-        match Message::from_bytes(&msg) {
-            Ok(message) => Ok(Some(message)),
+        match Message::from_bytes(msg.freeze()) {
+            Ok(message) => Ok(Some(Frame::Message(message))),
             Err(e) => Err(io::Error::new(
                 io::ErrorKind::InvalidData,
                 format!("Failed to decode message: {}", e),
@@ -65,18 +216,229 @@ impl Decoder for LengthPrefixedCodec {
 }
 
 impl Encoder for LengthPrefixedCodec {
-    type Item<'a> = Message;
+    type Item<'a> = &'a Message;
     type Error = io::Error;
 
     fn encode(&mut self, item: Self::Item<'_>, dst: &mut BytesMut) -> Result<(), Self::Error> {
-        let msg_len = item.len();
+        match (self.version, item) {
+            (Version::V2, item) => self.encode_frame(item, dst),
+            (Version::V1, Message::Batch { messages }) => {
+                for message in messages {
+                    self.encode_downgraded(message, dst);
+                }
+            }
+            // Without a way to express unsubscriptions, a snapshot can only add topics.
+            (Version::V1, Message::Snapshot { topics } | Message::SnapshotPart { topics }) => {
+                for topic in topics {
+                    self.encode_frame(&Message::Subscribe { topic: *topic }, dst);
+                }
+            }
+            (Version::V1, item) => self.encode_downgraded(item, dst),
+        }
+        Ok(())
+    }
+}
 
-        let mut varint_buf = unsigned_varint::encode::usize_buffer();
-        let encoded_len = unsigned_varint::encode::usize(msg_len, &mut varint_buf);
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        dst.extend_from_slice(&encoded_len);
-        dst.extend_from_slice(&item.to_bytes());
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
 
-        Ok(())
+    use crate::types::Metadata;
+
+    #[test]
+    fn test_streamed_decode() {
+        let topic = Topic::new(b"topic");
+        let payload = Bytes::from(vec![7u8; 100]);
+        let mut codec = LengthPrefixedCodec::new(1024).with_stream_threshold(Some(10));
+
+        let mut encoded = BytesMut::new();
+        codec
+            .encode(&Message::broadcast(topic, payload.clone()), &mut encoded)
+            .unwrap();
+        codec
+            .encode(&Message::Subscribe { topic }, &mut encoded)
+            .unwrap();
+
+        // Feed the encoded bytes in small pieces, as they would arrive from the network.
+        let mut src = BytesMut::new();
+        let mut frames = Vec::new();
+        for piece in encoded.chunks(30) {
+            src.extend_from_slice(piece);
+            while let Some(frame) = codec.decode(&mut src).unwrap() {
+                frames.push(frame);
+            }
+        }
+
+        assert_eq!(frames[0], Frame::StreamStart { topic, len: 100 });
+        let mut received = Vec::new();
+        for frame in &frames[1..frames.len() - 1] {
+            match frame {
+                Frame::StreamChunk { data, last } => {
+                    received.extend_from_slice(data);
+                    assert_eq!(*last, received.len() == payload.len());
+                }
+                frame => panic!("unexpected frame {:?}", frame),
+            }
+        }
+        assert_eq!(received, payload);
+        assert_eq!(
+            frames.last(),
+            Some(&Frame::Message(Message::Subscribe { topic }))
+        );
+    }
+
+    #[test]
+    fn test_arbitrary_split_decode() {
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..200 {
+            let messages: Vec<_> = (0..rng.gen_range(1..8))
+                .map(|_| {
+                    let len = rng.gen_range(0..=Topic::MAX_TOPIC_LENGTH);
+                    let topic = Topic::new(&(0..len).map(|_| rng.gen()).collect::<Vec<u8>>());
+                    if rng.gen_bool(0.2) {
+                        return Message::Subscribe { topic };
+                    }
+                    let len = rng.gen_range(0..2048);
+                    Message::broadcast(topic, (0..len).map(|_| rng.gen()).collect::<Bytes>())
+                })
+                .collect();
+            let threshold = rng.gen_bool(0.5).then(|| rng.gen_range(0..1024));
+            let mut codec = LengthPrefixedCodec::new(4096).with_stream_threshold(threshold);
+            let mut encoded = BytesMut::new();
+            for message in &messages {
+                codec.encode(message, &mut encoded).unwrap();
+            }
+
+            // Split the frames at arbitrary points, reassembling the streamed broadcasts.
+            let (mut src, mut decoded) = (BytesMut::new(), Vec::new());
+            let mut streamed: Option<(Topic, Vec<u8>)> = None;
+            while !encoded.is_empty() {
+                let len = rng.gen_range(1..=encoded.len().min(300));
+                src.extend_from_slice(&encoded.split_to(len));
+                while let Some(frame) = codec.decode(&mut src).unwrap() {
+                    match frame {
+                        Frame::Message(message) => decoded.push(message),
+                        Frame::StreamStart { topic, .. } => streamed = Some((topic, Vec::new())),
+                        Frame::StreamChunk { data, last } => {
+                            let (topic, payload) = streamed.as_mut().unwrap();
+                            payload.extend_from_slice(&data);
+                            if last {
+                                decoded.push(Message::broadcast(*topic, payload.clone()));
+                                streamed = None;
+                            }
+                        }
+                    }
+                }
+            }
+            assert!(src.is_empty());
+            assert_eq!(decoded, messages);
+        }
+    }
+
+    #[test]
+    fn test_decode_never_panics() {
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..1_000 {
+            let threshold = rng.gen_bool(0.5).then(|| rng.gen_range(0..64));
+            let mut codec = LengthPrefixedCodec::new(256).with_stream_threshold(threshold);
+            let mut src = BytesMut::new();
+            'feed: for _ in 0..rng.gen_range(1..8) {
+                let len = rng.gen_range(0..64);
+                src.extend((0..len).map(|_| rng.gen::<u8>()));
+                loop {
+                    match codec.decode(&mut src) {
+                        Ok(Some(_)) => {}
+                        Ok(None) => break,
+                        // The substream is closed on the first error.
+                        Err(_) => break 'feed,
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_nested_batch() {
+        // Rejected whatever the depth, rather than decoded recursively.
+        let nested = Message::Batch {
+            messages: vec![Message::Batch {
+                messages: vec![Message::Query],
+            }],
+        };
+        let mut codec = LengthPrefixedCodec::new(1024);
+        let mut src = BytesMut::new();
+        codec.encode(&nested, &mut src).unwrap();
+        assert!(codec.decode(&mut src).is_err());
+    }
+
+    #[test]
+    fn test_frame_too_large() {
+        let topic = Topic::new(b"topic");
+        let mut codec = LengthPrefixedCodec::new(16);
+
+        let mut src = BytesMut::new();
+        codec
+            .encode(
+                &Message::broadcast(topic, Bytes::from(vec![0u8; 32])),
+                &mut src,
+            )
+            .unwrap();
+        let error = codec.decode(&mut src).unwrap_err();
+        assert_eq!(FrameTooLarge::size_of(&error), Some(38));
+
+        let error = io::Error::new(io::ErrorKind::InvalidData, "other");
+        assert_eq!(FrameTooLarge::size_of(&error), None);
+    }
+
+    #[test]
+    fn test_v1_downgrade() {
+        let (a, b) = (Topic::new(b"a"), Topic::new(b"b"));
+        let json = Bytes::from_static(b"{}");
+        let batch = Message::Batch {
+            messages: vec![
+                Message::Subscribe { topic: a },
+                Message::Unsubscribe { topic: b },
+                Message::Broadcast {
+                    topic: a,
+                    payload: json.clone().into(),
+                    metadata: Metadata::default()
+                        .with_content_type("application/json")
+                        .into(),
+                },
+                Message::SubscribeShared { topic: b, group: a },
+                // Not understood by the remote, dropped.
+                Message::Query,
+                Message::Ack { delivery_id: 1 },
+            ],
+        };
+        let mut codec = LengthPrefixedCodec::new(1024).with_version(Version::V1);
+
+        let mut src = BytesMut::new();
+        codec.encode(&batch, &mut src).unwrap();
+        codec
+            .encode(&Message::broadcast(b, json.clone()), &mut src)
+            .unwrap();
+        codec
+            .encode(&Message::Snapshot { topics: vec![a, b] }, &mut src)
+            .unwrap();
+        let mut frames = Vec::new();
+        while let Some(frame) = codec.decode(&mut src).unwrap() {
+            frames.push(frame);
+        }
+        assert_eq!(
+            frames,
+            [
+                Frame::Message(Message::Subscribe { topic: a }),
+                Frame::Message(Message::Unsubscribe { topic: b }),
+                Frame::Message(Message::broadcast(a, json.clone())),
+                Frame::Message(Message::Subscribe { topic: b }),
+                Frame::Message(Message::broadcast(b, json)),
+                Frame::Message(Message::Subscribe { topic: a }),
+                Frame::Message(Message::Subscribe { topic: b }),
+            ]
+        );
     }
 }
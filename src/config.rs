@@ -1,6 +1,351 @@
-#[derive(Clone, Debug)]
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use libp2p::multiaddr::Protocol;
+use libp2p::Multiaddr;
+
+#[cfg(feature = "chaos")]
+use crate::chaos::FaultInjection;
+use crate::clock::{Clock, SystemClock};
+use crate::domain::Domain;
+use crate::drop_policy::{DropNewest, DropPolicy};
+#[cfg(feature = "erasure")]
+use crate::erasure::ErasureCoding;
+use crate::group::GroupSelection;
+use crate::matcher::{ExactMatcher, TopicMatcher};
+use crate::queue::Priority;
+use crate::scheduler::SendScheduler;
+use crate::timer::{FuturesTimer, Timer};
+use crate::types::{Capabilities, MessageId, Topic, TraceContext};
+
+/// Decides whether a queued broadcast became stale, given its topic and payload. Stale broadcasts
+/// are discarded instead of being sent.
+pub type ExpiryPredicate = Arc<dyn Fn(&Topic, &[u8]) -> bool + Send + Sync>;
+
+/// Creates the send scheduler of each connection.
+pub type SendSchedulerFactory = Arc<dyn Fn() -> Box<dyn SendScheduler> + Send + Sync>;
+
+/// What to do about a peer whose messages wait in its queue for longer than
+/// `Config::slow_consumer_threshold`. `Event::SlowConsumer` is emitted in any case.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum SlowConsumerAction {
+    /// Only report the peer.
+    #[default]
+    Report,
+    /// Stop routing broadcasts to the peer as if it unsubscribed from all its topics, and drop
+    /// the broadcasts queued for it.
+    Unsubscribe,
+    /// Close the connections to the peer.
+    Disconnect,
+}
+
+/// Which connection messages to a peer with several connections are sent over. Each message is
+/// sent over exactly one of them. Duplicates a remote sends over several connections are merged
+/// by duplicate suppression, see `Config::with_seen_cache`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum ConnectionPreference {
+    /// Leave the choice to the swarm.
+    #[default]
+    Any,
+    /// The most recently established connection.
+    Newest,
+    /// The connection whose handler flushes messages the fastest, as measured by the handlers.
+    /// Connections without a measurement yet are preferred, so that each gets measured.
+    LowestLatency,
+}
+
+/// What to do when the remote opens a new inbound substream while the previous one is still open.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum InboundReplacement {
+    /// Drop the previous substream in favour of the new one.
+    #[default]
+    Replace,
+    /// Drop the new substream and keep reading from the previous one. The remote sees its send
+    /// fail and has to retry once the previous substream is gone.
+    Refuse,
+    /// Keep reading from the previous substream until the remote closes it, then switch to the
+    /// new one. A newer substream replaces the one held back.
+    Defer,
+}
+
+/// Kind of transport a connection runs over, as far as its addresses tell.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum TransportClass {
+    Tcp,
+    Quic,
+    /// Relayed through a circuit relay, whatever the transport to the relay.
+    Relay,
+    Other,
+}
+
+impl TransportClass {
+    pub fn from_addr(addr: &Multiaddr) -> Self {
+        let mut class = TransportClass::Other;
+        for protocol in addr.iter() {
+            match protocol {
+                Protocol::P2pCircuit => return TransportClass::Relay,
+                Protocol::Quic | Protocol::QuicV1 => class = TransportClass::Quic,
+                Protocol::Tcp(_) => class = TransportClass::Tcp,
+                _ => {}
+            }
+        }
+        class
+    }
+}
+
+/// How the handler of a connection writes to its outbound substream.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct SendTuning {
+    /// Number of frames written to the substream before it is flushed, as long as more frames
+    /// are ready to be sent.
+    pub frames_per_flush: usize,
+    /// Batches longer than this are split into smaller frames. The largest frame the remote
+    /// accepts applies in any case.
+    pub max_frame_size: Option<usize>,
+}
+
+impl Default for SendTuning {
+    fn default() -> Self {
+        Self {
+            frames_per_flush: 1,
+            max_frame_size: None,
+        }
+    }
+}
+
+/// Bounds on the broadcasts of a topic kept in the outbox, see `Behaviour::set_outbox`, or in
+/// its history, see `Config::with_history`. Once a bound is exceeded, the oldest broadcasts are
+/// dropped.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Retention {
+    pub max_messages: Option<usize>,
+    /// Total length of the payloads.
+    pub max_bytes: Option<usize>,
+    /// Time since the broadcast was kept. Broadcasts left in the outbox by a previous run count
+    /// from the time the outbox was installed.
+    pub max_age: Option<Duration>,
+}
+
+impl Retention {
+    pub fn with_max_messages(mut self, max_messages: usize) -> Self {
+        self.max_messages = Some(max_messages);
+        self
+    }
+
+    pub fn with_max_bytes(mut self, max_bytes: usize) -> Self {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+
+    pub fn with_max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+}
+
+/// How the broadcasts of an at-least-once topic are redelivered, see
+/// `Config::with_at_least_once`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Redelivery {
+    /// How long to wait for the acknowledgment of a broadcast before sending it again.
+    pub timeout: Duration,
+    /// How many times a broadcast is sent again before giving up on it.
+    pub max_retries: u32,
+}
+
+/// How long broadcasts received out of order are held back, see `Config::with_reordering`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Reordering {
+    /// Number of broadcasts of a publisher held back at most. Once exceeded, the missing
+    /// broadcasts are skipped and `Event::ReorderOverflow` is reported.
+    pub max_buffered: usize,
+    /// How long a broadcast is held back at most. Once exceeded, the missing broadcasts are
+    /// skipped and `Event::ReorderTimeout` is reported.
+    pub timeout: Duration,
+}
+
+impl Reordering {
+    /// Interval at which the broadcasts held back are checked for their timeout.
+    pub const TICK: Duration = Duration::from_millis(10);
+}
+
+/// How many bytes each peer may send us per interval, see `Config::with_bandwidth_cap`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct BandwidthCap {
+    pub max_bytes: u64,
+    pub interval: Duration,
+    pub action: CapAction,
+}
+
+/// What happens to a peer exceeding its `BandwidthCap`. Either way, `Event::PeerThrottled` is
+/// reported with `ThrottleKind::Bandwidth`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CapAction {
+    /// Drop the broadcasts, shards and control payloads the peer sends until the interval
+    /// ends, including the chunked broadcasts it starts. Subscriptions and other control
+    /// messages are still accepted.
+    Throttle,
+    /// Drop what the peer sends like `Throttle`, and close the connections to it.
+    Disconnect,
+}
+
+/// How often the network is probed, see `Config::with_self_test`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct SelfTest {
+    pub interval: Duration,
+    /// How long the peers have to echo a probe before it counts as failed.
+    pub deadline: Duration,
+}
+
+impl SelfTest {
+    /// Reserved topic the probes and their echoes are exchanged on.
+    pub const TOPIC: &'static [u8] = b"/broadcast/self-test";
+}
+
+#[derive(Clone)]
 pub struct Config {
     pub max_buf_size: usize,
+    /// Broadcasts with a payload larger than this are delivered in chunks as they arrive
+    /// (`Event::ReceivedChunk`) instead of being buffered whole. Disabled when `None`. Broadcasts
+    /// carrying metadata are always buffered whole.
+    pub stream_threshold: Option<usize>,
+    /// Priority of the broadcasts of each topic. Topics not listed have `Priority::Normal`.
+    pub topic_priorities: HashMap<Topic, Priority>,
+    /// Priority of the broadcasts on the topics of each domain, unless the topic has its own.
+    pub domain_priorities: Vec<(Domain, Priority)>,
+    /// How connections of each transport class send. Classes not listed use
+    /// `SendTuning::default`.
+    pub send_tuning: HashMap<TransportClass, SendTuning>,
+    /// Topics whose broadcasts are erasure coded, along with the shape of their code. All
+    /// subscribers of such a topic must support erasure coding.
+    #[cfg(feature = "erasure")]
+    pub erasure_coding: HashMap<Topic, ErasureCoding>,
+    /// Consulted by the handlers right before sending each queued broadcast.
+    pub expiry_predicate: Option<ExpiryPredicate>,
+    /// Number of messages queued for a peer beyond which `drop_policy` sheds broadcasts.
+    /// Queues are unbounded when `None`.
+    pub max_queue_len: Option<usize>,
+    /// Decides which broadcast to shed when a queue is full.
+    pub drop_policy: Arc<dyn DropPolicy>,
+    /// Creates the scheduler deciding when and in which order each connection sends its queued
+    /// messages. Messages are sent as soon as possible when `None`.
+    pub send_scheduler: Option<SendSchedulerFactory>,
+    /// How long the id of a received broadcast is remembered to suppress duplicates. Duplicate
+    /// suppression is disabled when `None`.
+    pub seen_ttl: Option<Duration>,
+    /// Maximum number of ids remembered for duplicate suppression.
+    pub seen_capacity: usize,
+    /// When set, suppressed duplicates are reported as `Event::DuplicateSuppressed`, at most
+    /// once per peer per this interval, e.g. to detect peers sending the same broadcast again or
+    /// forwarding loops. Zero reports every duplicate.
+    pub duplicate_events: Option<Duration>,
+    /// When set, duplicates are suppressed with a bloom filter of fixed size instead of an exact
+    /// cache. Unique broadcasts are then wrongly dropped with roughly this probability.
+    pub seen_false_positive_rate: Option<f64>,
+    /// Function computing the id of a broadcast for duplicate suppression. Broadcasts streamed in
+    /// chunks, see `Config::stream_threshold`, are never held whole, so they are identified by
+    /// `MessageId::from_content` instead.
+    pub message_id_fn: fn(&Topic, &[u8]) -> MessageId,
+    /// Decides which subscriptions a broadcast is routed to, exact topics by default.
+    pub topic_matcher: Arc<dyn TopicMatcher>,
+    /// Source of the current time.
+    pub clock: Arc<dyn Clock>,
+    /// Creates the timers of the behaviour and the handlers.
+    pub timer: Arc<dyn Timer>,
+    /// Seeds the random number generator of the behaviour, which otherwise draws from the
+    /// operating system. Fixing it makes runs reproducible under a simulator.
+    pub rng_seed: Option<u64>,
+    /// Whether published broadcasts carry the time at which they were published.
+    pub timestamps: bool,
+    /// Captures the trace context of the current span when publishing, e.g. from
+    /// `tracing-opentelemetry`, so that subscribers can link their delivery to it. Broadcasts
+    /// whose metadata already carries a trace keep it.
+    pub trace_context: Option<fn() -> Option<TraceContext>>,
+    /// Timestamped broadcasts older than this are dropped on receipt. Broadcasts without a
+    /// timestamp are always accepted.
+    pub max_message_age: Option<Duration>,
+    /// How far the clocks of the peers may drift apart. Added to `max_message_age`, and bounds
+    /// how far in the future a timestamp may lie.
+    pub clock_skew_tolerance: Duration,
+    /// Whether published broadcasts carry a sequence number, which subscribers with a
+    /// `delivery_window` deduplicate them by.
+    pub sequence_numbers: bool,
+    /// Number of the latest sequence numbers of each publisher on a topic within which a
+    /// broadcast is delivered at most once, see `Config::with_delivery_window`. Disabled when
+    /// `None`.
+    pub delivery_window: Option<u64>,
+    /// Whether subscribers pass the broadcasts they receive on to the other subscribers of the
+    /// topic they are connected to, so that broadcasts reach peers that are not connected to
+    /// the publisher. Chunked and erasure-coded broadcasts are not forwarded.
+    pub forwarding: bool,
+    /// Number of consecutive failures to negotiate a substream with a peer or to send it a
+    /// message after which no broadcast is sent to the peer for `failure_cooldown`. Subscription
+    /// changes are still sent. Disabled when `None`.
+    pub failure_threshold: Option<u32>,
+    /// How long a peer that reached `failure_threshold` is skipped when routing.
+    pub failure_cooldown: Duration,
+    /// A peer is considered a slow consumer once the oldest message queued for it has been
+    /// waiting for longer than this. Disabled when `None`.
+    pub slow_consumer_threshold: Option<Duration>,
+    pub slow_consumer_action: SlowConsumerAction,
+    /// How many bytes each peer may send us per interval. Chunked broadcasts count towards the
+    /// cap, but are not dropped. Disabled when `None`.
+    pub bandwidth_cap: Option<BandwidthCap>,
+    /// Probes the network periodically when set, see `Config::with_self_test`.
+    pub self_test: Option<SelfTest>,
+    /// When set, connections to peers we share no subscribed topic with are no longer kept alive
+    /// once they carried no traffic for this long, which lets the swarm close them after its
+    /// idle connection timeout. Nodes publishing on topics they do not subscribe to should leave
+    /// it disabled.
+    pub prune_grace: Option<Duration>,
+    /// How often the subscriptions of every connected peer are queried to heal our view of them,
+    /// e.g. after flaky reconnects. Disabled when `None`.
+    pub resync_interval: Option<Duration>,
+    /// Retention of the broadcasts kept in the outbox, per topic. Enforced on every heartbeat.
+    pub retention: HashMap<Topic, Retention>,
+    pub heartbeat_interval: Duration,
+    /// Topics whose published broadcasts are kept, within the given retention, and replayed to
+    /// the peers subscribing later.
+    pub history: HashMap<Topic, Retention>,
+    /// Broadcasts replayed per second to each peer that subscribed late.
+    pub history_replay_rate: u32,
+    /// Topics whose broadcasts are delivered in the order their publisher published them.
+    pub reordering: HashMap<Topic, Reordering>,
+    /// Topics whose broadcasts are sent again to the subscribers that do not acknowledge them.
+    pub at_least_once: HashMap<Topic, Redelivery>,
+    /// Topics whose broadcasts subscribers only accept as many of as they granted credits for.
+    pub flow_control: HashMap<Topic, u32>,
+    /// Topics whose broadcasts are written to the outbox before being queued, and only
+    /// acknowledged once flushed to every subscriber. Requires `Behaviour::set_outbox`.
+    pub write_ahead: HashSet<Topic>,
+    /// When set, our subscriptions are announced to a newly connected peer after a random delay
+    /// of up to this long rather than right away, so that many peers reconnecting at once after
+    /// a network blip do not trigger a burst of announcements.
+    pub resubscribe_jitter: Option<Duration>,
+    /// Which connection messages to a peer are sent over.
+    pub connection_preference: ConnectionPreference,
+    pub inbound_replacement: InboundReplacement,
+    /// Largest number of inbound substreams being upgraded at once on a connection. Further
+    /// substreams the remote opens in the meantime are refused. Defaults to 4.
+    pub max_inbound_upgrades: usize,
+    /// Optional features announced to peers. Each feature is only used with the peers that
+    /// announce it too.
+    pub capabilities: Capabilities,
+    /// Longest topic accepted from peers. Defaults to `Topic::MAX_PLAIN_LENGTH`.
+    pub max_topic_length: usize,
+    /// Whether the handlers report when their substreams are opened, reopened, closed or fail
+    /// to be negotiated, as `Event::Substream`.
+    pub substream_events: bool,
+    /// How broadcasts pick the member of a group they are sent to. Defaults to round robin.
+    pub group_selection: GroupSelection,
+    /// Faults the handlers inject into the frames they send.
+    #[cfg(feature = "chaos")]
+    pub fault_injection: Option<FaultInjection>,
+    /// Faults the behaviour injects into the broadcasts it delivers.
+    #[cfg(feature = "chaos")]
+    pub delivery_faults: Option<FaultInjection>,
 }
 
 impl Config {
@@ -8,12 +353,497 @@ impl Config {
         self.max_buf_size = max_buf_size;
         self
     }
+
+    pub fn with_stream_threshold(mut self, stream_threshold: usize) -> Self {
+        self.stream_threshold = Some(stream_threshold);
+        self
+    }
+
+    pub fn with_topic_priority(mut self, topic: Topic, priority: Priority) -> Self {
+        self.topic_priorities.insert(topic, priority);
+        self
+    }
+
+    pub fn with_domain_priority(mut self, domain: Domain, priority: Priority) -> Self {
+        self.domain_priorities.retain(|(other, _)| *other != domain);
+        self.domain_priorities.push((domain, priority));
+        self
+    }
+
+    pub fn with_send_tuning(mut self, transport: TransportClass, tuning: SendTuning) -> Self {
+        assert!(tuning.frames_per_flush > 0, "frames must be flushed");
+        self.send_tuning.insert(transport, tuning);
+        self
+    }
+
+    pub fn send_tuning(&self, transport: TransportClass) -> SendTuning {
+        self.send_tuning
+            .get(&transport)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    pub fn priority(&self, topic: &Topic) -> Priority {
+        self.topic_priorities
+            .get(topic)
+            .or_else(|| {
+                self.domain_priorities
+                    .iter()
+                    .find(|(domain, _)| domain.contains(topic))
+                    .map(|(_, priority)| priority)
+            })
+            .copied()
+            .unwrap_or_default()
+    }
+
+    #[cfg(feature = "erasure")]
+    pub fn with_erasure_coding(mut self, topic: Topic, coding: ErasureCoding) -> Self {
+        // Checked again, as the fields are public.
+        let coding = ErasureCoding::new(coding.data_shards, coding.parity_shards);
+        self.erasure_coding.insert(topic, coding);
+        self
+    }
+
+    pub fn with_expiry_predicate(
+        mut self,
+        predicate: impl Fn(&Topic, &[u8]) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.expiry_predicate = Some(Arc::new(predicate));
+        self
+    }
+
+    pub fn with_max_queue_len(mut self, len: usize) -> Self {
+        self.max_queue_len = Some(len);
+        self
+    }
+
+    pub fn with_drop_policy(mut self, policy: impl DropPolicy + 'static) -> Self {
+        self.drop_policy = Arc::new(policy);
+        self
+    }
+
+    pub fn with_send_scheduler<S: SendScheduler + 'static>(
+        mut self,
+        factory: impl Fn() -> S + Send + Sync + 'static,
+    ) -> Self {
+        self.send_scheduler = Some(Arc::new(move || Box::new(factory())));
+        self
+    }
+
+    /// Returns `true` if the broadcast should be discarded rather than sent.
+    pub(crate) fn is_expired(&self, topic: &Topic, payload: &[u8]) -> bool {
+        self.expiry_predicate
+            .as_ref()
+            .is_some_and(|is_expired| is_expired(topic, payload))
+    }
+
+    pub fn with_seen_cache(mut self, ttl: Duration, capacity: usize) -> Self {
+        self.seen_ttl = Some(ttl);
+        self.seen_capacity = capacity;
+        self
+    }
+
+    /// Suppresses duplicates with a bloom filter, see `seen_false_positive_rate`. Duplicate
+    /// suppression is enabled with a two minute ttl unless already configured.
+    pub fn with_seen_false_positive_rate(mut self, fp_rate: f64) -> Self {
+        assert!(
+            fp_rate > 0.0 && fp_rate < 1.0,
+            "false positive rate must be in (0, 1)"
+        );
+        self.seen_false_positive_rate = Some(fp_rate);
+        self.seen_ttl.get_or_insert(Duration::from_secs(120));
+        self
+    }
+
+    pub fn with_duplicate_events(mut self, min_interval: Duration) -> Self {
+        self.duplicate_events = Some(min_interval);
+        self
+    }
+
+    pub fn with_message_id_fn(mut self, message_id_fn: fn(&Topic, &[u8]) -> MessageId) -> Self {
+        self.message_id_fn = message_id_fn;
+        self
+    }
+
+    pub fn with_topic_matcher(mut self, matcher: impl TopicMatcher + 'static) -> Self {
+        self.topic_matcher = Arc::new(matcher);
+        self
+    }
+
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    pub fn with_timer(mut self, timer: impl Timer + 'static) -> Self {
+        self.timer = Arc::new(timer);
+        self
+    }
+
+    pub fn with_rng_seed(mut self, seed: u64) -> Self {
+        self.rng_seed = Some(seed);
+        self
+    }
+
+    pub fn with_timestamps(mut self) -> Self {
+        self.timestamps = true;
+        self
+    }
+
+    pub fn with_trace_context(mut self, capture: fn() -> Option<TraceContext>) -> Self {
+        self.trace_context = Some(capture);
+        self
+    }
+
+    pub fn with_max_message_age(mut self, max_age: Duration, skew_tolerance: Duration) -> Self {
+        self.max_message_age = Some(max_age);
+        self.clock_skew_tolerance = skew_tolerance;
+        self
+    }
+
+    /// Numbers the broadcasts we publish on each topic, see `Config::with_delivery_window` and
+    /// `Config::with_reordering`. Numbers start from the time of the first broadcast on the
+    /// topic, so that they keep increasing across restarts as long as fewer than a thousand
+    /// broadcasts are published on it per millisecond on average.
+    pub fn with_sequence_numbers(mut self) -> Self {
+        self.sequence_numbers = true;
+        self
+    }
+
+    /// Delivers the broadcasts carrying a sequence number at most once within the latest `size`
+    /// sequence numbers of their publisher on the topic, so that redeliveries and replays of the
+    /// history after a reconnect do not surface twice, whatever their content. Older broadcasts
+    /// are dropped, as they cannot be told apart from duplicates.
+    pub fn with_delivery_window(mut self, size: u64) -> Self {
+        assert!(size > 0, "window must not be empty");
+        self.delivery_window = Some(size);
+        self
+    }
+
+    /// Returns `true` if a broadcast received with this skew should be dropped as stale or
+    /// implausibly early.
+    pub(crate) fn is_stale(&self, skew: i64) -> bool {
+        let Some(max_age) = self.max_message_age else {
+            return false;
+        };
+        let tolerance = self.clock_skew_tolerance.as_millis() as i64;
+        skew < -tolerance || skew > max_age.as_millis() as i64 + tolerance
+    }
+
+    /// Enables forwarding. Duplicate suppression stops forwarded broadcasts from circulating,
+    /// so it is enabled with a two minute ttl unless already configured.
+    pub fn with_forwarding(mut self) -> Self {
+        self.forwarding = true;
+        self.seen_ttl.get_or_insert(Duration::from_secs(120));
+        self
+    }
+
+    pub fn with_circuit_breaker(mut self, threshold: u32, cooldown: Duration) -> Self {
+        assert!(threshold > 0, "failure threshold must be positive");
+        self.failure_threshold = Some(threshold);
+        self.failure_cooldown = cooldown;
+        self
+    }
+
+    pub fn with_slow_consumer_detection(
+        mut self,
+        threshold: Duration,
+        action: SlowConsumerAction,
+    ) -> Self {
+        self.slow_consumer_threshold = Some(threshold);
+        self.slow_consumer_action = action;
+        self
+    }
+
+    pub fn with_bandwidth_cap(mut self, cap: BandwidthCap) -> Self {
+        assert!(!cap.interval.is_zero(), "interval must not be empty");
+        self.bandwidth_cap = Some(cap);
+        self
+    }
+
+    /// Publishes a probe on `SelfTest::TOPIC` every interval, which the peers enabling the
+    /// self-test too echo back. A probe passes if it was sent to at least one peer and echoed by
+    /// at least one within the deadline, which is reported as `Event::SelfTest` and in
+    /// `Health::self_test`. Probes and echoes are not delivered to the application.
+    pub fn with_self_test(mut self, self_test: SelfTest) -> Self {
+        assert!(
+            !self_test.deadline.is_zero() && self_test.deadline < self_test.interval,
+            "deadline must be positive and shorter than the interval"
+        );
+        self.self_test = Some(self_test);
+        self
+    }
+
+    pub fn with_connection_pruning(mut self, grace: Duration) -> Self {
+        self.prune_grace = Some(grace);
+        self
+    }
+
+    pub fn with_resync_interval(mut self, interval: Duration) -> Self {
+        self.resync_interval = Some(interval);
+        self
+    }
+
+    pub fn with_retention(mut self, topic: Topic, retention: Retention) -> Self {
+        self.retention.insert(topic, retention);
+        self
+    }
+
+    pub fn with_history(mut self, topic: Topic, retention: Retention) -> Self {
+        self.history.insert(topic, retention);
+        self
+    }
+
+    pub fn with_history_replay_rate(mut self, per_second: u32) -> Self {
+        assert!(per_second > 0, "replay rate must be positive");
+        self.history_replay_rate = per_second;
+        self
+    }
+
+    /// Delivers the broadcasts of the topic at least once to the subscribers negotiating
+    /// `Capabilities::ACKS`. They acknowledge each broadcast they receive, and the broadcasts
+    /// they do not acknowledge within the timeout are sent again, up to the retry cap, after
+    /// which `Event::Unacknowledged` is reported. Receivers suppress the redelivered duplicates,
+    /// remembering the delivery ids of the last 4096 broadcasts.
+    ///
+    /// Other subscribers receive the broadcasts at most once, as usual.
+    pub fn with_at_least_once(mut self, topic: Topic, redelivery: Redelivery) -> Self {
+        assert!(
+            redelivery.timeout > Duration::ZERO,
+            "timeout must be positive"
+        );
+        self.at_least_once.insert(topic, redelivery);
+        self
+    }
+
+    /// Grants the peers negotiating `Capabilities::CREDITS` credits for `window` broadcasts on the
+    /// topic when we subscribe to it, and more as we receive them, so that they stop sending
+    /// once we fall behind instead of relying on the backpressure of the transport. Broadcasts
+    /// out of credits are held back by the sender until they are granted more.
+    pub fn with_flow_control(mut self, topic: Topic, window: u32) -> Self {
+        assert!(window > 0, "window must not be empty");
+        self.flow_control.insert(topic, window);
+        self
+    }
+
+    /// Delivers the broadcasts on the topic carrying a sequence number in the order of their
+    /// numbers, per publisher, see `Config::with_sequence_numbers`. Broadcasts received ahead of
+    /// their turn are held back until the ones before them arrived, within the bounds of
+    /// `reordering`, after which the missing broadcasts are skipped. Broadcasts arriving after
+    /// their turn was skipped are dropped.
+    pub fn with_reordering(mut self, topic: Topic, reordering: Reordering) -> Self {
+        assert!(reordering.max_buffered > 0, "buffer must not be empty");
+        self.reordering.insert(topic, reordering);
+        self
+    }
+
+    pub fn with_write_ahead(mut self, topic: Topic) -> Self {
+        self.write_ahead.insert(topic);
+        self
+    }
+
+    pub fn with_heartbeat_interval(mut self, interval: Duration) -> Self {
+        assert!(!interval.is_zero(), "heartbeat interval must be positive");
+        self.heartbeat_interval = interval;
+        self
+    }
+
+    pub fn with_resubscribe_jitter(mut self, max: Duration) -> Self {
+        self.resubscribe_jitter = Some(max);
+        self
+    }
+
+    pub fn with_inbound_replacement(mut self, replacement: InboundReplacement) -> Self {
+        self.inbound_replacement = replacement;
+        self
+    }
+
+    pub fn with_max_inbound_upgrades(mut self, max_inbound_upgrades: usize) -> Self {
+        assert!(max_inbound_upgrades > 0, "inbound upgrades must be allowed");
+        self.max_inbound_upgrades = max_inbound_upgrades;
+        self
+    }
+
+    pub fn with_connection_preference(mut self, preference: ConnectionPreference) -> Self {
+        self.connection_preference = preference;
+        self
+    }
+
+    /// Announces the optional features. Panics if it names a reserved feature, which is not
+    /// implemented.
+    pub fn with_capabilities(mut self, capabilities: Capabilities) -> Self {
+        assert_eq!(
+            capabilities & Capabilities::RESERVED,
+            Capabilities::empty(),
+            "reserved capabilities cannot be announced"
+        );
+        self.capabilities = capabilities;
+        self
+    }
+
+    /// Accepts topics of up to `max` bytes from peers, dropping the messages naming longer ones.
+    /// Topics longer than `Topic::MAX_PLAIN_LENGTH` require the `long-topics` feature.
+    ///
+    /// Such topics are sent in extended frames, which only the peers negotiating
+    /// `Capabilities::LONG_TOPICS` understand. It is announced if `max` exceeds that length, and
+    /// the messages naming such topics are not sent to other peers.
+    pub fn with_max_topic_length(mut self, max: usize) -> Self {
+        assert!(max <= Topic::MAX_TOPIC_LENGTH, "topics cannot be that long");
+        self.max_topic_length = max;
+        self
+    }
+
+    /// Optional features announced to peers, including the ones implied by other settings.
+    pub(crate) fn announced_capabilities(&self) -> Capabilities {
+        if self.max_topic_length > Topic::MAX_PLAIN_LENGTH {
+            self.capabilities | Capabilities::LONG_TOPICS
+        } else {
+            self.capabilities
+        }
+    }
+
+    pub fn with_substream_events(mut self) -> Self {
+        self.substream_events = true;
+        self
+    }
+
+    pub fn with_group_selection(mut self, selection: GroupSelection) -> Self {
+        self.group_selection = selection;
+        self
+    }
+
+    #[cfg(feature = "chaos")]
+    pub fn with_fault_injection(mut self, faults: FaultInjection) -> Self {
+        self.fault_injection = Some(faults);
+        self
+    }
+
+    /// Drops, duplicates and delays the broadcasts the behaviour delivers as `Event::Received`,
+    /// as if they had been lost or held up in the network, so that staging clusters can check
+    /// how the application copes without tampering with the links. Broadcasts are acknowledged
+    /// and forwarded before the faults apply.
+    #[cfg(feature = "chaos")]
+    pub fn with_delivery_faults(mut self, faults: FaultInjection) -> Self {
+        self.delivery_faults = Some(faults);
+        self
+    }
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
             max_buf_size: 1024 * 1024 * 4, // 4 MiB
+            stream_threshold: None,
+            topic_priorities: HashMap::new(),
+            domain_priorities: Vec::new(),
+            send_tuning: HashMap::new(),
+            #[cfg(feature = "erasure")]
+            erasure_coding: HashMap::new(),
+            expiry_predicate: None,
+            max_queue_len: None,
+            drop_policy: Arc::new(DropNewest),
+            send_scheduler: None,
+            seen_ttl: None,
+            seen_capacity: 1024 * 1024,
+            duplicate_events: None,
+            seen_false_positive_rate: None,
+            message_id_fn: MessageId::from_content,
+            topic_matcher: Arc::new(ExactMatcher),
+            clock: Arc::new(SystemClock),
+            timer: Arc::new(FuturesTimer),
+            rng_seed: None,
+            timestamps: false,
+            trace_context: None,
+            sequence_numbers: false,
+            delivery_window: None,
+            max_message_age: None,
+            clock_skew_tolerance: Duration::ZERO,
+            forwarding: false,
+            failure_threshold: None,
+            failure_cooldown: Duration::from_secs(30),
+            slow_consumer_threshold: None,
+            slow_consumer_action: SlowConsumerAction::default(),
+            bandwidth_cap: None,
+            self_test: None,
+            prune_grace: None,
+            resync_interval: None,
+            retention: HashMap::new(),
+            heartbeat_interval: Duration::from_secs(1),
+            history: HashMap::new(),
+            at_least_once: HashMap::new(),
+            flow_control: HashMap::new(),
+            reordering: HashMap::new(),
+            history_replay_rate: 1000,
+            write_ahead: HashSet::new(),
+            resubscribe_jitter: None,
+            connection_preference: ConnectionPreference::default(),
+            inbound_replacement: InboundReplacement::default(),
+            max_inbound_upgrades: 4,
+            capabilities: Capabilities::default(),
+            max_topic_length: Topic::MAX_PLAIN_LENGTH,
+            substream_events: false,
+            group_selection: GroupSelection::default(),
+            #[cfg(feature = "chaos")]
+            fault_injection: None,
+            #[cfg(feature = "chaos")]
+            delivery_faults: None,
         }
     }
 }
+
+impl fmt::Debug for Config {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut f = f.debug_struct("Config");
+        f.field("max_buf_size", &self.max_buf_size)
+            .field("stream_threshold", &self.stream_threshold)
+            .field("topic_priorities", &self.topic_priorities)
+            .field("domain_priorities", &self.domain_priorities)
+            .field("send_tuning", &self.send_tuning);
+        #[cfg(feature = "erasure")]
+        f.field("erasure_coding", &self.erasure_coding);
+        f.field("expiry_predicate", &self.expiry_predicate.is_some())
+            .field("max_queue_len", &self.max_queue_len)
+            .field("send_scheduler", &self.send_scheduler.is_some())
+            .field("seen_ttl", &self.seen_ttl)
+            .field("seen_capacity", &self.seen_capacity)
+            .field("duplicate_events", &self.duplicate_events)
+            .field("seen_false_positive_rate", &self.seen_false_positive_rate)
+            .field("exact_topic_matcher", &self.topic_matcher.is_exact())
+            .field("rng_seed", &self.rng_seed)
+            .field("timestamps", &self.timestamps)
+            .field("trace_context", &self.trace_context.is_some())
+            .field("sequence_numbers", &self.sequence_numbers)
+            .field("delivery_window", &self.delivery_window)
+            .field("max_message_age", &self.max_message_age)
+            .field("clock_skew_tolerance", &self.clock_skew_tolerance)
+            .field("forwarding", &self.forwarding)
+            .field("failure_threshold", &self.failure_threshold)
+            .field("failure_cooldown", &self.failure_cooldown)
+            .field("slow_consumer_threshold", &self.slow_consumer_threshold)
+            .field("slow_consumer_action", &self.slow_consumer_action)
+            .field("bandwidth_cap", &self.bandwidth_cap)
+            .field("self_test", &self.self_test)
+            .field("prune_grace", &self.prune_grace)
+            .field("resync_interval", &self.resync_interval)
+            .field("retention", &self.retention)
+            .field("heartbeat_interval", &self.heartbeat_interval)
+            .field("history", &self.history)
+            .field("at_least_once", &self.at_least_once)
+            .field("flow_control", &self.flow_control)
+            .field("reordering", &self.reordering)
+            .field("history_replay_rate", &self.history_replay_rate)
+            .field("write_ahead", &self.write_ahead)
+            .field("resubscribe_jitter", &self.resubscribe_jitter)
+            .field("connection_preference", &self.connection_preference)
+            .field("inbound_replacement", &self.inbound_replacement)
+            .field("max_inbound_upgrades", &self.max_inbound_upgrades)
+            .field("capabilities", &self.capabilities)
+            .field("max_topic_length", &self.max_topic_length)
+            .field("substream_events", &self.substream_events)
+            .field("group_selection", &self.group_selection);
+        #[cfg(feature = "chaos")]
+        f.field("fault_injection", &self.fault_injection)
+            .field("delivery_faults", &self.delivery_faults);
+        f.finish_non_exhaustive()
+    }
+}
@@ -0,0 +1,162 @@
+use std::collections::VecDeque;
+
+use fnv::FnvHashMap;
+use libp2p::swarm::ConnectionId;
+
+use crate::handler::Outbound;
+use crate::types::{Message, Topic};
+
+/// Broadcasts received on each connection on each flow-controlled topic since its handler was
+/// last granted credits for them, see `Config::with_flow_control`. Credits are tracked by the
+/// handlers, so they are granted back to the connection that consumed them.
+#[derive(Debug, Default)]
+pub(crate) struct Grants {
+    consumed: FnvHashMap<(ConnectionId, Topic), u32>,
+}
+
+impl Grants {
+    /// Records a broadcast received on the connection on a topic with the window. Returns the
+    /// credits to grant back once half of the window was consumed.
+    pub fn consume(&mut self, connection: ConnectionId, topic: Topic, window: u32) -> Option<u32> {
+        let consumed = self.consumed.entry((connection, topic)).or_default();
+        *consumed += 1;
+        if *consumed < window.div_ceil(2) {
+            return None;
+        }
+        self.consumed.remove(&(connection, topic))
+    }
+
+    /// Forgets the broadcasts consumed on the connection, once it is granted whole windows again
+    /// or closed.
+    pub fn remove_connection(&mut self, connection: ConnectionId) {
+        self.consumed.retain(|(other, _), _| *other != connection);
+    }
+}
+
+/// Broadcasts the remote still accepts on the topics it grants credits for, and the ones held
+/// back until it grants more.
+#[derive(Debug, Default)]
+pub(crate) struct Credits {
+    credits: FnvHashMap<Topic, u32>,
+    stalled: VecDeque<Outbound>,
+}
+
+impl Credits {
+    pub fn grant(&mut self, topic: Topic, credits: u32) {
+        let available = self.credits.entry(topic).or_default();
+        *available = available.saturating_add(credits);
+    }
+
+    /// Forgets the credits of a topic the remote unsubscribed from, dropping its stalled
+    /// broadcasts.
+    pub fn revoke(&mut self, topic: &Topic) {
+        self.credits.remove(topic);
+        self.stalled
+            .retain(|outbound| outbound.message.topic() != Some(topic));
+    }
+
+    /// Takes a credit for each broadcast of the message. Returns `false` if it is a broadcast
+    /// on a topic out of credits, which is to be held back. Batches are never held back, as
+    /// they carry replays and announcements.
+    pub fn charge(&mut self, message: &Message) -> bool {
+        match message {
+            Message::Broadcast { topic, .. } => match self.credits.get_mut(topic) {
+                Some(0) => false,
+                Some(credits) => {
+                    *credits -= 1;
+                    true
+                }
+                None => true,
+            },
+            Message::Batch { messages } => {
+                for message in messages {
+                    if let Message::Broadcast { topic, .. } = message {
+                        if let Some(credits) = self.credits.get_mut(topic) {
+                            *credits = credits.saturating_sub(1);
+                        }
+                    }
+                }
+                true
+            }
+            _ => true,
+        }
+    }
+
+    /// Holds back a broadcast until its topic is granted credits. Returns `true` if no other
+    /// broadcast of the topic was held back, i.e. the topic just ran out of credits.
+    pub fn stall(&mut self, outbound: Outbound) -> bool {
+        let topic = outbound.message.topic();
+        let first = !self
+            .stalled
+            .iter()
+            .any(|stalled| stalled.message.topic() == topic);
+        self.stalled.push_back(outbound);
+        first
+    }
+
+    /// Takes the oldest stalled broadcast whose topic was granted credits since.
+    pub fn unstall(&mut self) -> Option<Outbound> {
+        let index = self.stalled.iter().position(|outbound| {
+            outbound
+                .message
+                .topic()
+                .and_then(|topic| self.credits.get(topic))
+                .is_none_or(|credits| *credits > 0)
+        })?;
+        self.stalled.remove(index)
+    }
+
+    /// Drops the stalled broadcasts, returning how many there were. The credits are kept, as the
+    /// remote only grants more as it receives broadcasts.
+    pub fn clear_stalled(&mut self) -> usize {
+        std::mem::take(&mut self.stalled).len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bytes::Bytes;
+
+    #[test]
+    fn test_credits() {
+        let (topic, other) = (Topic::new(b"feed"), Topic::new(b"other"));
+        let broadcast = |topic| Message::broadcast(topic, Bytes::from_static(b"item"));
+        let mut credits = Credits::default();
+        assert!(credits.charge(&broadcast(topic)));
+
+        credits.grant(topic, 1);
+        assert!(credits.charge(&broadcast(topic)));
+        assert!(!credits.charge(&broadcast(topic)));
+        assert!(credits.charge(&broadcast(other)));
+        assert!(credits.stall(broadcast(topic).into()));
+        assert!(!credits.stall(broadcast(topic).into()));
+        assert!(credits.unstall().is_none());
+
+        credits.grant(topic, 2);
+        for _ in 0..2 {
+            assert_eq!(
+                credits.unstall().map(|outbound| outbound.message),
+                Some(broadcast(topic))
+            );
+        }
+        assert!(credits.stall(broadcast(topic).into()));
+        credits.revoke(&topic);
+        credits.grant(topic, 1);
+        assert!(credits.unstall().is_none());
+
+        let mut grants = Grants::default();
+        let (first, second) = (
+            ConnectionId::new_unchecked(0),
+            ConnectionId::new_unchecked(1),
+        );
+        let consumed: Vec<_> = (0..4).map(|_| grants.consume(first, topic, 4)).collect();
+        assert_eq!(consumed, [None, Some(2), None, Some(2)]);
+        assert_eq!(grants.consume(first, topic, 4), None);
+        assert_eq!(grants.consume(second, topic, 4), None);
+        grants.remove_connection(first);
+        assert_eq!(grants.consume(first, topic, 4), None);
+        assert_eq!(grants.consume(second, topic, 4), Some(2));
+    }
+}
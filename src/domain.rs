@@ -0,0 +1,140 @@
+use bytes::Bytes;
+
+use crate::{Behaviour, BroadcastReport, Event, Topic};
+
+/// A namespace of topics named `<name>/<topic>`, which lets several applications share one
+/// behaviour, and a single protocol registration, without their topics colliding.
+///
+/// Routing state stays isolated as peers subscribe to the topics of each domain separately.
+/// Settings keyed by topic apply to the topics of a domain as usual, and
+/// `Config::with_domain_priority` sets the priority of a whole domain.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct Domain {
+    prefix: Vec<u8>,
+}
+
+impl Domain {
+    /// Panics if the name is empty, contains a `/`, or leaves no room for topics.
+    pub fn new(name: &[u8]) -> Self {
+        assert!(
+            !name.is_empty() && !name.contains(&b'/'),
+            "domain name must be non-empty and not contain '/'"
+        );
+        assert!(
+            name.len() + 1 < Topic::MAX_TOPIC_LENGTH,
+            "domain name too long"
+        );
+        let mut prefix = name.to_vec();
+        prefix.push(b'/');
+        Self { prefix }
+    }
+
+    pub fn name(&self) -> &[u8] {
+        &self.prefix[..self.prefix.len() - 1]
+    }
+
+    /// Topic of the domain with this name. Panics if the name is too long to fit.
+    pub fn topic(&self, topic: &[u8]) -> Topic {
+        assert!(
+            self.prefix.len() + topic.len() <= Topic::MAX_TOPIC_LENGTH,
+            "topic too long for the domain"
+        );
+        let mut name = self.prefix.clone();
+        name.extend_from_slice(topic);
+        Topic::new(&name)
+    }
+
+    /// Name of the topic within the domain, if it belongs to the domain.
+    pub fn local<'a>(&self, topic: &'a Topic) -> Option<&'a [u8]> {
+        topic.strip_prefix(self.prefix.as_slice())
+    }
+
+    pub fn contains(&self, topic: &Topic) -> bool {
+        self.local(topic).is_some()
+    }
+
+    /// Returns `true` if the event concerns the domain, i.e. it is about one of its topics or
+    /// about no topic in particular, like `Event::PeerFailed`.
+    pub fn accepts(&self, event: &Event) -> bool {
+        match event {
+            Event::PeerSubscriptions(_, topics) => topics.iter().any(|topic| self.contains(topic)),
+            _ => event.topic().is_none_or(|topic| self.contains(topic)),
+        }
+    }
+
+    pub fn subscribe(&self, behaviour: &mut Behaviour, topic: &[u8]) {
+        behaviour.subscribe(self.topic(topic));
+    }
+
+    pub fn unsubscribe(&self, behaviour: &mut Behaviour, topic: &[u8]) {
+        behaviour.unsubscribe(&self.topic(topic));
+    }
+
+    pub fn broadcast(
+        &self,
+        behaviour: &mut Behaviour,
+        topic: &[u8],
+        msg: Bytes,
+    ) -> BroadcastReport {
+        behaviour.broadcast(&self.topic(topic), msg)
+    }
+
+    /// Names of the topics of the domain we are subscribed to.
+    pub fn subscribed<'a>(&'a self, behaviour: &'a Behaviour) -> impl Iterator<Item = &'a [u8]> {
+        behaviour
+            .subscribed()
+            .filter_map(move |topic| self.local(topic))
+    }
+
+    /// Unsubscribes from all topics of the domain, cancelling their broadcasts still queued for
+    /// peers, e.g. when the application served by the domain shuts down.
+    pub fn clear(&self, behaviour: &mut Behaviour) {
+        let topics: Vec<Topic> = behaviour
+            .subscribed()
+            .filter(|topic| self.contains(topic))
+            .copied()
+            .collect();
+        for topic in &topics {
+            behaviour.cancel(topic);
+        }
+        behaviour.unsubscribe_many(topics);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use libp2p::PeerId;
+
+    use crate::{Config, Priority};
+
+    #[test]
+    fn test_domains() {
+        let mut behaviour = Behaviour::default();
+        let (chat, game) = (Domain::new(b"chat"), Domain::new(b"game"));
+        assert_eq!(chat.topic(b"lobby"), Topic::new(b"chat/lobby"));
+        assert_eq!(chat.local(&Topic::new(b"chat/lobby")), Some(&b"lobby"[..]));
+        assert_eq!(chat.local(&Topic::new(b"game/lobby")), None);
+
+        chat.subscribe(&mut behaviour, b"lobby");
+        game.subscribe(&mut behaviour, b"lobby");
+        game.subscribe(&mut behaviour, b"scores");
+        let mut topics: Vec<_> = game.subscribed(&behaviour).collect();
+        topics.sort();
+        assert_eq!(topics, [&b"lobby"[..], b"scores"]);
+
+        let peer = PeerId::random();
+        assert!(chat.accepts(&Event::Subscribed(peer, chat.topic(b"lobby"))));
+        assert!(!chat.accepts(&Event::Subscribed(peer, game.topic(b"lobby"))));
+        assert!(chat.accepts(&Event::PeerFailed(peer)));
+
+        let config = Config::default().with_domain_priority(chat.clone(), Priority::High);
+        assert_eq!(config.priority(&chat.topic(b"lobby")), Priority::High);
+        assert_eq!(config.priority(&game.topic(b"lobby")), Priority::Normal);
+
+        game.clear(&mut behaviour);
+        assert_eq!(game.subscribed(&behaviour).count(), 0);
+        assert_eq!(chat.subscribed(&behaviour).count(), 1);
+    }
+}
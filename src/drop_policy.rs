@@ -0,0 +1,77 @@
+use crate::queue::Priority;
+use crate::types::{Payload, Topic};
+
+/// A broadcast waiting in the queue of a peer, as seen by a `DropPolicy`.
+#[derive(Clone, Copy, Debug)]
+pub struct QueuedBroadcast<'a> {
+    /// Position of the broadcast among all queued messages, in the order they are sent.
+    pub index: usize,
+    pub topic: &'a Topic,
+    pub payload: &'a Payload,
+    pub priority: Priority,
+}
+
+/// What to drop when a broadcast arrives at a full queue.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Decision {
+    /// Drop the incoming broadcast.
+    DropIncoming,
+    /// Drop the queued broadcast at this index and queue the incoming one.
+    DropQueued(usize),
+}
+
+/// Decides which broadcast to shed once the queue of a peer holds `Config::max_queue_len`
+/// messages, set with `Config::with_drop_policy`.
+///
+/// Only single broadcasts are subject to the limit: subscription changes and batches are always
+/// queued, and never offered as victims.
+pub trait DropPolicy: Send + Sync {
+    /// Chooses between the queued broadcasts, in the order they would be sent, and the incoming
+    /// one. Choosing an index not in `queued` drops the incoming broadcast.
+    fn select_victim(
+        &self,
+        queued: &[QueuedBroadcast<'_>],
+        topic: &Topic,
+        payload: &Payload,
+        priority: Priority,
+    ) -> Decision;
+}
+
+/// Drops the incoming broadcast. The default.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DropNewest;
+
+impl DropPolicy for DropNewest {
+    fn select_victim(
+        &self,
+        _: &[QueuedBroadcast<'_>],
+        _: &Topic,
+        _: &Payload,
+        _: Priority,
+    ) -> Decision {
+        Decision::DropIncoming
+    }
+}
+
+/// Drops the queued broadcast of the lowest priority that would be sent first, unless the
+/// incoming broadcast has an even lower priority.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DropOldest;
+
+impl DropPolicy for DropOldest {
+    fn select_victim(
+        &self,
+        queued: &[QueuedBroadcast<'_>],
+        _: &Topic,
+        _: &Payload,
+        priority: Priority,
+    ) -> Decision {
+        queued
+            .iter()
+            .filter(|queued| queued.priority <= priority)
+            .min_by_key(|queued| queued.priority)
+            .map_or(Decision::DropIncoming, |victim| {
+                Decision::DropQueued(victim.index)
+            })
+    }
+}
@@ -0,0 +1,138 @@
+use std::time::Duration;
+
+use libp2p::PeerId;
+
+use crate::behaviour::NegotiatedProtocol;
+use crate::churn::Churn;
+use crate::health::Health;
+use crate::json::{self, Object};
+use crate::queue::QueueStats;
+use crate::types::Topic;
+
+/// Everything the behaviour knows about its peers and itself, taken with
+/// `Behaviour::dump_state`, e.g. to attach to a bug report or to serve on a debug endpoint.
+/// Unlike a `BehaviourSnapshot`, it cannot be restored.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StateDump {
+    /// Time the dump was taken at, in milliseconds since the Unix epoch.
+    pub taken_at: u64,
+    /// Topics we are subscribed to, sorted.
+    pub subscriptions: Vec<Topic>,
+    /// Topics we are subscribed to as a member of a group, along with the group.
+    pub shared_subscriptions: Vec<(Topic, Topic)>,
+    /// The connected peers, sorted.
+    pub peers: Vec<PeerState>,
+    pub health: Health,
+    /// The config, as formatted by `Debug`.
+    pub config: String,
+}
+
+/// What the behaviour knows about a connected peer, see `StateDump`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PeerState {
+    pub peer: PeerId,
+    /// Topics the peer is subscribed to, sorted.
+    pub topics: Vec<Topic>,
+    pub connections: usize,
+    pub protocol: NegotiatedProtocol,
+    pub queue: QueueStats,
+    pub churn: Option<Churn>,
+    /// Lowest smoothed flush latency of the connections to the peer, if measured.
+    pub latency: Option<Duration>,
+    /// Remaining cooldown if the circuit breaker skips the peer.
+    pub cooldown: Option<Duration>,
+}
+
+fn millis(duration: Duration) -> u64 {
+    duration.as_millis().min(u64::MAX.into()) as u64
+}
+
+impl PeerState {
+    fn to_json(&self) -> String {
+        let mut object = Object::new()
+            .peer(&self.peer)
+            .raw("topics", &json::array(self.topics.iter().map(json::topic)))
+            .number("connections", self.connections as u64);
+        if let Some(version) = self.protocol.version {
+            object = object.string("version", &format!("{version:?}"));
+        }
+        if let Some(capabilities) = self.protocol.capabilities {
+            object = object.number("capabilities", capabilities.bits());
+        }
+        let mut queue = Object::new()
+            .number("messages", self.queue.messages as u64)
+            .number("bytes", self.queue.bytes as u64);
+        if let Some(age) = self.queue.oldest_age {
+            queue = queue.number("oldest_age_ms", millis(age));
+        }
+        object = object.raw("queue", &queue.finish());
+        if let Some(churn) = self.churn {
+            let churn = Object::new()
+                .number("reconnects", churn.reconnects)
+                .number("substream_restarts", churn.substream_restarts);
+            object = object.raw("churn", &churn.finish());
+        }
+        if let Some(latency) = self.latency {
+            object = object.number("latency_ms", millis(latency));
+        }
+        if let Some(cooldown) = self.cooldown {
+            object = object.number("cooldown_ms", millis(cooldown));
+        }
+        object.finish()
+    }
+}
+
+impl StateDump {
+    /// Formats the dump as a single JSON object. Topics are written as strings, with invalid
+    /// UTF-8 replaced, peers as base58 peer ids and durations in milliseconds.
+    pub fn to_json(&self) -> String {
+        let health = &self.health;
+        let errors = &health.recent_errors;
+        let recent_errors = Object::new()
+            .number("peer_failures", errors.peer_failures as u64)
+            .number("rejected", errors.rejected as u64)
+            .number("received_oversized", errors.received_oversized as u64)
+            .number("shed", errors.shed as u64)
+            .number("expired", errors.expired as u64)
+            .finish();
+        let self_test = health.self_test.map(|result| result.passed);
+        let health = Object::new()
+            .number("connected_peers", health.connected_peers as u64)
+            .number("subscribers", health.subscribers as u64)
+            .raw(
+                "stuck_queues",
+                &json::array(
+                    health
+                        .stuck_queues
+                        .iter()
+                        .map(|peer| json::string(&peer.to_base58())),
+                ),
+            )
+            .raw("recent_errors", &recent_errors);
+        let health = match self_test {
+            Some(passed) => health.boolean("self_test_passed", passed),
+            None => health,
+        }
+        .finish();
+        let shared = self.shared_subscriptions.iter().map(|(topic, group)| {
+            Object::new()
+                .topic(topic)
+                .string("group", &String::from_utf8_lossy(group))
+                .finish()
+        });
+        Object::new()
+            .number("taken_at", self.taken_at)
+            .raw(
+                "subscriptions",
+                &json::array(self.subscriptions.iter().map(json::topic)),
+            )
+            .raw("shared_subscriptions", &json::array(shared))
+            .raw(
+                "peers",
+                &json::array(self.peers.iter().map(PeerState::to_json)),
+            )
+            .raw("health", &health)
+            .string("config", &self.config)
+            .finish()
+    }
+}
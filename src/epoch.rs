@@ -0,0 +1,131 @@
+use std::collections::BTreeSet;
+
+use crate::{Behaviour, Topic};
+
+/// Manages a family of topics scoped to an epoch or round, named `<prefix>/<epoch>`.
+///
+/// Advancing to an epoch subscribes to its topic and the ones of the next `lookahead` epochs,
+/// so that early messages of the upcoming epochs are not missed, and unsubscribes from the
+/// topics of the epochs left behind, cancelling their broadcasts still queued for peers.
+#[derive(Clone, Debug)]
+pub struct EpochTopics {
+    prefix: Vec<u8>,
+    lookahead: u64,
+    subscribed: BTreeSet<u64>,
+}
+
+impl EpochTopics {
+    /// Longest epoch suffix of a topic, a `/` followed by up to 20 digits.
+    const MAX_SUFFIX_LENGTH: usize = 21;
+
+    pub fn new(prefix: &[u8]) -> Self {
+        assert!(
+            prefix.len() + Self::MAX_SUFFIX_LENGTH <= Topic::MAX_TOPIC_LENGTH,
+            "epoch topic prefix too long"
+        );
+        Self {
+            prefix: prefix.to_vec(),
+            lookahead: 1,
+            subscribed: BTreeSet::new(),
+        }
+    }
+
+    /// Number of upcoming epochs subscribed to in advance. Defaults to 1.
+    pub fn with_lookahead(mut self, lookahead: u64) -> Self {
+        self.lookahead = lookahead;
+        self
+    }
+
+    /// Topic of the epoch.
+    pub fn topic(&self, epoch: u64) -> Topic {
+        let mut name = self.prefix.clone();
+        name.push(b'/');
+        name.extend_from_slice(epoch.to_string().as_bytes());
+        Topic::new(&name)
+    }
+
+    /// Epoch of the topic, if it belongs to this family.
+    pub fn epoch(&self, topic: &Topic) -> Option<u64> {
+        let suffix = topic
+            .strip_prefix(self.prefix.as_slice())?
+            .strip_prefix(b"/")?;
+        if suffix.is_empty() || !suffix.iter().all(u8::is_ascii_digit) {
+            return None;
+        }
+        std::str::from_utf8(suffix).ok()?.parse().ok()
+    }
+
+    /// Epochs whose topics are currently subscribed to.
+    pub fn subscribed(&self) -> impl Iterator<Item = u64> + '_ {
+        self.subscribed.iter().copied()
+    }
+
+    /// Moves to the epoch, adjusting the subscriptions of the behaviour.
+    pub fn advance(&mut self, behaviour: &mut Behaviour, epoch: u64) {
+        let last = epoch.saturating_add(self.lookahead);
+        let expired: Vec<u64> = self
+            .subscribed
+            .iter()
+            .copied()
+            .filter(|e| *e < epoch || *e > last)
+            .collect();
+        let mut unsubscribed = Vec::new();
+        for e in expired {
+            let topic = self.topic(e);
+            behaviour.cancel(&topic);
+            unsubscribed.push(topic);
+            self.subscribed.remove(&e);
+        }
+        behaviour.unsubscribe_many(unsubscribed);
+        let mut subscribed = Vec::new();
+        for e in epoch..=last {
+            if self.subscribed.insert(e) {
+                subscribed.push(self.topic(e));
+            }
+        }
+        behaviour.subscribe_many(subscribed);
+    }
+
+    /// Unsubscribes from all topics of the family.
+    pub fn clear(&mut self, behaviour: &mut Behaviour) {
+        let mut unsubscribed = Vec::new();
+        for e in std::mem::take(&mut self.subscribed) {
+            let topic = self.topic(e);
+            behaviour.cancel(&topic);
+            unsubscribed.push(topic);
+        }
+        behaviour.unsubscribe_many(unsubscribed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_advance() {
+        let mut behaviour = Behaviour::default();
+        let mut rounds = EpochTopics::new(b"votes").with_lookahead(2);
+        assert_eq!(rounds.topic(7), Topic::new(b"votes/7"));
+        assert_eq!(rounds.epoch(&Topic::new(b"votes/7")), Some(7));
+        assert_eq!(rounds.epoch(&Topic::new(b"votes/x")), None);
+        assert_eq!(rounds.epoch(&Topic::new(b"blocks/7")), None);
+
+        rounds.advance(&mut behaviour, 1);
+        rounds.advance(&mut behaviour, 2);
+        assert_eq!(rounds.subscribed().collect::<Vec<_>>(), [2, 3, 4]);
+        let mut topics: Vec<_> = behaviour.subscribed().copied().collect();
+        topics.sort();
+        assert_eq!(
+            topics,
+            [
+                Topic::new(b"votes/2"),
+                Topic::new(b"votes/3"),
+                Topic::new(b"votes/4")
+            ]
+        );
+
+        rounds.clear(&mut behaviour);
+        assert_eq!(behaviour.subscribed().count(), 0);
+    }
+}
@@ -0,0 +1,230 @@
+use std::collections::VecDeque;
+use std::hash::Hasher;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use fnv::{FnvHashMap, FnvHasher};
+use libp2p::PeerId;
+use reed_solomon_erasure::galois_8::ReedSolomon;
+
+use crate::seen::SeenCache;
+use crate::targets::BEHAVIOUR;
+use crate::types::{MessageId, Shard};
+
+/// Maximum number of payloads reassembled concurrently. The oldest is abandoned beyond that.
+const MAX_PENDING: usize = 256;
+
+/// How long a partially received payload is kept waiting for more shards.
+const PENDING_TTL: Duration = Duration::from_secs(30);
+
+/// How long the ids of reassembled payloads are remembered to ignore their remaining shards.
+const COMPLETED_TTL: Duration = Duration::from_secs(120);
+
+/// Shape of the erasure code used for the broadcasts of a topic.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ErasureCoding {
+    /// Number of shards the payload is split into. Any `data_shards` shards suffice to
+    /// reconstruct it.
+    pub data_shards: u8,
+    /// Number of additional shards computed to tolerate lost or slow peers.
+    pub parity_shards: u8,
+}
+
+impl ErasureCoding {
+    /// Maximum number of shards, data and parity together, of the code.
+    pub const MAX_SHARDS: usize = 256;
+
+    pub fn new(data_shards: u8, parity_shards: u8) -> Self {
+        assert!(data_shards > 0, "at least one data shard is required");
+        assert!(
+            data_shards as usize + parity_shards as usize <= Self::MAX_SHARDS,
+            "at most 256 shards are supported"
+        );
+        Self {
+            data_shards,
+            parity_shards,
+        }
+    }
+
+    /// Splits the payload into `data_shards + parity_shards` shards.
+    pub(crate) fn encode(&self, id: u64, payload: &[u8]) -> Vec<Shard> {
+        let data_shards = self.data_shards as usize;
+        let shard_len = payload.len().div_ceil(data_shards).max(1);
+        let mut shards: Vec<Vec<u8>> = payload
+            .chunks(shard_len)
+            .map(|chunk| chunk.to_vec())
+            .chain(std::iter::repeat_with(Vec::new))
+            .take(data_shards + self.parity_shards as usize)
+            .collect();
+        for shard in &mut shards {
+            shard.resize(shard_len, 0);
+        }
+
+        if self.parity_shards > 0 {
+            ReedSolomon::new(data_shards, self.parity_shards as usize)
+                .expect("valid shard counts")
+                .encode(&mut shards)
+                .expect("shards of equal length");
+        }
+
+        shards
+            .into_iter()
+            .enumerate()
+            .map(|(index, data)| Shard {
+                origin: None,
+                id,
+                index: index as u8,
+                data_shards: self.data_shards,
+                parity_shards: self.parity_shards,
+                payload_len: payload.len() as u32,
+                data: data.into(),
+            })
+            .collect()
+    }
+}
+
+/// A payload for which some shards have been received.
+struct Pending {
+    shards: Vec<Option<Vec<u8>>>,
+    received: usize,
+    started: Instant,
+}
+
+/// Collects shards until their payloads can be reconstructed.
+pub(crate) struct Reassembler {
+    pending: FnvHashMap<(PeerId, u64), Pending>,
+    order: VecDeque<(PeerId, u64)>,
+    completed: SeenCache,
+}
+
+impl Default for Reassembler {
+    fn default() -> Self {
+        Self {
+            pending: FnvHashMap::default(),
+            order: VecDeque::new(),
+            completed: SeenCache::new(COMPLETED_TTL, MAX_PENDING * 16),
+        }
+    }
+}
+
+impl Reassembler {
+    /// Adds a shard of a payload published by `origin`, returning the payload once enough
+    /// shards have been received.
+    pub fn insert(&mut self, origin: PeerId, shard: Shard, now: Instant) -> Option<Bytes> {
+        let key = (origin, shard.id);
+        let total = shard.data_shards as usize + shard.parity_shards as usize;
+        if shard.data_shards == 0 || shard.index as usize >= total {
+            tracing::debug!(target: BEHAVIOUR, "Ignoring shard with invalid index {}", shard.index);
+            return None;
+        }
+        if self.completed.contains(&completed_id(&key)) {
+            return None;
+        }
+
+        self.expire(now);
+        if !self.pending.contains_key(&key) {
+            self.order.push_back(key);
+        }
+        let pending = self.pending.entry(key).or_insert_with(|| Pending {
+            shards: vec![None; total],
+            received: 0,
+            started: now,
+        });
+        if pending.shards.len() != total {
+            tracing::debug!(target: BEHAVIOUR, "Ignoring shard with inconsistent shard counts");
+            return None;
+        }
+        let slot = &mut pending.shards[shard.index as usize];
+        if slot.is_some() {
+            return None;
+        }
+        *slot = Some(shard.data.to_vec());
+        pending.received += 1;
+
+        if pending.received < shard.data_shards as usize {
+            while self.pending.len() > MAX_PENDING {
+                self.evict_oldest();
+            }
+            return None;
+        }
+
+        let mut pending = self.pending.remove(&key).expect("pending payload exists");
+        self.completed.insert(completed_id(&key), now);
+        if shard.parity_shards > 0 {
+            let codec =
+                ReedSolomon::new(shard.data_shards as usize, shard.parity_shards as usize).ok()?;
+            if let Err(e) = codec.reconstruct_data(&mut pending.shards) {
+                tracing::debug!(
+                    target: BEHAVIOUR,
+                    "Failed to reconstruct payload from shards: {e:?}"
+                );
+                return None;
+            }
+        }
+        let mut payload: Vec<u8> = pending
+            .shards
+            .into_iter()
+            .take(shard.data_shards as usize)
+            .flatten()
+            .flatten()
+            .collect();
+        if payload.len() < shard.payload_len as usize {
+            tracing::debug!(target: BEHAVIOUR, "Reconstructed payload is shorter than announced");
+            return None;
+        }
+        payload.truncate(shard.payload_len as usize);
+        Some(payload.into())
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while let Some(key) = self.order.front() {
+            match self.pending.get(key) {
+                Some(pending) if now < pending.started + PENDING_TTL => break,
+                _ => self.evict_oldest(),
+            }
+        }
+    }
+
+    fn evict_oldest(&mut self) {
+        if let Some(key) = self.order.pop_front() {
+            self.pending.remove(&key);
+        }
+    }
+}
+
+/// Id under which a reassembled payload is remembered.
+fn completed_id((origin, id): &(PeerId, u64)) -> MessageId {
+    let mut hasher = FnvHasher::default();
+    hasher.write(&origin.to_bytes());
+    hasher.write_u64(*id);
+    MessageId::new(hasher.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reconstruct_from_any_shards() {
+        let payload: Vec<u8> = (0..1000u32).map(|i| i as u8).collect();
+        let origin = PeerId::random();
+        let shards = ErasureCoding::new(4, 2).encode(7, &payload);
+        assert_eq!(shards.len(), 6);
+
+        let mut reassembler = Reassembler::default();
+        let now = Instant::now();
+        let mut insert = |index: usize| reassembler.insert(origin, shards[index].clone(), now);
+        assert_eq!(insert(5), None);
+        assert_eq!(insert(1), None);
+        assert_eq!(insert(1), None);
+        assert_eq!(insert(4), None);
+        assert_eq!(insert(2), Some(Bytes::from(payload)));
+        assert_eq!(insert(0), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "at most 256 shards are supported")]
+    fn test_too_many_shards() {
+        ErasureCoding::new(200, 57);
+    }
+}
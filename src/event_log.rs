@@ -0,0 +1,346 @@
+use std::io::Write;
+
+use crate::behaviour::{Event, ThrottleKind};
+use crate::config::CapAction;
+use crate::json::{self, Object};
+use crate::substream::{Direction, SubstreamEvent};
+use crate::targets::BEHAVIOUR;
+
+/// Receives every event the behaviour hands to the application, e.g. to feed a log pipeline.
+/// Installed with `Behaviour::set_event_sink`.
+///
+/// Events are passed synchronously as they are polled, along with the time in milliseconds since
+/// the Unix epoch. Sinks doing I/O should buffer.
+pub trait EventSink: Send {
+    fn export(&mut self, at: u64, event: &Event);
+}
+
+impl<F> EventSink for F
+where
+    F: FnMut(u64, &Event) + Send,
+{
+    fn export(&mut self, at: u64, event: &Event) {
+        self(at, event)
+    }
+}
+
+/// Writes the events to a writer as JSON lines. Write errors are logged and the event is
+/// skipped.
+///
+/// Each line is an object with the time as `at`, the kind of event in snake case as `event`,
+/// and its fields: `peer` as a base58 peer id, `topic` as a string, with invalid UTF-8 replaced,
+/// and counts and sizes as numbers. Payloads are left out, only their length is written as
+/// `bytes`.
+#[derive(Debug)]
+pub struct JsonEventWriter<W> {
+    writer: W,
+}
+
+impl<W: Write> JsonEventWriter<W> {
+    pub fn new(writer: W) -> Self {
+        Self { writer }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W: Write + Send> EventSink for JsonEventWriter<W> {
+    fn export(&mut self, at: u64, event: &Event) {
+        let mut line = to_json(at, event);
+        line.push('\n');
+        if let Err(e) = self.writer.write_all(line.as_bytes()) {
+            tracing::warn!(target: BEHAVIOUR, "Failed to export an event: {e}");
+        }
+    }
+}
+
+fn direction(direction: &Direction) -> &'static str {
+    match direction {
+        Direction::Inbound => "inbound",
+        Direction::Outbound => "outbound",
+    }
+}
+
+fn cap_action(action: &CapAction) -> &'static str {
+    match action {
+        CapAction::Throttle => "throttle",
+        CapAction::Disconnect => "disconnect",
+    }
+}
+
+/// Formats the event as a single line of JSON, see `JsonEventWriter`.
+pub(crate) fn to_json(at: u64, event: &Event) -> String {
+    let object = match event {
+        Event::Subscribed(peer, topic) => Object::new()
+            .number("at", at)
+            .string("event", "subscribed")
+            .peer(peer)
+            .topic(topic),
+        Event::SubscriptionConfirmed(peer, topic) => Object::new()
+            .number("at", at)
+            .string("event", "subscription_confirmed")
+            .peer(peer)
+            .topic(topic),
+        Event::PeerSubscriptions(peer, topics) => {
+            let topics = json::array(topics.iter().map(json::topic));
+            Object::new()
+                .number("at", at)
+                .string("event", "peer_subscriptions")
+                .peer(peer)
+                .raw("topics", &topics)
+        }
+        Event::Unsubscribed(peer, topic) => Object::new()
+            .number("at", at)
+            .string("event", "unsubscribed")
+            .peer(peer)
+            .topic(topic),
+        Event::Received(peer, topic, payload, metadata) => {
+            let mut object = Object::new()
+                .number("at", at)
+                .string("event", "received")
+                .peer(peer)
+                .topic(topic)
+                .number("bytes", payload.len() as u64);
+            if let Some(origin) = &metadata.origin {
+                object = object.string("origin", &origin.to_base58());
+            }
+            if let Some(sequence) = metadata.sequence {
+                object = object.number("sequence", sequence);
+            }
+            if let Some(trace) = &metadata.trace {
+                object = object
+                    .string("trace_id", &trace.trace_id_hex())
+                    .string("span_id", &trace.span_id_hex());
+            }
+            object
+        }
+        Event::ReceivedChunk(peer, topic, chunk) => Object::new()
+            .number("at", at)
+            .string("event", "received_chunk")
+            .peer(peer)
+            .topic(topic)
+            .number("stream", chunk.stream)
+            .number("offset", chunk.offset as u64)
+            .number("bytes", chunk.data.len() as u64)
+            .number("len", chunk.len as u64),
+        Event::ReceiveAborted(peer, topic, stream) => Object::new()
+            .number("at", at)
+            .string("event", "receive_aborted")
+            .peer(peer)
+            .topic(topic)
+            .number("stream", *stream),
+        Event::PeerThrottled(peer, topic, kind) => {
+            let mut object = Object::new()
+                .number("at", at)
+                .string("event", "peer_throttled")
+                .peer(peer);
+            if let Some(topic) = topic {
+                object = object.topic(topic);
+            }
+            match kind {
+                ThrottleKind::HistoryReplay => object.string("kind", "history_replay"),
+                ThrottleKind::Credits => object.string("kind", "credits"),
+                ThrottleKind::Bandwidth(action) => object
+                    .string("kind", "bandwidth")
+                    .string("action", cap_action(action)),
+            }
+        }
+        Event::DuplicateSuppressed(peer, id, count) => Object::new()
+            .number("at", at)
+            .string("event", "duplicate_suppressed")
+            .peer(peer)
+            .number("id", id.as_u64())
+            .number("count", *count as u64),
+        Event::ReceivedControl(peer, topic, payload) => Object::new()
+            .number("at", at)
+            .string("event", "received_control")
+            .peer(peer)
+            .topic(topic)
+            .number("bytes", payload.len() as u64),
+        Event::Expired(peer, count) => Object::new()
+            .number("at", at)
+            .string("event", "expired")
+            .peer(peer)
+            .number("count", *count as u64),
+        Event::Oversized(peer, count) => Object::new()
+            .number("at", at)
+            .string("event", "oversized")
+            .peer(peer)
+            .number("count", *count as u64),
+        Event::Shed(peer, count) => Object::new()
+            .number("at", at)
+            .string("event", "shed")
+            .peer(peer)
+            .number("count", *count as u64),
+        Event::ReceivedOversized(peer, size) => Object::new()
+            .number("at", at)
+            .string("event", "received_oversized")
+            .peer(peer)
+            .number("bytes", *size as u64),
+        Event::Rejected(peer, size) => Object::new()
+            .number("at", at)
+            .string("event", "rejected")
+            .peer(peer)
+            .number("bytes", *size as u64),
+        Event::PeerFailed(peer) => Object::new()
+            .number("at", at)
+            .string("event", "peer_failed")
+            .peer(peer),
+        Event::SlowConsumer(peer, waited) => Object::new()
+            .number("at", at)
+            .string("event", "slow_consumer")
+            .peer(peer)
+            .number("waited_ms", waited.as_millis().min(u64::MAX.into()) as u64),
+        Event::NoSharedTopics(peer) => Object::new()
+            .number("at", at)
+            .string("event", "no_shared_topics")
+            .peer(peer),
+        Event::ReplayCompleted(peer, topic) => Object::new()
+            .number("at", at)
+            .string("event", "replay_completed")
+            .peer(peer)
+            .topic(topic),
+        Event::Substream(peer, connection, event) => {
+            let object = Object::new()
+                .number("at", at)
+                .string("event", "substream")
+                .peer(peer)
+                .string("connection", &connection.to_string());
+            match event {
+                SubstreamEvent::Opened(dir) => object
+                    .string("change", "opened")
+                    .string("direction", direction(dir)),
+                SubstreamEvent::Reopened(dir) => object
+                    .string("change", "reopened")
+                    .string("direction", direction(dir)),
+                SubstreamEvent::Closed(dir, reason) => {
+                    let object = object
+                        .string("change", "closed")
+                        .string("direction", direction(dir));
+                    match reason {
+                        Some(reason) => object.string("reason", reason),
+                        None => object,
+                    }
+                }
+                SubstreamEvent::Failed(dir, reason) => object
+                    .string("change", "failed")
+                    .string("direction", direction(dir))
+                    .string("reason", reason),
+            }
+        }
+        Event::Unacknowledged(peer, topic, id) => Object::new()
+            .number("at", at)
+            .string("event", "unacknowledged")
+            .peer(peer)
+            .topic(topic)
+            .number("delivery_id", *id),
+        Event::ReorderOverflow(peer, topic) => Object::new()
+            .number("at", at)
+            .string("event", "reorder_overflow")
+            .peer(peer)
+            .topic(topic),
+        Event::ReorderTimeout(peer, topic) => Object::new()
+            .number("at", at)
+            .string("event", "reorder_timeout")
+            .peer(peer)
+            .topic(topic),
+        Event::Missing(peer, topic, missing) => Object::new()
+            .number("at", at)
+            .string("event", "missing")
+            .peer(peer)
+            .topic(topic)
+            .number("from", missing.start)
+            .number("to", missing.end),
+        Event::SelfTest(result) => {
+            let object = Object::new()
+                .number("at", at)
+                .string("event", "self_test")
+                .boolean("passed", result.passed)
+                .number("probed", result.probed as u64)
+                .number("echoes", result.echoes as u64);
+            match result.round_trip {
+                Some(round_trip) => object.number(
+                    "round_trip_ms",
+                    round_trip.as_millis().min(u64::MAX.into()) as u64,
+                ),
+                None => object,
+            }
+        }
+        Event::Negotiated(peer, capabilities) => {
+            let capabilities = json::array(capabilities.names().into_iter().map(json::string));
+            Object::new()
+                .number("at", at)
+                .string("event", "negotiated")
+                .peer(peer)
+                .raw("capabilities", &capabilities)
+        }
+    };
+    object.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    use bytes::Bytes;
+    use libp2p::PeerId;
+
+    use crate::types::{Capabilities, Metadata, Topic};
+
+    #[test]
+    fn test_json_lines() {
+        let peer = PeerId::random();
+        let topic = Topic::new(b"votes \"1\"");
+        let metadata = Metadata {
+            sequence: Some(3),
+            ..Metadata::default()
+        };
+        let mut writer = JsonEventWriter::new(Vec::new());
+        writer.export(
+            1,
+            &Event::Received(peer, topic, Bytes::from_static(b"yes"), metadata),
+        );
+        writer.export(2, &Event::SlowConsumer(peer, Duration::from_millis(1500)));
+        let output = String::from_utf8(writer.into_inner()).unwrap();
+        let lines: Vec<_> = output.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                format!(
+                    concat!(
+                        r#"{{"at":1,"event":"received","peer":"{}","topic":"votes \"1\"","#,
+                        r#""bytes":3,"sequence":3}}"#
+                    ),
+                    peer
+                ),
+                format!(r#"{{"at":2,"event":"slow_consumer","peer":"{peer}","waited_ms":1500}}"#),
+            ]
+        );
+
+        let closed = SubstreamEvent::Closed(Direction::Inbound, Some("reset\n".into()));
+        let connection = libp2p::swarm::ConnectionId::new_unchecked(4);
+        let json = to_json(0, &Event::Substream(peer, connection, closed));
+        assert!(json.ends_with(r#""change":"closed","direction":"inbound","reason":"reset\n"}"#));
+
+        let capabilities = Capabilities::ACKS | Capabilities::LONG_TOPICS;
+        let json = to_json(0, &Event::Negotiated(peer, capabilities));
+        assert!(json.ends_with(r#""capabilities":["acks","long_topics"]}"#));
+
+        let throttled = ThrottleKind::Bandwidth(CapAction::Disconnect);
+        let json = to_json(0, &Event::PeerThrottled(peer, None, throttled));
+        assert_eq!(
+            json,
+            format!(
+                concat!(
+                    r#"{{"at":0,"event":"peer_throttled","peer":"{}","kind":"bandwidth","#,
+                    r#""action":"disconnect"}}"#
+                ),
+                peer
+            )
+        );
+    }
+}
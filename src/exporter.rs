@@ -0,0 +1,242 @@
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use prometheus_client::encoding::text::encode;
+use prometheus_client::registry::Registry;
+
+use crate::targets::BEHAVIOUR;
+
+/// Time a client gets to send its request and read the response, so that a stalled or trickling
+/// client cannot hold up the scrapes queued behind it.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Longest request or header line read, including the line break.
+const MAX_LINE_LEN: usize = 8 * 1024;
+
+/// Most header lines read before giving up on a request.
+const MAX_HEADERS: usize = 64;
+
+/// Serves the metrics of a registry at `/metrics` over HTTP, in the OpenMetrics text format.
+///
+/// Requests are answered one at a time on a background thread, which is plenty for a scraper
+/// and keeps the crate free of an HTTP server dependency. Clients are given `REQUEST_TIMEOUT`
+/// to send their request and read the response, and overlong requests are rejected.
+///
+/// Dropping the exporter stops the thread and closes the listener, once the request being
+/// answered if any is done, so that the address can be bound again.
+#[derive(Debug)]
+pub struct MetricsExporter {
+    local_addr: SocketAddr,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl MetricsExporter {
+    pub fn spawn(addr: impl ToSocketAddrs, registry: Arc<Mutex<Registry>>) -> io::Result<Self> {
+        Self::spawn_with_timeout(addr, registry, REQUEST_TIMEOUT)
+    }
+
+    fn spawn_with_timeout(
+        addr: impl ToSocketAddrs,
+        registry: Arc<Mutex<Registry>>,
+        timeout: Duration,
+    ) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        let local_addr = listener.local_addr()?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        let thread = thread::Builder::new()
+            .name("metrics-exporter".into())
+            .spawn(move || {
+                for stream in listener.incoming() {
+                    if stopped.load(Ordering::Acquire) {
+                        break;
+                    }
+                    let result = stream.and_then(|stream| serve(stream, &registry, timeout));
+                    if let Err(e) = result {
+                        tracing::debug!(target: BEHAVIOUR, "Failed to serve metrics: {e}");
+                    }
+                }
+            })?;
+        Ok(Self {
+            local_addr,
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    /// Address the exporter listens on, e.g. to find the port picked when binding to port 0.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+impl Drop for MetricsExporter {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Release);
+        // Wakes up the thread blocked accepting connections.
+        let mut addr = self.local_addr;
+        if addr.ip().is_unspecified() {
+            addr.set_ip(match addr.ip() {
+                IpAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                IpAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+            });
+        }
+        if let Err(e) = TcpStream::connect(addr) {
+            tracing::warn!(target: BEHAVIOUR, "Failed to stop the metrics exporter: {e}");
+            return;
+        }
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Reads from a stream until a deadline, however slowly the peer trickles in its bytes.
+struct Deadline<'a> {
+    stream: &'a TcpStream,
+    deadline: Instant,
+}
+
+impl Read for Deadline<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        self.stream.set_read_timeout(Some(remaining))?;
+        self.stream.read(buf)
+    }
+}
+
+fn read_line(reader: &mut impl BufRead, line: &mut String) -> io::Result<usize> {
+    let len = reader.take(MAX_LINE_LEN as u64).read_line(line)?;
+    if len == MAX_LINE_LEN && !line.ends_with('\n') {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "line too long"));
+    }
+    Ok(len)
+}
+
+fn serve(mut stream: TcpStream, registry: &Mutex<Registry>, timeout: Duration) -> io::Result<()> {
+    stream.set_write_timeout(Some(timeout))?;
+    let mut reader = BufReader::new(Deadline {
+        stream: &stream,
+        deadline: Instant::now() + timeout,
+    });
+    let mut request_line = String::new();
+    read_line(&mut reader, &mut request_line)?;
+    // Consume the headers, closing the connection with unread data would reset it.
+    let mut header = String::new();
+    let mut headers = 0;
+    while read_line(&mut reader, &mut header)? > 2 {
+        headers += 1;
+        if headers > MAX_HEADERS {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "too many headers",
+            ));
+        }
+        header.clear();
+    }
+    let mut parts = request_line.split_whitespace();
+    let (status, content_type, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            let mut body = String::new();
+            let registry = registry.lock().unwrap_or_else(|e| e.into_inner());
+            encode(&mut body, &registry).map_err(io::Error::other)?;
+            (
+                "200 OK",
+                "application/openmetrics-text; version=1.0.0; charset=utf-8",
+                body,
+            )
+        }
+        _ => ("404 Not Found", "text/plain", "Not Found\n".to_owned()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )?;
+    stream.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use prometheus_client::metrics::counter::Counter;
+
+    fn get(addr: SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn test_exporter() {
+        let mut registry = Registry::default();
+        let counter: Counter = Counter::default();
+        registry.register("broadcasts", "Broadcasts sent", counter.clone());
+        counter.inc();
+        let exporter =
+            MetricsExporter::spawn("127.0.0.1:0", Arc::new(Mutex::new(registry))).unwrap();
+
+        let response = get(exporter.local_addr(), "/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains("broadcasts_total 1"));
+        assert!(get(exporter.local_addr(), "/").starts_with("HTTP/1.1 404"));
+    }
+
+    #[test]
+    fn test_drop() {
+        let registry = Arc::new(Mutex::new(Registry::default()));
+        let exporter = MetricsExporter::spawn("127.0.0.1:0", registry.clone()).unwrap();
+        let addr = exporter.local_addr();
+        drop(exporter);
+
+        assert!(TcpStream::connect(addr).is_err());
+        let exporter = MetricsExporter::spawn(addr, registry).unwrap();
+        assert!(get(exporter.local_addr(), "/metrics").starts_with("HTTP/1.1 200 OK"));
+    }
+
+    #[test]
+    fn test_slow_clients() {
+        let timeout = Duration::from_millis(200);
+        let exporter = MetricsExporter::spawn_with_timeout(
+            "127.0.0.1:0",
+            Arc::new(Mutex::new(Registry::default())),
+            timeout,
+        )
+        .unwrap();
+        let addr = exporter.local_addr();
+
+        // Trickles in a request without ever finishing it.
+        let mut slow = TcpStream::connect(addr).unwrap();
+        let trickle = thread::spawn(move || {
+            for _ in 0..20 {
+                if write!(slow, "G").is_err() {
+                    break;
+                }
+                thread::sleep(Duration::from_millis(50));
+            }
+        });
+        let start = Instant::now();
+        assert!(get(addr, "/metrics").starts_with("HTTP/1.1 200 OK"));
+        assert!(start.elapsed() < timeout * 4);
+        trickle.join().unwrap();
+
+        // Sends an endless request line.
+        let mut long = TcpStream::connect(addr).unwrap();
+        let _ = long.write_all(&[b'a'; MAX_LINE_LEN + 1]);
+        let mut response = String::new();
+        let _ = long.read_to_string(&mut response);
+        assert!(response.is_empty());
+        assert!(get(addr, "/metrics").starts_with("HTTP/1.1 200 OK"));
+    }
+}
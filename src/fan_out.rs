@@ -0,0 +1,88 @@
+use libp2p::PeerId;
+use rand::seq::SliceRandom;
+use rand::RngCore;
+
+use crate::types::Topic;
+
+/// Selects the peers a broadcast is sent to among the subscribers of its topic, installed with
+/// `Behaviour::set_fan_out`. Applies to the broadcasts we publish and forward, so that sending
+/// to a sample of the subscribers of a very large topic still reaches all of them when
+/// `Config::forwarding` is enabled.
+pub trait FanOut: Send {
+    /// Retains the peers to send the broadcast on the topic to, in the order to send to them.
+    /// `peers` holds the routable subscribers, and `rng` is seeded with `Config::rng_seed`.
+    fn select(&mut self, topic: &Topic, peers: &mut Vec<PeerId>, rng: &mut dyn RngCore);
+}
+
+/// Sends to all subscribers. The default.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AllPeers;
+
+impl FanOut for AllPeers {
+    fn select(&mut self, _: &Topic, _: &mut Vec<PeerId>, _: &mut dyn RngCore) {}
+}
+
+/// Sends to a random sample of this many subscribers.
+#[derive(Clone, Copy, Debug)]
+pub struct RandomSample(pub usize);
+
+impl FanOut for RandomSample {
+    fn select(&mut self, _: &Topic, peers: &mut Vec<PeerId>, rng: &mut dyn RngCore) {
+        if peers.len() > self.0 {
+            peers.partial_shuffle(rng, self.0);
+            peers.truncate(self.0);
+        }
+    }
+}
+
+/// Sends to the `k` subscribers with the highest score, best first.
+pub struct TopK<F> {
+    k: usize,
+    score: F,
+}
+
+impl<F: FnMut(&PeerId) -> f64 + Send> TopK<F> {
+    pub fn new(k: usize, score: F) -> Self {
+        Self { k, score }
+    }
+}
+
+impl<F: FnMut(&PeerId) -> f64 + Send> FanOut for TopK<F> {
+    fn select(&mut self, _: &Topic, peers: &mut Vec<PeerId>, _: &mut dyn RngCore) {
+        let mut scored: Vec<_> = peers
+            .drain(..)
+            .map(|peer| ((self.score)(&peer), peer))
+            .collect();
+        scored.sort_by(|(a, _), (b, _)| b.total_cmp(a));
+        peers.extend(scored.into_iter().take(self.k).map(|(_, peer)| peer));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_fan_out() {
+        let topic = Topic::new(b"topic");
+        let peers: Vec<_> = (0..10).map(|_| PeerId::random()).collect();
+        let mut rng = StdRng::seed_from_u64(0);
+
+        let mut selected = peers.clone();
+        AllPeers.select(&topic, &mut selected, &mut rng);
+        assert_eq!(selected, peers);
+
+        let mut selected = peers.clone();
+        RandomSample(3).select(&topic, &mut selected, &mut rng);
+        assert_eq!(selected.len(), 3);
+        assert!(selected.iter().all(|peer| peers.contains(peer)));
+
+        let rank = |peer: &PeerId| peers.iter().position(|p| p == peer).unwrap() as f64;
+        let mut selected = peers.clone();
+        TopK::new(2, rank).select(&topic, &mut selected, &mut rng);
+        assert_eq!(selected, [peers[9], peers[8]]);
+    }
+}
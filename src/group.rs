@@ -0,0 +1,159 @@
+use fnv::FnvHashMap;
+use libp2p::PeerId;
+use rand::seq::SliceRandom;
+use rand::RngCore;
+
+use crate::types::Topic;
+
+/// How a broadcast picks the member of a group it is sent to, see `Behaviour::subscribe_shared`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum GroupSelection {
+    /// Each member in turn. The default.
+    #[default]
+    RoundRobin,
+    /// A member at random, drawn from the rng seeded with `Config::rng_seed`.
+    Random,
+    /// The member with the fewest messages queued, see `Behaviour::pending`.
+    LeastQueued,
+}
+
+/// Groups the peers joined with their subscriptions, and where the round robin of each group
+/// stands.
+#[derive(Debug, Default)]
+pub(crate) struct SharedGroups {
+    /// Group of each subscription of each peer that subscribed as a member of one.
+    members: FnvHashMap<PeerId, FnvHashMap<Topic, Topic>>,
+    /// Number of broadcasts sent to each group of each subscription.
+    cursors: FnvHashMap<(Topic, Topic), usize>,
+}
+
+impl SharedGroups {
+    pub fn join(&mut self, peer: PeerId, topic: Topic, group: Topic) {
+        self.members.entry(peer).or_default().insert(topic, group);
+    }
+
+    /// Leaves the group the subscription of the peer was a member of, if any.
+    pub fn leave(&mut self, peer: &PeerId, topic: &Topic) {
+        if let Some(groups) = self.members.get_mut(peer) {
+            groups.remove(topic);
+            if groups.is_empty() {
+                self.members.remove(peer);
+            }
+        }
+    }
+
+    pub fn remove_peer(&mut self, peer: &PeerId) {
+        self.members.remove(peer);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.members.is_empty()
+    }
+
+    /// Keeps a single member of each group among the peers, along with the peers that are not a
+    /// member of any. `matches` tells whether a subscription matches the topic of the broadcast,
+    /// and `queued` how many messages are queued for a peer.
+    pub fn select(
+        &mut self,
+        peers: &mut Vec<PeerId>,
+        selection: GroupSelection,
+        matches: impl Fn(&Topic) -> bool,
+        queued: impl Fn(&PeerId) -> usize,
+        rng: &mut dyn RngCore,
+    ) {
+        let mut groups: FnvHashMap<(Topic, Topic), Vec<PeerId>> = FnvHashMap::default();
+        peers.retain(|peer| {
+            let group = self.members.get(peer).and_then(|groups| {
+                groups
+                    .iter()
+                    .find(|(subscription, _)| matches(subscription))
+            });
+            match group {
+                Some((subscription, group)) => {
+                    groups
+                        .entry((*subscription, *group))
+                        .or_default()
+                        .push(*peer);
+                    false
+                }
+                None => true,
+            }
+        });
+        for (key, mut members) in groups {
+            // Sorted so that the round robin does not depend on the order of the subscribers.
+            members.sort();
+            let member = match selection {
+                GroupSelection::RoundRobin => {
+                    let cursor = self.cursors.entry(key).or_default();
+                    let member = members[*cursor % members.len()];
+                    *cursor = cursor.wrapping_add(1);
+                    member
+                }
+                GroupSelection::Random => *members.choose(rng).expect("groups are not empty"),
+                GroupSelection::LeastQueued => *members
+                    .iter()
+                    .min_by_key(|member| queued(member))
+                    .expect("groups are not empty"),
+            };
+            peers.push(member);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_select() {
+        let (topic, group) = (Topic::new(b"jobs"), Topic::new(b"workers"));
+        let (a, b, other) = (PeerId::random(), PeerId::random(), PeerId::random());
+        let mut groups = SharedGroups::default();
+        groups.join(a, topic, group);
+        groups.join(b, topic, group);
+        let mut rng = StdRng::seed_from_u64(0);
+
+        let mut selected = Vec::new();
+        for _ in 0..4 {
+            let mut peers = vec![a, b, other];
+            let exact = |subscription: &Topic| *subscription == topic;
+            groups.select(
+                &mut peers,
+                GroupSelection::RoundRobin,
+                exact,
+                |_| 0,
+                &mut rng,
+            );
+            assert_eq!(peers.len(), 2);
+            assert!(peers.contains(&other));
+            selected.extend(peers.into_iter().filter(|peer| *peer != other));
+        }
+        assert_eq!(selected.iter().filter(|peer| **peer == a).count(), 2);
+        assert_ne!(selected[0], selected[1]);
+
+        let mut peers = vec![a, b];
+        let queued = |peer: &PeerId| if *peer == a { 5 } else { 1 };
+        groups.select(
+            &mut peers,
+            GroupSelection::LeastQueued,
+            |_| true,
+            queued,
+            &mut rng,
+        );
+        assert_eq!(peers, [b]);
+
+        groups.leave(&b, &topic);
+        let mut peers = vec![a, b];
+        groups.select(
+            &mut peers,
+            GroupSelection::Random,
+            |_| true,
+            |_| 0,
+            &mut rng,
+        );
+        assert_eq!(peers, [b, a]);
+    }
+}
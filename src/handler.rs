@@ -1,24 +1,154 @@
 use std::{
-    collections::VecDeque,
+    collections::{BTreeSet, VecDeque},
     pin::Pin,
+    sync::Arc,
     task::{Context, Poll},
+    time::{Duration, Instant},
 };
 
 use asynchronous_codec::Framed;
+use bytes::Bytes;
 use futures::prelude::*;
 use libp2p::swarm::{
-    handler::{ConnectionEvent, DialUpgradeError, FullyNegotiatedInbound, FullyNegotiatedOutbound},
-    ConnectionHandler, ConnectionHandlerEvent, Stream, SubstreamProtocol,
+    handler::{
+        ConnectionEvent, DialUpgradeError, FullyNegotiatedInbound, FullyNegotiatedOutbound,
+        ListenUpgradeError, ProtocolsChange,
+    },
+    ConnectionHandler, ConnectionHandlerEvent, ConnectionId, Stream, SubstreamProtocol,
 };
+use libp2p::PeerId;
 
-use crate::{codec::LengthPrefixedCodec, config::Config, protocol::Protocol, types::Message};
+#[cfg(feature = "chaos")]
+use crate::chaos::Chaos;
+use crate::{
+    codec::{Frame, FrameTooLarge, LengthPrefixedCodec},
+    config::{Config, ConnectionPreference, InboundReplacement, SendTuning, TransportClass},
+    credit::Credits,
+    drop_policy::Decision,
+    protocol::Version,
+    queue::{OutboundQueue, Priority},
+    scheduler::{Schedule, SendScheduler},
+    substream::{Direction, SubstreamEvent},
+    targets::HANDLER,
+    timer::{Sleep, Timer},
+    types::{Capabilities, Handshake, Message, Topic},
+    upgrade::Protocol,
+};
 
+// Long topics make the messages larger, boxing them would cost an allocation per message.
+#[cfg_attr(feature = "long-topics", allow(clippy::large_enum_variant))]
 #[derive(Debug)]
 pub enum HandlerEvent {
     /// We received a `Message` from a remote.
     Rx(Message),
+    /// We started receiving a large broadcast whose payload follows in chunks.
+    RxStreamStart(Topic, usize),
+    /// We received a chunk of the payload of the broadcast being streamed.
+    RxStreamChunk(Bytes, bool),
+    /// The inbound substream failed before the streamed payload was complete.
+    RxStreamAborted,
+    /// We discarded this many queued broadcasts because they expired before being sent.
+    Expired(usize),
+    /// We dropped this many queued messages because they exceed the largest frame the remote
+    /// accepts.
+    Oversized(usize),
+    /// The drop policy shed this many broadcasts because the queue was full.
+    Shed(usize),
+    /// The remote ran out of credits for the topic, the broadcasts on it are held back until
+    /// it grants more.
+    Stalled(Topic),
+    /// The remote sent a frame of this size, larger than `Config::max_buf_size`. The inbound
+    /// substream was closed.
+    RxOversized(usize),
+    /// The outbound substream failed after we sent a frame of this size, larger than any the
+    /// remote accepted before, which suggests the remote rejected it as too large.
+    TxRejected(usize),
+    /// The remote sent its handshake. Holds the optional features both sides support.
+    Negotiated(Capabilities),
+    /// We negotiated this version of the protocol on the outbound substream.
+    NegotiatedVersion(Version),
+    /// The remote stopped supporting the protocol on this connection.
+    Unsupported,
+    /// The remote supports the protocol again after it stopped doing so.
+    Supported,
+    /// Sending to the remote failed `Config::failure_threshold` times in a row. The queued
+    /// broadcasts were dropped, the subscription changes are kept.
+    Failed,
+    /// The oldest queued message has been waiting for this long, longer than
+    /// `Config::slow_consumer_threshold`.
+    Slow(Duration),
+    /// Smoothed time it takes to flush a message, reported when it changed noticeably. Only
+    /// measured for `ConnectionPreference::LowestLatency`.
+    Latency(Duration),
+    /// Number of queued messages, their encoded size and when the oldest of them was queued,
+    /// reported whenever the queue changed.
+    Queue(usize, usize, Option<Instant>),
+    /// A substream was opened, closed or failed, reported with `Config::substream_events`.
+    /// Restarts are reported regardless.
+    Substream(SubstreamEvent),
     /// We successfully sent a `Message`.
     Tx,
+    /// The messages queued with these `Outbound::ack` values were flushed to the remote.
+    Flushed(Vec<u64>),
+    /// The messages queued with these `Outbound::ack` values were written to an outbound
+    /// substream that failed or was reset before they were flushed.
+    Unflushed(Vec<u64>),
+    /// How long each message taken off the queue waited between being queued and being flushed,
+    /// reported on every flush.
+    QueueDelays(Vec<Duration>),
+}
+
+/// A message to send to the remote.
+#[derive(Clone, Debug)]
+pub struct Outbound {
+    pub message: Message,
+    /// Replaces a queued but unsent message of the same topic with the same key.
+    pub supersede_key: Option<u64>,
+    /// Reported back in `HandlerEvent::Flushed` once the message is flushed to the remote.
+    pub ack: Option<u64>,
+    /// Discarded, and reported in `HandlerEvent::Expired`, if not sent by then.
+    pub deadline: Option<Instant>,
+}
+
+impl From<Message> for Outbound {
+    fn from(message: Message) -> Self {
+        Self {
+            message,
+            supersede_key: None,
+            ack: None,
+            deadline: None,
+        }
+    }
+}
+
+/// Instruction from the behaviour.
+// See `HandlerEvent`.
+#[cfg_attr(feature = "long-topics", allow(clippy::large_enum_variant))]
+#[derive(Clone, Debug)]
+pub enum Command {
+    /// Queue the message for sending.
+    Send(Outbound),
+    /// Drop the queued broadcasts of the topic.
+    Cancel(Topic),
+    /// Whether we share a subscribed topic with the remote, which keeps the connection alive
+    /// when `Config::prune_grace` is set.
+    SharesTopics(bool),
+    /// Drop the substreams and the queued messages, see `Behaviour::reset_peer`.
+    Reset,
+    /// Apply the config, keeping the queued messages, see `Behaviour::reconfigure`.
+    Reconfigure(Box<Config>),
+}
+
+impl From<Outbound> for Command {
+    fn from(outbound: Outbound) -> Self {
+        Self::Send(outbound)
+    }
+}
+
+impl From<Message> for Command {
+    fn from(message: Message) -> Self {
+        Self::Send(message.into())
+    }
 }
 
 enum InboundSubstreamState {
@@ -30,6 +160,8 @@ enum InboundSubstreamState {
     Poisoned,
 }
 
+// The message is only held while it waits for the substream, see `HandlerEvent`.
+#[cfg_attr(feature = "long-topics", allow(clippy::large_enum_variant))]
 enum OutboundSubstreamState {
     /// Waiting for an outbound message to be sent. The idle state for an outbound
     /// substream.
@@ -44,59 +176,672 @@ enum OutboundSubstreamState {
 
 pub struct Handler {
     config: Config,
+    /// How the outbound substream is written to, depending on the transport of the connection.
+    tuning: SendTuning,
+    transport: Option<TransportClass>,
 
     /// The single long-lived inbound substream.
     inbound_substream: Option<InboundSubstreamState>,
+    /// Inbound substream held back until the remote closes the current one, see
+    /// `InboundReplacement::Defer`.
+    deferred_inbound_substream: Option<Framed<Stream, LengthPrefixedCodec>>,
+    /// Shared with the inbound upgrades in flight, counting them.
+    upgrade_permits: Arc<()>,
     /// The single long-lived outbound substream.
     outbound_substream: Option<OutboundSubstreamState>,
     /// Flag indicating that an outbound substream is being established to prevent
     /// concurrent establishment attempts.
     establishing_outbound_substream: bool,
+    /// Flags indicating that a substream was opened in the direction before, to report the
+    /// following ones as reopened.
+    inbound_opened: bool,
+    outbound_opened: bool,
+    /// Flag indicating that the payload of a broadcast is currently being streamed in.
+    inbound_streaming: bool,
+    /// Versions of the protocol the remote supports, as far as we know. Nothing is sent while
+    /// it is empty.
+    remote_versions: BTreeSet<Version>,
+    /// Events waiting to be reported to the behaviour.
+    pending_events: VecDeque<HandlerEvent>,
 
     /// Queue of messages that are pending to be sent.
-    pending_messages: VecDeque<Message>,
+    pending_messages: OutboundQueue,
+    /// Parts of a batch too large for the remote, sent ahead of the queue.
+    fragments: VecDeque<Message>,
+    /// Decides when and in which order the queue is drained.
+    scheduler: Option<Box<dyn SendScheduler>>,
+    /// Fires when the scheduler is to be asked again after it delayed sending.
+    send_delay: Option<Sleep>,
+    /// Fires at the earliest deadline of the queued messages.
+    deadline_timer: Option<(Instant, Sleep)>,
+    /// Fires when the grace period of an idle connection ends, for the swarm to check again
+    /// whether to keep it alive.
+    grace_timer: Option<(Instant, Sleep)>,
+    /// Credits the remote granted, and the broadcasts held back for lack of them.
+    credits: Credits,
+    /// Length of the largest frame the remote accepts, once it sent its handshake.
+    remote_max_frame_size: Option<usize>,
+    /// Optional features both sides support, once the remote sent its handshake.
+    capabilities: Capabilities,
+    /// Optional features the remote announced in its handshake.
+    remote_capabilities: Option<Capabilities>,
+    /// Flag indicating that the outbound substream is to be replaced once idle, so that the
+    /// remote receives a handshake with the new config.
+    restart_outbound: bool,
+    /// Number of expired messages discarded since the last report to the behaviour.
+    expired_messages: usize,
+    /// Number of oversized messages dropped since the last report to the behaviour.
+    oversized_messages: usize,
+    /// Number of broadcasts shed by the drop policy since the last report to the behaviour.
+    shed_messages: usize,
+    /// Length of the last frame handed to the outbound substream, and the largest one sent
+    /// before it without the substream failing.
+    last_frame_len: Option<usize>,
+    largest_accepted_len: usize,
+    /// Number of failures to negotiate the outbound substream or to send on it since the last
+    /// successful send.
+    consecutive_failures: u32,
+    /// Frames written to the outbound substream since it was last flushed.
+    unflushed: usize,
+    /// Acks of the messages handed out by `next_message` that were not flushed yet. Dropped
+    /// when the outbound substream fails.
+    unflushed_acks: Vec<u64>,
+    /// When the messages handed out by `next_message` that were not flushed yet were queued.
+    /// Dropped along with `unflushed_acks`.
+    unflushed_queued_at: Vec<Instant>,
+    /// When the first frame not yet flushed was handed to the substream.
+    send_started: Option<Instant>,
+    /// Smoothed flush latency, and the value last reported to the behaviour.
+    latency: Option<Duration>,
+    reported_latency: Option<Duration>,
+    /// Flag indicating that we share a subscribed topic with the remote.
+    shares_topics: bool,
+    /// Last time a message was sent or received, or the shared topics changed.
+    last_activity: Instant,
+    /// Depth of the queue last reported to the behaviour.
+    reported_queue: (usize, usize, Option<Instant>),
+    /// Flag indicating that the remote was reported as a slow consumer, cleared once its queue
+    /// catches up.
+    slow: bool,
+    /// Span the events of the handler are recorded in, naming the peer and the connection.
+    span: tracing::Span,
+    /// Faults injected into the sent messages.
+    #[cfg(feature = "chaos")]
+    chaos: Option<Chaos>,
 }
 
 impl Handler {
     pub(super) fn new(config: Config) -> Self {
         Self {
+            #[cfg(feature = "chaos")]
+            chaos: config
+                .fault_injection
+                .clone()
+                .map(|faults| Chaos::new(faults, config.rng_seed, config.timer.clone())),
+            last_activity: config.clock.now(),
+            scheduler: config.send_scheduler.as_ref().map(|factory| factory()),
+            tuning: SendTuning::default(),
+            transport: None,
             config,
             inbound_substream: None,
+            deferred_inbound_substream: None,
+            upgrade_permits: Arc::new(()),
             outbound_substream: None,
             establishing_outbound_substream: false,
-            pending_messages: VecDeque::new(),
+            inbound_opened: false,
+            outbound_opened: false,
+            inbound_streaming: false,
+            remote_versions: IntoIterator::into_iter([Version::V1, Version::V2]).collect(),
+            pending_events: VecDeque::new(),
+            pending_messages: OutboundQueue::default(),
+            fragments: VecDeque::new(),
+            send_delay: None,
+            deadline_timer: None,
+            grace_timer: None,
+            credits: Credits::default(),
+            remote_max_frame_size: None,
+            capabilities: Capabilities::empty(),
+            remote_capabilities: None,
+            restart_outbound: false,
+            expired_messages: 0,
+            oversized_messages: 0,
+            shed_messages: 0,
+            last_frame_len: None,
+            largest_accepted_len: 0,
+            consecutive_failures: 0,
+            unflushed: 0,
+            unflushed_acks: Vec::new(),
+            unflushed_queued_at: Vec::new(),
+            send_started: None,
+            latency: None,
+            reported_latency: None,
+            shares_topics: false,
+            reported_queue: (0, 0, None),
+            slow: false,
+            span: tracing::Span::none(),
+        }
+    }
+
+    /// Records the events of the handler in a span naming the peer and the connection.
+    pub(super) fn with_peer(mut self, peer: PeerId, connection: ConnectionId) -> Self {
+        self.span = tracing::debug_span!(target: HANDLER, "connection", %peer, %connection);
+        self
+    }
+
+    /// Applies the send tuning configured for the transport of the connection.
+    pub(super) fn with_transport(mut self, transport: TransportClass) -> Self {
+        self.tuning = self.config.send_tuning(transport);
+        self.transport = Some(transport);
+        self
+    }
+
+    /// Applies a new config while keeping the connection and the queued messages. The inbound
+    /// substream decodes the following frames with the new limits, while the outbound substream
+    /// is replaced once everything written to it was flushed, announcing the new limits and
+    /// capabilities in the handshake of its successor.
+    fn reconfigure(&mut self, config: Config) {
+        tracing::debug!(target: HANDLER, "Reconfiguring the handler");
+        #[cfg(feature = "chaos")]
+        {
+            self.chaos = config
+                .fault_injection
+                .clone()
+                .map(|faults| Chaos::new(faults, config.rng_seed, config.timer.clone()));
+        }
+        self.scheduler = config.send_scheduler.as_ref().map(|factory| factory());
+        self.tuning = self
+            .transport
+            .map(|transport| config.send_tuning(transport))
+            .unwrap_or_default();
+        self.config = config;
+        self.send_delay = None;
+        if let Some(InboundSubstreamState::WaitingInput(substream)) = &mut self.inbound_substream {
+            substream
+                .codec_mut()
+                .set_limits(self.config.max_buf_size, self.config.stream_threshold);
+        }
+        if let Some(remote) = self.remote_capabilities {
+            let capabilities = self.config.announced_capabilities() & remote;
+            if capabilities != self.capabilities {
+                self.capabilities = capabilities;
+                self.pending_events
+                    .push_back(HandlerEvent::Negotiated(capabilities));
+            }
+        }
+        self.restart_outbound = self.outbound_substream.is_some();
+    }
+
+    /// Length beyond which batches are split, if any.
+    fn fragment_size(&self) -> Option<usize> {
+        match (self.remote_max_frame_size, self.tuning.max_frame_size) {
+            (Some(remote), Some(local)) => Some(remote.min(local)),
+            (remote, local) => remote.or(local),
+        }
+    }
+
+    /// Pops the next message to send, discarding the broadcasts that expired while queued and
+    /// splitting the batches the remote does not accept whole.
+    pub(super) fn next_message(&mut self) -> Option<Message> {
+        #[cfg(feature = "chaos")]
+        if let Some(message) = self.chaos.as_mut().and_then(Chaos::pop_released) {
+            return Some(message);
+        }
+        while let Some((mut message, ack, deadline, queued_at)) = self
+            .fragments
+            .pop_front()
+            .map(|message| (message, None, None, None))
+            .or_else(|| {
+                let (outbound, queued_at) = match self.credits.unstall() {
+                    Some(outbound) => (outbound, None),
+                    None => self
+                        .pop_scheduled()
+                        .map(|(outbound, since)| (outbound, Some(since)))?,
+                };
+                Some((outbound.message, outbound.ack, outbound.deadline, queued_at))
+            })
+        {
+            if deadline.is_some_and(|deadline| self.config.clock.now() >= deadline) {
+                self.expired_messages += 1;
+                continue;
+            }
+            let config = &self.config;
+            self.expired_messages += message.discard(|message| match message {
+                Message::Broadcast { topic, payload, .. } => config.is_expired(topic, payload),
+                _ => false,
+            });
+            // Until the remote negotiated them, topics too long for plain frames are not sent.
+            // The behaviour announces our subscriptions to them again once it did.
+            if !self.capabilities.contains(Capabilities::LONG_TOPICS) {
+                let dropped =
+                    message.discard(|message| message.longest_topic() > Topic::MAX_PLAIN_LENGTH);
+                if dropped > 0 {
+                    tracing::debug!(
+                        target: HANDLER,
+                        "Dropping {dropped} messages naming topics too long for the remote"
+                    );
+                }
+            }
+            if message.is_empty() {
+                continue;
+            }
+            if let Some(max_len) = self
+                .fragment_size()
+                .filter(|max_len| message.len() > *max_len)
+            {
+                if let Message::Batch { messages } = message {
+                    for fragment in Message::batch(messages, max_len).into_iter().rev() {
+                        self.fragments.push_front(fragment);
+                    }
+                    continue;
+                }
+                // A snapshot of a single topic cannot be split any further.
+                message = match message {
+                    Message::Snapshot { topics } if topics.len() > 1 => {
+                        for part in Message::snapshot(topics, max_len).into_iter().rev() {
+                            self.fragments.push_front(part);
+                        }
+                        continue;
+                    }
+                    message => message,
+                };
+            }
+            if self
+                .remote_max_frame_size
+                .is_some_and(|max_len| message.len() > max_len)
+            {
+                tracing::debug!(
+                    target: HANDLER,
+                    "Dropping a message larger than the remote accepts"
+                );
+                self.oversized_messages += 1;
+                continue;
+            }
+            if !self.credits.charge(&message) {
+                let outbound = Outbound {
+                    message,
+                    supersede_key: None,
+                    ack,
+                    deadline,
+                };
+                let topic = outbound.message.topic().copied();
+                if self.credits.stall(outbound) {
+                    self.pending_events.extend(topic.map(HandlerEvent::Stalled));
+                }
+                continue;
+            }
+            self.unflushed_acks.extend(ack);
+            self.unflushed_queued_at.extend(queued_at);
+            #[cfg(feature = "chaos")]
+            if let Some(chaos) = &mut self.chaos {
+                match chaos.inject(message) {
+                    Some(message) => return Some(message),
+                    None => continue,
+                }
+            }
+            return Some(message);
+        }
+        None
+    }
+
+    /// Takes the message the scheduler picks off the queue, if it does not delay sending, along
+    /// with the time it was queued at.
+    fn pop_scheduled(&mut self) -> Option<(Outbound, Instant)> {
+        let Some(scheduler) = self.scheduler.as_mut() else {
+            return self.pending_messages.pop();
+        };
+        if self.send_delay.is_some() || self.pending_messages.is_empty() {
+            return None;
+        }
+        let now = self.config.clock.now();
+        match scheduler.schedule(&self.pending_messages.broadcasts(), now) {
+            Schedule::Next => self.pending_messages.pop(),
+            Schedule::Pick(index) => self
+                .pending_messages
+                .remove(index)
+                .or_else(|| self.pending_messages.pop()),
+            Schedule::Delay(delay) => {
+                self.send_delay = Some(self.config.timer.sleep(delay));
+                None
+            }
+        }
+    }
+
+    /// Consults the drop policy when a broadcast arrives at a full queue. Returns `false` if the
+    /// broadcast is to be dropped instead of queued.
+    fn make_room(&mut self, outbound: &Outbound, priority: Priority) -> bool {
+        let Message::Broadcast { topic, payload, .. } = &outbound.message else {
+            return true;
+        };
+        let Some(max_len) = self.config.max_queue_len else {
+            return true;
+        };
+        if self.pending_messages.len() < max_len
+            || self.pending_messages.supersedes(outbound, priority)
+        {
+            return true;
+        }
+        let queued = self.pending_messages.broadcasts();
+        let victim = match self
+            .config
+            .drop_policy
+            .select_victim(&queued, topic, payload, priority)
+        {
+            Decision::DropQueued(index) => queued.iter().find(|queued| queued.index == index),
+            Decision::DropIncoming => None,
+        }
+        .map(|victim| victim.index);
+        let Some(index) = victim else {
+            return false;
+        };
+        self.pending_messages.remove(index);
+        self.shed_messages += 1;
+        true
+    }
+
+    /// Number of messages waiting to be sent.
+    #[cfg(feature = "load")]
+    pub(super) fn queue_len(&self) -> usize {
+        self.pending_messages.len() + self.fragments.len()
+    }
+
+    /// Called when the outbound substream failed. Remotes that did not announce the largest frame
+    /// they accept reset the substream when we exceed it, so a failure right after sending a
+    /// frame larger than any sent before is reported as a likely rejection.
+    fn check_rejected(&mut self) {
+        if let Some(len) = self.last_frame_len.take() {
+            if self.remote_max_frame_size.is_none() && len > self.largest_accepted_len {
+                self.pending_events.push_back(HandlerEvent::TxRejected(len));
+            }
+        }
+    }
+
+    /// Reports the change of a substream with `Config::substream_events`. Restarts are always
+    /// reported, as they count towards the churn of the peer.
+    fn report_substream(&mut self, event: SubstreamEvent) {
+        if self.config.substream_events || matches!(event, SubstreamEvent::Reopened(_)) {
+            self.pending_events
+                .push_back(HandlerEvent::Substream(event));
+        }
+    }
+
+    /// Reports a substream that was just opened in the direction.
+    fn report_opened(&mut self, direction: Direction) {
+        let opened = match direction {
+            Direction::Inbound => &mut self.inbound_opened,
+            Direction::Outbound => &mut self.outbound_opened,
+        };
+        let event = if std::mem::replace(opened, true) {
+            SubstreamEvent::Reopened(direction)
+        } else {
+            SubstreamEvent::Opened(direction)
+        };
+        self.report_substream(event);
+    }
+
+    fn on_frame_sent(&mut self, len: usize) {
+        if let Some(previous) = self.last_frame_len.replace(len) {
+            self.largest_accepted_len = self.largest_accepted_len.max(previous);
         }
     }
 
+    fn priority(&self, message: &Message) -> Priority {
+        match message {
+            Message::Broadcast { topic, .. } | Message::Shard { topic, .. } => {
+                self.config.priority(topic)
+            }
+            // Subscription changes are tiny and affect what we receive, send them first.
+            Message::Subscribe { .. }
+            | Message::Unsubscribe { .. }
+            | Message::SubscribeShared { .. } => Priority::High,
+            Message::Batch { messages } => messages
+                .iter()
+                .map(|message| self.priority(message))
+                .max()
+                .unwrap_or_default(),
+            Message::Handshake { .. }
+            | Message::Snapshot { .. }
+            | Message::SnapshotPart { .. }
+            | Message::Query
+            | Message::Control { .. }
+            | Message::SubscribeAck { .. }
+            | Message::Ack { .. }
+            | Message::Credit { .. } => Priority::High,
+        }
+    }
+
+    /// Returns `true` if there are messages waiting to be sent.
+    fn has_pending_messages(&self) -> bool {
+        #[cfg(feature = "chaos")]
+        if self.chaos.as_ref().is_some_and(Chaos::has_released) {
+            return true;
+        }
+        !self.fragments.is_empty() || !self.pending_messages.is_empty()
+    }
+
+    /// Checks how long the oldest queued message has been waiting. Returns the wait when the
+    /// remote becomes a slow consumer.
+    fn check_slow_consumer(&mut self) -> Option<Duration> {
+        let threshold = self.config.slow_consumer_threshold?;
+        let now = self.config.clock.now();
+        let wait = self
+            .pending_messages
+            .oldest()
+            .map(|since| now.saturating_duration_since(since))
+            .filter(|wait| *wait > threshold);
+        let became_slow = wait.is_some() && !self.slow;
+        self.slow = wait.is_some();
+        wait.filter(|_| became_slow)
+    }
+
+    /// Returns the depth of the queue when it changed since it was last reported. The fragments
+    /// of a batch count towards the number and size of the queued messages, but not their age.
+    fn check_queue(&mut self) -> Option<(usize, usize, Option<Instant>)> {
+        let fragments: usize = self.fragments.iter().map(Message::len).sum();
+        let depth = (
+            self.pending_messages.len() + self.fragments.len(),
+            self.pending_messages.bytes() + fragments,
+            self.pending_messages.oldest(),
+        );
+        (depth != self.reported_queue).then(|| {
+            self.reported_queue = depth;
+            depth
+        })
+    }
+
+    /// Folds the flush latency of a message into the smoothed latency. Returns the smoothed
+    /// latency when it drifted by more than a fifth from the value last reported.
+    fn record_latency(&mut self, sample: Duration) -> Option<Duration> {
+        if self.config.connection_preference != ConnectionPreference::LowestLatency {
+            return None;
+        }
+        let latency = match self.latency {
+            Some(latency) => (latency * 7 + sample) / 8,
+            None => sample,
+        };
+        self.latency = Some(latency);
+        let drifted = self.reported_latency.is_none_or(|reported| {
+            let delta = latency.max(reported) - latency.min(reported);
+            delta > reported / 5
+        });
+        if drifted {
+            self.reported_latency = Some(latency);
+        }
+        drifted.then_some(latency)
+    }
+
+    /// Counts a failed negotiation or send, dropping the queued broadcasts once the failures
+    /// reach the threshold of the circuit breaker.
+    fn on_failure(&mut self) {
+        self.consecutive_failures += 1;
+        let Some(threshold) = self.config.failure_threshold else {
+            return;
+        };
+        if self.consecutive_failures >= threshold {
+            self.consecutive_failures = 0;
+            // Subscription changes are kept, as the remote would otherwise never learn of them.
+            let dropped =
+                self.pending_messages.shed() + self.fragments.len() + self.credits.clear_stalled();
+            self.fragments.clear();
+            tracing::debug!(
+                target: HANDLER,
+                "Sending failed {threshold} times in a row, dropping {dropped} broadcasts"
+            );
+            self.pending_events.push_back(HandlerEvent::Failed);
+        }
+    }
+
+    /// Drops the substreams and the queued messages. The remote opens a new inbound substream once
+    /// it notices, and a new outbound substream is opened for the next message.
+    fn reset(&mut self) {
+        let dropped =
+            self.pending_messages.clear() + self.fragments.len() + self.credits.clear_stalled();
+        self.fragments.clear();
+        tracing::debug!(target: HANDLER, "Resetting the substreams, dropping {dropped} messages");
+        if self.outbound_substream.take().is_some() {
+            self.report_substream(SubstreamEvent::Closed(
+                Direction::Outbound,
+                Some("reset".to_owned()),
+            ));
+        }
+        if self.inbound_substream.take().is_some() {
+            self.report_substream(SubstreamEvent::Closed(
+                Direction::Inbound,
+                Some("reset".to_owned()),
+            ));
+        }
+        self.deferred_inbound_substream = None;
+        self.inbound_streaming = false;
+        self.send_delay = None;
+        self.last_frame_len = None;
+        self.consecutive_failures = 0;
+        self.drop_unflushed();
+        self.slow = false;
+    }
+
+    /// Forgets the frames written to an outbound substream that is gone before they were
+    /// flushed. The acks of the messages among them are reported back, so that the behaviour
+    /// sends them again.
+    fn drop_unflushed(&mut self) {
+        self.unflushed = 0;
+        self.unflushed_queued_at.clear();
+        self.send_started = None;
+        if !self.unflushed_acks.is_empty() {
+            let acks = std::mem::take(&mut self.unflushed_acks);
+            self.pending_events.push_back(HandlerEvent::Unflushed(acks));
+        }
+    }
+
+    /// Applies the handshake of the remote. Returns the optional features both sides support.
+    fn on_handshake(&mut self, handshake: Handshake) -> Capabilities {
+        tracing::trace!(
+            target: HANDLER,
+            "Remote accepts frames of up to {} bytes, with capabilities {:?}",
+            handshake.max_frame_size,
+            handshake.capabilities
+        );
+        self.remote_max_frame_size = Some(handshake.max_frame_size);
+        self.remote_capabilities = Some(handshake.capabilities);
+        self.capabilities = self.config.announced_capabilities() & handshake.capabilities;
+        self.capabilities
+    }
+
     fn on_fully_negotiated_inbound(
         &mut self,
         FullyNegotiatedInbound {
-            protocol: stream,
+            protocol: (mut stream, _),
             info: (),
         }: FullyNegotiatedInbound<<Self as ConnectionHandler>::InboundProtocol>,
     ) {
-        self.inbound_substream = Some(InboundSubstreamState::WaitingInput(Framed::new(
+        if self.config.prune_grace.is_some() {
+            stream.ignore_for_keep_alive();
+        }
+        let substream = Framed::new(
             stream,
-            LengthPrefixedCodec::new(self.config.max_buf_size),
-        )))
+            LengthPrefixedCodec::new(self.config.max_buf_size)
+                .with_stream_threshold(self.config.stream_threshold),
+        );
+        let open = matches!(
+            self.inbound_substream,
+            Some(InboundSubstreamState::WaitingInput(_))
+        );
+        match self.replace_inbound(open) {
+            InboundReplacement::Replace => {
+                self.inbound_substream = Some(InboundSubstreamState::WaitingInput(substream));
+            }
+            InboundReplacement::Refuse => {}
+            InboundReplacement::Defer => self.deferred_inbound_substream = Some(substream),
+        }
+    }
+
+    /// Applies `Config::inbound_replacement` to a new inbound substream, given whether the
+    /// previous one is still open. Returns what becomes of the new substream.
+    fn replace_inbound(&mut self, open: bool) -> InboundReplacement {
+        match self.config.inbound_replacement {
+            InboundReplacement::Refuse if open => {
+                tracing::debug!(
+                    target: HANDLER,
+                    "Refusing an inbound substream while the previous one is open"
+                );
+                self.report_substream(SubstreamEvent::Failed(
+                    Direction::Inbound,
+                    "refused while the previous one is open".to_owned(),
+                ));
+                return InboundReplacement::Refuse;
+            }
+            InboundReplacement::Defer if open => {
+                tracing::debug!(
+                    target: HANDLER,
+                    "Deferring an inbound substream until the previous one closes"
+                );
+                return InboundReplacement::Defer;
+            }
+            _ => {}
+        }
+        if std::mem::take(&mut self.inbound_streaming) {
+            // The rest of the payload streamed in on the replaced substream is lost.
+            self.pending_events.push_back(HandlerEvent::RxStreamAborted);
+        }
+        self.report_opened(Direction::Inbound);
+        InboundReplacement::Replace
     }
 
     fn on_fully_negotiated_outbound(
         &mut self,
         FullyNegotiatedOutbound {
-            protocol: stream,
+            protocol: (mut stream, version),
             info: (),
         }: FullyNegotiatedOutbound<<Self as ConnectionHandler>::OutboundProtocol>,
     ) {
+        if self.config.prune_grace.is_some() {
+            stream.ignore_for_keep_alive();
+        }
         assert!(
             self.outbound_substream.is_none(),
             "Established an outbound substream with one already available"
         );
+        self.establishing_outbound_substream = false;
+        self.unflushed = 0;
+        self.unflushed_acks.clear();
+        self.unflushed_queued_at.clear();
+        self.send_started = None;
+        self.pending_events
+            .push_back(HandlerEvent::NegotiatedVersion(version));
+        self.report_opened(Direction::Outbound);
 
-        self.outbound_substream = Some(OutboundSubstreamState::WaitingOutput(Framed::new(
+        let substream = Framed::new(
             stream,
-            LengthPrefixedCodec::new(self.config.max_buf_size),
-        )));
+            LengthPrefixedCodec::new(self.config.max_buf_size).with_version(version),
+        );
+        self.outbound_substream = Some(match version {
+            Version::V1 => OutboundSubstreamState::WaitingOutput(substream),
+            Version::V2 => {
+                let handshake = Handshake {
+                    max_frame_size: self.config.max_buf_size,
+                    capabilities: self.config.announced_capabilities(),
+                };
+                OutboundSubstreamState::PendingSend(substream, Message::Handshake { handshake })
+            }
+        });
     }
 
     fn on_dial_upgrade_error(
@@ -106,19 +851,78 @@ impl Handler {
             <Self as ConnectionHandler>::OutboundProtocol,
         >,
     ) {
+        self.establishing_outbound_substream = false;
+        self.report_substream(SubstreamEvent::Failed(
+            Direction::Outbound,
+            error.to_string(),
+        ));
+        self.on_failure();
         tracing::warn!(
+            target: HANDLER,
             "{}",
             format!(
                 "Dial upgrade error, dropping {} messages: {:?}",
-                self.pending_messages.drain(..).count(),
+                self.pending_messages.clear(),
                 error
             )
         );
     }
+
+    fn on_listen_upgrade_error(
+        &mut self,
+        ListenUpgradeError { error, info: () }: ListenUpgradeError<
+            (),
+            <Self as ConnectionHandler>::InboundProtocol,
+        >,
+    ) {
+        // The substream is dropped, the remote is free to open another one once its other
+        // upgrades completed.
+        tracing::debug!(target: HANDLER, "Inbound substream upgrade failed: {error}");
+        self.report_substream(SubstreamEvent::Failed(
+            Direction::Inbound,
+            error.to_string(),
+        ));
+    }
+
+    /// Tracks which versions of the protocol the remote supports. Once it supports none, the
+    /// queued messages are dropped and the behaviour is told to stop routing to the peer.
+    fn on_remote_protocols_change<'a>(
+        &mut self,
+        added: bool,
+        protocols: impl IntoIterator<Item = &'a str>,
+    ) {
+        let was_supported = !self.remote_versions.is_empty();
+        for version in protocols.into_iter().filter_map(Version::from_protocol) {
+            if added {
+                self.remote_versions.insert(version);
+            } else {
+                self.remote_versions.remove(&version);
+            }
+        }
+        match (was_supported, self.remote_versions.is_empty()) {
+            (true, true) => {
+                let dropped = self.pending_messages.clear() + self.credits.clear_stalled();
+                self.fragments.clear();
+                if self.outbound_substream.take().is_some() {
+                    self.report_substream(SubstreamEvent::Closed(
+                        Direction::Outbound,
+                        Some("remote dropped support for the protocol".to_owned()),
+                    ));
+                }
+                tracing::debug!(
+                    target: HANDLER,
+                    "Remote dropped support for the protocol, dropping {dropped} messages"
+                );
+                self.pending_events.push_back(HandlerEvent::Unsupported);
+            }
+            (false, false) => self.pending_events.push_back(HandlerEvent::Supported),
+            _ => {}
+        }
+    }
 }
 
 impl ConnectionHandler for Handler {
-    type FromBehaviour = Message;
+    type FromBehaviour = Command;
     type ToBehaviour = HandlerEvent;
     type InboundProtocol = Protocol;
     type OutboundProtocol = Protocol;
@@ -126,26 +930,96 @@ impl ConnectionHandler for Handler {
     type OutboundOpenInfo = ();
 
     fn listen_protocol(&self) -> SubstreamProtocol<Self::InboundProtocol> {
-        SubstreamProtocol::new(Protocol {}, ())
+        // Every protocol handed out holds a reference to the permits while upgrading.
+        let in_flight = Arc::strong_count(&self.upgrade_permits) - 1;
+        let protocol = if in_flight < self.config.max_inbound_upgrades {
+            Protocol::with_permit(self.upgrade_permits.clone())
+        } else {
+            Protocol::refused()
+        };
+        SubstreamProtocol::new(protocol, ())
     }
 
-    fn on_behaviour_event(&mut self, msg: Self::FromBehaviour) {
-        self.pending_messages.push_back(msg);
+    fn connection_keep_alive(&self) -> bool {
+        // Without pruning, the substreams keep the connection alive.
+        let Some(grace) = self.config.prune_grace else {
+            return false;
+        };
+        self.shares_topics
+            || self.has_pending_messages()
+            || self.config.clock.now() < self.last_activity + grace
     }
 
-    #[tracing::instrument(level = "trace", name = "ConnectionHandler::poll", skip(self, cx))]
+    fn on_behaviour_event(&mut self, command: Self::FromBehaviour) {
+        let _span = self.span.clone().entered();
+        let outbound = match command {
+            Command::Send(outbound) => outbound,
+            Command::Cancel(topic) => {
+                let cancelled = self.pending_messages.cancel(&topic);
+                tracing::trace!(target: HANDLER, "Cancelled {cancelled} queued messages");
+                return;
+            }
+            Command::SharesTopics(shares_topics) => {
+                self.shares_topics = shares_topics;
+                self.last_activity = self.config.clock.now();
+                return;
+            }
+            Command::Reset => {
+                self.reset();
+                return;
+            }
+            Command::Reconfigure(config) => {
+                self.reconfigure(*config);
+                return;
+            }
+        };
+        if self.remote_versions.is_empty() {
+            tracing::trace!(
+                target: HANDLER,
+                "Dropping a message for a remote without protocol support"
+            );
+            return;
+        }
+        let priority = self.priority(&outbound.message);
+        if !self.make_room(&outbound, priority) {
+            self.shed_messages += 1;
+            return;
+        }
+        let now = self.config.clock.now();
+        if !self.pending_messages.push(outbound, priority, now) {
+            tracing::trace!(target: HANDLER, "Superseded a queued message");
+        }
+    }
+
+    #[tracing::instrument(
+        parent = &self.span,
+        level = "trace",
+        target = HANDLER,
+        name = "ConnectionHandler::poll",
+        skip(self, cx)
+    )]
     fn poll(
         &mut self,
         cx: &mut Context<'_>,
     ) -> Poll<ConnectionHandlerEvent<Self::OutboundProtocol, (), Self::ToBehaviour>> {
+        if let Some(event) = self.pending_events.pop_front() {
+            return Poll::Ready(ConnectionHandlerEvent::NotifyBehaviour(event));
+        }
+
+        #[cfg(feature = "chaos")]
+        if let Some(chaos) = &mut self.chaos {
+            chaos.poll_delayed(cx);
+        }
+
         // Determine if we need to create an outbound substream
-        if !self.pending_messages.is_empty()
+        if self.has_pending_messages()
+            && !self.remote_versions.is_empty()
             && self.outbound_substream.is_none()
             && !self.establishing_outbound_substream
         {
             self.establishing_outbound_substream = true;
             return Poll::Ready(ConnectionHandlerEvent::OutboundSubstreamRequest {
-                protocol: SubstreamProtocol::new(Protocol {}, ()),
+                protocol: SubstreamProtocol::new(Protocol::new(), ()),
             });
         }
 
@@ -157,25 +1031,65 @@ impl ConnectionHandler for Handler {
             {
                 Some(InboundSubstreamState::WaitingInput(mut substream)) => {
                     match substream.poll_next_unpin(cx) {
-                        Poll::Ready(Some(Ok(message))) => {
+                        Poll::Ready(Some(Ok(frame))) => {
                             self.inbound_substream =
                                 Some(InboundSubstreamState::WaitingInput(substream));
-                            return Poll::Ready(ConnectionHandlerEvent::NotifyBehaviour(
-                                HandlerEvent::Rx(message),
-                            ));
+                            self.last_activity = self.config.clock.now();
+                            let event = match frame {
+                                Frame::Message(Message::Handshake { handshake }) => {
+                                    HandlerEvent::Negotiated(self.on_handshake(handshake))
+                                }
+                                Frame::Message(Message::Credit { topic, credits }) => {
+                                    self.credits.grant(topic, credits);
+                                    continue;
+                                }
+                                Frame::Message(Message::Unsubscribe { topic }) => {
+                                    self.credits.revoke(&topic);
+                                    HandlerEvent::Rx(Message::Unsubscribe { topic })
+                                }
+                                Frame::Message(message) => HandlerEvent::Rx(message),
+                                Frame::StreamStart { topic, len } => {
+                                    self.inbound_streaming = true;
+                                    HandlerEvent::RxStreamStart(topic, len)
+                                }
+                                Frame::StreamChunk { data, last } => {
+                                    self.inbound_streaming = !last;
+                                    HandlerEvent::RxStreamChunk(data, last)
+                                }
+                            };
+                            return Poll::Ready(ConnectionHandlerEvent::NotifyBehaviour(event));
                         }
                         Poll::Ready(Some(Err(e))) => {
                             // Close this side of the substream. If the peer is still around,
                             // they will re-establish their outbound substream, i.e., our inbound substream.
-                            tracing::debug!("Inbound substream error: {e}");
+                            tracing::debug!(target: HANDLER, "Inbound substream error: {e}");
+                            self.report_substream(SubstreamEvent::Closed(
+                                Direction::Inbound,
+                                Some(e.to_string()),
+                            ));
+                            if let Some(size) = FrameTooLarge::size_of(&e) {
+                                self.pending_events
+                                    .push_back(HandlerEvent::RxOversized(size));
+                            }
                             self.inbound_substream =
                                 Some(InboundSubstreamState::Closing(substream));
+                            if std::mem::take(&mut self.inbound_streaming) {
+                                return Poll::Ready(ConnectionHandlerEvent::NotifyBehaviour(
+                                    HandlerEvent::RxStreamAborted,
+                                ));
+                            }
                             break;
                         }
                         Poll::Ready(None) => {
-                            tracing::debug!("Inbound substream closed by remote");
+                            tracing::debug!(target: HANDLER, "Inbound substream closed by remote");
+                            self.report_substream(SubstreamEvent::Closed(Direction::Inbound, None));
                             self.inbound_substream =
                                 Some(InboundSubstreamState::Closing(substream));
+                            if std::mem::take(&mut self.inbound_streaming) {
+                                return Poll::Ready(ConnectionHandlerEvent::NotifyBehaviour(
+                                    HandlerEvent::RxStreamAborted,
+                                ));
+                            }
                         }
                         Poll::Pending => {
                             self.inbound_substream =
@@ -191,10 +1105,13 @@ impl ConnectionHandler for Handler {
                                 // Don't close the connection but just drop the inbound substream.
                                 // In case the remote has more to send, the will open up a new
                                 // substream.
-                                tracing::debug!("Inbound substream error while closing: {e}");
+                                tracing::debug!(
+                                    target: HANDLER,
+                                    "Inbound substream error while closing: {e}"
+                                );
                             }
                             self.inbound_substream = None;
-                            break;
+                            continue;
                         }
                         Poll::Pending => {
                             self.inbound_substream =
@@ -204,8 +1121,14 @@ impl ConnectionHandler for Handler {
                     }
                 }
                 None => {
-                    self.inbound_substream = None;
-                    break;
+                    self.inbound_substream = self
+                        .deferred_inbound_substream
+                        .take()
+                        .map(InboundSubstreamState::WaitingInput);
+                    if self.inbound_substream.is_none() {
+                        break;
+                    }
+                    self.report_opened(Direction::Inbound);
                 }
                 Some(InboundSubstreamState::Poisoned) => {
                     unreachable!("Error occurred during inbound substream processing")
@@ -213,6 +1136,12 @@ impl ConnectionHandler for Handler {
             }
         }
 
+        if let Some(delay) = self.send_delay.as_mut() {
+            if delay.poll_unpin(cx).is_ready() {
+                self.send_delay = None;
+            }
+        }
+
         // Process outbound substream
         loop {
             match self
@@ -220,11 +1149,28 @@ impl ConnectionHandler for Handler {
                 .replace(OutboundSubstreamState::Poisoned)
             {
                 Some(OutboundSubstreamState::WaitingOutput(substream)) => {
-                    if let Some(message) = self.pending_messages.pop_front() {
+                    if self.restart_outbound && self.unflushed == 0 {
+                        // Nothing is lost, as everything written was flushed.
+                        drop(substream);
+                        self.restart_outbound = false;
+                        self.outbound_substream = None;
+                        self.report_substream(SubstreamEvent::Closed(
+                            Direction::Outbound,
+                            Some("reconfigured".to_owned()),
+                        ));
+                        cx.waker().wake_by_ref();
+                        break;
+                    }
+                    if let Some(message) = self.next_message() {
                         self.outbound_substream =
                             Some(OutboundSubstreamState::PendingSend(substream, message));
                         continue;
                     }
+                    if self.unflushed > 0 {
+                        self.outbound_substream =
+                            Some(OutboundSubstreamState::PendingFlush(substream));
+                        continue;
+                    }
 
                     self.outbound_substream =
                         Some(OutboundSubstreamState::WaitingOutput(substream));
@@ -233,23 +1179,51 @@ impl ConnectionHandler for Handler {
                 Some(OutboundSubstreamState::PendingSend(mut substream, message)) => {
                     match Sink::poll_ready(Pin::new(&mut substream), cx) {
                         Poll::Ready(Ok(())) => {
-                            match Sink::start_send(Pin::new(&mut substream), message) {
+                            let len = message.len();
+                            match Sink::start_send(Pin::new(&mut substream), &message) {
                                 Ok(()) => {
+                                    self.on_frame_sent(len);
+                                    let now = self.config.clock.now();
+                                    self.send_started.get_or_insert(now);
+                                    self.unflushed += 1;
+                                    // Further frames are written first, then flushed together.
                                     self.outbound_substream =
-                                        Some(OutboundSubstreamState::PendingFlush(substream));
+                                        Some(if self.unflushed < self.tuning.frames_per_flush {
+                                            OutboundSubstreamState::WaitingOutput(substream)
+                                        } else {
+                                            OutboundSubstreamState::PendingFlush(substream)
+                                        });
                                 }
                                 Err(e) => {
                                     tracing::debug!(
+                                        target: HANDLER,
                                         "Failed to send message on outbound substream: {e}"
                                     );
                                     self.outbound_substream = None;
+                                    self.report_substream(SubstreamEvent::Closed(
+                                        Direction::Outbound,
+                                        Some(e.to_string()),
+                                    ));
+                                    self.drop_unflushed();
+                                    self.check_rejected();
+                                    self.on_failure();
                                     break;
                                 }
                             }
                         }
                         Poll::Ready(Err(e)) => {
-                            tracing::debug!("Failed to send message on outbound substream: {e}");
+                            tracing::debug!(
+                                target: HANDLER,
+                                "Failed to send message on outbound substream: {e}"
+                            );
                             self.outbound_substream = None;
+                            self.report_substream(SubstreamEvent::Closed(
+                                Direction::Outbound,
+                                Some(e.to_string()),
+                            ));
+                            self.drop_unflushed();
+                            self.check_rejected();
+                            self.on_failure();
                             break;
                         }
                         Poll::Pending => {
@@ -262,12 +1236,46 @@ impl ConnectionHandler for Handler {
                 Some(OutboundSubstreamState::PendingFlush(mut substream)) => {
                     match Sink::poll_flush(Pin::new(&mut substream), cx) {
                         Poll::Ready(Ok(())) => {
+                            self.consecutive_failures = 0;
+                            self.unflushed = 0;
+                            if !self.unflushed_acks.is_empty() {
+                                let acks = std::mem::take(&mut self.unflushed_acks);
+                                self.pending_events.push_back(HandlerEvent::Flushed(acks));
+                            }
+                            if !self.unflushed_queued_at.is_empty() {
+                                let now = self.config.clock.now();
+                                let delays = self
+                                    .unflushed_queued_at
+                                    .drain(..)
+                                    .map(|since| now.saturating_duration_since(since))
+                                    .collect();
+                                self.pending_events
+                                    .push_back(HandlerEvent::QueueDelays(delays));
+                            }
+                            self.last_activity = self.config.clock.now();
                             self.outbound_substream =
                                 Some(OutboundSubstreamState::WaitingOutput(substream));
+                            if let Some(started) = self.send_started.take() {
+                                let elapsed = self.config.clock.now() - started;
+                                if let Some(latency) = self.record_latency(elapsed) {
+                                    self.pending_events
+                                        .push_back(HandlerEvent::Latency(latency));
+                                }
+                            }
                         }
                         Poll::Ready(Err(e)) => {
-                            tracing::debug!("Failed to flush outbound substream: {e}");
+                            tracing::debug!(
+                                target: HANDLER,
+                                "Failed to flush outbound substream: {e}"
+                            );
                             self.outbound_substream = None;
+                            self.report_substream(SubstreamEvent::Closed(
+                                Direction::Outbound,
+                                Some(e.to_string()),
+                            ));
+                            self.drop_unflushed();
+                            self.check_rejected();
+                            self.on_failure();
                             break;
                         }
                         Poll::Pending => {
@@ -287,6 +1295,70 @@ impl ConnectionHandler for Handler {
             }
         }
 
+        // Registers the waker of a delay the scheduler just asked for.
+        if self
+            .send_delay
+            .as_mut()
+            .is_some_and(|delay| delay.poll_unpin(cx).is_ready())
+        {
+            self.send_delay = None;
+            cx.waker().wake_by_ref();
+        }
+
+        // Discards the messages whose deadline passed while queued, and wakes up at the next one.
+        let now = self.config.clock.now();
+        self.expired_messages += self.pending_messages.expire(now);
+        let deadline = self.pending_messages.next_deadline();
+        poll_timer(
+            &mut self.deadline_timer,
+            deadline,
+            now,
+            &*self.config.timer,
+            cx,
+        );
+        let grace_end = self
+            .config
+            .prune_grace
+            .filter(|_| !self.shares_topics)
+            .map(|grace| self.last_activity + grace)
+            .filter(|end| *end > now);
+        poll_timer(
+            &mut self.grace_timer,
+            grace_end,
+            now,
+            &*self.config.timer,
+            cx,
+        );
+
+        if self.expired_messages > 0 {
+            return Poll::Ready(ConnectionHandlerEvent::NotifyBehaviour(
+                HandlerEvent::Expired(std::mem::take(&mut self.expired_messages)),
+            ));
+        }
+        if let Some(wait) = self.check_slow_consumer() {
+            return Poll::Ready(ConnectionHandlerEvent::NotifyBehaviour(HandlerEvent::Slow(
+                wait,
+            )));
+        }
+        if self.oversized_messages > 0 {
+            return Poll::Ready(ConnectionHandlerEvent::NotifyBehaviour(
+                HandlerEvent::Oversized(std::mem::take(&mut self.oversized_messages)),
+            ));
+        }
+        if self.shed_messages > 0 {
+            return Poll::Ready(ConnectionHandlerEvent::NotifyBehaviour(HandlerEvent::Shed(
+                std::mem::take(&mut self.shed_messages),
+            )));
+        }
+        if let Some(event) = self.pending_events.pop_front() {
+            return Poll::Ready(ConnectionHandlerEvent::NotifyBehaviour(event));
+        }
+        if let Some((messages, bytes, oldest)) = self.check_queue() {
+            return Poll::Ready(ConnectionHandlerEvent::NotifyBehaviour(
+                HandlerEvent::Queue(messages, bytes, oldest),
+            ));
+        }
+
         Poll::Pending
     }
 
@@ -294,6 +1366,7 @@ impl ConnectionHandler for Handler {
         &mut self,
         event: ConnectionEvent<Self::InboundProtocol, Self::OutboundProtocol>,
     ) {
+        let _span = self.span.clone().entered();
         match event {
             ConnectionEvent::FullyNegotiatedInbound(fully_negotiated_inbound) => {
                 self.on_fully_negotiated_inbound(fully_negotiated_inbound)
@@ -304,7 +1377,611 @@ impl ConnectionHandler for Handler {
             ConnectionEvent::DialUpgradeError(dial_upgrade_error) => {
                 self.on_dial_upgrade_error(dial_upgrade_error)
             }
+            ConnectionEvent::ListenUpgradeError(listen_upgrade_error) => {
+                self.on_listen_upgrade_error(listen_upgrade_error)
+            }
+            ConnectionEvent::RemoteProtocolsChange(ProtocolsChange::Added(protocols)) => {
+                self.on_remote_protocols_change(true, protocols.map(AsRef::as_ref))
+            }
+            ConnectionEvent::RemoteProtocolsChange(ProtocolsChange::Removed(protocols)) => {
+                self.on_remote_protocols_change(false, protocols.map(AsRef::as_ref))
+            }
+            ConnectionEvent::AddressChange(_) | ConnectionEvent::LocalProtocolsChange(_) => {}
             _ => {}
         }
     }
 }
+
+/// Keeps the timer set to fire at the instant, if any, and wakes up the task once it fired.
+fn poll_timer(
+    timer: &mut Option<(Instant, Sleep)>,
+    at: Option<Instant>,
+    now: Instant,
+    timers: &dyn Timer,
+    cx: &mut Context<'_>,
+) {
+    match at {
+        Some(at) if timer.as_ref().is_none_or(|(current, _)| *current != at) => {
+            *timer = Some((at, timers.sleep(at.saturating_duration_since(now))));
+        }
+        Some(_) => {}
+        None => *timer = None,
+    }
+    if let Some((_, sleep)) = timer {
+        if sleep.poll_unpin(cx).is_ready() {
+            *timer = None;
+            cx.waker().wake_by_ref();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Instant;
+
+    use libp2p::Multiaddr;
+
+    use crate::test_support::ManualClock;
+
+    #[test]
+    fn test_expired_messages_are_discarded() {
+        let topic = Topic::new(b"votes");
+        let config = Config::default().with_expiry_predicate(|_, payload| payload == b"old");
+        let mut handler = Handler::new(config);
+
+        handler.on_behaviour_event(Message::broadcast(topic, Bytes::from_static(b"old")).into());
+        handler.on_behaviour_event(Message::broadcast(topic, Bytes::from_static(b"new")).into());
+
+        assert_eq!(
+            handler.next_message(),
+            Some(Message::broadcast(topic, Bytes::from_static(b"new")))
+        );
+        assert_eq!(handler.next_message(), None);
+        assert_eq!(handler.expired_messages, 1);
+    }
+
+    #[test]
+    fn test_deadline() {
+        let topic = Topic::new(b"quotes");
+        let mut handler = Handler::new(Config::default());
+        let stale = Message::broadcast(topic, Bytes::from_static(b"stale"));
+        handler.on_behaviour_event(
+            Outbound {
+                deadline: Some(Instant::now()),
+                ..stale.into()
+            }
+            .into(),
+        );
+        let fresh = Message::broadcast(topic, Bytes::from_static(b"fresh"));
+        handler.on_behaviour_event(fresh.clone().into());
+
+        assert_eq!(handler.next_message(), Some(fresh));
+        assert_eq!(handler.expired_messages, 1);
+    }
+
+    #[test]
+    fn test_drop_policy() {
+        use crate::drop_policy::{DropPolicy, QueuedBroadcast};
+        use crate::types::Payload;
+
+        /// Keeps the latest broadcast of each topic, shedding the oldest one of the same topic.
+        struct KeepLatestPerTopic;
+
+        impl DropPolicy for KeepLatestPerTopic {
+            fn select_victim(
+                &self,
+                queued: &[QueuedBroadcast<'_>],
+                topic: &Topic,
+                _: &Payload,
+                _: Priority,
+            ) -> Decision {
+                queued
+                    .iter()
+                    .find(|queued| queued.topic == topic)
+                    .map_or(Decision::DropIncoming, |victim| {
+                        Decision::DropQueued(victim.index)
+                    })
+            }
+        }
+
+        let (a, b) = (Topic::new(b"a"), Topic::new(b"b"));
+        let config = Config::default()
+            .with_max_queue_len(2)
+            .with_drop_policy(KeepLatestPerTopic);
+        let mut handler = Handler::new(config);
+        for (topic, payload) in [(a, "a1"), (b, "b1"), (a, "a2"), (b, "b2"), (a, "a3")] {
+            handler.on_behaviour_event(Message::broadcast(topic, Bytes::from(payload)).into());
+        }
+        handler.on_behaviour_event(Message::Subscribe { topic: b }.into());
+        assert_eq!(handler.shed_messages, 3);
+
+        assert_eq!(
+            handler.next_message(),
+            Some(Message::Subscribe { topic: b })
+        );
+        assert_eq!(
+            handler.next_message(),
+            Some(Message::broadcast(b, Bytes::from("b2")))
+        );
+        assert_eq!(
+            handler.next_message(),
+            Some(Message::broadcast(a, Bytes::from("a3")))
+        );
+        assert_eq!(handler.next_message(), None);
+    }
+
+    #[test]
+    fn test_send_scheduler() {
+        use crate::drop_policy::QueuedBroadcast;
+
+        /// Sends the newest broadcast first, and nothing once two were sent.
+        #[derive(Default)]
+        struct Newest(usize);
+
+        impl SendScheduler for Newest {
+            fn schedule(&mut self, queued: &[QueuedBroadcast<'_>], _: Instant) -> Schedule {
+                self.0 += 1;
+                match queued.last() {
+                    _ if self.0 > 2 => Schedule::Delay(Duration::from_secs(1)),
+                    Some(newest) => Schedule::Pick(newest.index),
+                    None => Schedule::Next,
+                }
+            }
+        }
+
+        let topic = Topic::new(b"t");
+        let broadcast = |payload: &'static [u8]| Message::broadcast(topic, Bytes::from(payload));
+        let config = Config::default().with_send_scheduler(Newest::default);
+        let mut handler = Handler::new(config);
+        for payload in [&b"a"[..], b"b", b"c"] {
+            handler.on_behaviour_event(broadcast(payload).into());
+        }
+
+        assert_eq!(handler.next_message(), Some(broadcast(b"c")));
+        assert_eq!(handler.next_message(), Some(broadcast(b"b")));
+        assert_eq!(handler.next_message(), None);
+        assert!(handler.send_delay.is_some());
+        assert!(handler.has_pending_messages());
+    }
+
+    #[test]
+    fn test_unflushed_acks() {
+        let topic = Topic::new(b"t");
+        let mut handler = Handler::new(Config::default());
+        handler.on_behaviour_event(Message::broadcast(topic, Bytes::from_static(b"plain")).into());
+        handler.on_behaviour_event(
+            Outbound {
+                message: Message::broadcast(topic, Bytes::from_static(b"acked")),
+                supersede_key: None,
+                ack: Some(7),
+                deadline: None,
+            }
+            .into(),
+        );
+
+        assert!(handler.next_message().is_some());
+        assert!(handler.unflushed_acks.is_empty());
+        assert!(handler.next_message().is_some());
+        assert_eq!(handler.unflushed_acks, [7]);
+
+        // The acks are reported back when the substream goes away before the flush.
+        handler.on_behaviour_event(Command::Reset);
+        assert!(handler.unflushed_acks.is_empty());
+        assert!(handler
+            .pending_events
+            .iter()
+            .any(|event| matches!(event, HandlerEvent::Unflushed(acks) if acks == &[7])));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_reserved_capabilities() {
+        Config::default().with_capabilities(Capabilities::ACKS | Capabilities::FRAGMENTATION);
+    }
+
+    #[test]
+    fn test_remote_frame_size() {
+        let topic = Topic::new(b"t");
+        let broadcast = |payload: &'static [u8]| Message::broadcast(topic, Bytes::from(payload));
+        let config =
+            Config::default().with_capabilities(Capabilities::ACKS | Capabilities::CONTROL);
+        let mut handler = Handler::new(config);
+        let negotiated = handler.on_handshake(Handshake {
+            max_frame_size: 10,
+            capabilities: Capabilities::ACKS | Capabilities::ALIASES,
+        });
+        assert_eq!(negotiated, Capabilities::ACKS);
+
+        // Each broadcast takes 6 bytes, and one more for its length within a batch.
+        let batch = vec![broadcast(b"aaaa"), broadcast(b"bbbb"), broadcast(b"cccc")];
+        handler.on_behaviour_event(Message::Batch { messages: batch }.into());
+        handler.on_behaviour_event(broadcast(b"far too long").into());
+
+        assert_eq!(handler.next_message(), Some(broadcast(b"aaaa")));
+        assert_eq!(handler.next_message(), Some(broadcast(b"bbbb")));
+        assert_eq!(handler.next_message(), Some(broadcast(b"cccc")));
+        assert_eq!(handler.next_message(), None);
+        assert_eq!(handler.oversized_messages, 1);
+    }
+
+    #[test]
+    fn test_snapshot_parts() {
+        let topics: Vec<_> = [&b"aaaa"[..], b"bbbb", b"cccc"]
+            .iter()
+            .map(|topic| Topic::new(topic))
+            .collect();
+        let snapshot = |topics: &[Topic]| Outbound {
+            supersede_key: Some(0),
+            ..Message::Snapshot {
+                topics: topics.to_vec(),
+            }
+            .into()
+        };
+        let mut handler = Handler::new(Config::default());
+        handler.on_handshake(Handshake {
+            max_frame_size: 11,
+            capabilities: Capabilities::empty(),
+        });
+        // A queued snapshot is replaced by a newer one.
+        handler.on_behaviour_event(snapshot(&topics[..1]).into());
+        handler.on_behaviour_event(snapshot(&topics).into());
+
+        // Each topic takes 5 bytes, after a byte for the kind.
+        assert_eq!(
+            handler.next_message(),
+            Some(Message::SnapshotPart {
+                topics: topics[..2].to_vec()
+            })
+        );
+        assert_eq!(
+            handler.next_message(),
+            Some(Message::Snapshot {
+                topics: topics[2..].to_vec()
+            })
+        );
+        assert_eq!(handler.next_message(), None);
+    }
+
+    #[test]
+    fn test_send_tuning() {
+        let topic = Topic::new(b"t");
+        let broadcast = |payload: &'static [u8]| Message::broadcast(topic, Bytes::from(payload));
+        let relay: Multiaddr = "/ip4/1.2.3.4/tcp/4001/p2p-circuit".parse().unwrap();
+        let quic: Multiaddr = "/ip4/1.2.3.4/udp/4001/quic-v1".parse().unwrap();
+        assert_eq!(TransportClass::from_addr(&relay), TransportClass::Relay);
+        assert_eq!(TransportClass::from_addr(&quic), TransportClass::Quic);
+
+        let tuning = SendTuning {
+            frames_per_flush: 4,
+            max_frame_size: Some(10),
+        };
+        let config = Config::default().with_send_tuning(TransportClass::Relay, tuning);
+        let mut handler = Handler::new(config.clone()).with_transport(TransportClass::Relay);
+        assert_eq!(handler.tuning, tuning);
+        let batch = vec![broadcast(b"aaaa"), broadcast(b"bbbb")];
+        handler.on_behaviour_event(Message::Batch { messages: batch }.into());
+        handler.on_behaviour_event(broadcast(b"far too long").into());
+
+        // Only batches are split for the local limit, other messages are sent as they are.
+        assert_eq!(handler.next_message(), Some(broadcast(b"aaaa")));
+        assert_eq!(handler.next_message(), Some(broadcast(b"bbbb")));
+        assert_eq!(handler.next_message(), Some(broadcast(b"far too long")));
+        assert_eq!(handler.oversized_messages, 0);
+
+        let handler = Handler::new(config).with_transport(TransportClass::Quic);
+        assert_eq!(handler.tuning, SendTuning::default());
+    }
+
+    #[test]
+    fn test_reconfigure() {
+        let topic = Topic::new(b"t");
+        let broadcast = Message::broadcast(topic, Bytes::from_static(b"kept"));
+        let config = Config::default().with_capabilities(Capabilities::ACKS);
+        let mut handler = Handler::new(config).with_transport(TransportClass::Relay);
+        handler.on_handshake(Handshake {
+            max_frame_size: 1024,
+            capabilities: Capabilities::ACKS | Capabilities::CONTROL,
+        });
+        handler.on_behaviour_event(broadcast.clone().into());
+
+        let tuning = SendTuning {
+            frames_per_flush: 4,
+            max_frame_size: None,
+        };
+        let config = Config::default()
+            .with_capabilities(Capabilities::ACKS | Capabilities::CONTROL)
+            .with_send_tuning(TransportClass::Relay, tuning);
+        handler.on_behaviour_event(Command::Reconfigure(Box::new(config)));
+        assert_eq!(handler.tuning, tuning);
+        assert!(matches!(
+            handler.pending_events.pop_front(),
+            Some(HandlerEvent::Negotiated(capabilities))
+                if capabilities == Capabilities::ACKS | Capabilities::CONTROL
+        ));
+        // Without an outbound substream, the next one opened carries the new handshake.
+        assert!(!handler.restart_outbound);
+        assert_eq!(handler.next_message(), Some(broadcast));
+    }
+
+    #[test]
+    fn test_rejected_frame() {
+        let mut handler = Handler::new(Config::default());
+        handler.on_frame_sent(10);
+        handler.on_frame_sent(100);
+        handler.check_rejected();
+        assert!(matches!(
+            handler.pending_events.pop_front(),
+            Some(HandlerEvent::TxRejected(100))
+        ));
+
+        // A frame no larger than one sent before is not what the remote rejected.
+        handler.on_frame_sent(10);
+        handler.check_rejected();
+        assert!(handler.pending_events.is_empty());
+    }
+
+    #[test]
+    fn test_max_inbound_upgrades() {
+        let handler = Handler::new(Config::default().with_max_inbound_upgrades(2));
+        let first = handler.listen_protocol();
+        let second = handler.listen_protocol();
+        assert!(!first.upgrade().refused && !second.upgrade().refused);
+        assert!(handler.listen_protocol().upgrade().refused);
+
+        drop(first);
+        assert!(!handler.listen_protocol().upgrade().refused);
+    }
+
+    #[test]
+    fn test_circuit_breaker() {
+        let topic = Topic::new(b"topic");
+        let config = Config::default().with_circuit_breaker(2, Duration::from_secs(10));
+        let mut handler = Handler::new(config);
+        handler.on_behaviour_event(Message::Subscribe { topic }.into());
+        handler.on_behaviour_event(Message::broadcast(topic, Bytes::from_static(b"msg")).into());
+
+        handler.on_failure();
+        assert!(handler.pending_events.is_empty());
+        assert_eq!(handler.pending_messages.len(), 2);
+        handler.on_failure();
+        assert!(matches!(
+            handler.pending_events.pop_front(),
+            Some(HandlerEvent::Failed)
+        ));
+        // The remote still needs to learn of the subscription.
+        assert_eq!(
+            handler
+                .pending_messages
+                .pop()
+                .map(|(outbound, _)| outbound.message),
+            Some(Message::Subscribe { topic })
+        );
+        assert!(!handler.has_pending_messages());
+    }
+
+    #[test]
+    fn test_credit_stall() {
+        let topic = Topic::new(b"topic");
+        let broadcast = |payload| Message::broadcast(topic, Bytes::from_static(payload));
+        let mut handler = Handler::new(Config::default());
+        handler.credits.grant(topic, 1);
+        for payload in [b"a", b"b", b"c"] {
+            handler.on_behaviour_event(broadcast(payload).into());
+        }
+
+        assert_eq!(handler.next_message(), Some(broadcast(b"a")));
+        assert_eq!(handler.next_message(), None);
+        // Reported once, as the topic ran out of credits.
+        assert!(matches!(
+            handler.pending_events.pop_front(),
+            Some(HandlerEvent::Stalled(stalled)) if stalled == topic
+        ));
+        assert!(handler.pending_events.is_empty());
+    }
+
+    #[test]
+    fn test_slow_consumer() {
+        let topic = Topic::new(b"topic");
+        let clock = ManualClock::new();
+        let config = Config::default()
+            .with_slow_consumer_detection(Duration::from_secs(1), Default::default())
+            .with_clock(clock.clone());
+        let mut handler = Handler::new(config);
+        handler.on_behaviour_event(Message::Subscribe { topic }.into());
+        assert_eq!(handler.check_slow_consumer(), None);
+
+        clock.advance(Duration::from_secs(2));
+        assert_eq!(handler.check_slow_consumer(), Some(Duration::from_secs(2)));
+        // Reported once until the queue catches up.
+        assert_eq!(handler.check_slow_consumer(), None);
+        handler.next_message();
+        assert_eq!(handler.check_slow_consumer(), None);
+        assert!(!handler.slow);
+    }
+
+    #[test]
+    fn test_latency_reports() {
+        let config =
+            Config::default().with_connection_preference(ConnectionPreference::LowestLatency);
+        let mut handler = Handler::new(config);
+        let ms = Duration::from_millis;
+
+        assert_eq!(handler.record_latency(ms(80)), Some(ms(80)));
+        assert_eq!(handler.record_latency(ms(88)), None);
+        assert_eq!(handler.record_latency(ms(0)), None);
+        assert_eq!(
+            handler.record_latency(ms(0)),
+            Some(Duration::from_nanos(62_015_625))
+        );
+    }
+
+    #[test]
+    fn test_keep_alive() {
+        let clock = ManualClock::new();
+        let config = Config::default()
+            .with_connection_pruning(Duration::from_secs(10))
+            .with_clock(clock.clone());
+        let mut handler = Handler::new(config);
+        assert!(handler.connection_keep_alive());
+
+        clock.advance(Duration::from_secs(11));
+        assert!(!handler.connection_keep_alive());
+        handler.on_behaviour_event(Command::SharesTopics(true));
+        clock.advance(Duration::from_secs(11));
+        assert!(handler.connection_keep_alive());
+        handler.on_behaviour_event(Command::SharesTopics(false));
+        assert!(handler.connection_keep_alive());
+        clock.advance(Duration::from_secs(11));
+        assert!(!handler.connection_keep_alive());
+    }
+
+    #[test]
+    fn test_grace_timer() {
+        let clock = ManualClock::new();
+        let config = Config::default()
+            .with_connection_pruning(Duration::from_secs(10))
+            .with_clock(clock.clone());
+        let mut handler = Handler::new(config);
+        let mut cx = Context::from_waker(futures::task::noop_waker_ref());
+        let grace_end = |handler: &Handler| handler.grace_timer.as_ref().map(|(at, _)| *at);
+
+        let start = handler.last_activity;
+        assert!(handler.poll(&mut cx).is_pending());
+        assert_eq!(grace_end(&handler), Some(start + Duration::from_secs(10)));
+
+        handler.on_behaviour_event(Command::SharesTopics(true));
+        assert!(handler.poll(&mut cx).is_pending());
+        assert_eq!(grace_end(&handler), None);
+
+        clock.advance(Duration::from_secs(1));
+        handler.on_behaviour_event(Command::SharesTopics(false));
+        assert!(handler.poll(&mut cx).is_pending());
+        assert_eq!(grace_end(&handler), Some(start + Duration::from_secs(11)));
+
+        // Nothing to wake up for once the grace period ended.
+        clock.advance(Duration::from_secs(10));
+        assert!(handler.poll(&mut cx).is_pending());
+        assert_eq!(grace_end(&handler), None);
+    }
+
+    #[test]
+    fn test_substream_events() {
+        let mut handler = Handler::new(Config::default());
+        handler.report_opened(Direction::Inbound);
+        assert!(handler.pending_events.is_empty());
+
+        let mut handler = Handler::new(Config::default().with_substream_events());
+        handler.report_opened(Direction::Outbound);
+        handler.report_opened(Direction::Inbound);
+        handler.report_opened(Direction::Outbound);
+        let events: Vec<_> = handler
+            .pending_events
+            .drain(..)
+            .filter_map(|event| match event {
+                HandlerEvent::Substream(event) => Some(event),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            events,
+            [
+                SubstreamEvent::Opened(Direction::Outbound),
+                SubstreamEvent::Opened(Direction::Inbound),
+                SubstreamEvent::Reopened(Direction::Outbound),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_replaced_while_streaming() {
+        let mut handler = Handler::new(Config::default());
+        handler.inbound_streaming = true;
+        assert_eq!(handler.replace_inbound(true), InboundReplacement::Replace);
+        assert!(matches!(
+            handler.pending_events.pop_front(),
+            Some(HandlerEvent::RxStreamAborted)
+        ));
+        assert!(handler.pending_events.is_empty());
+
+        assert_eq!(handler.replace_inbound(true), InboundReplacement::Replace);
+        assert!(matches!(
+            handler.pending_events.pop_front(),
+            Some(HandlerEvent::Substream(SubstreamEvent::Reopened(
+                Direction::Inbound
+            )))
+        ));
+        assert!(handler.pending_events.is_empty());
+    }
+
+    #[test]
+    fn test_inbound_replacement() {
+        for replacement in [
+            InboundReplacement::Replace,
+            InboundReplacement::Refuse,
+            InboundReplacement::Defer,
+        ] {
+            let config = Config::default()
+                .with_inbound_replacement(replacement)
+                .with_substream_events();
+            let mut handler = Handler::new(config);
+            // The first substream, or one replacing a closing one, is always used.
+            assert_eq!(handler.replace_inbound(false), InboundReplacement::Replace);
+            assert_eq!(handler.replace_inbound(false), InboundReplacement::Replace);
+            handler.inbound_streaming = true;
+
+            assert_eq!(handler.replace_inbound(true), replacement);
+            let mut events: Vec<_> = handler.pending_events.drain(..).skip(2).collect();
+            match replacement {
+                InboundReplacement::Replace => assert!(matches!(
+                    &events[..],
+                    [
+                        HandlerEvent::RxStreamAborted,
+                        HandlerEvent::Substream(SubstreamEvent::Reopened(Direction::Inbound)),
+                    ]
+                )),
+                InboundReplacement::Refuse => assert!(
+                    matches!(
+                        events.pop(),
+                        Some(HandlerEvent::Substream(SubstreamEvent::Failed(
+                            Direction::Inbound,
+                            _
+                        )))
+                    ) && events.is_empty()
+                ),
+                // Still reading the stream from the previous substream.
+                InboundReplacement::Defer => assert!(events.is_empty()),
+            }
+            assert_eq!(
+                handler.inbound_streaming,
+                replacement != InboundReplacement::Replace
+            );
+        }
+    }
+
+    #[test]
+    fn test_remote_protocols_change() {
+        let topic = Topic::new(b"topic");
+        let mut handler = Handler::new(Config::default());
+        handler.on_behaviour_event(Message::Subscribe { topic }.into());
+
+        handler.on_remote_protocols_change(false, ["/ax/broadcast/2.0.0"]);
+        assert!(handler.pending_events.is_empty());
+        handler.on_remote_protocols_change(false, ["/ax/broadcast/1.0.0", "/ipfs/id/1.0.0"]);
+        assert!(matches!(
+            handler.pending_events.pop_front(),
+            Some(HandlerEvent::Unsupported)
+        ));
+        assert!(!handler.has_pending_messages());
+
+        handler.on_behaviour_event(Message::Subscribe { topic }.into());
+        assert!(!handler.has_pending_messages());
+
+        handler.on_remote_protocols_change(true, ["/ax/broadcast/2.0.0"]);
+        assert!(matches!(
+            handler.pending_events.pop_front(),
+            Some(HandlerEvent::Supported)
+        ));
+    }
+}
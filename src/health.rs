@@ -0,0 +1,118 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use libp2p::PeerId;
+
+use crate::self_test::SelfTestResult;
+
+/// Aggregate state of the behaviour, see `Behaviour::health`, e.g. to feed the readiness
+/// endpoint of a node.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Health {
+    /// Number of connected peers speaking the protocol.
+    pub connected_peers: usize,
+    /// Number of connected peers subscribed to at least one topic.
+    pub subscribers: usize,
+    /// Peers whose oldest queued message has been waiting for longer than
+    /// `Config::slow_consumer_threshold`, or `Health::STUCK_AFTER` without one.
+    pub stuck_queues: Vec<PeerId>,
+    /// Errors reported over the last `Health::ERROR_WINDOW`.
+    pub recent_errors: ErrorCounts,
+    /// Peers skipped by the circuit breaker, and for how much longer.
+    pub cooling_down: Vec<(PeerId, Duration)>,
+    /// Outcome of the last probe of `Config::self_test`, if any.
+    pub self_test: Option<SelfTestResult>,
+}
+
+impl Health {
+    /// How long the oldest queued message waits before a queue is considered stuck, unless
+    /// `Config::slow_consumer_threshold` is set.
+    pub const STUCK_AFTER: Duration = Duration::from_secs(30);
+
+    /// How far back errors are counted.
+    pub const ERROR_WINDOW: Duration = Duration::from_secs(60);
+}
+
+/// Number of errors of each kind, as reported by the events of the same name.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct ErrorCounts {
+    /// `Event::PeerFailed`.
+    pub peer_failures: usize,
+    /// `Event::Rejected`.
+    pub rejected: usize,
+    /// `Event::ReceivedOversized`.
+    pub received_oversized: usize,
+    /// Messages counted by `Event::Shed`.
+    pub shed: usize,
+    /// Messages counted by `Event::Expired`.
+    pub expired: usize,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum ErrorKind {
+    PeerFailure,
+    Rejected,
+    ReceivedOversized,
+    Shed,
+    Expired,
+}
+
+/// Errors reported within the last `Health::ERROR_WINDOW`.
+#[derive(Debug, Default)]
+pub(crate) struct ErrorLog {
+    entries: VecDeque<(Instant, ErrorKind, usize)>,
+}
+
+impl ErrorLog {
+    pub fn record(&mut self, kind: ErrorKind, count: usize, now: Instant) {
+        while let Some((at, ..)) = self.entries.front() {
+            if now.saturating_duration_since(*at) <= Health::ERROR_WINDOW {
+                break;
+            }
+            self.entries.pop_front();
+        }
+        self.entries.push_back((now, kind, count));
+    }
+
+    pub fn counts(&self, now: Instant) -> ErrorCounts {
+        let mut counts = ErrorCounts::default();
+        let recent = self
+            .entries
+            .iter()
+            .filter(|(at, ..)| now.saturating_duration_since(*at) <= Health::ERROR_WINDOW);
+        for (_, kind, count) in recent {
+            let counter = match kind {
+                ErrorKind::PeerFailure => &mut counts.peer_failures,
+                ErrorKind::Rejected => &mut counts.rejected,
+                ErrorKind::ReceivedOversized => &mut counts.received_oversized,
+                ErrorKind::Shed => &mut counts.shed,
+                ErrorKind::Expired => &mut counts.expired,
+            };
+            *counter += count;
+        }
+        counts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_log() {
+        let start = Instant::now();
+        let mut log = ErrorLog::default();
+        log.record(ErrorKind::Shed, 3, start);
+        log.record(ErrorKind::Shed, 2, start + Duration::from_secs(30));
+        log.record(ErrorKind::PeerFailure, 1, start + Duration::from_secs(30));
+
+        let counts = log.counts(start + Duration::from_secs(30));
+        assert_eq!((counts.shed, counts.peer_failures), (5, 1));
+
+        let counts = log.counts(start + Duration::from_secs(61));
+        assert_eq!((counts.shed, counts.peer_failures), (2, 1));
+
+        log.record(ErrorKind::Expired, 1, start + Duration::from_secs(120));
+        assert_eq!(log.entries.len(), 1);
+    }
+}
@@ -0,0 +1,99 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+
+use crate::config::Retention;
+use crate::types::Metadata;
+
+/// Broadcasts published on a topic with history, replayed to the peers subscribing later.
+///
+/// Entries are numbered in the order they were published, so that a replay in progress keeps
+/// its position while old entries are dropped and new ones appended.
+#[derive(Debug, Default)]
+pub(crate) struct History {
+    entries: VecDeque<(Instant, Bytes, Arc<Metadata>)>,
+    /// Sequence number of the oldest entry.
+    first_seq: u64,
+    bytes: usize,
+}
+
+impl History {
+    /// Interval at which the next broadcasts of the replays in progress are sent.
+    pub const REPLAY_TICK: Duration = Duration::from_millis(10);
+
+    pub fn push(&mut self, now: Instant, payload: Bytes, metadata: Arc<Metadata>) {
+        self.bytes += payload.len();
+        self.entries.push_back((now, payload, metadata));
+    }
+
+    /// Sequence number of the oldest entry still kept.
+    pub fn first_seq(&self) -> u64 {
+        self.first_seq
+    }
+
+    /// Sequence number the next entry will get.
+    pub fn end_seq(&self) -> u64 {
+        self.first_seq + self.entries.len() as u64
+    }
+
+    pub fn get(&self, seq: u64) -> Option<(&Bytes, &Metadata)> {
+        let index = seq.checked_sub(self.first_seq)?;
+        let (_, payload, metadata) = self.entries.get(index as usize)?;
+        Some((payload, metadata))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Drops the oldest entries until the retention is met. Returns the number dropped.
+    pub fn enforce(&mut self, retention: &Retention, now: Instant) -> usize {
+        let mut dropped = 0;
+        while let Some((kept_at, payload, _)) = self.entries.front() {
+            let exceeded = retention
+                .max_messages
+                .is_some_and(|max| self.entries.len() > max)
+                || retention.max_bytes.is_some_and(|max| self.bytes > max)
+                || retention
+                    .max_age
+                    .is_some_and(|max| now.saturating_duration_since(*kept_at) > max);
+            if !exceeded {
+                break;
+            }
+            self.bytes -= payload.len();
+            self.entries.pop_front();
+            self.first_seq += 1;
+            dropped += 1;
+        }
+        dropped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_history_retention() {
+        let now = Instant::now();
+        let mut history = History::default();
+        for payload in [&b"a"[..], b"bb", b"ccc"] {
+            history.push(now, Bytes::from(payload), Arc::default());
+        }
+        assert_eq!(
+            history.enforce(&Retention::default().with_max_bytes(5), now),
+            1
+        );
+        assert_eq!((history.first_seq(), history.end_seq()), (1, 3));
+        assert_eq!(history.get(0), None);
+        assert_eq!(history.get(2).unwrap().0, &Bytes::from_static(b"ccc"));
+
+        let later = now + Duration::from_secs(2);
+        let retention = Retention::default().with_max_age(Duration::from_secs(1));
+        assert_eq!(history.enforce(&retention, later), 2);
+        assert!(history.is_empty());
+        assert_eq!(history.end_seq(), 3);
+    }
+}
@@ -0,0 +1,33 @@
+use bytes::Bytes;
+use libp2p::PeerId;
+
+use crate::types::{Metadata, Topic};
+
+/// Observes, rewrites or drops broadcasts on their way out and in, e.g. to compress payloads,
+/// tag them or filter them. Installed with `Behaviour::add_interceptor`; interceptors run in the
+/// order they were added, and a broadcast dropped by one is not passed to the next.
+///
+/// Duplicates are recognized by the payload as published and as delivered, so an interceptor
+/// rewriting outbound payloads should undo it inbound. Broadcasts streamed in chunks or
+/// erasure-coded bypass the interceptors.
+pub trait Interceptor: Send {
+    /// Called before a broadcast is sent, whether published or forwarded. Returns `false` to
+    /// drop it.
+    fn on_outbound(&mut self, topic: &Topic, payload: &mut Bytes, metadata: &mut Metadata) -> bool {
+        let _ = (topic, payload, metadata);
+        true
+    }
+
+    /// Called when a broadcast is received from the peer, before it is checked for duplicates
+    /// and delivered. Returns `false` to drop it.
+    fn on_inbound(
+        &mut self,
+        peer: &PeerId,
+        topic: &Topic,
+        payload: &mut Bytes,
+        metadata: &mut Metadata,
+    ) -> bool {
+        let _ = (peer, topic, payload, metadata);
+        true
+    }
+}
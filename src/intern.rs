@@ -0,0 +1,90 @@
+use std::convert::TryFrom;
+
+use fnv::FnvHashMap;
+
+use crate::types::Topic;
+
+/// Small id standing in for a topic, see `TopicInterner`.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub(crate) struct TopicId(u32);
+
+/// Assigns small ids to the topics in use, so that the maps keyed on them hold a `u32` rather than
+/// a whole `Topic` per entry. This saves memory only: looking up a topic still hashes it to find
+/// its id. Each id is held until released as often as it was interned, after which it is reused
+/// for the next new topic.
+#[derive(Debug, Default)]
+pub(crate) struct TopicInterner {
+    ids: FnvHashMap<Topic, TopicId>,
+    /// Topic of each id, along with how often it is held. `None` for the ids free for reuse.
+    slots: Vec<Option<(Topic, usize)>>,
+    free: Vec<TopicId>,
+}
+
+impl TopicInterner {
+    /// Returns the id of the topic, assigning one if it has none, and holds it once more.
+    pub fn intern(&mut self, topic: Topic) -> TopicId {
+        if let Some(id) = self.ids.get(&topic) {
+            if let Some((_, held)) = &mut self.slots[id.0 as usize] {
+                *held += 1;
+            }
+            return *id;
+        }
+        let id = self.free.pop().unwrap_or_else(|| {
+            let id = u32::try_from(self.slots.len()).expect("fewer than 2^32 topics in use");
+            self.slots.push(None);
+            TopicId(id)
+        });
+        self.slots[id.0 as usize] = Some((topic, 1));
+        self.ids.insert(topic, id);
+        id
+    }
+
+    /// Id of the topic, if it is in use.
+    pub fn get(&self, topic: &Topic) -> Option<TopicId> {
+        self.ids.get(topic).copied()
+    }
+
+    pub fn resolve(&self, id: TopicId) -> &Topic {
+        let (topic, _) = self.slots[id.0 as usize]
+            .as_ref()
+            .expect("ids are resolved while held");
+        topic
+    }
+
+    /// Releases the id once, freeing it once no longer held.
+    pub fn release(&mut self, id: TopicId) {
+        let slot = &mut self.slots[id.0 as usize];
+        let Some((topic, held)) = slot else {
+            return;
+        };
+        *held -= 1;
+        if *held == 0 {
+            self.ids.remove(topic);
+            *slot = None;
+            self.free.push(id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_intern() {
+        let (a, b, c) = (Topic::new(b"a"), Topic::new(b"b"), Topic::new(b"c"));
+        let mut interner = TopicInterner::default();
+        let id = interner.intern(a);
+        assert_eq!(interner.intern(a), id);
+        assert_ne!(interner.intern(b), id);
+        assert_eq!(interner.resolve(id), &a);
+
+        // Ids are freed once released as often as they were interned, and reused.
+        interner.release(id);
+        assert_eq!(interner.get(&a), Some(id));
+        interner.release(id);
+        assert_eq!(interner.get(&a), None);
+        assert_eq!(interner.intern(c), id);
+        assert!(interner.get(&b).is_some());
+    }
+}
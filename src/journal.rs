@@ -0,0 +1,186 @@
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+
+use bytes::Bytes;
+use libp2p::PeerId;
+
+use crate::targets::BEHAVIOUR;
+use crate::types::{Message, Metadata, Topic};
+
+/// Whether a journaled broadcast was published by us or delivered to us.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum JournalKind {
+    Published,
+    /// Delivered in `Event::Received`, received from the given peer.
+    Delivered(PeerId),
+}
+
+/// A broadcast passed to a `JournalSink`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct JournalRecord {
+    pub kind: JournalKind,
+    /// Time the broadcast was published or delivered at, in milliseconds since the Unix epoch.
+    pub at: u64,
+    pub topic: Topic,
+    pub payload: Bytes,
+    /// Metadata as sent, or as delivered to the application.
+    pub metadata: Metadata,
+}
+
+/// Receives every broadcast published or delivered by the behaviour, e.g. to keep an audit
+/// trail or to reproduce a bug with `Behaviour::replay`. Installed with
+/// `Behaviour::set_journal`.
+///
+/// Records are passed synchronously while the behaviour is polled, sinks doing I/O should
+/// buffer.
+pub trait JournalSink: Send {
+    fn record(&mut self, record: &JournalRecord);
+}
+
+impl<F> JournalSink for F
+where
+    F: FnMut(&JournalRecord) + Send,
+{
+    fn record(&mut self, record: &JournalRecord) {
+        self(record)
+    }
+}
+
+const PUBLISHED: u8 = 0;
+const DELIVERED: u8 = 1;
+
+/// Writes the records to a writer, one after the other, in a format read back by
+/// `read_journal`. Write errors are logged and the record is skipped.
+///
+/// Each record is its kind as a byte, followed by the peer id for delivered broadcasts, the time
+/// as a big-endian `u64`, and the broadcast as a frame of the wire format. Peer ids and frames
+/// are preceded by their length as a big-endian `u32`. Like on the wire, `Metadata::skew` is
+/// left out.
+#[derive(Debug)]
+pub struct JournalWriter<W> {
+    writer: W,
+}
+
+impl<W: Write> JournalWriter<W> {
+    pub fn new(writer: W) -> Self {
+        Self { writer }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    fn write(&mut self, record: &JournalRecord) -> io::Result<()> {
+        let mut buf = Vec::new();
+        match record.kind {
+            JournalKind::Published => buf.push(PUBLISHED),
+            JournalKind::Delivered(peer) => {
+                buf.push(DELIVERED);
+                write_chunk(&mut buf, &peer.to_bytes());
+            }
+        }
+        buf.extend_from_slice(&record.at.to_be_bytes());
+        let frame = Message::broadcast_with_metadata(
+            record.topic,
+            record.payload.clone(),
+            record.metadata.clone(),
+        );
+        write_chunk(&mut buf, &frame.to_bytes());
+        self.writer.write_all(&buf)
+    }
+}
+
+impl<W: Write + Send> JournalSink for JournalWriter<W> {
+    fn record(&mut self, record: &JournalRecord) {
+        if let Err(e) = self.write(record) {
+            tracing::warn!(target: BEHAVIOUR, "Failed to write a journal record: {e}");
+        }
+    }
+}
+
+fn write_chunk(buf: &mut Vec<u8>, chunk: &[u8]) {
+    buf.extend_from_slice(&(chunk.len() as u32).to_be_bytes());
+    buf.extend_from_slice(chunk);
+}
+
+fn read_chunk(reader: &mut impl Read) -> io::Result<Vec<u8>> {
+    let mut len = [0; 4];
+    reader.read_exact(&mut len)?;
+    let mut chunk = vec![0; u32::from_be_bytes(len) as usize];
+    reader.read_exact(&mut chunk)?;
+    Ok(chunk)
+}
+
+fn invalid_data(error: impl ToString) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error.to_string())
+}
+
+/// Reads the records written by a `JournalWriter`, up to the end of the reader.
+pub fn read_journal(mut reader: impl Read) -> io::Result<Vec<JournalRecord>> {
+    let mut records = Vec::new();
+    loop {
+        let mut kind = [0];
+        if reader.read(&mut kind)? == 0 {
+            return Ok(records);
+        }
+        let kind = match kind[0] {
+            PUBLISHED => JournalKind::Published,
+            DELIVERED => {
+                let peer = PeerId::from_bytes(&read_chunk(&mut reader)?).map_err(invalid_data)?;
+                JournalKind::Delivered(peer)
+            }
+            kind => return Err(invalid_data(format!("unknown journal record kind {kind}"))),
+        };
+        let mut at = [0; 8];
+        reader.read_exact(&mut at)?;
+        let frame = read_chunk(&mut reader)?;
+        let Message::Broadcast {
+            topic,
+            payload,
+            metadata,
+        } = Message::from_bytes(frame.into())?
+        else {
+            return Err(invalid_data("journal record is not a broadcast"));
+        };
+        records.push(JournalRecord {
+            kind,
+            at: u64::from_be_bytes(at),
+            topic,
+            payload: payload.into(),
+            metadata: Arc::unwrap_or_clone(metadata),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_journal_roundtrip() {
+        let topic = Topic::new(b"orders");
+        let records = vec![
+            JournalRecord {
+                kind: JournalKind::Published,
+                at: 1_700_000_000_000,
+                topic,
+                payload: Bytes::from_static(b"buy"),
+                metadata: Metadata::default().with_correlation_id(7),
+            },
+            JournalRecord {
+                kind: JournalKind::Delivered(PeerId::random()),
+                at: 1_700_000_000_500,
+                topic,
+                payload: Bytes::from_static(b"sell"),
+                metadata: Metadata::default(),
+            },
+        ];
+        let mut writer = JournalWriter::new(Vec::new());
+        for record in &records {
+            writer.record(record);
+        }
+        let bytes = writer.into_inner();
+        assert_eq!(read_journal(&bytes[..]).unwrap(), records);
+        assert!(read_journal(&bytes[..bytes.len() - 1]).is_err());
+    }
+}
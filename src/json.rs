@@ -0,0 +1,93 @@
+use std::fmt::Write;
+
+use libp2p::PeerId;
+
+use crate::types::Topic;
+
+/// A JSON object being written field by field, for the exports meant for log pipelines and
+/// debug endpoints. Keys are written as given, they are expected not to need escaping.
+pub(crate) struct Object(String);
+
+impl Object {
+    pub fn new() -> Self {
+        Self(String::from("{"))
+    }
+
+    fn key(&mut self, key: &str) {
+        if self.0.len() > 1 {
+            self.0.push(',');
+        }
+        let _ = write!(self.0, "\"{key}\":");
+    }
+
+    pub fn number(mut self, key: &str, value: impl Into<u64>) -> Self {
+        self.key(key);
+        let _ = write!(self.0, "{}", value.into());
+        self
+    }
+
+    pub fn boolean(self, key: &str, value: bool) -> Self {
+        self.raw(key, if value { "true" } else { "false" })
+    }
+
+    pub fn string(mut self, key: &str, value: &str) -> Self {
+        self.key(key);
+        push_string(&mut self.0, value);
+        self
+    }
+
+    /// Writes a value already formatted as JSON, e.g. a nested object or an array.
+    pub fn raw(mut self, key: &str, json: &str) -> Self {
+        self.key(key);
+        self.0.push_str(json);
+        self
+    }
+
+    pub fn peer(self, peer: &PeerId) -> Self {
+        self.string("peer", &peer.to_base58())
+    }
+
+    pub fn topic(self, topic: &Topic) -> Self {
+        self.string("topic", &String::from_utf8_lossy(topic))
+    }
+
+    pub fn finish(mut self) -> String {
+        self.0.push('}');
+        self.0
+    }
+}
+
+/// Formats the values, already formatted as JSON, as an array.
+pub(crate) fn array(values: impl IntoIterator<Item = String>) -> String {
+    let values: Vec<_> = values.into_iter().collect();
+    format!("[{}]", values.join(","))
+}
+
+/// Formats the topic as a JSON string, with invalid UTF-8 replaced.
+pub(crate) fn topic(topic: &Topic) -> String {
+    string(&String::from_utf8_lossy(topic))
+}
+
+pub(crate) fn string(value: &str) -> String {
+    let mut buf = String::new();
+    push_string(&mut buf, value);
+    buf
+}
+
+fn push_string(buf: &mut String, value: &str) {
+    buf.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => buf.push_str("\\\""),
+            '\\' => buf.push_str("\\\\"),
+            '\n' => buf.push_str("\\n"),
+            '\r' => buf.push_str("\\r"),
+            '\t' => buf.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(buf, "\\u{:04x}", c as u32);
+            }
+            c => buf.push(c),
+        }
+    }
+    buf.push('"');
+}
@@ -1,380 +1,220 @@
-use std::collections::VecDeque;
-use std::fmt;
-use std::task::{Context, Poll};
-
-use bytes::Bytes;
-use fnv::{FnvHashMap, FnvHashSet};
-use libp2p::core::transport::PortUse;
-use libp2p::core::Endpoint;
-use libp2p::swarm::derive_prelude::FromSwarm;
-use libp2p::swarm::{
-    ConnectionDenied, ConnectionHandler, ConnectionId, NetworkBehaviour, NotifyHandler, THandler,
-    ToSwarm,
-};
-use libp2p::{Multiaddr, PeerId};
-use prometheus_client::registry::Registry;
-
+#[cfg(feature = "spill")]
+mod assembler;
+#[cfg(feature = "behaviour")]
+mod behaviour;
+#[cfg(feature = "behaviour")]
+mod bloom;
+#[cfg(feature = "behaviour")]
+mod callbacks;
+#[cfg(feature = "chaos")]
+mod chaos;
+#[cfg(feature = "behaviour")]
+mod churn;
+#[cfg(feature = "behaviour")]
+mod clock;
+#[cfg(any(feature = "behaviour", feature = "wire-only"))]
 mod codec;
+#[cfg(feature = "behaviour")]
 mod config;
+#[cfg(feature = "behaviour")]
+mod credit;
+#[cfg(feature = "behaviour")]
+mod domain;
+#[cfg(feature = "behaviour")]
+mod drop_policy;
+#[cfg(feature = "behaviour")]
+mod dump;
+#[cfg(feature = "behaviour")]
+mod epoch;
+#[cfg(feature = "erasure")]
+mod erasure;
+#[cfg(feature = "behaviour")]
+mod event_log;
+#[cfg(feature = "prometheus")]
+mod exporter;
+#[cfg(feature = "behaviour")]
+mod fan_out;
+#[cfg(feature = "behaviour")]
+mod group;
+#[cfg(feature = "behaviour")]
 mod handler;
+#[cfg(feature = "behaviour")]
+mod health;
+#[cfg(feature = "behaviour")]
+mod history;
+#[cfg(feature = "behaviour")]
+mod interceptor;
+#[cfg(feature = "behaviour")]
+mod intern;
+#[cfg(feature = "behaviour")]
+mod journal;
+#[cfg(feature = "behaviour")]
+mod json;
+#[cfg(feature = "load")]
+mod load;
+#[cfg(feature = "behaviour")]
+mod matcher;
+#[cfg(feature = "behaviour")]
+mod membership;
+#[cfg(feature = "prometheus")]
 mod metrics;
+#[cfg(feature = "behaviour")]
+mod metrics_sink;
+#[cfg(feature = "mqtt")]
+mod mqtt;
+#[cfg(feature = "behaviour")]
+mod outbox;
+#[cfg(feature = "behaviour")]
+mod pipeline;
+#[cfg(any(feature = "behaviour", feature = "wire-only"))]
 mod protocol;
+#[cfg(feature = "behaviour")]
+mod queue;
+#[cfg(feature = "behaviour")]
+mod quota;
+#[cfg(feature = "behaviour")]
+mod redelivery;
+#[cfg(feature = "behaviour")]
+mod reorder;
+#[cfg(feature = "behaviour")]
+mod report;
+#[cfg(feature = "behaviour")]
+mod reputation;
+#[cfg(feature = "behaviour")]
+mod routing;
+#[cfg(feature = "behaviour")]
+mod scheduler;
+#[cfg(feature = "behaviour")]
+mod seen;
+#[cfg(feature = "behaviour")]
+mod seen_store;
+#[cfg(feature = "behaviour")]
+mod self_test;
+#[cfg(feature = "behaviour")]
+mod sequence;
+#[cfg(feature = "behaviour")]
+mod snapshot;
+#[cfg(feature = "behaviour")]
+mod subscription_store;
+#[cfg(feature = "behaviour")]
+mod substream;
+mod targets;
+#[cfg(all(test, feature = "behaviour"))]
+mod test_support;
+#[cfg(feature = "behaviour")]
+mod timer;
+#[cfg(feature = "behaviour")]
+mod topic_stats;
 mod types;
-
-pub use config::Config;
+#[cfg(feature = "behaviour")]
+mod upgrade;
+#[cfg(feature = "test-vectors")]
+mod vectors;
+
+#[cfg(feature = "spill")]
+pub use assembler::{AssembledPayload, ChunkAssembler};
+#[cfg(feature = "behaviour")]
+pub use behaviour::{Behaviour, Event, HandlerEvent, NegotiatedProtocol, ThrottleKind};
+#[cfg(feature = "behaviour")]
+pub use bloom::RotatingBloomFilter;
+#[cfg(feature = "chaos")]
+pub use chaos::FaultInjection;
+#[cfg(feature = "behaviour")]
+pub use churn::Churn;
+#[cfg(feature = "behaviour")]
+pub use clock::{Clock, SystemClock};
+#[cfg(feature = "behaviour")]
+pub use config::{
+    BandwidthCap, CapAction, Config, ConnectionPreference, ExpiryPredicate, InboundReplacement,
+    Redelivery, Reordering, Retention, SelfTest, SendSchedulerFactory, SendTuning,
+    SlowConsumerAction, TransportClass,
+};
+#[cfg(feature = "behaviour")]
+pub use domain::Domain;
+#[cfg(feature = "behaviour")]
+pub use drop_policy::{Decision, DropNewest, DropOldest, DropPolicy, QueuedBroadcast};
+#[cfg(feature = "behaviour")]
+pub use dump::{PeerState, StateDump};
+#[cfg(feature = "behaviour")]
+pub use epoch::EpochTopics;
+#[cfg(feature = "erasure")]
+pub use erasure::ErasureCoding;
+#[cfg(feature = "behaviour")]
+pub use event_log::{EventSink, JsonEventWriter};
+#[cfg(feature = "prometheus")]
+pub use exporter::MetricsExporter;
+#[cfg(feature = "behaviour")]
+pub use fan_out::{AllPeers, FanOut, RandomSample, TopK};
+#[cfg(feature = "behaviour")]
+pub use group::GroupSelection;
+#[cfg(feature = "behaviour")]
+pub use health::{ErrorCounts, Health};
+#[cfg(feature = "behaviour")]
+pub use interceptor::Interceptor;
+#[cfg(feature = "behaviour")]
+pub use journal::{read_journal, JournalKind, JournalRecord, JournalSink, JournalWriter};
+#[cfg(feature = "load")]
+pub use load::{LoadGenerator, LoadProfile, LoadReport};
+#[cfg(feature = "behaviour")]
+pub use matcher::{ExactMatcher, PatternMatcher, PrefixMatcher, TopicMatcher};
+#[cfg(feature = "behaviour")]
+pub use membership::Membership;
+#[cfg(feature = "prometheus")]
 pub use metrics::Metrics;
-pub use types::Topic;
-
-use crate::handler::{Handler, HandlerEvent::*};
-use crate::types::Message::{self, *};
-
-#[derive(Clone, Debug, Eq, PartialEq)]
-pub enum Event {
-    Subscribed(PeerId, Topic),
-    Unsubscribed(PeerId, Topic),
-    Received(PeerId, Topic, Bytes),
-}
-
-#[derive(Default)]
-pub struct Behaviour {
-    config: Config,
-    subscriptions: FnvHashSet<Topic>,
-    peers: FnvHashMap<PeerId, FnvHashSet<Topic>>,
-    topics: FnvHashMap<Topic, FnvHashSet<PeerId>>,
-    events: VecDeque<ToSwarm<Event, Message>>,
-    metrics: Option<Metrics>,
-}
-
-impl fmt::Debug for Behaviour {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.debug_struct("Behaviour")
-            .field("config", &self.config)
-            .field("subscriptions", &self.subscriptions)
-            .field("peers", &self.peers)
-            .field("topics", &self.topics)
-            .finish()
-    }
-}
-
-impl Behaviour {
-    pub fn new(config: Config) -> Self {
-        Self {
-            config,
-            ..Default::default()
-        }
-    }
-
-    pub fn new_with_metrics(config: Config, registry: &mut Registry) -> Self {
-        Self {
-            config,
-            metrics: Some(Metrics::new(registry)),
-            ..Default::default()
-        }
-    }
-
-    pub fn subscribed(&self) -> impl Iterator<Item = &Topic> + '_ {
-        self.subscriptions.iter()
-    }
-
-    pub fn peers(&self, topic: &Topic) -> Option<impl Iterator<Item = &PeerId> + '_> {
-        self.topics.get(topic).map(|peers| peers.iter())
-    }
-
-    pub fn topics(&self, peer: &PeerId) -> Option<impl Iterator<Item = &Topic> + '_> {
-        self.peers.get(peer).map(|topics| topics.iter())
-    }
-
-    pub fn subscribe(&mut self, topic: Topic) {
-        self.subscriptions.insert(topic);
-        let msg = Message::Subscribe(topic);
-        for peer in self.peers.keys() {
-            self.events.push_back(ToSwarm::NotifyHandler {
-                peer_id: *peer,
-                event: msg.clone(),
-                handler: NotifyHandler::Any,
-            });
-        }
-
-        if let Some(metrics) = &mut self.metrics {
-            metrics.subscribe(&topic);
-        }
-    }
-
-    pub fn unsubscribe(&mut self, topic: &Topic) {
-        self.subscriptions.remove(topic);
-        let msg = Message::Unsubscribe(*topic);
-        if let Some(peers) = self.topics.get(topic) {
-            for peer in peers {
-                self.events.push_back(ToSwarm::NotifyHandler {
-                    peer_id: *peer,
-                    event: msg.clone(),
-                    handler: NotifyHandler::Any,
-                });
-            }
-        }
-
-        if let Some(metrics) = &mut self.metrics {
-            metrics.unsubscribe(topic);
-        }
-    }
-
-    pub fn broadcast(&mut self, topic: &Topic, msg: Bytes) {
-        let msg = Message::Broadcast(*topic, msg);
-        if let Some(peers) = self.topics.get(topic) {
-            for peer in peers {
-                self.events.push_back(ToSwarm::NotifyHandler {
-                    peer_id: *peer,
-                    event: msg.clone(),
-                    handler: NotifyHandler::Any,
-                });
-            }
-        }
-
-        if let Some(metrics) = &mut self.metrics {
-            metrics.msg_sent(topic, msg.len());
-            metrics.register_published_message(topic);
-        }
-    }
-
-    fn inject_connected(&mut self, peer: &PeerId) {
-        self.peers.insert(*peer, FnvHashSet::default());
-        for topic in &self.subscriptions {
-            self.events.push_back(ToSwarm::NotifyHandler {
-                peer_id: *peer,
-                event: Message::Subscribe(*topic),
-                handler: NotifyHandler::Any,
-            });
-        }
-    }
-
-    fn inject_disconnected(&mut self, peer: &PeerId) {
-        if let Some(topics) = self.peers.remove(peer) {
-            for topic in topics {
-                if let Some(peers) = self.topics.get_mut(&topic) {
-                    peers.remove(peer);
-                }
-            }
-        }
-    }
-}
-
-impl NetworkBehaviour for Behaviour {
-    type ConnectionHandler = Handler;
-    type ToSwarm = Event;
-
-    fn handle_established_inbound_connection(
-        &mut self,
-        _connection_id: ConnectionId,
-        _peer: PeerId,
-        _local_addr: &Multiaddr,
-        _remote_addr: &Multiaddr,
-    ) -> Result<THandler<Self>, ConnectionDenied> {
-        Ok(Handler::new(self.config.clone()))
-    }
-
-    fn handle_established_outbound_connection(
-        &mut self,
-        _connection_id: ConnectionId,
-        _peer: PeerId,
-        _addr: &Multiaddr,
-        _role_override: Endpoint,
-        _port_use: PortUse,
-    ) -> Result<THandler<Self>, ConnectionDenied> {
-        Ok(Handler::new(self.config.clone()))
-    }
-
-    fn on_swarm_event(&mut self, event: FromSwarm<'_>) {
-        match event {
-            FromSwarm::ConnectionEstablished(c) => {
-                if c.other_established == 0 {
-                    self.inject_connected(&c.peer_id);
-                }
-            }
-            FromSwarm::ConnectionClosed(c) => {
-                if c.remaining_established == 0 {
-                    self.inject_disconnected(&c.peer_id);
-                }
-            }
-            _ => {}
-        }
-    }
-
-    fn on_connection_handler_event(
-        &mut self,
-        peer: PeerId,
-        _connection_id: ConnectionId,
-        event: <Self::ConnectionHandler as ConnectionHandler>::ToBehaviour,
-    ) {
-        let ev = match event {
-            Rx(Subscribe(topic)) => {
-                let peers = self.topics.entry(topic).or_default();
-                self.peers.entry(peer).or_default().insert(topic);
-                peers.insert(peer);
-                if let Some(metrics) = self.metrics.as_mut() {
-                    metrics.inc_topic_peers(&topic);
-                }
-                Event::Subscribed(peer, topic)
-            }
-
-            Rx(Broadcast(topic, msg)) => {
-                if let Some(metrics) = self.metrics.as_mut() {
-                    metrics.msg_received(&topic, msg.len());
-                }
-                Event::Received(peer, topic, msg)
-            }
-
-            Rx(Unsubscribe(topic)) => {
-                self.peers.entry(peer).or_default().remove(&topic);
-                if let Some(peers) = self.topics.get_mut(&topic) {
-                    peers.remove(&peer);
-                }
-                if let Some(metrics) = self.metrics.as_mut() {
-                    metrics.dec_topic_peers(&topic);
-                }
-                Event::Unsubscribed(peer, topic)
-            }
-
-            Tx => {
-                return;
-            }
-        };
-        self.events.push_back(ToSwarm::GenerateEvent(ev));
-    }
-
-    fn poll(&mut self, _: &mut Context) -> Poll<ToSwarm<Event, Message>> {
-        if let Some(event) = self.events.pop_front() {
-            Poll::Ready(event)
-        } else {
-            Poll::Pending
-        }
-    }
-}
-
-/// Transmission between the `OneShotHandler` and the `BroadcastHandler`.
-#[derive(Debug)]
-pub enum HandlerEvent {
-    /// We received a `Message` from a remote.
-    Rx(Message),
-    /// We successfully sent a `Message`.
-    Tx,
-}
-
-impl From<Message> for HandlerEvent {
-    fn from(message: Message) -> Self {
-        Self::Rx(message)
-    }
-}
-
-impl From<()> for HandlerEvent {
-    fn from(_: ()) -> Self {
-        Self::Tx
-    }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    use std::sync::{Arc, Mutex};
-
-    struct DummySwarm {
-        peer_id: PeerId,
-        behaviour: Arc<Mutex<Behaviour>>,
-        connections: FnvHashMap<PeerId, Arc<Mutex<Behaviour>>>,
-    }
-
-    impl DummySwarm {
-        fn new() -> Self {
-            Self {
-                peer_id: PeerId::random(),
-                behaviour: Default::default(),
-                connections: Default::default(),
-            }
-        }
-
-        fn peer_id(&self) -> &PeerId {
-            &self.peer_id
-        }
-
-        fn dial(&mut self, other: &mut DummySwarm) {
-            self.behaviour
-                .lock()
-                .unwrap()
-                .inject_connected(other.peer_id());
-            self.connections
-                .insert(*other.peer_id(), other.behaviour.clone());
-            other
-                .behaviour
-                .lock()
-                .unwrap()
-                .inject_connected(self.peer_id());
-            other
-                .connections
-                .insert(*self.peer_id(), self.behaviour.clone());
-        }
-
-        fn next(&self) -> Option<Event> {
-            let waker = futures::task::noop_waker();
-            let mut ctx = Context::from_waker(&waker);
-            let mut me = self.behaviour.lock().unwrap();
-            loop {
-                match me.poll(&mut ctx) {
-                    Poll::Ready(ToSwarm::NotifyHandler { peer_id, event, .. }) => {
-                        if let Some(other) = self.connections.get(&peer_id) {
-                            let mut other = other.lock().unwrap();
-                            other.on_connection_handler_event(
-                                *self.peer_id(),
-                                ConnectionId::new_unchecked(0),
-                                Rx(event),
-                            );
-                        }
-                    }
-                    Poll::Ready(ToSwarm::GenerateEvent(event)) => {
-                        return Some(event);
-                    }
-                    Poll::Ready(_) => panic!(),
-                    Poll::Pending => {
-                        return None;
-                    }
-                }
-            }
-        }
-
-        fn subscribe(&self, topic: Topic) {
-            let mut me = self.behaviour.lock().unwrap();
-            me.subscribe(topic);
-        }
-
-        fn unsubscribe(&self, topic: &Topic) {
-            let mut me = self.behaviour.lock().unwrap();
-            me.unsubscribe(topic);
-        }
-
-        fn broadcast(&self, topic: &Topic, msg: Bytes) {
-            let mut me = self.behaviour.lock().unwrap();
-            me.broadcast(topic, msg);
-        }
-    }
-
-    #[test]
-    fn test_broadcast() {
-        let topic = Topic::new(b"topic");
-        let msg = Bytes::from_static(b"msg");
-        let mut a = DummySwarm::new();
-        let mut b = DummySwarm::new();
-
-        a.subscribe(topic);
-        a.dial(&mut b);
-        assert!(a.next().is_none());
-        assert_eq!(b.next().unwrap(), Event::Subscribed(*a.peer_id(), topic));
-        b.subscribe(topic);
-        assert!(b.next().is_none());
-        assert_eq!(a.next().unwrap(), Event::Subscribed(*b.peer_id(), topic));
-        b.broadcast(&topic, msg.clone());
-        assert!(b.next().is_none());
-        assert_eq!(a.next().unwrap(), Event::Received(*b.peer_id(), topic, msg));
-        a.unsubscribe(&topic);
-        assert!(a.next().is_none());
-        assert_eq!(b.next().unwrap(), Event::Unsubscribed(*a.peer_id(), topic));
-    }
+#[cfg(feature = "behaviour")]
+pub use metrics_sink::MetricsSink;
+#[cfg(feature = "mqtt")]
+pub use mqtt::{BridgeDirection, MqttBridge, MqttPublisher};
+#[cfg(feature = "sled")]
+pub use outbox::SledOutbox;
+#[cfg(feature = "behaviour")]
+pub use outbox::{MemoryOutbox, OutboxEntry, OutboxStore};
+#[cfg(feature = "behaviour")]
+pub use pipeline::{PayloadTransform, Pipeline, Pipelines};
+#[cfg(feature = "behaviour")]
+pub use protocol::Version;
+#[cfg(feature = "behaviour")]
+pub use queue::{Priority, QueueStats};
+#[cfg(feature = "behaviour")]
+pub use report::{BroadcastReport, PeerOutcome, SkipReason};
+#[cfg(feature = "behaviour")]
+pub use reputation::PeerReputations;
+#[cfg(feature = "behaviour")]
+pub use scheduler::{Schedule, SendScheduler};
+#[cfg(feature = "behaviour")]
+pub use seen::{DuplicateStore, SeenCache};
+#[cfg(feature = "sled")]
+pub use seen_store::SledSeenStore;
+#[cfg(feature = "behaviour")]
+pub use seen_store::{MemorySeenStore, SeenStore};
+#[cfg(feature = "behaviour")]
+pub use self_test::SelfTestResult;
+#[cfg(feature = "behaviour")]
+pub use snapshot::BehaviourSnapshot;
+#[cfg(feature = "sled")]
+pub use subscription_store::SledSubscriptionStore;
+#[cfg(feature = "behaviour")]
+pub use subscription_store::{MemorySubscriptionStore, SubscriptionStore};
+#[cfg(feature = "behaviour")]
+pub use substream::{Direction, SubstreamEvent};
+#[cfg(feature = "async-std")]
+pub use timer::AsyncStdTimer;
+#[cfg(feature = "tokio")]
+pub use timer::TokioTimer;
+#[cfg(feature = "behaviour")]
+pub use timer::{FuturesTimer, Sleep, Timer};
+#[cfg(feature = "behaviour")]
+pub use topic_stats::TopicStats;
+pub use types::{Capabilities, Chunk, MessageId, Metadata, Payload, Topic, TraceContext};
+#[cfg(feature = "test-vectors")]
+pub use vectors::{test_vectors, TestVector};
+
+/// Frames of the wire format and the codec reading and writing them, for tools that speak the
+/// protocol without running the behaviour, e.g. fuzzers or bridges. Build with
+/// `default-features = false` to leave out the libp2p dependencies.
+#[cfg(feature = "wire-only")]
+pub mod wire {
+    pub use crate::codec::{Frame, FrameTooLarge, LengthPrefixedCodec};
+    pub use crate::protocol::Version;
+    pub use crate::types::{Handshake, Message, Shard};
 }
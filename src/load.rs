@@ -0,0 +1,405 @@
+use std::task::{Context, Poll};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use fnv::FnvHashMap;
+use libp2p::core::transport::PortUse;
+use libp2p::core::{ConnectedPoint, Endpoint};
+use libp2p::swarm::behaviour::ConnectionEstablished;
+use libp2p::swarm::{ConnectionHandler, ConnectionId, FromSwarm, NetworkBehaviour, ToSwarm};
+use libp2p::{Multiaddr, PeerId};
+
+use crate::handler::{Handler, HandlerEvent};
+use crate::types::{Message, Topic};
+use crate::{Behaviour, Config, Event};
+
+/// Shape of the load applied by a `LoadGenerator`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LoadProfile {
+    /// Length of the payloads, at least 8 bytes which carry a sequence number.
+    pub message_size: usize,
+    /// Broadcasts published per second.
+    pub rate: u32,
+    /// Number of topics the broadcasts are spread over, round-robin.
+    pub topics: usize,
+    /// Number of peers subscribed to every topic.
+    pub peers: usize,
+    /// How long broadcasts are published for.
+    pub duration: Duration,
+    /// Bytes per second each connection carries, unlimited if `None`. Frames beyond it stay
+    /// queued in the handler.
+    pub link_rate: Option<u64>,
+}
+
+impl Default for LoadProfile {
+    fn default() -> Self {
+        Self {
+            message_size: 256,
+            rate: 1000,
+            topics: 1,
+            peers: 4,
+            duration: Duration::from_secs(1),
+            link_rate: None,
+        }
+    }
+}
+
+impl LoadProfile {
+    pub fn with_message_size(mut self, message_size: usize) -> Self {
+        assert!(message_size >= 8, "messages must be at least 8 bytes long");
+        self.message_size = message_size;
+        self
+    }
+
+    pub fn with_rate(mut self, rate: u32) -> Self {
+        assert!(rate > 0, "rate must be positive");
+        self.rate = rate;
+        self
+    }
+
+    pub fn with_topics(mut self, topics: usize) -> Self {
+        assert!(topics > 0, "at least one topic is needed");
+        self.topics = topics;
+        self
+    }
+
+    pub fn with_peers(mut self, peers: usize) -> Self {
+        assert!(peers > 0, "at least one peer is needed");
+        self.peers = peers;
+        self
+    }
+
+    pub fn with_duration(mut self, duration: Duration) -> Self {
+        self.duration = duration;
+        self
+    }
+
+    pub fn with_link_rate(mut self, bytes_per_sec: u64) -> Self {
+        assert!(bytes_per_sec > 0, "link rate must be positive");
+        self.link_rate = Some(bytes_per_sec);
+        self
+    }
+}
+
+/// Outcome of a load run.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LoadReport {
+    /// Broadcasts published.
+    pub published: u64,
+    /// Broadcasts delivered, counting each subscriber that received one.
+    pub delivered: u64,
+    /// Frames sent over all connections.
+    pub frames: u64,
+    /// Bytes of the frames sent over all connections, without their length prefix.
+    pub bytes: u64,
+    /// Largest number of messages queued in a single handler.
+    pub max_queue_len: usize,
+    /// Mean and largest time between publishing a broadcast and its delivery.
+    pub mean_latency: Duration,
+    pub max_latency: Duration,
+    /// Time from the first broadcast until the last queue drained, or the drain gave up.
+    pub elapsed: Duration,
+}
+
+impl LoadReport {
+    /// Deliveries per second.
+    pub fn throughput(&self) -> f64 {
+        self.delivered as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+/// Publishes broadcasts at a steady rate from one node to peers subscribed to every topic, and
+/// reports how they were delivered. For integration tests and capacity planning, not meant for
+/// production use.
+///
+/// The nodes run in process, each connection driven by a real handler: broadcasts go through
+/// its queue, batching and frame size limits, are encoded and decoded again, and are handed to
+/// the receiving behaviour. Only the substreams are left out. Runs on the calling thread and
+/// paces itself with the wall clock.
+#[derive(Debug)]
+pub struct LoadGenerator {
+    profile: LoadProfile,
+    config: Config,
+}
+
+impl LoadGenerator {
+    /// Interval at which broadcasts are published and frames exchanged.
+    const TICK: Duration = Duration::from_millis(1);
+
+    pub fn new(profile: LoadProfile) -> Self {
+        Self {
+            profile,
+            config: Config::default(),
+        }
+    }
+
+    /// Configuration of the behaviours of every node.
+    pub fn with_config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn run(&self) -> LoadReport {
+        let profile = &self.profile;
+        let topics: Vec<Topic> = (0..profile.topics)
+            .map(|i| Topic::new(format!("load-{i}").as_bytes()))
+            .collect();
+        let mut network = Network::default();
+        for _ in 0..=profile.peers {
+            let mut node = Node::new(self.config.clone());
+            node.behaviour.subscribe_many(topics.iter().copied());
+            network.nodes.push(node);
+        }
+        for peer in 1..network.nodes.len() {
+            network.connect(0, peer);
+        }
+        // Let the subscriptions settle before publishing.
+        while network.step(None) {}
+
+        let total = u64::from(profile.rate) * profile.duration.as_millis() as u64 / 1000;
+        let started = Instant::now();
+        let mut last_tick = started;
+        let mut published_at = Vec::with_capacity(total as usize);
+        let mut drained = false;
+        while !drained && started.elapsed() < profile.duration * 2 {
+            let now = Instant::now();
+            let due = (now.duration_since(started).as_secs_f64() * f64::from(profile.rate)) as u64;
+            while (published_at.len() as u64) < due.min(total) {
+                let seq = published_at.len() as u64;
+                let mut payload = vec![0; profile.message_size];
+                payload[..8].copy_from_slice(&seq.to_be_bytes());
+                let topic = &topics[seq as usize % topics.len()];
+                network.nodes[0]
+                    .behaviour
+                    .broadcast(topic, Bytes::from(payload));
+                published_at.push(Instant::now());
+            }
+            let budget = profile
+                .link_rate
+                .map(|rate| rate as f64 * now.duration_since(last_tick).as_secs_f64());
+            last_tick = now;
+            while network.step(budget) {}
+            network.record_latencies(&published_at);
+            drained = published_at.len() as u64 == total && network.is_drained();
+            if !drained {
+                thread::sleep(Self::TICK);
+            }
+        }
+
+        let report = &mut network.report;
+        report.published = published_at.len() as u64;
+        report.elapsed = started.elapsed();
+        if report.delivered > 0 {
+            report.mean_latency /= report.delivered as u32;
+        }
+        network.report
+    }
+}
+
+/// Sending side of a connection.
+struct Link {
+    connection_id: ConnectionId,
+    handler: Handler,
+    /// Bytes the link may still carry, negative once a frame overshot it.
+    budget: f64,
+}
+
+struct Node {
+    peer_id: PeerId,
+    behaviour: Behaviour,
+    links: FnvHashMap<PeerId, Link>,
+}
+
+impl Node {
+    fn new(config: Config) -> Self {
+        Self {
+            peer_id: PeerId::random(),
+            behaviour: Behaviour::new(config),
+            links: Default::default(),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Network {
+    nodes: Vec<Node>,
+    index: FnvHashMap<PeerId, usize>,
+    next_connection_id: usize,
+    /// Sequence numbers of the broadcasts delivered since the latencies were last recorded,
+    /// along with the delivery time.
+    deliveries: Vec<(u64, Instant)>,
+    report: LoadReport,
+}
+
+impl Network {
+    fn connect(&mut self, dialer: usize, listener: usize) {
+        let address: Multiaddr = format!("/memory/{listener}").parse().unwrap();
+        let (dialer_id, listener_id) = (self.nodes[dialer].peer_id, self.nodes[listener].peer_id);
+        self.index.insert(dialer_id, dialer);
+        self.index.insert(listener_id, listener);
+        let dialer_endpoint = ConnectedPoint::Dialer {
+            address: address.clone(),
+            role_override: Endpoint::Dialer,
+            port_use: PortUse::New,
+        };
+        let listener_endpoint = ConnectedPoint::Listener {
+            local_addr: address.clone(),
+            send_back_addr: format!("/memory/{dialer}").parse().unwrap(),
+        };
+        for (node, remote, endpoint) in [
+            (dialer, listener_id, dialer_endpoint),
+            (listener, dialer_id, listener_endpoint),
+        ] {
+            let connection_id = ConnectionId::new_unchecked(self.next_connection_id);
+            self.next_connection_id += 1;
+            let behaviour = &mut self.nodes[node].behaviour;
+            let handler = match &endpoint {
+                ConnectedPoint::Dialer { address, .. } => behaviour
+                    .handle_established_outbound_connection(
+                        connection_id,
+                        remote,
+                        address,
+                        Endpoint::Dialer,
+                        PortUse::New,
+                    ),
+                ConnectedPoint::Listener {
+                    local_addr,
+                    send_back_addr,
+                } => behaviour.handle_established_inbound_connection(
+                    connection_id,
+                    remote,
+                    local_addr,
+                    send_back_addr,
+                ),
+            }
+            .expect("the behaviour does not deny connections");
+            behaviour.on_swarm_event(FromSwarm::ConnectionEstablished(ConnectionEstablished {
+                peer_id: remote,
+                connection_id,
+                endpoint: &endpoint,
+                failed_addresses: &[],
+                other_established: 0,
+            }));
+            self.nodes[node].links.insert(
+                remote,
+                Link {
+                    connection_id,
+                    handler,
+                    budget: 0.0,
+                },
+            );
+        }
+    }
+
+    /// Polls the behaviours, then moves the frames the links may carry, adding `budget` bytes to
+    /// each link first if they are limited. Returns whether anything happened.
+    fn step(&mut self, budget: Option<f64>) -> bool {
+        let waker = futures::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut active = false;
+        for node in &mut self.nodes {
+            while let Poll::Ready(event) = node.behaviour.poll(&mut cx) {
+                active = true;
+                match event {
+                    ToSwarm::NotifyHandler { peer_id, event, .. } => {
+                        if let Some(link) = node.links.get_mut(&peer_id) {
+                            link.handler.on_behaviour_event(event);
+                        }
+                    }
+                    ToSwarm::GenerateEvent(Event::Received(_, _, payload, _)) => {
+                        let mut seq = [0; 8];
+                        seq.copy_from_slice(&payload[..8]);
+                        self.deliveries
+                            .push((u64::from_be_bytes(seq), Instant::now()));
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        let mut frames = Vec::new();
+        for node in &mut self.nodes {
+            for (remote, link) in &mut node.links {
+                self.report.max_queue_len = self.report.max_queue_len.max(link.handler.queue_len());
+                if let Some(budget) = budget {
+                    // Unused bandwidth does not accumulate beyond one tick.
+                    link.budget = (link.budget + budget).min(budget);
+                }
+                while budget.is_none() || link.budget > 0.0 {
+                    let Some(message) = link.handler.next_message() else {
+                        break;
+                    };
+                    let frame = message.to_bytes();
+                    link.budget -= frame.len() as f64;
+                    self.report.frames += 1;
+                    self.report.bytes += frame.len() as u64;
+                    frames.push((node.peer_id, link.connection_id, *remote, frame));
+                }
+            }
+        }
+        for (sender, connection_id, remote, frame) in frames {
+            active = true;
+            self.nodes[self.index[&sender]]
+                .behaviour
+                .on_connection_handler_event(remote, connection_id, HandlerEvent::Tx);
+            let message = Message::from_bytes(frame.into()).expect("frames decode");
+            let receiver = &mut self.nodes[self.index[&remote]];
+            let connection_id = receiver.links[&sender].connection_id;
+            receiver.behaviour.on_connection_handler_event(
+                sender,
+                connection_id,
+                HandlerEvent::Rx(message),
+            );
+        }
+        active
+    }
+
+    fn record_latencies(&mut self, published_at: &[Instant]) {
+        for (seq, delivered_at) in self.deliveries.drain(..) {
+            let latency = delivered_at.duration_since(published_at[seq as usize]);
+            self.report.delivered += 1;
+            self.report.mean_latency += latency;
+            self.report.max_latency = self.report.max_latency.max(latency);
+        }
+    }
+
+    fn is_drained(&self) -> bool {
+        self.nodes
+            .iter()
+            .flat_map(|node| node.links.values())
+            .all(|link| link.handler.queue_len() == 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load() {
+        let profile = LoadProfile::default()
+            .with_rate(2000)
+            .with_topics(3)
+            .with_peers(3)
+            .with_duration(Duration::from_millis(50));
+        let report = LoadGenerator::new(profile).run();
+        assert_eq!(report.published, 100);
+        assert_eq!(report.delivered, 300);
+        assert!(report.bytes >= 300 * 256);
+        assert!(report.max_latency >= report.mean_latency);
+    }
+
+    #[test]
+    fn test_link_rate() {
+        // The link carries about 10 messages per tick, while 40 are published per tick.
+        let profile = LoadProfile::default()
+            .with_message_size(1000)
+            .with_rate(40_000)
+            .with_peers(1)
+            .with_duration(Duration::from_millis(20))
+            .with_link_rate(10_000_000);
+        let report = LoadGenerator::new(profile).run();
+        assert!(report.max_queue_len > 1);
+    }
+}
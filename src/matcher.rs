@@ -0,0 +1,91 @@
+use crate::types::Topic;
+
+/// Decides which subscriptions a broadcast is routed to, set with `Config::with_topic_matcher`.
+///
+/// Both sides should use the same matcher: the publisher routes a broadcast to the peers
+/// subscribed to a matching topic, and a subscriber forwards the broadcasts that match one of
+/// its subscriptions.
+pub trait TopicMatcher: Send + Sync {
+    /// Returns `true` if a peer subscribed to `subscription` receives the broadcasts on `topic`.
+    fn matches(&self, subscription: &Topic, topic: &Topic) -> bool;
+
+    /// Returns `true` if the matcher only matches equal topics, which lets the routing table
+    /// look subscriptions up instead of scanning them.
+    fn is_exact(&self) -> bool {
+        false
+    }
+}
+
+/// Matches equal topics only. The default.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ExactMatcher;
+
+impl TopicMatcher for ExactMatcher {
+    fn matches(&self, subscription: &Topic, topic: &Topic) -> bool {
+        subscription == topic
+    }
+
+    fn is_exact(&self) -> bool {
+        true
+    }
+}
+
+/// Matches the topics starting with the subscription, e.g. `blocks/` matches `blocks/1`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PrefixMatcher;
+
+impl TopicMatcher for PrefixMatcher {
+    fn matches(&self, subscription: &Topic, topic: &Topic) -> bool {
+        topic.starts_with(subscription)
+    }
+}
+
+/// Matches `/`-separated topics against patterns, where a `*` segment matches any one segment
+/// and a trailing `#` segment matches any number of remaining segments, like MQTT filters.
+/// For example `shards/*/votes` matches `shards/3/votes` and `shards/#` matches
+/// `shards/3/votes`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PatternMatcher;
+
+impl TopicMatcher for PatternMatcher {
+    fn matches(&self, subscription: &Topic, topic: &Topic) -> bool {
+        let mut patterns = subscription.split(|byte| *byte == b'/');
+        let mut segments = topic.split(|byte| *byte == b'/');
+        loop {
+            match (patterns.next(), segments.next()) {
+                (Some(b"#"), _) => return patterns.next().is_none(),
+                (Some(b"*"), Some(_)) => {}
+                (Some(pattern), Some(segment)) if pattern == segment => {}
+                (None, None) => return true,
+                _ => return false,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(matcher: impl TopicMatcher, subscription: &[u8], topic: &[u8]) -> bool {
+        matcher.matches(&Topic::new(subscription), &Topic::new(topic))
+    }
+
+    #[test]
+    fn test_matchers() {
+        assert!(matches(ExactMatcher, b"a/b", b"a/b"));
+        assert!(!matches(ExactMatcher, b"a", b"a/b"));
+
+        assert!(matches(PrefixMatcher, b"a/", b"a/b"));
+        assert!(!matches(PrefixMatcher, b"a/b", b"a/"));
+
+        assert!(matches(PatternMatcher, b"a/*/c", b"a/b/c"));
+        assert!(!matches(PatternMatcher, b"a/*/c", b"a/b/d"));
+        assert!(!matches(PatternMatcher, b"a/*", b"a/b/c"));
+        assert!(matches(PatternMatcher, b"a/#", b"a/b/c"));
+        assert!(matches(PatternMatcher, b"a/#", b"a"));
+        assert!(!matches(PatternMatcher, b"a/#/c", b"a/b/c"));
+        assert!(matches(PatternMatcher, b"a/b", b"a/b"));
+        assert!(!matches(PatternMatcher, b"a/b", b"a/b/c"));
+    }
+}
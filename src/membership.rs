@@ -0,0 +1,20 @@
+use libp2p::PeerId;
+
+/// Decides which peers take part in broadcasting, e.g. the validators of the current epoch.
+///
+/// The behaviour consults it every time it routes a message to, or accepts a message from, a
+/// peer, so changes to the membership take effect immediately without reconnecting anyone.
+/// Subscriptions of non-members are still tracked, so a peer joining the set is routed to as soon
+/// as it becomes a member.
+pub trait Membership: Send {
+    fn is_member(&self, peer: &PeerId) -> bool;
+}
+
+impl<F> Membership for F
+where
+    F: Fn(&PeerId) -> bool + Send,
+{
+    fn is_member(&self, peer: &PeerId) -> bool {
+        self(peer)
+    }
+}
@@ -1,15 +1,26 @@
 use std::collections::HashMap;
 
 use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::exemplar::HistogramWithExemplars;
 use prometheus_client::metrics::family::Family;
 use prometheus_client::metrics::gauge::Gauge;
+use prometheus_client::metrics::histogram::{exponential_buckets, Histogram};
 use prometheus_client::registry::Registry;
 
+use crate::metrics_sink::MetricsSink;
+use crate::types::TraceContext;
 use crate::Topic;
 
+/// Labels of the exemplars linking an observation to the trace it was made in.
+type TraceLabels = [(&'static str, String); 2];
+
+type DelayHistogram = HistogramWithExemplars<TraceLabels>;
+
 pub struct Metrics {
     /// Information needed to decide if a topic is allowed or not.
     topic_info: HashMap<Topic, EverSubscribed>,
+    /// Number of topics labelled with their own name, see `Metrics::with_max_topic_labels`.
+    max_topic_labels: Option<usize>,
     /// Status of our subscription to this topic. This metric allows analyzing other topic metrics
     /// filtered by our current subscription status.
     topic_subscription_status: Family<Topic, Gauge>,
@@ -28,6 +39,29 @@ pub struct Metrics {
     topic_msg_recv_counts: Family<Topic, Counter>,
     /// Bytes received from messages for each topic.
     topic_msg_recv_bytes: Family<Topic, Counter>,
+
+    /// Number of queued messages discarded because they expired before being sent.
+    msg_expired: Counter,
+    /// Number of times a peer exceeded its bandwidth cap and was throttled.
+    peer_bandwidth_throttled: Counter,
+    /// Number of times a peer exceeded its bandwidth cap and was disconnected.
+    peer_bandwidth_disconnected: Counter,
+    /// Bytes received over the bandwidth cap of their peer and dropped.
+    peer_bandwidth_dropped_bytes: Counter,
+    /// Number of times a peer connected again shortly after disconnecting.
+    peer_reconnects: Counter,
+    /// Number of substreams reopened after the previous one closed or failed.
+    substream_restarts: Counter,
+    /// Number of probes of the self-test that passed and failed.
+    self_test_passed: Counter,
+    self_test_failed: Counter,
+    /// Smoothed flush latency reported by the connections.
+    flush_latency: Histogram,
+    /// Time each message waited between being queued and being flushed.
+    queue_delay: Histogram,
+    /// Time timestamped broadcasts took from being published to being delivered on each topic,
+    /// with the trace of the slowest ones as exemplars.
+    delivery_delay: Family<Topic, DelayHistogram, fn() -> DelayHistogram>,
 }
 
 type EverSubscribed = bool;
@@ -74,8 +108,84 @@ impl Metrics {
             "Bytes received from gossip messages for each topic"
         );
 
+        let msg_expired = Counter::default();
+        registry.register(
+            "msg_expired",
+            "Number of queued messages discarded because they expired before being sent",
+            msg_expired.clone(),
+        );
+
+        let peer_bandwidth_throttled = Counter::default();
+        registry.register(
+            "peer_bandwidth_throttled",
+            "Number of times a peer exceeded its bandwidth cap and was throttled",
+            peer_bandwidth_throttled.clone(),
+        );
+        let peer_bandwidth_disconnected = Counter::default();
+        registry.register(
+            "peer_bandwidth_disconnected",
+            "Number of times a peer exceeded its bandwidth cap and was disconnected",
+            peer_bandwidth_disconnected.clone(),
+        );
+        let peer_bandwidth_dropped_bytes = Counter::default();
+        registry.register(
+            "peer_bandwidth_dropped_bytes",
+            "Bytes received over the bandwidth cap of their peer and dropped",
+            peer_bandwidth_dropped_bytes.clone(),
+        );
+
+        let peer_reconnects = Counter::default();
+        registry.register(
+            "peer_reconnects",
+            "Number of times a peer connected again shortly after disconnecting",
+            peer_reconnects.clone(),
+        );
+        let substream_restarts = Counter::default();
+        registry.register(
+            "substream_restarts",
+            "Number of substreams reopened after the previous one closed or failed",
+            substream_restarts.clone(),
+        );
+
+        let self_test_passed = Counter::default();
+        registry.register(
+            "self_test_passed",
+            "Number of self-test probes echoed by a peer within their deadline",
+            self_test_passed.clone(),
+        );
+        let self_test_failed = Counter::default();
+        registry.register(
+            "self_test_failed",
+            "Number of self-test probes not echoed by any peer within their deadline",
+            self_test_failed.clone(),
+        );
+
+        let flush_latency = Histogram::new(exponential_buckets(0.0005, 2.0, 14));
+        registry.register(
+            "flush_latency_seconds",
+            "Smoothed time it takes connections to flush a message",
+            flush_latency.clone(),
+        );
+        let queue_delay = Histogram::new(exponential_buckets(0.0005, 2.0, 14));
+        registry.register(
+            "queue_delay_seconds",
+            "Time messages wait between being queued and being flushed",
+            queue_delay.clone(),
+        );
+
+        let delivery_delay: Family<Topic, DelayHistogram, fn() -> DelayHistogram> =
+            Family::new_with_constructor(|| {
+                HistogramWithExemplars::new(exponential_buckets(0.001, 2.0, 16))
+            });
+        registry.register(
+            "delivery_delay_seconds",
+            "Time broadcasts take from being published to being delivered on each topic",
+            delivery_delay.clone(),
+        );
+
         Self {
             topic_info: HashMap::new(),
+            max_topic_labels: None,
             topic_subscription_status,
             topic_peers_count,
             topic_msg_sent_counts,
@@ -83,60 +193,169 @@ impl Metrics {
             topic_msg_sent_bytes,
             topic_msg_recv_counts,
             topic_msg_recv_bytes,
+            msg_expired,
+            peer_bandwidth_throttled,
+            peer_bandwidth_disconnected,
+            peer_bandwidth_dropped_bytes,
+            peer_reconnects,
+            substream_restarts,
+            self_test_passed,
+            self_test_failed,
+            flush_latency,
+            queue_delay,
+            delivery_delay,
         }
     }
 
-    /// Registers a topic if not already known
-    fn register_topic(&mut self, topic: &Topic) {
-        if !self.topic_info.contains_key(topic) {
-            self.topic_info.entry(*topic).or_insert(false);
-            self.topic_subscription_status.get_or_create(topic).set(0);
-        }
+    /// Labels the metrics of the first `max` topics seen with their name, and counts the
+    /// metrics of the others under the `other` topic, so that applications using a topic per
+    /// entity do not blow up the number of series.
+    pub fn with_max_topic_labels(mut self, max: usize) -> Self {
+        self.max_topic_labels = Some(max);
+        self
     }
 
-    pub(crate) fn subscribe(&mut self, topic: &Topic) {
-        self.register_topic(topic);
-        self.topic_info.entry(*topic).or_insert(false);
-        self.topic_subscription_status.get_or_create(topic).set(1);
+    /// Registers a topic if not already known, returning the label to record its metrics under.
+    fn register_topic(&mut self, topic: &Topic) -> Topic {
+        if self.topic_info.contains_key(topic) {
+            return *topic;
+        }
+        let topic = if self
+            .max_topic_labels
+            .is_none_or(|max| self.topic_info.len() < max)
+        {
+            *topic
+        } else {
+            Topic::new(OTHER_TOPIC)
+        };
+        if !self.topic_info.contains_key(&topic) {
+            self.topic_info.entry(topic).or_insert(false);
+            self.topic_subscription_status.get_or_create(&topic).set(0);
+        }
+        topic
     }
+}
+
+/// Label of the topics beyond `Metrics::with_max_topic_labels`.
+const OTHER_TOPIC: &[u8] = b"other";
 
-    pub(crate) fn unsubscribe(&mut self, topic: &Topic) {
-        self.register_topic(topic);
-        self.topic_subscription_status.get_or_create(topic).set(0);
+impl MetricsSink for Metrics {
+    fn counter(&mut self, name: &'static str, topic: Option<&Topic>, increment: u64) {
+        let counter = match name {
+            "msg_expired" => Some(&self.msg_expired),
+            "peer_bandwidth_throttled" => Some(&self.peer_bandwidth_throttled),
+            "peer_bandwidth_disconnected" => Some(&self.peer_bandwidth_disconnected),
+            "peer_bandwidth_dropped_bytes" => Some(&self.peer_bandwidth_dropped_bytes),
+            "peer_reconnects" => Some(&self.peer_reconnects),
+            "substream_restarts" => Some(&self.substream_restarts),
+            "self_test_passed" => Some(&self.self_test_passed),
+            "self_test_failed" => Some(&self.self_test_failed),
+            _ => None,
+        };
+        if let Some(counter) = counter {
+            counter.inc_by(increment);
+            return;
+        }
+        let Some(topic) = topic else {
+            return;
+        };
+        let topic = self.register_topic(topic);
+        let family = match name {
+            "topic_msg_sent_counts" => &self.topic_msg_sent_counts,
+            "topic_msg_sent_bytes" => &self.topic_msg_sent_bytes,
+            "topic_msg_published" => &self.topic_msg_published,
+            "topic_msg_recv_counts" => &self.topic_msg_recv_counts,
+            "topic_msg_recv_bytes" => &self.topic_msg_recv_bytes,
+            _ => return,
+        };
+        family.get_or_create(&topic).inc_by(increment);
     }
 
-    /// Increase the number of peers that are subscribed to this topic.
-    pub(crate) fn inc_topic_peers(&mut self, topic: &Topic) {
-        self.register_topic(topic);
-        self.topic_peers_count.get_or_create(topic).inc();
+    fn gauge(&mut self, name: &'static str, topic: Option<&Topic>, delta: i64) {
+        let Some(topic) = topic else {
+            return;
+        };
+        let topic = self.register_topic(topic);
+        let family = match name {
+            "topic_subscription_status" => &self.topic_subscription_status,
+            "topic_peers_counts" => &self.topic_peers_count,
+            _ => return,
+        };
+        family.get_or_create(&topic).inc_by(delta);
     }
 
-    /// Decrease the number of peers that are subscribed to this topic.
-    pub(crate) fn dec_topic_peers(&mut self, topic: &Topic) {
-        self.register_topic(topic);
-        self.topic_peers_count.get_or_create(topic).dec();
+    fn histogram(&mut self, name: &'static str, topic: Option<&Topic>, value: f64) {
+        match (name, topic) {
+            ("flush_latency_seconds", _) => self.flush_latency.observe(value),
+            ("queue_delay_seconds", _) => self.queue_delay.observe(value),
+            ("delivery_delay_seconds", Some(topic)) => {
+                let topic = self.register_topic(topic);
+                self.delivery_delay
+                    .get_or_create(&topic)
+                    .observe(value, None);
+            }
+            _ => {}
+        }
     }
 
-    pub(crate) fn register_published_message(&mut self, topic: &Topic) {
-        self.register_topic(topic);
-        self.topic_msg_published.get_or_create(topic).inc();
+    fn histogram_with_trace(
+        &mut self,
+        name: &'static str,
+        topic: Option<&Topic>,
+        value: f64,
+        trace: &TraceContext,
+    ) {
+        let (Some(topic), "delivery_delay_seconds") = (topic, name) else {
+            return self.histogram(name, topic, value);
+        };
+        let topic = self.register_topic(topic);
+        let labels = [
+            ("trace_id", trace.trace_id_hex()),
+            ("span_id", trace.span_id_hex()),
+        ];
+        self.delivery_delay
+            .get_or_create(&topic)
+            .observe(value, Some(labels));
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    /// Register sending a message over a topic.
-    pub(crate) fn msg_sent(&mut self, topic: &Topic, bytes: usize) {
-        self.register_topic(topic);
-        self.topic_msg_sent_counts.get_or_create(topic).inc();
-        self.topic_msg_sent_bytes
-            .get_or_create(topic)
-            .inc_by(bytes as u64);
+    use prometheus_client::encoding::text::encode;
+
+    #[test]
+    fn test_max_topic_labels() {
+        let mut registry = Registry::default();
+        let mut metrics = Metrics::new(&mut registry).with_max_topic_labels(2);
+        let sink: &mut dyn MetricsSink = &mut metrics;
+        for topic in [&b"a"[..], b"b", b"c", b"d", b"a"] {
+            sink.msg_received(&Topic::new(topic), 1);
+        }
+
+        let mut text = String::new();
+        encode(&mut text, &registry).unwrap();
+        assert!(text.contains(r#"topic_msg_recv_counts_total{topic="a"} 2"#));
+        assert!(text.contains(r#"topic_msg_recv_counts_total{topic="other"} 2"#));
+        assert!(!text.contains(r#"topic="c""#));
     }
 
-    /// Register that a message was received .
-    pub(crate) fn msg_received(&mut self, topic: &Topic, bytes: usize) {
-        self.register_topic(topic);
-        self.topic_msg_recv_counts.get_or_create(topic).inc();
-        self.topic_msg_recv_bytes
-            .get_or_create(topic)
-            .inc_by(bytes as u64);
+    #[test]
+    fn test_trace_exemplars() {
+        let mut registry = Registry::default();
+        let mut metrics = Metrics::new(&mut registry);
+        let sink: &mut dyn MetricsSink = &mut metrics;
+        let (topic, trace) = (Topic::new(b"a"), TraceContext::new(0xabc, 0x12));
+        sink.delivery_delay(&topic, 1500, Some(&trace));
+        sink.delivery_delay(&topic, -20, None);
+
+        let mut text = String::new();
+        encode(&mut text, &registry).unwrap();
+        assert!(text.contains(r#"delivery_delay_seconds_count{topic="a"} 2"#));
+        assert!(text.contains(concat!(
+            r#"# {trace_id="00000000000000000000000000000abc","#,
+            r#"span_id="0000000000000012"} 1.5"#
+        )));
     }
 }
@@ -0,0 +1,191 @@
+use std::time::Duration;
+
+use crate::config::CapAction;
+use crate::types::{Topic, TraceContext};
+
+/// Records the metrics of the behaviour, installed with `Behaviour::set_metrics_sink`, e.g. to
+/// forward them to statsd or the `metrics` crate. The Prometheus `Metrics` are one such sink.
+///
+/// Metrics are identified by name, and labelled with a topic where noted:
+///
+/// - Counters: `topic_msg_sent_counts`, `topic_msg_sent_bytes`, `topic_msg_published`,
+///   `topic_msg_recv_counts`, `topic_msg_recv_bytes` by topic, `msg_expired`,
+///   `peer_bandwidth_throttled`, `peer_bandwidth_disconnected` and `peer_bandwidth_dropped_bytes`
+///   for `Config::bandwidth_cap`, `peer_reconnects` and `substream_restarts` for the churn of
+///   `Behaviour::churn`, and `self_test_passed` and `self_test_failed` for the probes of
+///   `Config::self_test`.
+/// - Gauges: `topic_subscription_status` and `topic_peers_counts` by topic.
+/// - Histograms: `flush_latency_seconds`, the smoothed flush latency reported by connections
+///   with `ConnectionPreference::LowestLatency`, and `queue_delay_seconds`, the time each
+///   message waited between being queued and being flushed, and `delivery_delay_seconds` by
+///   topic, the time timestamped broadcasts took from being published to being delivered,
+///   skewed by the offset between the clocks of the peers.
+pub trait MetricsSink: Send {
+    /// Increments the counter.
+    fn counter(&mut self, name: &'static str, topic: Option<&Topic>, increment: u64);
+
+    /// Adds `delta` to the gauge.
+    fn gauge(&mut self, name: &'static str, topic: Option<&Topic>, delta: i64);
+
+    /// Records an observation in the histogram.
+    fn histogram(&mut self, name: &'static str, topic: Option<&Topic>, value: f64);
+
+    /// Records an observation made while delivering a broadcast published in a trace, e.g. to
+    /// attach the trace as an exemplar. Defaults to `MetricsSink::histogram`.
+    fn histogram_with_trace(
+        &mut self,
+        name: &'static str,
+        topic: Option<&Topic>,
+        value: f64,
+        trace: &TraceContext,
+    ) {
+        let _ = trace;
+        self.histogram(name, topic, value);
+    }
+}
+
+impl dyn MetricsSink + '_ {
+    pub(crate) fn subscribe(&mut self, topic: &Topic) {
+        self.gauge("topic_subscription_status", Some(topic), 1);
+    }
+
+    pub(crate) fn unsubscribe(&mut self, topic: &Topic) {
+        self.gauge("topic_subscription_status", Some(topic), -1);
+    }
+
+    /// Increase the number of peers that are subscribed to this topic.
+    pub(crate) fn inc_topic_peers(&mut self, topic: &Topic) {
+        self.gauge("topic_peers_counts", Some(topic), 1);
+    }
+
+    /// Decrease the number of peers that are subscribed to this topic.
+    pub(crate) fn dec_topic_peers(&mut self, topic: &Topic) {
+        self.gauge("topic_peers_counts", Some(topic), -1);
+    }
+
+    pub(crate) fn register_published_message(&mut self, topic: &Topic) {
+        self.counter("topic_msg_published", Some(topic), 1);
+    }
+
+    /// Register sending a message over a topic.
+    pub(crate) fn msg_sent(&mut self, topic: &Topic, bytes: usize) {
+        self.counter("topic_msg_sent_counts", Some(topic), 1);
+        self.counter("topic_msg_sent_bytes", Some(topic), bytes as u64);
+    }
+
+    /// Register that a message was received.
+    pub(crate) fn msg_received(&mut self, topic: &Topic, bytes: usize) {
+        self.counter("topic_msg_recv_counts", Some(topic), 1);
+        self.counter("topic_msg_recv_bytes", Some(topic), bytes as u64);
+    }
+
+    /// Register that queued messages expired before being sent.
+    pub(crate) fn msg_expired(&mut self, count: usize) {
+        self.counter("msg_expired", None, count as u64);
+    }
+
+    /// Register that a peer exceeded `Config::bandwidth_cap`, and what was done about it.
+    pub(crate) fn bandwidth_exceeded(&mut self, action: CapAction) {
+        let name = match action {
+            CapAction::Throttle => "peer_bandwidth_throttled",
+            CapAction::Disconnect => "peer_bandwidth_disconnected",
+        };
+        self.counter(name, None, 1);
+    }
+
+    /// Register that bytes received from a peer over its bandwidth cap were dropped.
+    pub(crate) fn bandwidth_dropped(&mut self, bytes: usize) {
+        self.counter("peer_bandwidth_dropped_bytes", None, bytes as u64);
+    }
+
+    pub(crate) fn flush_latency(&mut self, latency: Duration) {
+        self.histogram("flush_latency_seconds", None, latency.as_secs_f64());
+    }
+
+    pub(crate) fn queue_delay(&mut self, delay: Duration) {
+        self.histogram("queue_delay_seconds", None, delay.as_secs_f64());
+    }
+
+    /// Register the delay of a timestamped broadcast, clamped at zero as skewed clocks can make it
+    /// negative.
+    pub(crate) fn delivery_delay(
+        &mut self,
+        topic: &Topic,
+        skew: i64,
+        trace: Option<&TraceContext>,
+    ) {
+        let delay = skew.max(0) as f64 / 1000.0;
+        match trace {
+            Some(trace) => {
+                self.histogram_with_trace("delivery_delay_seconds", Some(topic), delay, trace)
+            }
+            None => self.histogram("delivery_delay_seconds", Some(topic), delay),
+        }
+    }
+
+    /// Register the outcome of a probe of `Config::self_test`.
+    pub(crate) fn self_test(&mut self, passed: bool) {
+        let name = if passed {
+            "self_test_passed"
+        } else {
+            "self_test_failed"
+        };
+        self.counter(name, None, 1);
+    }
+
+    pub(crate) fn reconnect(&mut self) {
+        self.counter("peer_reconnects", None, 1);
+    }
+
+    pub(crate) fn substream_restart(&mut self) {
+        self.counter("substream_restarts", None, 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashMap;
+
+    #[derive(Default)]
+    struct Recorder(HashMap<(&'static str, Option<Topic>), f64>);
+
+    impl MetricsSink for Recorder {
+        fn counter(&mut self, name: &'static str, topic: Option<&Topic>, increment: u64) {
+            *self.0.entry((name, topic.copied())).or_default() += increment as f64;
+        }
+
+        fn gauge(&mut self, name: &'static str, topic: Option<&Topic>, delta: i64) {
+            *self.0.entry((name, topic.copied())).or_default() += delta as f64;
+        }
+
+        fn histogram(&mut self, name: &'static str, topic: Option<&Topic>, value: f64) {
+            self.0.insert((name, topic.copied()), value);
+        }
+    }
+
+    #[test]
+    fn test_metrics_sink() {
+        let topic = Topic::new(b"topic");
+        let mut recorder = Recorder::default();
+        let sink: &mut dyn MetricsSink = &mut recorder;
+        sink.subscribe(&topic);
+        sink.inc_topic_peers(&topic);
+        sink.inc_topic_peers(&topic);
+        sink.dec_topic_peers(&topic);
+        sink.msg_sent(&topic, 10);
+        sink.msg_sent(&topic, 5);
+        sink.flush_latency(Duration::from_millis(250));
+
+        let get = |name| recorder.0.get(&(name, Some(topic))).copied();
+        assert_eq!(get("topic_subscription_status"), Some(1.0));
+        assert_eq!(get("topic_peers_counts"), Some(1.0));
+        assert_eq!(get("topic_msg_sent_counts"), Some(2.0));
+        assert_eq!(get("topic_msg_sent_bytes"), Some(15.0));
+        assert_eq!(
+            recorder.0.get(&("flush_latency_seconds", None)),
+            Some(&0.25)
+        );
+    }
+}
@@ -0,0 +1,248 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use fnv::FnvHashMap;
+
+use crate::{Behaviour, Event, MessageId, Topic};
+
+/// Client of the MQTT broker the bridge publishes to, implemented on top of the application's
+/// MQTT library. Publishing is expected to queue the message rather than block.
+pub trait MqttPublisher {
+    fn publish(&mut self, topic: &str, payload: Bytes);
+}
+
+/// Which way the messages of a mapped topic are mirrored.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BridgeDirection {
+    /// Broadcasts received from peers are published to the broker.
+    ToMqtt,
+    /// Messages received from the broker are broadcast to peers.
+    FromMqtt,
+    Both,
+}
+
+impl BridgeDirection {
+    fn mirrors_to_mqtt(self) -> bool {
+        self != BridgeDirection::FromMqtt
+    }
+
+    fn mirrors_from_mqtt(self) -> bool {
+        self != BridgeDirection::ToMqtt
+    }
+}
+
+/// Mirrors broadcast topics to and from the topics of an MQTT broker, passing payloads through
+/// unchanged. Metadata and chunked broadcasts are not mirrored.
+///
+/// The bridge does not talk to the broker itself: feed it the events of the behaviour and the
+/// messages received from the broker, and subscribe the MQTT client to `mqtt_topics`. Messages
+/// of topics mirrored both ways are not echoed back to where they came from, as long as the
+/// broker echoes them within `MqttBridge::DEFAULT_ECHO_TTL`, see `MqttBridge::with_echo_ttl`.
+#[derive(Debug)]
+pub struct MqttBridge {
+    to_mqtt: FnvHashMap<Topic, String>,
+    from_mqtt: HashMap<String, Topic>,
+    echo_ttl: Duration,
+    /// Ids of the broadcasts recently published to the broker, expected back from it until the
+    /// given time.
+    echoes: FnvHashMap<MessageId, Instant>,
+    echo_order: VecDeque<(Instant, MessageId)>,
+}
+
+impl Default for MqttBridge {
+    fn default() -> Self {
+        Self {
+            to_mqtt: FnvHashMap::default(),
+            from_mqtt: HashMap::new(),
+            echo_ttl: Self::DEFAULT_ECHO_TTL,
+            echoes: FnvHashMap::default(),
+            echo_order: VecDeque::new(),
+        }
+    }
+}
+
+impl MqttBridge {
+    /// Number of published broadcasts remembered to suppress their echo.
+    const MAX_ECHOES: usize = 1024;
+
+    /// How long the echo of a published broadcast is expected by default.
+    pub const DEFAULT_ECHO_TTL: Duration = Duration::from_secs(5);
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Expects the echo of a published broadcast for this long. A message identical to it that
+    /// the broker delivers later is broadcast, e.g. if the broker never echoes, as with the
+    /// no-local option of MQTT 5.
+    pub fn with_echo_ttl(mut self, ttl: Duration) -> Self {
+        self.echo_ttl = ttl;
+        self
+    }
+
+    /// Mirrors the broadcast topic to the MQTT topic, which may not contain wildcards.
+    pub fn with_mapping(
+        mut self,
+        topic: Topic,
+        mqtt_topic: &str,
+        direction: BridgeDirection,
+    ) -> Self {
+        assert!(
+            !mqtt_topic.is_empty() && !mqtt_topic.contains(['+', '#'].as_ref()),
+            "invalid MQTT topic"
+        );
+        if direction.mirrors_to_mqtt() {
+            self.to_mqtt.insert(topic, mqtt_topic.to_owned());
+        }
+        if direction.mirrors_from_mqtt() {
+            self.from_mqtt.insert(mqtt_topic.to_owned(), topic);
+        }
+        self
+    }
+
+    /// MQTT topics the client needs to subscribe to.
+    pub fn mqtt_topics(&self) -> impl Iterator<Item = &str> + '_ {
+        self.from_mqtt.keys().map(String::as_str)
+    }
+
+    /// Subscribes the behaviour to the topics mirrored to the broker.
+    pub fn subscribe(&self, behaviour: &mut Behaviour) {
+        behaviour.subscribe_many(self.to_mqtt.keys().copied());
+    }
+
+    /// Publishes a received broadcast to the broker if its topic is mirrored. Returns `true` if
+    /// it was published.
+    pub fn on_event(&mut self, event: &Event, publisher: &mut impl MqttPublisher) -> bool {
+        let Event::Received(_, topic, payload, _) = event else {
+            return false;
+        };
+        let Some(mqtt_topic) = self.to_mqtt.get(topic) else {
+            return false;
+        };
+        publisher.publish(mqtt_topic, payload.clone());
+        if self.from_mqtt.contains_key(mqtt_topic) {
+            self.remember_echo(MessageId::from_content(topic, payload));
+        }
+        true
+    }
+
+    /// Broadcasts a message received from the broker if its topic is mirrored and it is not the
+    /// echo of a broadcast published by the bridge. Returns `true` if it was broadcast.
+    pub fn on_mqtt_message(
+        &mut self,
+        behaviour: &mut Behaviour,
+        mqtt_topic: &str,
+        payload: Bytes,
+    ) -> bool {
+        let Some(topic) = self.from_mqtt.get(mqtt_topic) else {
+            return false;
+        };
+        let id = MessageId::from_content(topic, &payload);
+        if self
+            .echoes
+            .remove(&id)
+            .is_some_and(|until| Instant::now() < until)
+        {
+            return false;
+        }
+        behaviour.broadcast(topic, payload);
+        true
+    }
+
+    fn remember_echo(&mut self, id: MessageId) {
+        let now = Instant::now();
+        let until = now + self.echo_ttl;
+        self.echoes.insert(id, until);
+        self.echo_order.push_back((until, id));
+        while let Some(&(until, oldest)) = self.echo_order.front() {
+            if until > now && self.echo_order.len() <= Self::MAX_ECHOES {
+                break;
+            }
+            self.echo_order.pop_front();
+            // Unless it was published again since.
+            if self.echoes.get(&oldest) == Some(&until) {
+                self.echoes.remove(&oldest);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use libp2p::PeerId;
+
+    use crate::Metadata;
+
+    #[derive(Default)]
+    struct Published(Vec<(String, Bytes)>);
+
+    impl MqttPublisher for Published {
+        fn publish(&mut self, topic: &str, payload: Bytes) {
+            self.0.push((topic.to_owned(), payload));
+        }
+    }
+
+    #[test]
+    fn test_bridge() {
+        let (sensors, commands) = (Topic::new(b"sensors"), Topic::new(b"commands"));
+        let mut bridge = MqttBridge::new()
+            .with_mapping(sensors, "plant/sensors", BridgeDirection::Both)
+            .with_mapping(commands, "plant/commands", BridgeDirection::FromMqtt);
+        let mut behaviour = Behaviour::default();
+        let mut published = Published::default();
+
+        bridge.subscribe(&mut behaviour);
+        assert_eq!(behaviour.subscribed().collect::<Vec<_>>(), [&sensors]);
+        let mut mqtt_topics: Vec<_> = bridge.mqtt_topics().collect();
+        mqtt_topics.sort_unstable();
+        assert_eq!(mqtt_topics, ["plant/commands", "plant/sensors"]);
+
+        let reading = Bytes::from_static(b"21.5");
+        let event = Event::Received(
+            PeerId::random(),
+            sensors,
+            reading.clone(),
+            Metadata::default(),
+        );
+        assert!(bridge.on_event(&event, &mut published));
+        assert_eq!(published.0, [("plant/sensors".to_owned(), reading.clone())]);
+        let event = Event::Received(
+            PeerId::random(),
+            commands,
+            reading.clone(),
+            Metadata::default(),
+        );
+        assert!(!bridge.on_event(&event, &mut published));
+
+        // The reading comes back from the broker, but only once.
+        assert!(!bridge.on_mqtt_message(&mut behaviour, "plant/sensors", reading.clone()));
+        assert!(bridge.on_mqtt_message(&mut behaviour, "plant/sensors", reading));
+        assert!(bridge.on_mqtt_message(&mut behaviour, "plant/commands", Bytes::new()));
+        assert!(!bridge.on_mqtt_message(&mut behaviour, "plant/other", Bytes::new()));
+    }
+
+    #[test]
+    fn test_no_echo() {
+        let sensors = Topic::new(b"sensors");
+        let mut bridge = MqttBridge::new()
+            .with_mapping(sensors, "plant/sensors", BridgeDirection::Both)
+            .with_echo_ttl(Duration::ZERO);
+        let mut behaviour = Behaviour::default();
+        let mut published = Published::default();
+
+        // The broker does not echo the reading, an identical one it delivers later is genuine.
+        let reading = Bytes::from_static(b"21.5");
+        let event = Event::Received(
+            PeerId::random(),
+            sensors,
+            reading.clone(),
+            Metadata::default(),
+        );
+        assert!(bridge.on_event(&event, &mut published));
+        assert!(bridge.on_mqtt_message(&mut behaviour, "plant/sensors", reading));
+        assert!(bridge.echoes.is_empty() && bridge.echo_order.is_empty());
+    }
+}
@@ -0,0 +1,190 @@
+use std::collections::BTreeMap;
+#[cfg(feature = "sled")]
+use std::convert::TryInto;
+use std::io;
+#[cfg(feature = "sled")]
+use std::sync::Arc;
+
+use bytes::Bytes;
+
+#[cfg(feature = "sled")]
+use crate::types::Message;
+use crate::types::{Metadata, Topic};
+
+/// A broadcast kept by an `OutboxStore` until it is delivered.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OutboxEntry {
+    pub id: u64,
+    pub topic: Topic,
+    pub payload: Bytes,
+    pub metadata: Metadata,
+}
+
+/// Keeps the broadcasts that could not be delivered yet, so that they survive restarts when the
+/// store is persistent. Installed with `Behaviour::set_outbox`.
+///
+/// A broadcast is appended when no subscriber of its topic is connected, and acknowledged once
+/// it was queued for a subscriber that showed up since.
+pub trait OutboxStore: Send {
+    /// Stores a broadcast and returns the id it is acknowledged with. Ids increase with every
+    /// appended broadcast, across restarts.
+    fn append(&mut self, topic: &Topic, payload: &Bytes, metadata: &Metadata) -> io::Result<u64>;
+
+    /// Removes a delivered broadcast.
+    fn ack(&mut self, id: u64) -> io::Result<()>;
+
+    /// Returns the broadcasts that were not acknowledged, oldest first.
+    fn replay(&mut self) -> io::Result<Vec<OutboxEntry>>;
+}
+
+/// Keeps the outbox in memory, which covers peers that are not connected yet but not restarts.
+#[derive(Debug, Default)]
+pub struct MemoryOutbox {
+    entries: BTreeMap<u64, OutboxEntry>,
+    next_id: u64,
+}
+
+impl MemoryOutbox {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl OutboxStore for MemoryOutbox {
+    fn append(&mut self, topic: &Topic, payload: &Bytes, metadata: &Metadata) -> io::Result<u64> {
+        let id = self.next_id;
+        self.next_id += 1;
+        let entry = OutboxEntry {
+            id,
+            topic: *topic,
+            payload: payload.clone(),
+            metadata: metadata.clone(),
+        };
+        self.entries.insert(id, entry);
+        Ok(id)
+    }
+
+    fn ack(&mut self, id: u64) -> io::Result<()> {
+        self.entries.remove(&id);
+        Ok(())
+    }
+
+    fn replay(&mut self) -> io::Result<Vec<OutboxEntry>> {
+        Ok(self.entries.values().cloned().collect())
+    }
+}
+
+/// Keeps the outbox in a sled tree. Entries are stored as broadcast frames keyed by their id.
+#[cfg(feature = "sled")]
+#[derive(Debug)]
+pub struct SledOutbox {
+    db: sled::Db,
+    tree: sled::Tree,
+}
+
+#[cfg(feature = "sled")]
+impl SledOutbox {
+    const TREE: &'static str = "libp2p-broadcast-outbox";
+
+    /// Opens the outbox in a tree of the database, which may be shared with the application.
+    pub fn open(db: sled::Db) -> io::Result<Self> {
+        let tree = db.open_tree(Self::TREE)?;
+        Ok(Self { db, tree })
+    }
+}
+
+#[cfg(feature = "sled")]
+impl OutboxStore for SledOutbox {
+    fn append(&mut self, topic: &Topic, payload: &Bytes, metadata: &Metadata) -> io::Result<u64> {
+        let id = self.db.generate_id()?;
+        let frame = Message::Broadcast {
+            topic: *topic,
+            payload: payload.clone().into(),
+            metadata: metadata.clone().into(),
+        };
+        self.tree.insert(id.to_be_bytes(), frame.to_bytes())?;
+        self.tree.flush()?;
+        Ok(id)
+    }
+
+    fn ack(&mut self, id: u64) -> io::Result<()> {
+        self.tree.remove(id.to_be_bytes())?;
+        Ok(())
+    }
+
+    fn replay(&mut self) -> io::Result<Vec<OutboxEntry>> {
+        let mut entries = Vec::new();
+        for entry in self.tree.iter() {
+            let (key, frame) = entry?;
+            let id = key
+                .as_ref()
+                .try_into()
+                .map(u64::from_be_bytes)
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid outbox key"))?;
+            match Message::from_slice(&frame)? {
+                Message::Broadcast {
+                    topic,
+                    payload,
+                    metadata,
+                } => entries.push(OutboxEntry {
+                    id,
+                    topic,
+                    payload: payload.into(),
+                    metadata: Arc::unwrap_or_clone(metadata),
+                }),
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "invalid outbox entry",
+                    ))
+                }
+            }
+        }
+        Ok(entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check_store(store: &mut impl OutboxStore) {
+        let topic = Topic::new(b"orders");
+        let metadata = Metadata::default().with_content_type("text/plain");
+        let first = store
+            .append(&topic, &Bytes::from_static(b"first"), &metadata)
+            .unwrap();
+        let second = store
+            .append(&topic, &Bytes::from_static(b"second"), &Metadata::default())
+            .unwrap();
+        assert!(second > first);
+        store.ack(first).unwrap();
+        assert_eq!(
+            store.replay().unwrap(),
+            [OutboxEntry {
+                id: second,
+                topic,
+                payload: Bytes::from_static(b"second"),
+                metadata: Metadata::default(),
+            }]
+        );
+        store.ack(second).unwrap();
+        let third = store
+            .append(&topic, &Bytes::from_static(b"third"), &metadata)
+            .unwrap();
+        assert_eq!(store.replay().unwrap()[0].id, third);
+        assert_eq!(store.replay().unwrap()[0].metadata, metadata);
+    }
+
+    #[test]
+    fn test_memory_outbox() {
+        check_store(&mut MemoryOutbox::new());
+    }
+
+    #[cfg(feature = "sled")]
+    #[test]
+    fn test_sled_outbox() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        check_store(&mut SledOutbox::open(db).unwrap());
+    }
+}
@@ -0,0 +1,173 @@
+use std::io;
+
+use bytes::Bytes;
+use fnv::FnvHashMap;
+use libp2p::PeerId;
+
+use crate::interceptor::Interceptor;
+use crate::targets::BEHAVIOUR;
+use crate::types::{Metadata, Topic};
+
+/// A reversible transformation of payloads, e.g. compression, encryption or signing.
+pub trait PayloadTransform: Send {
+    /// Transforms a payload about to be sent.
+    fn apply(&mut self, payload: Bytes) -> io::Result<Bytes>;
+
+    /// Undoes `apply` on a received payload. Errors drop the broadcast, e.g. when a signature
+    /// does not verify.
+    fn reverse(&mut self, payload: Bytes) -> io::Result<Bytes>;
+}
+
+/// Transforms applied in order on send and in reverse order on receive, e.g. compress, then
+/// encrypt, then sign.
+#[derive(Default)]
+pub struct Pipeline {
+    transforms: Vec<Box<dyn PayloadTransform>>,
+}
+
+impl Pipeline {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends a transform, applied after the ones before it on send.
+    pub fn then(mut self, transform: impl PayloadTransform + 'static) -> Self {
+        self.transforms.push(Box::new(transform));
+        self
+    }
+
+    pub fn apply(&mut self, payload: Bytes) -> io::Result<Bytes> {
+        self.transforms
+            .iter_mut()
+            .try_fold(payload, |payload, transform| transform.apply(payload))
+    }
+
+    pub fn reverse(&mut self, payload: Bytes) -> io::Result<Bytes> {
+        self.transforms
+            .iter_mut()
+            .rev()
+            .try_fold(payload, |payload, transform| transform.reverse(payload))
+    }
+}
+
+/// The pipeline of each topic, installed as an interceptor with `Behaviour::add_interceptor`.
+/// Broadcasts on other topics pass through untouched, and broadcasts a pipeline fails on are
+/// dropped.
+#[derive(Default)]
+pub struct Pipelines {
+    pipelines: FnvHashMap<Topic, Pipeline>,
+}
+
+impl Pipelines {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_pipeline(mut self, topic: Topic, pipeline: Pipeline) -> Self {
+        self.pipelines.insert(topic, pipeline);
+        self
+    }
+}
+
+impl Interceptor for Pipelines {
+    fn on_outbound(&mut self, topic: &Topic, payload: &mut Bytes, _: &mut Metadata) -> bool {
+        let Some(pipeline) = self.pipelines.get_mut(topic) else {
+            return true;
+        };
+        match pipeline.apply(std::mem::take(payload)) {
+            Ok(transformed) => {
+                *payload = transformed;
+                true
+            }
+            Err(e) => {
+                tracing::warn!(target: BEHAVIOUR, "Failed to transform a payload to send: {e}");
+                false
+            }
+        }
+    }
+
+    fn on_inbound(
+        &mut self,
+        peer: &PeerId,
+        topic: &Topic,
+        payload: &mut Bytes,
+        _: &mut Metadata,
+    ) -> bool {
+        let Some(pipeline) = self.pipelines.get_mut(topic) else {
+            return true;
+        };
+        match pipeline.reverse(std::mem::take(payload)) {
+            Ok(transformed) => {
+                *payload = transformed;
+                true
+            }
+            Err(e) => {
+                tracing::debug!(
+                    target: BEHAVIOUR,
+                    "Dropping a payload from {peer} failing to transform back: {e}"
+                );
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Xors every byte with a key.
+    struct Xor(u8);
+
+    impl PayloadTransform for Xor {
+        fn apply(&mut self, payload: Bytes) -> io::Result<Bytes> {
+            Ok(payload.iter().map(|byte| byte ^ self.0).collect())
+        }
+
+        fn reverse(&mut self, payload: Bytes) -> io::Result<Bytes> {
+            self.apply(payload)
+        }
+    }
+
+    /// Appends a trailer, checked and removed on receive.
+    struct Trailer(&'static [u8]);
+
+    impl PayloadTransform for Trailer {
+        fn apply(&mut self, payload: Bytes) -> io::Result<Bytes> {
+            Ok([&payload[..], self.0].concat().into())
+        }
+
+        fn reverse(&mut self, mut payload: Bytes) -> io::Result<Bytes> {
+            if !payload.ends_with(self.0) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "missing trailer",
+                ));
+            }
+            payload.truncate(payload.len() - self.0.len());
+            Ok(payload)
+        }
+    }
+
+    #[test]
+    fn test_pipelines() {
+        let (topic, other) = (Topic::new(b"topic"), Topic::new(b"other"));
+        let pipeline = Pipeline::new().then(Xor(0x20)).then(Trailer(b"!"));
+        let mut pipelines = Pipelines::new().with_pipeline(topic, pipeline);
+        let mut metadata = Metadata::default();
+
+        let mut payload = Bytes::from_static(b"abc");
+        assert!(pipelines.on_outbound(&topic, &mut payload, &mut metadata));
+        assert_eq!(payload, Bytes::from_static(b"ABC!"));
+        let peer = PeerId::random();
+        assert!(pipelines.on_inbound(&peer, &topic, &mut payload, &mut metadata));
+        assert_eq!(payload, Bytes::from_static(b"abc"));
+
+        let mut untouched = Bytes::from_static(b"abc");
+        assert!(pipelines.on_outbound(&other, &mut untouched, &mut metadata));
+        assert_eq!(untouched, Bytes::from_static(b"abc"));
+
+        let mut tampered = Bytes::from_static(b"ABC?");
+        assert!(!pipelines.on_inbound(&peer, &topic, &mut tampered, &mut metadata));
+    }
+}
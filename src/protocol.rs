@@ -1,38 +1,23 @@
-use std::convert::Infallible;
-
-use futures::future::{ready, Ready};
-use libp2p::core::{InboundUpgrade, OutboundUpgrade, UpgradeInfo};
-use libp2p::swarm::Stream;
-
-const PROTOCOL_INFO: &str = "/ax/broadcast/1.0.0";
-
-pub struct Protocol {}
-
-impl UpgradeInfo for Protocol {
-    type Info = &'static str;
-    type InfoIter = std::iter::Once<Self::Info>;
-
-    fn protocol_info(&self) -> Self::InfoIter {
-        std::iter::once(PROTOCOL_INFO)
-    }
+pub(crate) const PROTOCOL_V1: &str = "/ax/broadcast/1.0.0";
+pub(crate) const PROTOCOL_V2: &str = "/ax/broadcast/2.0.0";
+
+/// Version of the protocol negotiated on a substream.
+///
+/// Version 2 adds the extended frames, e.g. batches. Messages sent to a peer that only speaks
+/// version 1 are downgraded by the codec where possible.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum Version {
+    V1,
+    V2,
 }
 
-impl InboundUpgrade<Stream> for Protocol {
-    type Output = Stream;
-    type Error = Infallible;
-    type Future = Ready<Result<Self::Output, Self::Error>>;
-
-    fn upgrade_inbound(self, socket: Stream, _: Self::Info) -> Self::Future {
-        ready(Ok(socket))
-    }
-}
-
-impl OutboundUpgrade<Stream> for Protocol {
-    type Output = Stream;
-    type Error = Infallible;
-    type Future = Ready<Result<Self::Output, Self::Error>>;
-
-    fn upgrade_outbound(self, socket: Stream, _: Self::Info) -> Self::Future {
-        ready(Ok(socket))
+impl Version {
+    /// Version of the protocol with this name, if it is one of ours.
+    pub fn from_protocol(protocol: &str) -> Option<Self> {
+        match protocol {
+            PROTOCOL_V1 => Some(Version::V1),
+            PROTOCOL_V2 => Some(Version::V2),
+            _ => None,
+        }
     }
 }
@@ -0,0 +1,397 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use crate::drop_policy::QueuedBroadcast;
+use crate::handler::Outbound;
+use crate::types::{Message, Topic};
+
+/// Urgency of the messages of a topic. Queued messages of a higher priority are sent to a peer
+/// before any queued message of a lower priority.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+impl Priority {
+    const COUNT: usize = 3;
+
+    fn lane(self) -> usize {
+        match self {
+            Priority::High => 0,
+            Priority::Normal => 1,
+            Priority::Low => 2,
+        }
+    }
+}
+
+/// Depth of the queues of the connections to a peer, see `Behaviour::pending`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct QueueStats {
+    /// Number of messages waiting to be sent.
+    pub messages: usize,
+    /// Encoded size of the waiting messages.
+    pub bytes: usize,
+    /// How long the message that has been waiting the longest was queued for.
+    pub oldest_age: Option<Duration>,
+}
+
+/// A queued message, along with the time it was queued at.
+#[derive(Debug)]
+struct Queued {
+    outbound: Outbound,
+    since: Instant,
+}
+
+/// Messages waiting to be sent to a peer, one FIFO lane per priority.
+#[derive(Debug, Default)]
+pub(crate) struct OutboundQueue {
+    lanes: [VecDeque<Queued>; Priority::COUNT],
+    /// Encoded size of the queued messages.
+    bytes: usize,
+    /// Earliest deadline of the queued messages. May be stale once messages were popped, in
+    /// which case `expire` finds nothing to discard and refreshes it.
+    next_deadline: Option<Instant>,
+}
+
+fn earliest(a: Option<Instant>, b: Option<Instant>) -> Option<Instant> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+impl OutboundQueue {
+    /// Queues the message. A message with a supersede key takes the place of a queued message of
+    /// the same topic with the same key, if any, in which case `false` is returned.
+    ///
+    /// A superseding message keeps the position and queueing time of the message it replaces.
+    pub fn push(&mut self, outbound: Outbound, priority: Priority, now: Instant) -> bool {
+        let len = outbound.message.len();
+        self.next_deadline = earliest(self.next_deadline, outbound.deadline);
+        if let Some(queued) = self.superseded(&outbound, priority) {
+            let replaced = std::mem::replace(&mut queued.outbound, outbound);
+            self.bytes = self.bytes + len - replaced.message.len();
+            return false;
+        }
+        self.bytes += len;
+        self.lanes[priority.lane()].push_back(Queued {
+            outbound,
+            since: now,
+        });
+        true
+    }
+
+    /// Returns `true` if pushing the message would replace a queued message instead of growing
+    /// the queue.
+    pub fn supersedes(&mut self, outbound: &Outbound, priority: Priority) -> bool {
+        self.superseded(outbound, priority).is_some()
+    }
+
+    fn superseded(&mut self, outbound: &Outbound, priority: Priority) -> Option<&mut Queued> {
+        let key = outbound.supersede_key?;
+        let topic = outbound.message.topic().copied();
+        self.lanes[priority.lane()].iter_mut().find(|queued| {
+            queued.outbound.supersede_key == Some(key)
+                && queued.outbound.message.topic().copied() == topic
+        })
+    }
+
+    /// The queued broadcasts, in the order they are sent.
+    pub fn broadcasts(&self) -> Vec<QueuedBroadcast<'_>> {
+        let priorities = [Priority::High, Priority::Normal, Priority::Low];
+        priorities
+            .iter()
+            .flat_map(|priority| {
+                self.lanes[priority.lane()]
+                    .iter()
+                    .map(move |queued| (*priority, &queued.outbound.message))
+            })
+            .enumerate()
+            .filter_map(|(index, (priority, message))| match message {
+                Message::Broadcast { topic, payload, .. } => Some(QueuedBroadcast {
+                    index,
+                    topic,
+                    payload,
+                    priority,
+                }),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Removes the message at the index, in the order messages are sent, along with the time
+    /// it was queued at.
+    pub fn remove(&mut self, mut index: usize) -> Option<(Outbound, Instant)> {
+        for lane in &mut self.lanes {
+            if index < lane.len() {
+                let queued = lane.remove(index)?;
+                self.bytes -= queued.outbound.message.len();
+                return Some((queued.outbound, queued.since));
+            }
+            index -= lane.len();
+        }
+        None
+    }
+
+    /// Takes the oldest message of the most urgent non-empty lane, along with the time it was
+    /// queued at.
+    pub fn pop(&mut self) -> Option<(Outbound, Instant)> {
+        let queued = self.lanes.iter_mut().find_map(|lane| lane.pop_front())?;
+        self.bytes -= queued.outbound.message.len();
+        Some((queued.outbound, queued.since))
+    }
+
+    /// Number of queued messages.
+    pub fn len(&self) -> usize {
+        self.lanes.iter().map(VecDeque::len).sum()
+    }
+
+    /// Encoded size of the queued messages.
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Time at which the message that has been waiting the longest was queued.
+    pub fn oldest(&self) -> Option<Instant> {
+        self.lanes
+            .iter()
+            .filter_map(|lane| lane.front())
+            .map(|queued| queued.since)
+            .min()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lanes.iter().all(|lane| lane.is_empty())
+    }
+
+    /// Drops the queued broadcasts of the topic, returning how many there were. Subscription
+    /// changes are kept as the remote still needs to learn about them.
+    pub fn cancel(&mut self, topic: &Topic) -> usize {
+        self.discard(|message| message.is_broadcast_on(topic))
+    }
+
+    /// Drops the queued broadcasts and shards, returning how many there were. Subscription
+    /// changes and snapshots are kept as the remote still needs to learn about them.
+    pub fn shed(&mut self) -> usize {
+        self.discard(|message| matches!(message, Message::Broadcast { .. } | Message::Shard { .. }))
+    }
+
+    /// Drops the queued messages matching the predicate, including from batches, returning how
+    /// many there were.
+    fn discard(&mut self, predicate: impl Fn(&Message) -> bool) -> usize {
+        let mut cancelled = 0;
+        let mut bytes = 0;
+        for lane in &mut self.lanes {
+            lane.retain_mut(|queued| {
+                let message = &mut queued.outbound.message;
+                cancelled += message.discard(&predicate);
+                if message.is_empty() {
+                    return false;
+                }
+                bytes += message.len();
+                true
+            });
+        }
+        self.bytes = bytes;
+        cancelled
+    }
+
+    /// Drops the messages whose deadline passed, returning how many there were.
+    pub fn expire(&mut self, now: Instant) -> usize {
+        if self.next_deadline.is_none_or(|deadline| now < deadline) {
+            return 0;
+        }
+        let mut expired = 0;
+        let mut next_deadline = None;
+        let bytes = &mut self.bytes;
+        for lane in &mut self.lanes {
+            lane.retain(|queued| match queued.outbound.deadline {
+                Some(deadline) if deadline <= now => {
+                    expired += 1;
+                    *bytes -= queued.outbound.message.len();
+                    false
+                }
+                deadline => {
+                    next_deadline = earliest(next_deadline, deadline);
+                    true
+                }
+            });
+        }
+        self.next_deadline = next_deadline;
+        expired
+    }
+
+    /// Earliest deadline of the queued messages, if any.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.next_deadline
+    }
+
+    /// Drops all queued messages, returning how many there were.
+    pub fn clear(&mut self) -> usize {
+        self.bytes = 0;
+        self.next_deadline = None;
+        self.lanes
+            .iter_mut()
+            .map(|lane| lane.drain(..).count())
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bytes::Bytes;
+
+    fn pop(queue: &mut OutboundQueue) -> Option<Message> {
+        queue.pop().map(|(outbound, _)| outbound.message)
+    }
+
+    #[test]
+    fn test_priority_order() {
+        let (votes, parts, telemetry) = (Topic::new(b"v"), Topic::new(b"p"), Topic::new(b"t"));
+        let mut queue = OutboundQueue::default();
+        let now = Instant::now();
+
+        queue.push(
+            Message::Subscribe { topic: telemetry }.into(),
+            Priority::Low,
+            now,
+        );
+        queue.push(
+            Message::Subscribe { topic: parts }.into(),
+            Priority::Normal,
+            now,
+        );
+        queue.push(
+            Message::Subscribe { topic: votes }.into(),
+            Priority::High,
+            now,
+        );
+        queue.push(
+            Message::Unsubscribe { topic: parts }.into(),
+            Priority::Normal,
+            now,
+        );
+        assert_eq!(queue.oldest(), Some(now));
+
+        assert_eq!(pop(&mut queue), Some(Message::Subscribe { topic: votes }));
+        assert_eq!(pop(&mut queue), Some(Message::Subscribe { topic: parts }));
+        assert_eq!(pop(&mut queue), Some(Message::Unsubscribe { topic: parts }));
+        assert_eq!(
+            pop(&mut queue),
+            Some(Message::Subscribe { topic: telemetry })
+        );
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn test_supersede() {
+        let (state, other) = (Topic::new(b"state"), Topic::new(b"other"));
+        let snapshot = |topic, payload: &'static [u8], key| Outbound {
+            message: Message::broadcast(topic, Bytes::from_static(payload)),
+            supersede_key: Some(key),
+            ack: None,
+            deadline: None,
+        };
+        let mut queue = OutboundQueue::default();
+        let now = Instant::now();
+
+        assert!(queue.push(snapshot(state, b"v1", 1), Priority::Normal, now));
+        assert!(queue.push(snapshot(state, b"w1", 2), Priority::Normal, now));
+        assert!(queue.push(snapshot(other, b"x1", 1), Priority::Normal, now));
+        assert!(!queue.push(snapshot(state, b"v2", 1), Priority::Normal, now));
+
+        let payloads: Vec<_> = std::iter::from_fn(|| pop(&mut queue))
+            .map(|message| match message {
+                Message::Broadcast { payload, .. } => Bytes::from(payload),
+                _ => panic!(),
+            })
+            .collect();
+        assert_eq!(payloads, ["v2", "w1", "x1"]);
+    }
+
+    #[test]
+    fn test_cancel() {
+        let (old, new) = (Topic::new(b"round/1"), Topic::new(b"round/2"));
+        let mut queue = OutboundQueue::default();
+        let now = Instant::now();
+
+        queue.push(
+            Message::Unsubscribe { topic: old }.into(),
+            Priority::High,
+            now,
+        );
+        queue.push(
+            Message::broadcast(old, Bytes::new()).into(),
+            Priority::Normal,
+            now,
+        );
+        queue.push(
+            Message::broadcast(new, Bytes::new()).into(),
+            Priority::Normal,
+            now,
+        );
+        queue.push(
+            Message::broadcast(old, Bytes::new()).into(),
+            Priority::Low,
+            now,
+        );
+        let batch = vec![
+            Message::broadcast(old, Bytes::new()),
+            Message::broadcast(new, Bytes::new()),
+        ];
+        queue.push(
+            Message::Batch { messages: batch }.into(),
+            Priority::Low,
+            now,
+        );
+
+        assert_eq!(queue.cancel(&old), 3);
+        let remaining = [
+            Message::Unsubscribe { topic: old },
+            Message::broadcast(new, Bytes::new()),
+            Message::Batch {
+                messages: vec![Message::broadcast(new, Bytes::new())],
+            },
+        ];
+        assert_eq!(queue.bytes(), remaining.iter().map(Message::len).sum());
+        assert_eq!(pop(&mut queue), Some(Message::Unsubscribe { topic: old }));
+        assert_eq!(pop(&mut queue), Some(Message::broadcast(new, Bytes::new())));
+        assert_eq!(
+            pop(&mut queue),
+            Some(Message::Batch {
+                messages: vec![Message::broadcast(new, Bytes::new())]
+            })
+        );
+        assert!(queue.is_empty());
+        assert_eq!(queue.bytes(), 0);
+    }
+
+    #[test]
+    fn test_expire() {
+        let topic = Topic::new(b"ticks");
+        let now = Instant::now();
+        let tick = |payload: &'static [u8], deadline| Outbound {
+            deadline,
+            ..Message::broadcast(topic, Bytes::from_static(payload)).into()
+        };
+        let mut queue = OutboundQueue::default();
+        queue.push(tick(b"late", Some(now)), Priority::Normal, now);
+        queue.push(tick(b"timely", None), Priority::Normal, now);
+        let later = now + Duration::from_secs(1);
+        queue.push(tick(b"soon", Some(later)), Priority::High, now);
+        assert_eq!(queue.next_deadline(), Some(now));
+
+        assert_eq!(queue.expire(now - Duration::from_millis(1)), 0);
+        assert_eq!(queue.expire(now), 1);
+        assert_eq!(queue.len(), 2);
+        assert_eq!(queue.bytes(), 1 + 5 + 6 + 1 + 5 + 4);
+        assert_eq!(queue.next_deadline(), Some(later));
+        assert_eq!(queue.expire(later), 1);
+        assert_eq!(queue.next_deadline(), None);
+    }
+}
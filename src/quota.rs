@@ -0,0 +1,95 @@
+use std::time::Instant;
+
+use fnv::FnvHashMap;
+use libp2p::PeerId;
+
+use crate::config::BandwidthCap;
+
+/// Whether the bytes charged to a peer are within its `BandwidthCap`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum Charge {
+    Within,
+    /// The peer exceeded the cap with these bytes, for the first time in the interval.
+    Exceeded,
+    /// The peer exceeded the cap earlier in the interval.
+    Throttled,
+}
+
+/// Bytes received from a peer since the start of the current interval.
+#[derive(Debug)]
+struct Usage {
+    since: Instant,
+    bytes: u64,
+}
+
+/// Bytes received from each peer per interval of `Config::bandwidth_cap`. The intervals are
+/// fixed, starting with the first bytes received from the peer after the previous one ended.
+#[derive(Debug, Default)]
+pub(crate) struct Quotas {
+    usage: FnvHashMap<PeerId, Usage>,
+}
+
+impl Quotas {
+    pub fn charge(&mut self, peer: PeerId, bytes: u64, cap: &BandwidthCap, now: Instant) -> Charge {
+        let usage = self.usage.entry(peer).or_insert(Usage {
+            since: now,
+            bytes: 0,
+        });
+        if now.saturating_duration_since(usage.since) >= cap.interval {
+            *usage = Usage {
+                since: now,
+                bytes: 0,
+            };
+        }
+        let before = usage.bytes;
+        usage.bytes = usage.bytes.saturating_add(bytes);
+        if usage.bytes <= cap.max_bytes {
+            Charge::Within
+        } else if before <= cap.max_bytes {
+            Charge::Exceeded
+        } else {
+            Charge::Throttled
+        }
+    }
+
+    /// Forgets the peers whose interval ended. Peers are not forgotten as they disconnect, so
+    /// that reconnecting does not reset their quota.
+    pub fn prune(&mut self, cap: &BandwidthCap, now: Instant) {
+        self.usage
+            .retain(|_, usage| now.saturating_duration_since(usage.since) < cap.interval);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    use crate::config::CapAction;
+
+    #[test]
+    fn test_charge() {
+        let cap = BandwidthCap {
+            max_bytes: 100,
+            interval: Duration::from_secs(1),
+            action: CapAction::Throttle,
+        };
+        let (peer, other) = (PeerId::random(), PeerId::random());
+        let start = Instant::now();
+        let mut quotas = Quotas::default();
+        assert_eq!(quotas.charge(peer, 60, &cap, start), Charge::Within);
+        assert_eq!(quotas.charge(peer, 40, &cap, start), Charge::Within);
+        assert_eq!(quotas.charge(peer, 1, &cap, start), Charge::Exceeded);
+        assert_eq!(quotas.charge(peer, 1, &cap, start), Charge::Throttled);
+        assert_eq!(quotas.charge(other, 100, &cap, start), Charge::Within);
+
+        // The quota is replenished once the interval ended.
+        let later = start + cap.interval;
+        quotas.prune(&cap, start);
+        assert_eq!(quotas.charge(peer, 1, &cap, start), Charge::Throttled);
+        quotas.prune(&cap, later);
+        assert!(quotas.usage.is_empty());
+        assert_eq!(quotas.charge(peer, 100, &cap, later), Charge::Within);
+    }
+}
@@ -0,0 +1,174 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use fnv::{FnvHashMap, FnvHashSet};
+use libp2p::PeerId;
+
+use crate::config::Redelivery;
+use crate::types::{Message, Topic};
+
+/// A broadcast of an at-least-once topic the peer did not acknowledge yet.
+#[derive(Debug)]
+struct Unacked {
+    topic: Topic,
+    message: Message,
+    redelivery: Redelivery,
+    sent_at: Instant,
+    retries: u32,
+}
+
+/// Broadcasts whose acknowledgment is overdue, see `Redeliveries::due`.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct Due {
+    /// The broadcasts to send again, and to whom.
+    pub resend: Vec<(PeerId, Message)>,
+    /// The broadcasts given up on once out of retries, by topic and delivery id.
+    pub exhausted: Vec<(PeerId, Topic, u64)>,
+}
+
+/// Broadcasts awaiting their acknowledgment, by peer and delivery id.
+#[derive(Debug, Default)]
+pub(crate) struct Redeliveries {
+    unacked: FnvHashMap<(PeerId, u64), Unacked>,
+}
+
+impl Redeliveries {
+    /// Interval at which the broadcasts awaiting an acknowledgment are checked.
+    pub const TICK: Duration = Duration::from_millis(100);
+
+    pub fn track(
+        &mut self,
+        peer: PeerId,
+        id: u64,
+        topic: Topic,
+        message: Message,
+        redelivery: Redelivery,
+        now: Instant,
+    ) {
+        let unacked = Unacked {
+            topic,
+            message,
+            redelivery,
+            sent_at: now,
+            retries: 0,
+        };
+        self.unacked.insert((peer, id), unacked);
+    }
+
+    /// Returns `false` if the broadcast was not awaiting an acknowledgment from the peer.
+    pub fn ack(&mut self, peer: &PeerId, id: u64) -> bool {
+        self.unacked.remove(&(*peer, id)).is_some()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.unacked.is_empty()
+    }
+
+    /// Returns the broadcasts to send again as of `now`, and the ones given up on.
+    pub fn due(&mut self, now: Instant) -> Due {
+        let mut due = Due::default();
+        self.unacked.retain(|(peer, id), unacked| {
+            if now.saturating_duration_since(unacked.sent_at) < unacked.redelivery.timeout {
+                return true;
+            }
+            if unacked.retries == unacked.redelivery.max_retries {
+                due.exhausted.push((*peer, unacked.topic, *id));
+                return false;
+            }
+            unacked.retries += 1;
+            unacked.sent_at = now;
+            due.resend.push((*peer, unacked.message.clone()));
+            true
+        });
+        due
+    }
+
+    /// Gives up on the broadcasts awaiting an acknowledgment from the peer, returning their
+    /// topic and delivery id.
+    pub fn remove_peer(&mut self, peer: &PeerId) -> Vec<(Topic, u64)> {
+        let mut removed = Vec::new();
+        self.unacked.retain(|(other, id), unacked| {
+            if other != peer {
+                return true;
+            }
+            removed.push((unacked.topic, *id));
+            false
+        });
+        removed
+    }
+}
+
+/// Delivery ids of the broadcasts received from each peer lately, so that the ones sent again
+/// because our acknowledgment was lost or late are not delivered twice.
+#[derive(Debug, Default)]
+pub(crate) struct Delivered {
+    ids: FnvHashSet<(PeerId, u64)>,
+    order: VecDeque<(PeerId, u64)>,
+}
+
+impl Delivered {
+    /// Number of delivery ids remembered, the oldest ones being forgotten first.
+    pub const CAPACITY: usize = 4096;
+
+    /// Records the delivery id of a broadcast from the peer. Returns `false` if it was already
+    /// delivered.
+    pub fn insert(&mut self, peer: PeerId, id: u64) -> bool {
+        if !self.ids.insert((peer, id)) {
+            return false;
+        }
+        self.order.push_back((peer, id));
+        if self.order.len() > Self::CAPACITY {
+            if let Some(oldest) = self.order.pop_front() {
+                self.ids.remove(&oldest);
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bytes::Bytes;
+
+    #[test]
+    fn test_due() {
+        let topic = Topic::new(b"orders");
+        let message = Message::broadcast(topic, Bytes::from_static(b"order"));
+        let peer = PeerId::random();
+        let redelivery = Redelivery {
+            timeout: Duration::from_secs(1),
+            max_retries: 2,
+        };
+        let start = Instant::now();
+        let mut redeliveries = Redeliveries::default();
+        redeliveries.track(peer, 1, topic, message.clone(), redelivery, start);
+        redeliveries.track(peer, 2, topic, message.clone(), redelivery, start);
+        assert!(redeliveries.ack(&peer, 2));
+        assert!(!redeliveries.ack(&peer, 2));
+
+        assert_eq!(redeliveries.due(start), Due::default());
+        for retry in 1..=2 {
+            let now = start + redelivery.timeout * retry;
+            assert_eq!(redeliveries.due(now).resend, [(peer, message.clone())]);
+        }
+        let now = start + redelivery.timeout * 3;
+        assert_eq!(redeliveries.due(now).exhausted, [(peer, topic, 1)]);
+        assert!(redeliveries.is_empty());
+    }
+
+    #[test]
+    fn test_delivered() {
+        let (a, b) = (PeerId::random(), PeerId::random());
+        let mut delivered = Delivered::default();
+        assert!(delivered.insert(a, 1));
+        assert!(!delivered.insert(a, 1));
+        assert!(delivered.insert(b, 1));
+
+        for id in 2..=Delivered::CAPACITY as u64 {
+            delivered.insert(b, id);
+        }
+        assert!(delivered.insert(a, 1));
+    }
+}
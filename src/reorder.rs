@@ -0,0 +1,197 @@
+use std::collections::BTreeMap;
+use std::ops::Range;
+use std::time::{Duration, Instant};
+
+use fnv::FnvHashMap;
+use libp2p::PeerId;
+
+use crate::config::Reordering;
+use crate::types::Topic;
+
+/// Broadcasts of a publisher on a topic, held back until the ones before them arrived.
+#[derive(Debug)]
+struct Stream<T> {
+    /// Sequence number of the next broadcast to release.
+    next: u64,
+    buffered: BTreeMap<u64, (Instant, T)>,
+}
+
+impl<T> Stream<T> {
+    /// Releases the buffered broadcasts that are next in sequence.
+    fn release(&mut self, released: &mut Vec<T>) {
+        while let Some(entry) = self.buffered.first_entry() {
+            if *entry.key() != self.next {
+                break;
+            }
+            released.push(entry.remove().1);
+            self.next += 1;
+        }
+    }
+
+    /// Gives up on the broadcasts missing before the first buffered one, returning their
+    /// sequence numbers.
+    fn skip(&mut self, released: &mut Vec<T>) -> Range<u64> {
+        let missing = self.next
+            ..self
+                .buffered
+                .keys()
+                .next()
+                .map_or(self.next, |first| *first);
+        self.next = missing.end;
+        self.release(released);
+        missing
+    }
+}
+
+/// Broadcasts of a stream released once the oldest of them waited past the timeout, see
+/// `ReorderBuffers::expire`.
+#[derive(Debug, PartialEq)]
+pub(crate) struct Expired<T> {
+    pub publisher: PeerId,
+    pub topic: Topic,
+    /// Sequence numbers of the broadcasts skipped.
+    pub missing: Range<u64>,
+    pub released: Vec<T>,
+}
+
+/// Broadcasts received out of order, held back per publisher and topic to be released in
+/// sequence order, see `Config::with_reordering`.
+#[derive(Debug)]
+pub(crate) struct ReorderBuffers<T> {
+    streams: FnvHashMap<(PeerId, Topic), Stream<T>>,
+}
+
+impl<T> Default for ReorderBuffers<T> {
+    fn default() -> Self {
+        Self {
+            streams: FnvHashMap::default(),
+        }
+    }
+}
+
+impl<T> ReorderBuffers<T> {
+    /// Takes in a broadcast of the publisher on the topic, returning the broadcasts released in
+    /// sequence order, and the ones skipped if the buffer overflowed. The first broadcast
+    /// received from a publisher on a topic starts its sequence. Broadcasts arriving after their
+    /// turn was skipped are dropped.
+    pub fn push(
+        &mut self,
+        publisher: PeerId,
+        topic: Topic,
+        seq: u64,
+        item: T,
+        reordering: Reordering,
+        now: Instant,
+    ) -> (Vec<T>, Option<Range<u64>>) {
+        let stream = self
+            .streams
+            .entry((publisher, topic))
+            .or_insert_with(|| Stream {
+                next: seq,
+                buffered: BTreeMap::new(),
+            });
+        let mut released = Vec::new();
+        if seq < stream.next {
+            return (released, None);
+        }
+        if seq > stream.next {
+            stream.buffered.entry(seq).or_insert((now, item));
+            if stream.buffered.len() <= reordering.max_buffered {
+                return (released, None);
+            }
+            let missing = stream.skip(&mut released);
+            return (released, Some(missing));
+        }
+        released.push(item);
+        stream.next += 1;
+        stream.release(&mut released);
+        (released, None)
+    }
+
+    /// Releases the broadcasts of the streams whose oldest buffered broadcast waited for longer
+    /// than the timeout of its topic, by publisher and topic, along with the ones skipped.
+    pub fn expire(
+        &mut self,
+        now: Instant,
+        timeout: impl Fn(&Topic) -> Option<Duration>,
+    ) -> Vec<Expired<T>> {
+        let mut expired = Vec::new();
+        for ((publisher, topic), stream) in &mut self.streams {
+            let Some(timeout) = timeout(topic) else {
+                continue;
+            };
+            let oldest = stream.buffered.values().map(|(since, _)| *since).min();
+            if oldest.is_some_and(|since| now.saturating_duration_since(since) >= timeout) {
+                let mut released = Vec::new();
+                let missing = stream.skip(&mut released);
+                expired.push(Expired {
+                    publisher: *publisher,
+                    topic: *topic,
+                    missing,
+                    released,
+                });
+            }
+        }
+        expired
+    }
+
+    /// Returns `true` if no broadcast is held back.
+    pub fn is_empty(&self) -> bool {
+        self.streams
+            .values()
+            .all(|stream| stream.buffered.is_empty())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reorder() {
+        let (publisher, topic) = (PeerId::random(), Topic::new(b"topic"));
+        let reordering = Reordering {
+            max_buffered: 2,
+            timeout: Duration::from_secs(1),
+        };
+        let start = Instant::now();
+        let mut buffers = ReorderBuffers::default();
+        let push = |buffers: &mut ReorderBuffers<u64>, seq, now| {
+            buffers.push(publisher, topic, seq, seq, reordering, now)
+        };
+
+        assert_eq!(push(&mut buffers, 10, start), (vec![10], None));
+        assert_eq!(push(&mut buffers, 12, start), (vec![], None));
+        assert_eq!(push(&mut buffers, 11, start), (vec![11, 12], None));
+        assert!(buffers.is_empty());
+
+        // 13 goes missing until the buffer overflows.
+        assert_eq!(push(&mut buffers, 14, start), (vec![], None));
+        assert_eq!(push(&mut buffers, 15, start), (vec![], None));
+        let overflow = (vec![14, 15, 16], Some(13..14));
+        assert_eq!(push(&mut buffers, 16, start), overflow);
+        assert_eq!(push(&mut buffers, 13, start), (vec![], None));
+
+        // 17 goes missing until the timeout.
+        assert_eq!(push(&mut buffers, 18, start), (vec![], None));
+        let timeout = |_: &Topic| Some(reordering.timeout);
+        assert!(buffers.expire(start, timeout).is_empty());
+        let later = start + reordering.timeout;
+        let expired = Expired {
+            publisher,
+            topic,
+            missing: 17..18,
+            released: vec![18],
+        };
+        assert_eq!(buffers.expire(later, timeout), [expired]);
+        assert!(buffers.is_empty());
+
+        // 19 and 20 go missing until the buffer overflows.
+        assert_eq!(push(&mut buffers, 21, later), (vec![], None));
+        assert_eq!(push(&mut buffers, 22, later), (vec![], None));
+        let overflow = (vec![21, 22, 23], Some(19..21));
+        assert_eq!(push(&mut buffers, 23, later), overflow);
+        assert_eq!(push(&mut buffers, 20, later), (vec![], None));
+        assert!(buffers.is_empty());
+    }
+}
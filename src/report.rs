@@ -0,0 +1,51 @@
+use libp2p::PeerId;
+
+/// Why a subscriber was not sent a broadcast.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SkipReason {
+    /// The peer is not a member, see `Behaviour::set_membership`.
+    Denied,
+    /// The peer is cooling down after repeated failures, see `Config::with_circuit_breaker`.
+    CoolingDown,
+    /// The fan-out did not select the peer, see `Behaviour::set_fan_out`.
+    NotSelected,
+}
+
+/// What became of a broadcast for one of the subscribers of its topic.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PeerOutcome {
+    /// The broadcast was handed to a connection to the peer. It may still be shed by
+    /// `Config::drop_policy` or expire before being sent, which is reported as `Event::Shed` and
+    /// `Event::Expired`.
+    Queued,
+    /// The peer receives the broadcast later, with the history it is catching up with.
+    Deferred,
+    /// An interceptor dropped the broadcast.
+    Dropped,
+    /// The broadcast was not sent to the peer.
+    Skipped(SkipReason),
+}
+
+/// What became of a broadcast for each subscriber of its topic, returned by
+/// `Behaviour::broadcast` and its variants.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct BroadcastReport {
+    /// The subscribers of the topic, and the outcome for each of them.
+    pub peers: Vec<(PeerId, PeerOutcome)>,
+    /// Whether the broadcast was kept in the outbox because no subscriber was routable, to be
+    /// published again once one is.
+    pub outboxed: bool,
+    /// The id the subscribers acknowledge the broadcast with on at-least-once topics, as
+    /// reported by `Event::Unacknowledged`. See `Config::with_at_least_once`.
+    pub delivery_id: Option<u64>,
+}
+
+impl BroadcastReport {
+    /// The peers the broadcast was queued for.
+    pub fn queued(&self) -> impl Iterator<Item = &PeerId> + '_ {
+        self.peers
+            .iter()
+            .filter(|(_, outcome)| *outcome == PeerOutcome::Queued)
+            .map(|(peer, _)| peer)
+    }
+}
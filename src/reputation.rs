@@ -0,0 +1,77 @@
+use std::io;
+use std::time::Duration;
+
+use libp2p::PeerId;
+
+use crate::snapshot::{invalid_data, write_len, write_peer, Reader};
+
+/// Peers skipped by the circuit breaker, exported with `Behaviour::reputations` and imported
+/// with `Behaviour::import_reputations`, e.g. to persist them across restarts or to share the
+/// peers to avoid between nodes.
+///
+/// Cooldowns are relative to `taken_at`, the time they were exported in milliseconds since the
+/// Unix epoch, so that the time elapsed until the import counts.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PeerReputations {
+    pub taken_at: u64,
+    /// Peers along with the remaining cooldown.
+    pub cooldowns: Vec<(PeerId, Duration)>,
+}
+
+const FORMAT_VERSION: u8 = 1;
+
+impl PeerReputations {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = vec![FORMAT_VERSION];
+        buf.extend_from_slice(&self.taken_at.to_be_bytes());
+        write_len(&mut buf, self.cooldowns.len());
+        for (peer, remaining) in &self.cooldowns {
+            write_peer(&mut buf, peer);
+            buf.extend_from_slice(&(remaining.as_millis() as u64).to_be_bytes());
+        }
+        buf
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<Self> {
+        let mut reader = Reader(bytes);
+        let version = reader.take(1)?[0];
+        if version != FORMAT_VERSION {
+            return Err(invalid_data(format!(
+                "unknown reputations version {version}"
+            )));
+        }
+        let mut reputations = Self {
+            taken_at: reader.u64()?,
+            ..Self::default()
+        };
+        for _ in 0..reader.len()? {
+            let peer = reader.peer()?;
+            reputations
+                .cooldowns
+                .push((peer, Duration::from_millis(reader.u64()?)));
+        }
+        if !reader.0.is_empty() {
+            return Err(invalid_data("trailing bytes after reputations"));
+        }
+        Ok(reputations)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reputations_roundtrip() {
+        let reputations = PeerReputations {
+            taken_at: 1_700_000_000_000,
+            cooldowns: vec![
+                (PeerId::random(), Duration::from_secs(3)),
+                (PeerId::random(), Duration::from_millis(250)),
+            ],
+        };
+        let bytes = reputations.to_bytes();
+        assert_eq!(PeerReputations::from_bytes(&bytes).unwrap(), reputations);
+        assert!(PeerReputations::from_bytes(&bytes[1..]).is_err());
+    }
+}
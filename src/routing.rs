@@ -0,0 +1,216 @@
+use std::fmt;
+use std::sync::Arc;
+
+use fnv::{FnvHashMap, FnvHashSet};
+use libp2p::PeerId;
+
+use crate::intern::{TopicId, TopicInterner};
+use crate::matcher::{ExactMatcher, TopicMatcher};
+use crate::types::Topic;
+
+/// Set of peers subscribed to a topic, reference counted so that routing hands it out without
+/// copying it.
+pub(crate) type Subscribers = Arc<FnvHashSet<PeerId>>;
+
+/// Subscription state of the connected peers, indexed both by topic and by peer.
+///
+/// The per-topic peer sets are copy-on-write: routing a publish clones a reference to the
+/// subscribers rather than the set, and a subscription change only copies a set if a route of it
+/// is still held. The table is owned by the behaviour and only ever used from its task, so this
+/// saves copying the subscribers of every publish, not contention. Empty topic entries are
+/// removed so the table does not grow with topics nobody subscribes to anymore.
+///
+/// Both indexes key on the interned ids of the topics, held once per subscription, rather than
+/// on the topics themselves. That saves memory, not lookups: routing a topic hashes it to find
+/// its id, then hashes the id.
+///
+/// Broadcasts are routed to the subscriptions the matcher accepts. Unless it is exact, routing
+/// scans the subscribed topics and builds a new set of peers.
+pub(crate) struct RoutingTable {
+    interner: TopicInterner,
+    topics: FnvHashMap<TopicId, Subscribers>,
+    peers: FnvHashMap<PeerId, FnvHashSet<TopicId>>,
+    matcher: Arc<dyn TopicMatcher>,
+}
+
+impl Default for RoutingTable {
+    fn default() -> Self {
+        Self::new(Arc::new(ExactMatcher))
+    }
+}
+
+impl fmt::Debug for RoutingTable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let topics: FnvHashMap<_, _> = self
+            .topics
+            .iter()
+            .map(|(id, subscribers)| (self.interner.resolve(*id), subscribers))
+            .collect();
+        let peers: FnvHashMap<_, Vec<_>> = self
+            .peers
+            .keys()
+            .map(|peer| (peer, self.topics(peer).into_iter().flatten().collect()))
+            .collect();
+        f.debug_struct("RoutingTable")
+            .field("topics", &topics)
+            .field("peers", &peers)
+            .field("exact", &self.matcher.is_exact())
+            .finish()
+    }
+}
+
+impl RoutingTable {
+    pub fn new(matcher: Arc<dyn TopicMatcher>) -> Self {
+        Self {
+            interner: TopicInterner::default(),
+            topics: FnvHashMap::default(),
+            peers: FnvHashMap::default(),
+            matcher,
+        }
+    }
+
+    pub fn add_peer(&mut self, peer: PeerId) {
+        self.peers.entry(peer).or_default();
+    }
+
+    /// Forgets the peer, returning the topics it was subscribed to.
+    pub fn remove_peer(&mut self, peer: &PeerId) -> FnvHashSet<Topic> {
+        let ids = self.peers.remove(peer).unwrap_or_default();
+        let topics = ids.iter().map(|id| *self.interner.resolve(*id)).collect();
+        for id in ids {
+            self.remove_subscriber(id, peer);
+        }
+        topics
+    }
+
+    /// Records the subscription. Returns `false` if the peer was already subscribed.
+    pub fn subscribe(&mut self, peer: PeerId, topic: Topic) -> bool {
+        let topics = self.peers.entry(peer).or_default();
+        if let Some(id) = self.interner.get(&topic) {
+            if topics.contains(&id) {
+                return false;
+            }
+        }
+        let id = self.interner.intern(topic);
+        topics.insert(id);
+        Arc::make_mut(self.topics.entry(id).or_default()).insert(peer);
+        true
+    }
+
+    /// Removes the subscription. Returns `false` if the peer was not subscribed.
+    pub fn unsubscribe(&mut self, peer: &PeerId, topic: &Topic) -> bool {
+        let Some(id) = self.interner.get(topic) else {
+            return false;
+        };
+        let removed = self
+            .peers
+            .get_mut(peer)
+            .is_some_and(|topics| topics.remove(&id));
+        if removed {
+            self.remove_subscriber(id, peer);
+        }
+        removed
+    }
+
+    /// Removes the peer from the subscribers of the topic, releasing the id held by its
+    /// subscription.
+    fn remove_subscriber(&mut self, id: TopicId, peer: &PeerId) {
+        if let Some(subscribers) = self.topics.get_mut(&id) {
+            Arc::make_mut(subscribers).remove(peer);
+            if subscribers.is_empty() {
+                self.topics.remove(&id);
+            }
+        }
+        self.interner.release(id);
+    }
+
+    /// Snapshot of the peers subscribed to a topic matching the topic.
+    pub fn route(&self, topic: &Topic) -> Option<Subscribers> {
+        if self.matcher.is_exact() {
+            let id = self.interner.get(topic)?;
+            return self.topics.get(&id).cloned();
+        }
+        let mut matching = self
+            .topics
+            .iter()
+            .filter(|(id, _)| self.matcher.matches(self.interner.resolve(**id), topic))
+            .map(|(_, peers)| peers);
+        let first = matching.next()?.clone();
+        Some(matching.fold(first, |mut all, peers| {
+            Arc::make_mut(&mut all).extend(peers.iter().copied());
+            all
+        }))
+    }
+
+    /// Returns `true` if the subscription matches the topic.
+    pub fn matches(&self, subscription: &Topic, topic: &Topic) -> bool {
+        self.matcher.matches(subscription, topic)
+    }
+
+    /// Peers subscribed to exactly the topic.
+    pub fn subscribers(&self, topic: &Topic) -> Option<&FnvHashSet<PeerId>> {
+        let id = self.interner.get(topic)?;
+        self.topics.get(&id).map(|peers| peers.as_ref())
+    }
+
+    pub fn topics(&self, peer: &PeerId) -> Option<impl Iterator<Item = &Topic> + '_> {
+        let ids = self.peers.get(peer)?;
+        Some(ids.iter().map(move |id| self.interner.resolve(*id)))
+    }
+
+    pub fn is_connected(&self, peer: &PeerId) -> bool {
+        self.peers.contains_key(peer)
+    }
+
+    /// Connected peers, whether or not they subscribed to anything.
+    pub fn peers(&self) -> impl Iterator<Item = &PeerId> + '_ {
+        self.peers.keys()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::matcher::PatternMatcher;
+
+    #[test]
+    fn test_copy_on_write() {
+        let topic = Topic::new(b"topic");
+        let (a, b) = (PeerId::random(), PeerId::random());
+        let mut table = RoutingTable::default();
+
+        assert!(table.subscribe(a, topic));
+        assert!(!table.subscribe(a, topic));
+        let snapshot = table.route(&topic).unwrap();
+
+        table.subscribe(b, topic);
+        assert_eq!(snapshot.len(), 1);
+        assert_eq!(table.route(&topic).unwrap().len(), 2);
+
+        assert!(table.unsubscribe(&a, &topic));
+        assert!(!table.unsubscribe(&a, &topic));
+        assert_eq!(table.remove_peer(&b).len(), 1);
+        assert!(table.route(&topic).is_none());
+        assert!(table.interner.get(&topic).is_none());
+        assert!(snapshot.contains(&a));
+    }
+
+    #[test]
+    fn test_matcher() {
+        let (a, b) = (PeerId::random(), PeerId::random());
+        let mut table = RoutingTable::new(Arc::new(PatternMatcher));
+        table.subscribe(a, Topic::new(b"shards/#"));
+        table.subscribe(b, Topic::new(b"shards/*/votes"));
+
+        assert_eq!(
+            table.route(&Topic::new(b"shards/1/votes")).unwrap().len(),
+            2
+        );
+        assert_eq!(
+            table.route(&Topic::new(b"shards/1/parts")).unwrap().len(),
+            1
+        );
+        assert!(table.route(&Topic::new(b"other")).is_none());
+    }
+}
@@ -0,0 +1,27 @@
+use std::time::{Duration, Instant};
+
+use crate::drop_policy::QueuedBroadcast;
+
+/// What a `SendScheduler` sends next.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Schedule {
+    /// The message first in line, i.e. the oldest one of the most urgent priority.
+    Next,
+    /// The queued broadcast at this index, or the message first in line if there is none.
+    Pick(usize),
+    /// Nothing for this long, after which the scheduler is asked again.
+    Delay(Duration),
+}
+
+/// Decides when and in which order the messages queued for a peer are sent, e.g. to pace them.
+/// Each connection gets its own scheduler from the factory given to
+/// `Config::with_send_scheduler`. Without one, messages are sent as soon as possible, in FIFO
+/// order within each priority.
+///
+/// Parts of batches too large for the remote are sent ahead of the queue without asking the
+/// scheduler.
+pub trait SendScheduler: Send {
+    /// Called when the handler is about to take a message off the non-empty queue, with the
+    /// queued broadcasts in the order they would be sent.
+    fn schedule(&mut self, queued: &[QueuedBroadcast<'_>], now: Instant) -> Schedule;
+}
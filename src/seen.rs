@@ -0,0 +1,222 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use fnv::FnvHashSet;
+
+use crate::bloom::RotatingBloomFilter;
+use crate::config::Config;
+use crate::types::MessageId;
+
+/// Number of independent hash sets the ids are spread over. Keeping each set small bounds the
+/// cost of any single rehash, so growing the cache never stalls the caller for long.
+const SHARDS: usize = 16;
+
+/// Number of slots in the expiry wheel. Entries expire with a granularity of `ttl / SLOTS`.
+const SLOTS: u32 = 32;
+
+fn shard(id: &MessageId) -> usize {
+    (id.as_u64() % SHARDS as u64) as usize
+}
+
+/// A group of ids inserted during the same slot of the expiry wheel.
+struct Slot {
+    started: Instant,
+    ids: VecDeque<MessageId>,
+}
+
+/// Set of recently seen message ids, bounded both in time and in size. The default
+/// `DuplicateStore`.
+///
+/// Expiry is driven by a time wheel: ids are appended to the slot covering the time they were
+/// inserted, and whole slots are dropped once they are older than the ttl. Every operation
+/// therefore only touches the ids that actually expire, instead of scanning the whole cache.
+pub struct SeenCache {
+    shards: Vec<FnvHashSet<MessageId>>,
+    wheel: VecDeque<Slot>,
+    ttl: Duration,
+    slot_duration: Duration,
+    capacity: usize,
+    len: usize,
+}
+
+impl SeenCache {
+    /// Creates a cache remembering ids for the ttl, and at most `capacity` of them.
+    pub fn new(ttl: Duration, capacity: usize) -> Self {
+        Self {
+            shards: (0..SHARDS).map(|_| FnvHashSet::default()).collect(),
+            wheel: VecDeque::with_capacity(SLOTS as usize + 1),
+            ttl,
+            slot_duration: ttl / SLOTS,
+            capacity,
+            len: 0,
+        }
+    }
+
+    /// Returns `true` if the id has been seen within the ttl.
+    #[cfg(feature = "erasure")]
+    pub fn contains(&self, id: &MessageId) -> bool {
+        self.shards[shard(id)].contains(id)
+    }
+
+    /// Records the id as seen at `now`. Returns `false` if it was already present.
+    pub fn insert(&mut self, id: MessageId, now: Instant) -> bool {
+        self.expire(now);
+
+        let shard = shard(&id);
+        if !self.shards[shard].insert(id) {
+            return false;
+        }
+
+        match self.wheel.back_mut() {
+            Some(slot) if now < slot.started + self.slot_duration => slot.ids.push_back(id),
+            _ => self.wheel.push_back(Slot {
+                started: now,
+                ids: VecDeque::from([id]),
+            }),
+        }
+        self.len += 1;
+
+        while self.len > self.capacity {
+            self.evict_oldest();
+        }
+
+        true
+    }
+
+    /// The ids in the cache along with how long before `now` they were seen, oldest first. Ages
+    /// have the granularity of the slots.
+    pub fn entries(&self, now: Instant) -> impl Iterator<Item = (MessageId, Duration)> + '_ {
+        self.wheel.iter().flat_map(move |slot| {
+            let age = now.saturating_duration_since(slot.started);
+            slot.ids.iter().map(move |id| (*id, age))
+        })
+    }
+
+    /// Drops every slot whose ids are older than the ttl.
+    pub fn expire(&mut self, now: Instant) {
+        while let Some(slot) = self.wheel.front() {
+            if now < slot.started + self.slot_duration + self.ttl {
+                break;
+            }
+            let slot = self.wheel.pop_front().expect("front slot exists");
+            self.len -= slot.ids.len();
+            for id in slot.ids {
+                self.shards[shard(&id)].remove(&id);
+            }
+        }
+    }
+
+    fn evict_oldest(&mut self) {
+        let Some(slot) = self.wheel.front_mut() else {
+            return;
+        };
+        if let Some(id) = slot.ids.pop_front() {
+            self.shards[shard(&id)].remove(&id);
+            self.len -= 1;
+        }
+        if self.wheel.front().is_some_and(|slot| slot.ids.is_empty()) {
+            self.wheel.pop_front();
+        }
+    }
+}
+
+/// Remembers the ids of the broadcasts seen to suppress duplicates, installed with
+/// `Behaviour::set_duplicate_store` in place of the one built from `Config::with_seen_cache`.
+///
+/// `SeenCache` is exact, and `RotatingBloomFilter` bounds memory at the cost of false positives.
+/// Either can be backed by a persistent `SeenStore` to survive restarts.
+pub trait DuplicateStore: Send {
+    /// Records the id as seen at `now`. Returns `false` if it is a duplicate.
+    fn insert(&mut self, id: MessageId, now: Instant) -> bool;
+
+    /// The ids seen along with how long before `now`, oldest first, kept in snapshots. Stores
+    /// that cannot list their ids return none.
+    fn entries(&self, now: Instant) -> Vec<(MessageId, Duration)> {
+        let _ = now;
+        Vec::new()
+    }
+}
+
+impl DuplicateStore for SeenCache {
+    fn insert(&mut self, id: MessageId, now: Instant) -> bool {
+        SeenCache::insert(self, id, now)
+    }
+
+    fn entries(&self, now: Instant) -> Vec<(MessageId, Duration)> {
+        SeenCache::entries(self, now).collect()
+    }
+}
+
+impl DuplicateStore for RotatingBloomFilter {
+    fn insert(&mut self, id: MessageId, now: Instant) -> bool {
+        RotatingBloomFilter::insert(self, id, now)
+    }
+}
+
+/// Builds the duplicate store requested by `config`, if duplicate suppression is enabled.
+pub(crate) fn from_config(config: &Config) -> Option<Box<dyn DuplicateStore>> {
+    let ttl = config.seen_ttl?;
+    Some(match config.seen_false_positive_rate {
+        Some(fp_rate) => Box::new(RotatingBloomFilter::new(ttl, config.seen_capacity, fp_rate)),
+        None => Box::new(SeenCache::new(ttl, config.seen_capacity)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expiry() {
+        let ttl = Duration::from_secs(32);
+        let mut cache = SeenCache::new(ttl, 100);
+        let start = Instant::now();
+
+        assert!(cache.insert(MessageId::new(1), start));
+        assert!(!cache.insert(MessageId::new(1), start + Duration::from_secs(10)));
+        assert!(cache.insert(MessageId::new(2), start + Duration::from_secs(20)));
+
+        cache.expire(start + ttl + Duration::from_secs(2));
+        assert_eq!(cache.len, 1);
+        assert!(cache.insert(MessageId::new(1), start + ttl + Duration::from_secs(2)));
+        assert!(!cache.insert(MessageId::new(2), start + ttl + Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn test_capacity() {
+        let mut cache = SeenCache::new(Duration::from_secs(60), 3);
+        let now = Instant::now();
+
+        for id in 0..10 {
+            assert!(cache.insert(MessageId::new(id), now));
+        }
+        assert_eq!(cache.len, 3);
+        assert_eq!(cache.shards.iter().map(|s| s.len()).sum::<usize>(), 3);
+        assert!(!cache.insert(MessageId::new(9), now));
+    }
+
+    #[test]
+    fn test_from_config() {
+        assert!(from_config(&Config::default()).is_none());
+
+        let ttl = Duration::from_secs(10);
+        for config in [
+            Config::default().with_seen_false_positive_rate(0.01),
+            Config::default()
+                .with_seen_cache(ttl, 1000)
+                .with_seen_false_positive_rate(0.01),
+            Config::default()
+                .with_seen_false_positive_rate(0.01)
+                .with_seen_cache(ttl, 1000),
+        ] {
+            let mut store = from_config(&config).unwrap();
+            let now = Instant::now();
+            assert!(store.insert(MessageId::new(1), now));
+            assert!(!store.insert(MessageId::new(1), now));
+        }
+        let config = Config::default()
+            .with_seen_cache(ttl, 1000)
+            .with_seen_false_positive_rate(0.01);
+        assert_eq!(config.seen_ttl, Some(ttl));
+    }
+}
@@ -0,0 +1,141 @@
+#[cfg(feature = "sled")]
+use std::convert::TryInto;
+use std::io;
+
+use fnv::FnvHashMap;
+
+use crate::types::MessageId;
+
+/// Keeps the ids of the messages seen recently, so that duplicate suppression survives restarts
+/// when the store is persistent. Installed with `Behaviour::set_seen_store`.
+///
+/// Times are in milliseconds since the Unix epoch.
+pub trait SeenStore: Send {
+    /// Records the id as seen at the given time.
+    fn insert(&mut self, id: MessageId, seen_at: u64) -> io::Result<()>;
+
+    /// Removes the ids seen before the given time.
+    fn prune(&mut self, before: u64) -> io::Result<()>;
+
+    /// Returns the ids kept along with the time they were seen at, in any order.
+    fn load(&mut self) -> io::Result<Vec<(MessageId, u64)>>;
+}
+
+/// Keeps the seen ids in memory, e.g. to share them between behaviours of the same process.
+#[derive(Debug, Default)]
+pub struct MemorySeenStore {
+    ids: FnvHashMap<MessageId, u64>,
+}
+
+impl MemorySeenStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl SeenStore for MemorySeenStore {
+    fn insert(&mut self, id: MessageId, seen_at: u64) -> io::Result<()> {
+        self.ids.insert(id, seen_at);
+        Ok(())
+    }
+
+    fn prune(&mut self, before: u64) -> io::Result<()> {
+        self.ids.retain(|_, seen_at| *seen_at >= before);
+        Ok(())
+    }
+
+    fn load(&mut self) -> io::Result<Vec<(MessageId, u64)>> {
+        Ok(self
+            .ids
+            .iter()
+            .map(|(id, seen_at)| (*id, *seen_at))
+            .collect())
+    }
+}
+
+/// Keeps the seen ids in a sled tree, keyed by id with the time as value, both big-endian.
+#[cfg(feature = "sled")]
+#[derive(Debug)]
+pub struct SledSeenStore {
+    tree: sled::Tree,
+}
+
+#[cfg(feature = "sled")]
+impl SledSeenStore {
+    const TREE: &'static str = "libp2p-broadcast-seen";
+
+    /// Opens the store in a tree of the database, which may be shared with the application or
+    /// a `SledOutbox`.
+    pub fn open(db: sled::Db) -> io::Result<Self> {
+        Ok(Self {
+            tree: db.open_tree(Self::TREE)?,
+        })
+    }
+}
+
+#[cfg(feature = "sled")]
+fn decode_u64(bytes: &[u8]) -> io::Result<u64> {
+    bytes
+        .try_into()
+        .map(u64::from_be_bytes)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid seen store entry"))
+}
+
+#[cfg(feature = "sled")]
+impl SeenStore for SledSeenStore {
+    fn insert(&mut self, id: MessageId, seen_at: u64) -> io::Result<()> {
+        self.tree
+            .insert(id.as_u64().to_be_bytes(), &seen_at.to_be_bytes())?;
+        Ok(())
+    }
+
+    fn prune(&mut self, before: u64) -> io::Result<()> {
+        for (id, seen_at) in self.load()? {
+            if seen_at < before {
+                self.tree.remove(id.as_u64().to_be_bytes())?;
+            }
+        }
+        self.tree.flush()?;
+        Ok(())
+    }
+
+    fn load(&mut self) -> io::Result<Vec<(MessageId, u64)>> {
+        let mut ids = Vec::new();
+        for entry in self.tree.iter() {
+            let (key, value) = entry?;
+            ids.push((MessageId::new(decode_u64(&key)?), decode_u64(&value)?));
+        }
+        Ok(ids)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check_store(store: &mut impl SeenStore) {
+        store.insert(MessageId::new(1), 1_000).unwrap();
+        store.insert(MessageId::new(2), 2_000).unwrap();
+        store.insert(MessageId::new(1), 3_000).unwrap();
+        let mut ids = store.load().unwrap();
+        ids.sort();
+        assert_eq!(
+            ids,
+            [(MessageId::new(1), 3_000), (MessageId::new(2), 2_000)]
+        );
+        store.prune(2_500).unwrap();
+        assert_eq!(store.load().unwrap(), [(MessageId::new(1), 3_000)]);
+    }
+
+    #[test]
+    fn test_memory_seen_store() {
+        check_store(&mut MemorySeenStore::new());
+    }
+
+    #[cfg(feature = "sled")]
+    #[test]
+    fn test_sled_seen_store() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        check_store(&mut SledSeenStore::open(db).unwrap());
+    }
+}
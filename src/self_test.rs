@@ -0,0 +1,164 @@
+use std::convert::TryInto;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use fnv::FnvHashSet;
+use libp2p::PeerId;
+
+/// Outcome of a probe of the self-test, see `Config::with_self_test`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct SelfTestResult {
+    /// Whether the probe was sent to at least one peer, and echoed by at least one of them
+    /// within the deadline.
+    pub passed: bool,
+    /// Number of peers the probe was sent to.
+    pub probed: usize,
+    /// Number of peers that echoed the probe within the deadline.
+    pub echoes: usize,
+    /// Time until the first echo arrived.
+    pub round_trip: Option<Duration>,
+}
+
+/// Payload of the broadcasts on `SelfTest::TOPIC`: a kind followed by the id of the probe.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum ProbeFrame {
+    Probe(u64),
+    Echo(u64),
+}
+
+impl ProbeFrame {
+    const PROBE: u8 = 0;
+    const ECHO: u8 = 1;
+
+    pub fn encode(self) -> Bytes {
+        let (kind, id) = match self {
+            ProbeFrame::Probe(id) => (Self::PROBE, id),
+            ProbeFrame::Echo(id) => (Self::ECHO, id),
+        };
+        let mut buf = Vec::with_capacity(9);
+        buf.push(kind);
+        buf.extend_from_slice(&id.to_be_bytes());
+        buf.into()
+    }
+
+    pub fn decode(payload: &[u8]) -> Option<Self> {
+        let (kind, id) = payload.split_first()?;
+        let id = u64::from_be_bytes(id.try_into().ok()?);
+        match *kind {
+            Self::PROBE => Some(ProbeFrame::Probe(id)),
+            Self::ECHO => Some(ProbeFrame::Echo(id)),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Probe {
+    id: u64,
+    sent_at: Instant,
+    probed: usize,
+    echoed: FnvHashSet<PeerId>,
+    round_trip: Option<Duration>,
+}
+
+/// The probe in flight and the outcome of the last one.
+#[derive(Debug, Default)]
+pub(crate) struct Prober {
+    next_id: u64,
+    pending: Option<Probe>,
+    last: Option<SelfTestResult>,
+}
+
+impl Prober {
+    /// Starts a new probe, returning its payload. The number of peers it was sent to is set
+    /// with `Prober::sent`.
+    pub fn start(&mut self, now: Instant) -> Bytes {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        self.pending = Some(Probe {
+            id,
+            sent_at: now,
+            probed: 0,
+            echoed: FnvHashSet::default(),
+            round_trip: None,
+        });
+        ProbeFrame::Probe(id).encode()
+    }
+
+    pub fn sent(&mut self, probed: usize) {
+        if let Some(probe) = &mut self.pending {
+            probe.probed = probed;
+        }
+    }
+
+    /// Records an echo from the peer. Echoes of earlier probes are ignored.
+    pub fn echo(&mut self, peer: PeerId, id: u64, now: Instant) {
+        let Some(probe) = self.pending.as_mut().filter(|probe| probe.id == id) else {
+            return;
+        };
+        if probe.echoed.insert(peer) && probe.round_trip.is_none() {
+            probe.round_trip = Some(now.saturating_duration_since(probe.sent_at));
+        }
+    }
+
+    pub fn is_probing(&self) -> bool {
+        self.pending.is_some()
+    }
+
+    /// Ends the probe in flight once its deadline passed, returning its outcome.
+    pub fn finish(&mut self) -> Option<SelfTestResult> {
+        let probe = self.pending.take()?;
+        let result = SelfTestResult {
+            passed: probe.probed > 0 && !probe.echoed.is_empty(),
+            probed: probe.probed,
+            echoes: probe.echoed.len(),
+            round_trip: probe.round_trip,
+        };
+        self.last = Some(result);
+        Some(result)
+    }
+
+    /// Outcome of the last probe that ended.
+    pub fn last(&self) -> Option<SelfTestResult> {
+        self.last
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prober() {
+        let start = Instant::now();
+        let (a, b) = (PeerId::random(), PeerId::random());
+        let mut prober = Prober::default();
+        let probe = prober.start(start);
+        assert_eq!(ProbeFrame::decode(&probe), Some(ProbeFrame::Probe(0)));
+        assert_eq!(ProbeFrame::decode(&probe[..5]), None);
+        prober.sent(2);
+
+        let later = start + Duration::from_millis(30);
+        prober.echo(a, 1, later);
+        prober.echo(a, 0, later);
+        prober.echo(a, 0, later + Duration::from_millis(10));
+        let result = prober.finish().unwrap();
+        assert_eq!(
+            result,
+            SelfTestResult {
+                passed: true,
+                probed: 2,
+                echoes: 1,
+                round_trip: Some(Duration::from_millis(30)),
+            }
+        );
+        assert!(prober.finish().is_none());
+
+        // Echoes arriving after the deadline do not count.
+        prober.start(later);
+        prober.sent(1);
+        assert!(!prober.finish().unwrap().passed);
+        prober.echo(b, 1, later);
+        assert_eq!(prober.last().map(|result| result.echoes), Some(0));
+    }
+}
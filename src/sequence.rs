@@ -0,0 +1,120 @@
+use fnv::FnvHashMap;
+use libp2p::PeerId;
+
+use crate::types::Topic;
+
+/// The sequence numbers recently received from a publisher on a topic: the highest one, and a
+/// bit for each of the `size` numbers up to it.
+#[derive(Debug)]
+struct Window {
+    highest: u64,
+    bits: Vec<u64>,
+}
+
+impl Window {
+    fn new(size: u64) -> Self {
+        Self {
+            highest: 0,
+            bits: vec![0; size.div_ceil(64) as usize],
+        }
+    }
+
+    fn bit(seq: u64, size: u64) -> (usize, u64) {
+        let index = seq % size;
+        ((index / 64) as usize, 1 << (index % 64))
+    }
+
+    fn is_set(&self, seq: u64, size: u64) -> bool {
+        let (word, mask) = Self::bit(seq, size);
+        self.bits[word] & mask != 0
+    }
+
+    fn set(&mut self, seq: u64, size: u64) {
+        let (word, mask) = Self::bit(seq, size);
+        self.bits[word] |= mask;
+    }
+
+    fn clear(&mut self, seq: u64, size: u64) {
+        let (word, mask) = Self::bit(seq, size);
+        self.bits[word] &= !mask;
+    }
+}
+
+/// Sequence numbers received from each publisher on each topic within the last `size` ones,
+/// see `Config::with_delivery_window`.
+#[derive(Debug)]
+pub(crate) struct DeliveryWindow {
+    size: u64,
+    publishers: FnvHashMap<(PeerId, Topic), Window>,
+}
+
+impl DeliveryWindow {
+    pub fn new(size: u64) -> Self {
+        assert!(size > 0, "window must not be empty");
+        Self {
+            size,
+            publishers: FnvHashMap::default(),
+        }
+    }
+
+    /// Records the sequence number of a broadcast of the publisher on the topic. Returns `false`
+    /// if it was received already, or is too old to tell.
+    pub fn insert(&mut self, publisher: PeerId, topic: Topic, seq: u64) -> bool {
+        let size = self.size;
+        let Some(window) = self.publishers.get_mut(&(publisher, topic)) else {
+            let mut window = Window::new(size);
+            window.highest = seq;
+            window.set(seq, size);
+            self.publishers.insert((publisher, topic), window);
+            return true;
+        };
+        if seq > window.highest {
+            if seq - window.highest >= size {
+                window.bits.fill(0);
+            } else {
+                for skipped in window.highest + 1..seq {
+                    window.clear(skipped, size);
+                }
+            }
+            window.highest = seq;
+            window.set(seq, size);
+            return true;
+        }
+        if window.highest - seq >= size || window.is_set(seq, size) {
+            return false;
+        }
+        window.set(seq, size);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_window() {
+        let (a, b) = (PeerId::random(), PeerId::random());
+        let (topic, other) = (Topic::new(b"topic"), Topic::new(b"other"));
+        let mut window = DeliveryWindow::new(100);
+        assert!(window.insert(a, topic, 1_000));
+        assert!(!window.insert(a, topic, 1_000));
+        assert!(window.insert(b, topic, 1_000));
+        assert!(window.insert(a, other, 1_000));
+
+        // Gaps are filled in later, duplicates are not.
+        assert!(window.insert(a, topic, 1_005));
+        assert!(window.insert(a, topic, 1_002));
+        assert!(!window.insert(a, topic, 1_002));
+
+        // Past the window, sequence numbers cannot be told apart from duplicates.
+        assert!(window.insert(a, topic, 1_100));
+        assert!(!window.insert(a, topic, 1_000));
+        assert!(window.insert(a, topic, 1_001));
+
+        // The bits of the numbers skipped over are cleared for reuse.
+        assert!(window.insert(a, topic, 1_500));
+        assert!(window.insert(a, topic, 1_499));
+        assert!(window.insert(a, topic, 1_405));
+    }
+}
@@ -0,0 +1,164 @@
+use std::convert::TryInto;
+use std::io;
+use std::time::Duration;
+
+use libp2p::PeerId;
+
+use crate::types::{MessageId, Topic};
+
+/// State of a behaviour that is worth keeping across restarts, taken with `Behaviour::snapshot`
+/// and restored with `Behaviour::restore`.
+///
+/// Durations are relative to `taken_at`, the time the snapshot was taken in milliseconds since
+/// the Unix epoch, so that the time spent down counts when restoring.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BehaviourSnapshot {
+    pub taken_at: u64,
+    /// Topics we are subscribed to.
+    pub subscriptions: Vec<Topic>,
+    /// Topics the connected peers were subscribed to.
+    pub peers: Vec<(PeerId, Vec<Topic>)>,
+    /// Ids of the messages seen recently, along with how long ago. Only kept with an exact
+    /// duplicate filter, a probabilistic one cannot list its ids.
+    pub seen: Vec<(MessageId, Duration)>,
+    /// Peers skipped by the circuit breaker, along with the remaining cooldown.
+    pub cooldowns: Vec<(PeerId, Duration)>,
+}
+
+const FORMAT_VERSION: u8 = 1;
+
+impl BehaviourSnapshot {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = vec![FORMAT_VERSION];
+        buf.extend_from_slice(&self.taken_at.to_be_bytes());
+        write_topics(&mut buf, &self.subscriptions);
+        write_len(&mut buf, self.peers.len());
+        for (peer, topics) in &self.peers {
+            write_peer(&mut buf, peer);
+            write_topics(&mut buf, topics);
+        }
+        write_len(&mut buf, self.seen.len());
+        for (id, age) in &self.seen {
+            buf.extend_from_slice(&id.as_u64().to_be_bytes());
+            buf.extend_from_slice(&(age.as_millis() as u64).to_be_bytes());
+        }
+        write_len(&mut buf, self.cooldowns.len());
+        for (peer, remaining) in &self.cooldowns {
+            write_peer(&mut buf, peer);
+            buf.extend_from_slice(&(remaining.as_millis() as u64).to_be_bytes());
+        }
+        buf
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<Self> {
+        let mut reader = Reader(bytes);
+        let version = reader.take(1)?[0];
+        if version != FORMAT_VERSION {
+            return Err(invalid_data(format!("unknown snapshot version {version}")));
+        }
+        let mut snapshot = Self {
+            taken_at: reader.u64()?,
+            subscriptions: reader.topics()?,
+            ..Self::default()
+        };
+        for _ in 0..reader.len()? {
+            let peer = reader.peer()?;
+            snapshot.peers.push((peer, reader.topics()?));
+        }
+        for _ in 0..reader.len()? {
+            let id = MessageId::new(reader.u64()?);
+            snapshot
+                .seen
+                .push((id, Duration::from_millis(reader.u64()?)));
+        }
+        for _ in 0..reader.len()? {
+            let peer = reader.peer()?;
+            snapshot
+                .cooldowns
+                .push((peer, Duration::from_millis(reader.u64()?)));
+        }
+        if !reader.0.is_empty() {
+            return Err(invalid_data("trailing bytes after snapshot"));
+        }
+        Ok(snapshot)
+    }
+}
+
+pub(crate) fn write_len(buf: &mut Vec<u8>, len: usize) {
+    buf.extend_from_slice(&(len as u32).to_be_bytes());
+}
+
+fn write_topics(buf: &mut Vec<u8>, topics: &[Topic]) {
+    write_len(buf, topics.len());
+    for topic in topics {
+        buf.push(topic.len() as u8);
+        buf.extend_from_slice(topic);
+    }
+}
+
+pub(crate) fn write_peer(buf: &mut Vec<u8>, peer: &PeerId) {
+    let bytes = peer.to_bytes();
+    buf.push(bytes.len() as u8);
+    buf.extend_from_slice(&bytes);
+}
+
+pub(crate) fn invalid_data(error: impl ToString) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error.to_string())
+}
+
+pub(crate) struct Reader<'a>(pub &'a [u8]);
+
+impl<'a> Reader<'a> {
+    pub fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if self.0.len() < len {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let (head, tail) = self.0.split_at(len);
+        self.0 = tail;
+        Ok(head)
+    }
+
+    pub fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub fn len(&mut self) -> io::Result<usize> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()) as usize)
+    }
+
+    fn topics(&mut self) -> io::Result<Vec<Topic>> {
+        let mut topics = Vec::new();
+        for _ in 0..self.len()? {
+            let len = self.take(1)?[0] as usize;
+            if len > Topic::MAX_TOPIC_LENGTH {
+                return Err(invalid_data("topic too long"));
+            }
+            topics.push(Topic::new(self.take(len)?));
+        }
+        Ok(topics)
+    }
+
+    pub fn peer(&mut self) -> io::Result<PeerId> {
+        let len = self.take(1)?[0] as usize;
+        PeerId::from_bytes(self.take(len)?).map_err(invalid_data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snapshot_roundtrip() {
+        let snapshot = BehaviourSnapshot {
+            taken_at: 1_700_000_000_000,
+            subscriptions: vec![Topic::new(b"a"), Topic::new(b"b")],
+            peers: vec![(PeerId::random(), vec![Topic::new(b"a")])],
+            seen: vec![(MessageId::new(42), Duration::from_millis(1500))],
+            cooldowns: vec![(PeerId::random(), Duration::from_secs(3))],
+        };
+        let bytes = snapshot.to_bytes();
+        assert_eq!(BehaviourSnapshot::from_bytes(&bytes).unwrap(), snapshot);
+        assert!(BehaviourSnapshot::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+    }
+}
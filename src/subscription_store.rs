@@ -0,0 +1,112 @@
+use std::io;
+
+use crate::types::Topic;
+
+/// Keeps the topics we are subscribed to, so that a restarting node subscribes to them again
+/// without the application driving it. Installed with `Behaviour::set_subscription_store`.
+pub trait SubscriptionStore: Send {
+    /// Replaces the stored topics, called on every change to the subscriptions.
+    fn save(&mut self, topics: &[Topic]) -> io::Result<()>;
+
+    /// Returns the stored topics, in any order.
+    fn load(&mut self) -> io::Result<Vec<Topic>>;
+}
+
+/// Keeps the subscriptions in memory, e.g. to carry them over to a new behaviour.
+#[derive(Debug, Default)]
+pub struct MemorySubscriptionStore {
+    topics: Vec<Topic>,
+}
+
+impl MemorySubscriptionStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl SubscriptionStore for MemorySubscriptionStore {
+    fn save(&mut self, topics: &[Topic]) -> io::Result<()> {
+        self.topics = topics.to_vec();
+        Ok(())
+    }
+
+    fn load(&mut self) -> io::Result<Vec<Topic>> {
+        Ok(self.topics.clone())
+    }
+}
+
+/// Keeps the subscriptions in a sled tree, one key per topic.
+#[cfg(feature = "sled")]
+#[derive(Debug)]
+pub struct SledSubscriptionStore {
+    tree: sled::Tree,
+}
+
+#[cfg(feature = "sled")]
+impl SledSubscriptionStore {
+    const TREE: &'static str = "libp2p-broadcast-subscriptions";
+
+    /// Opens the store in a tree of the database, which may be shared with the application or
+    /// the other sled stores.
+    pub fn open(db: sled::Db) -> io::Result<Self> {
+        Ok(Self {
+            tree: db.open_tree(Self::TREE)?,
+        })
+    }
+}
+
+#[cfg(feature = "sled")]
+impl SubscriptionStore for SledSubscriptionStore {
+    fn save(&mut self, topics: &[Topic]) -> io::Result<()> {
+        let mut batch = sled::Batch::default();
+        for key in self.tree.iter().keys() {
+            batch.remove(key?);
+        }
+        for topic in topics {
+            batch.insert(topic.as_ref(), sled::IVec::default());
+        }
+        self.tree.apply_batch(batch)?;
+        self.tree.flush()?;
+        Ok(())
+    }
+
+    fn load(&mut self) -> io::Result<Vec<Topic>> {
+        let mut topics = Vec::new();
+        for key in self.tree.iter().keys() {
+            let key = key?;
+            if key.len() > Topic::MAX_TOPIC_LENGTH {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "topic too long"));
+            }
+            topics.push(Topic::new(&key));
+        }
+        Ok(topics)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check_store(store: &mut impl SubscriptionStore) {
+        assert!(store.load().unwrap().is_empty());
+        let (a, b) = (Topic::new(b"a"), Topic::new(b"b"));
+        store.save(&[a, b]).unwrap();
+        let mut topics = store.load().unwrap();
+        topics.sort();
+        assert_eq!(topics, [a, b]);
+        store.save(&[b]).unwrap();
+        assert_eq!(store.load().unwrap(), [b]);
+    }
+
+    #[test]
+    fn test_memory_subscription_store() {
+        check_store(&mut MemorySubscriptionStore::new());
+    }
+
+    #[cfg(feature = "sled")]
+    #[test]
+    fn test_sled_subscription_store() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        check_store(&mut SledSubscriptionStore::open(db).unwrap());
+    }
+}
@@ -0,0 +1,23 @@
+/// Which way a substream carries messages.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Direction {
+    /// Opened by the remote, carrying its messages to us.
+    Inbound,
+    /// Opened by us, carrying our messages to the remote.
+    Outbound,
+}
+
+/// A change in the lifecycle of a substream of a connection, reported with
+/// `Config::with_substream_events` to correlate delivery gaps with connection churn.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum SubstreamEvent {
+    /// The first substream in this direction was opened on the connection.
+    Opened(Direction),
+    /// A substream in this direction was opened again after the previous one closed or failed.
+    Reopened(Direction),
+    /// The substream was closed, by the remote or because it failed with the given reason.
+    /// Queued messages are sent over the next outbound substream.
+    Closed(Direction, Option<String>),
+    /// Negotiating a substream failed for the given reason.
+    Failed(Direction, String),
+}
@@ -0,0 +1,25 @@
+//! Targets of the tracing events, so that the verbosity of each layer can be set on its own,
+//! e.g. with `RUST_LOG=broadcast=info,broadcast::handler=trace`.
+
+/// Routing, delivery and everything else the behaviour does. Events about a peer or a topic are
+/// recorded in spans carrying them as `peer` and `topic` fields.
+#[cfg(any(feature = "behaviour", feature = "spill"))]
+pub(crate) const BEHAVIOUR: &str = "broadcast::behaviour";
+
+/// The connection handlers and their substreams, in spans carrying `peer` and `connection`.
+#[cfg(feature = "behaviour")]
+pub(crate) const HANDLER: &str = "broadcast::handler";
+
+/// Faults injected by the `chaos` feature.
+#[cfg(feature = "chaos")]
+pub(crate) const CHAOS: &str = "broadcast::chaos";
+
+/// Encoding and decoding of frames.
+#[cfg(any(feature = "behaviour", feature = "wire-only"))]
+pub(crate) const CODEC: &str = "broadcast::codec";
+
+/// The topic as a `topic` span field, with invalid UTF-8 replaced.
+#[cfg(feature = "behaviour")]
+pub(crate) fn topic(topic: &crate::types::Topic) -> std::borrow::Cow<'_, str> {
+    String::from_utf8_lossy(topic.as_ref())
+}
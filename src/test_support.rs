@@ -0,0 +1,43 @@
+//! Fixtures shared by the tests of several modules.
+
+use std::sync::{Arc, Mutex};
+use std::task::Poll;
+use std::time::{Duration, Instant};
+
+use crate::clock::Clock;
+use crate::timer::{Sleep, Timer};
+
+/// Clock that only moves when advanced, shared between the test and the config it is set on.
+#[derive(Debug)]
+pub(crate) struct ManualClock(Mutex<Instant>);
+
+impl ManualClock {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self(Mutex::new(Instant::now())))
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.0.lock().unwrap() += by;
+    }
+}
+
+impl Clock for Arc<ManualClock> {
+    fn now(&self) -> Instant {
+        *self.0.lock().unwrap()
+    }
+}
+
+/// Sleeps until the clock is advanced past the deadline. The task is not woken up, the tests
+/// poll again after advancing the clock.
+impl Timer for Arc<ManualClock> {
+    fn sleep(&self, duration: Duration) -> Sleep {
+        let clock = self.clone();
+        let deadline = clock.now() + duration;
+        Box::pin(futures::future::poll_fn(move |_| {
+            if clock.now() < deadline {
+                return Poll::Pending;
+            }
+            Poll::Ready(())
+        }))
+    }
+}
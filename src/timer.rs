@@ -0,0 +1,59 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Duration;
+
+use futures_timer::Delay;
+
+/// Future completing once the duration passed to `Timer::sleep` elapsed.
+///
+/// It is `Send` on every target, as the connection handlers holding it are. The browser timers
+/// are not, see `FuturesTimer` for how to use them on `wasm32-unknown-unknown`.
+pub type Sleep = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Creates the timers driving the time-dependent features of the behaviour, such as periodic
+/// resyncs, jittered announcements and injected delays.
+///
+/// The default, `FuturesTimer`, runs its own timer thread and works with any executor. To use
+/// the timers of your runtime instead, pass `TokioTimer` or `AsyncStdTimer` to
+/// `Config::with_timer`, behind the features of the same name, or implement this trait.
+pub trait Timer: Send + Sync {
+    fn sleep(&self, duration: Duration) -> Sleep;
+}
+
+/// Timer backed by `futures-timer`.
+///
+/// On `wasm32-unknown-unknown`, where no timer thread can be spawned, enable the `wasm-bindgen`
+/// feature for it to use the timers of the browser instead. These are wrapped to be `Send`,
+/// which is sound as the target is single-threaded.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FuturesTimer;
+
+impl Timer for FuturesTimer {
+    fn sleep(&self, duration: Duration) -> Sleep {
+        Box::pin(Delay::new(duration))
+    }
+}
+
+/// Timer backed by tokio, for use within a tokio runtime.
+#[cfg(feature = "tokio")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TokioTimer;
+
+#[cfg(feature = "tokio")]
+impl Timer for TokioTimer {
+    fn sleep(&self, duration: Duration) -> Sleep {
+        Box::pin(tokio::time::sleep(duration))
+    }
+}
+
+/// Timer backed by async-std.
+#[cfg(feature = "async-std")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AsyncStdTimer;
+
+#[cfg(feature = "async-std")]
+impl Timer for AsyncStdTimer {
+    fn sleep(&self, duration: Duration) -> Sleep {
+        Box::pin(async_std::task::sleep(duration))
+    }
+}
@@ -0,0 +1,98 @@
+use std::time::{Duration, Instant};
+
+/// Activity on a topic, see `Behaviour::topic_stats`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct TopicStats {
+    /// Number of connected peers subscribed to the topic.
+    pub subscribers: usize,
+    /// Broadcasts published or received on the topic per second, averaged over the last
+    /// `TopicStats::WINDOW` or so.
+    pub message_rate: f64,
+    /// Bytes of the broadcasts published or received on the topic per second, averaged alike.
+    pub byte_rate: f64,
+    /// When a broadcast was last published or received on the topic.
+    pub last_activity: Option<Instant>,
+}
+
+impl TopicStats {
+    /// Time constant of the rates: the older broadcasts weigh exponentially less.
+    pub const WINDOW: Duration = Duration::from_secs(10);
+}
+
+/// Number of `TopicStats::WINDOW`s a topic stays idle before its activity is forgotten, by when
+/// its rates decayed to a fraction of a percent.
+const IDLE_WINDOWS: u32 = 6;
+
+/// Exponentially decayed counts of the broadcasts on a topic, from which the rates derive
+/// without keeping the individual broadcasts.
+#[derive(Debug)]
+pub(crate) struct TopicActivity {
+    messages: f64,
+    bytes: f64,
+    last: Instant,
+}
+
+impl TopicActivity {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            messages: 0.0,
+            bytes: 0.0,
+            last: now,
+        }
+    }
+
+    fn decay(&self, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        (-elapsed / TopicStats::WINDOW.as_secs_f64()).exp()
+    }
+
+    pub fn record(&mut self, bytes: usize, now: Instant) {
+        let decay = self.decay(now);
+        self.messages = self.messages * decay + 1.0;
+        self.bytes = self.bytes * decay + bytes as f64;
+        self.last = now;
+    }
+
+    /// Returns `true` once nothing was published or received on the topic for long enough that
+    /// its rates are all but zero.
+    pub fn is_idle(&self, now: Instant) -> bool {
+        now.saturating_duration_since(self.last) >= TopicStats::WINDOW * IDLE_WINDOWS
+    }
+
+    /// Fills in the rates and last activity of the stats.
+    pub fn stats(&self, subscribers: usize, now: Instant) -> TopicStats {
+        let scale = self.decay(now) / TopicStats::WINDOW.as_secs_f64();
+        TopicStats {
+            subscribers,
+            message_rate: self.messages * scale,
+            byte_rate: self.bytes * scale,
+            last_activity: Some(self.last),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rates() {
+        let start = Instant::now();
+        let mut activity = TopicActivity::new(start);
+        // 100 broadcasts of 10 bytes per second, for a minute.
+        for i in 1..=6000 {
+            activity.record(10, start + Duration::from_millis(10 * i));
+        }
+        let now = start + Duration::from_secs(60);
+        let stats = activity.stats(3, now);
+        assert_eq!(stats.subscribers, 3);
+        assert!((stats.message_rate - 100.0).abs() < 1.0);
+        assert!((stats.byte_rate - 1000.0).abs() < 10.0);
+        assert_eq!(stats.last_activity, Some(now));
+
+        let later = activity.stats(3, now + TopicStats::WINDOW * 5);
+        assert!(later.message_rate < 1.0);
+        assert!(!activity.is_idle(now + TopicStats::WINDOW * 5));
+        assert!(activity.is_idle(now + TopicStats::WINDOW * IDLE_WINDOWS));
+    }
+}
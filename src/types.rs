@@ -1,22 +1,48 @@
 use std::{
-    fmt,
+    borrow::Cow,
+    hash::Hasher,
+    ops::{BitAnd, BitOr},
+    time::{SystemTime, UNIX_EPOCH},
+};
+#[cfg(any(feature = "behaviour", feature = "wire-only"))]
+use std::{
+    convert::TryInto,
     io::{Error, ErrorKind, Result},
+    sync::Arc,
 };
 
 use bytes::Bytes;
+use fnv::FnvHasher;
+use libp2p_identity::PeerId;
+#[cfg(feature = "prometheus")]
 use prometheus_client::encoding::{EncodeLabelSet, LabelSetEncoder};
 
+#[cfg(any(feature = "behaviour", feature = "wire-only"))]
+use crate::targets::CODEC;
+
 #[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
 pub struct Topic {
     len: u8,
-    bytes: [u8; 64],
+    bytes: [u8; Topic::MAX_TOPIC_LENGTH],
 }
 
 impl Topic {
-    pub const MAX_TOPIC_LENGTH: usize = 64;
+    /// Longest topic that can be represented, `MAX_PLAIN_LENGTH` unless built with the
+    /// `long-topics` feature.
+    #[cfg(not(feature = "long-topics"))]
+    pub const MAX_TOPIC_LENGTH: usize = Self::MAX_PLAIN_LENGTH;
+    /// Longest topic the length prefixes of extended frames can tell the length of. Topics
+    /// longer than `MAX_PLAIN_LENGTH` are only exchanged with the peers negotiating
+    /// `Capabilities::LONG_TOPICS`, see `Config::with_max_topic_length`.
+    #[cfg(feature = "long-topics")]
+    pub const MAX_TOPIC_LENGTH: usize = 255;
+    /// Longest topic the header of a plain frame can tell the length of.
+    pub const MAX_PLAIN_LENGTH: usize = 63;
 
+    /// Panics if the topic is longer than `MAX_TOPIC_LENGTH`.
     pub fn new(topic: &[u8]) -> Self {
-        let mut bytes = [0u8; 64];
+        assert!(topic.len() <= Self::MAX_TOPIC_LENGTH, "topic too long");
+        let mut bytes = [0u8; Self::MAX_TOPIC_LENGTH];
         bytes[..topic.len()].copy_from_slice(topic);
         Self {
             len: topic.len() as _,
@@ -25,8 +51,9 @@ impl Topic {
     }
 }
 
+#[cfg(feature = "prometheus")]
 impl EncodeLabelSet for Topic {
-    fn encode(&self, mut encoder: LabelSetEncoder) -> fmt::Result {
+    fn encode(&self, mut encoder: LabelSetEncoder) -> std::fmt::Result {
         use prometheus_client::encoding::{EncodeLabelKey, EncodeLabelValue};
 
         let mut label_encoder = encoder.encode_label();
@@ -53,95 +80,1768 @@ impl AsRef<[u8]> for Topic {
     }
 }
 
+/// The payload of a broadcast. Payloads of up to `INLINE_CAPACITY` bytes copied into a message
+/// are stored in it, sparing an allocation for each of the small broadcasts, e.g. votes or
+/// heartbeats. Larger ones, and the ones given as `Bytes`, are shared instead of copied.
+#[derive(Clone)]
+pub struct Payload(Repr);
+
+#[derive(Clone)]
+enum Repr {
+    Inline {
+        len: u8,
+        bytes: [u8; Payload::INLINE_CAPACITY],
+    },
+    Shared(Bytes),
+}
+
+impl Payload {
+    /// Longest payload stored inline.
+    pub const INLINE_CAPACITY: usize = 56;
+
+    /// Copies the payload, inline if it fits.
+    pub fn copy_from_slice(payload: &[u8]) -> Self {
+        if payload.len() > Self::INLINE_CAPACITY {
+            return Self(Repr::Shared(Bytes::copy_from_slice(payload)));
+        }
+        let mut bytes = [0u8; Self::INLINE_CAPACITY];
+        bytes[..payload.len()].copy_from_slice(payload);
+        Self(Repr::Inline {
+            len: payload.len() as _,
+            bytes,
+        })
+    }
+
+    /// Stores the payload inline if it fits, copying it out of `bytes`, and shares it otherwise.
+    pub fn compact(bytes: Bytes) -> Self {
+        if bytes.len() > Self::INLINE_CAPACITY {
+            return Self(Repr::Shared(bytes));
+        }
+        Self::copy_from_slice(&bytes)
+    }
+
+    /// Returns `true` if the payload is stored in the message rather than on the heap.
+    pub fn is_inline(&self) -> bool {
+        matches!(self.0, Repr::Inline { .. })
+    }
+}
+
+impl std::ops::Deref for Payload {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        self.as_ref()
+    }
+}
+
+impl AsRef<[u8]> for Payload {
+    fn as_ref(&self) -> &[u8] {
+        match &self.0 {
+            Repr::Inline { len, bytes } => &bytes[..(*len as usize)],
+            Repr::Shared(bytes) => bytes,
+        }
+    }
+}
+
+impl std::fmt::Debug for Payload {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "b\"{}\"", self.escape_ascii())
+    }
+}
+
+impl PartialEq for Payload {
+    fn eq(&self, other: &Self) -> bool {
+        self[..] == other[..]
+    }
+}
+
+impl Eq for Payload {}
+
+impl PartialEq<[u8]> for Payload {
+    fn eq(&self, other: &[u8]) -> bool {
+        &self[..] == other
+    }
+}
+
+impl Default for Payload {
+    fn default() -> Self {
+        Self::copy_from_slice(&[])
+    }
+}
+
+impl From<Bytes> for Payload {
+    fn from(bytes: Bytes) -> Self {
+        Self(Repr::Shared(bytes))
+    }
+}
+
+impl From<&[u8]> for Payload {
+    fn from(payload: &[u8]) -> Self {
+        Self::copy_from_slice(payload)
+    }
+}
+
+impl From<Vec<u8>> for Payload {
+    fn from(payload: Vec<u8>) -> Self {
+        if payload.len() > Self::INLINE_CAPACITY {
+            return Bytes::from(payload).into();
+        }
+        Self::copy_from_slice(&payload)
+    }
+}
+
+impl From<Payload> for Bytes {
+    /// Copies an inline payload out, and hands a shared one over.
+    fn from(payload: Payload) -> Self {
+        match payload.0 {
+            Repr::Inline { .. } => Bytes::copy_from_slice(&payload),
+            Repr::Shared(bytes) => bytes,
+        }
+    }
+}
+
+/// Part of the payload of a large broadcast, delivered before the whole payload has arrived.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Chunk {
+    /// Identifies the broadcast this chunk belongs to, unique within a `Behaviour`.
+    pub stream: u64,
+    /// Position of the chunk within the payload.
+    pub offset: usize,
+    /// Total length of the payload.
+    pub len: usize,
+    pub data: Bytes,
+}
+
+impl Chunk {
+    /// Returns `true` if this chunk completes the payload.
+    pub fn is_last(&self) -> bool {
+        self.offset + self.data.len() == self.len
+    }
+}
+
+/// Identifier of a broadcast, used to recognize duplicates.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct MessageId(u64);
+
+impl MessageId {
+    pub fn new(id: u64) -> Self {
+        Self(id)
+    }
+
+    /// Derives an id from the topic and payload of a broadcast.
+    ///
+    /// This is the default id function. It hashes the content with FNV-1a, which is fast and
+    /// stable across versions but not collision resistant; use a custom function if remotes
+    /// must not be able to forge colliding ids.
+    pub fn from_content(topic: &Topic, payload: &[u8]) -> Self {
+        let mut hasher = ContentHasher::new(topic);
+        hasher.update(payload);
+        hasher.finish()
+    }
+
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+}
+
+/// Computes `MessageId::from_content` over a payload received in parts.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct ContentHasher(u64);
+
+impl ContentHasher {
+    pub fn new(topic: &Topic) -> Self {
+        let mut hasher = FnvHasher::default();
+        hasher.write_u8(topic.len() as u8);
+        hasher.write(topic);
+        Self(hasher.finish())
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        let mut hasher = FnvHasher::with_key(self.0);
+        hasher.write(data);
+        self.0 = hasher.finish();
+    }
+
+    pub fn finish(self) -> MessageId {
+        MessageId(self.0)
+    }
+}
+
+/// One erasure-coded piece of a broadcast payload.
+#[cfg(any(feature = "behaviour", feature = "wire-only"))]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Shard {
+    /// The peer that published the payload, set when the shard is relayed by a subscriber.
+    /// Shards sent by the publisher itself leave it out.
+    pub origin: Option<PeerId>,
+    /// Identifies the payload among the ones published by the origin.
+    pub id: u64,
+    pub index: u8,
+    pub data_shards: u8,
+    pub parity_shards: u8,
+    /// Length of the payload, which is padded to a multiple of `data_shards` before encoding.
+    pub payload_len: u32,
+    pub data: Bytes,
+}
+
+/// Set of optional protocol features. A feature is only used with a peer if both sides
+/// announce it in their handshake.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    /// Reserved for payload compression, not implemented yet.
+    pub(crate) const COMPRESSION: Self = Self(1);
+    pub const ACKS: Self = Self(1 << 1);
+    /// Reserved for fragmenting large broadcasts, not implemented yet.
+    pub(crate) const FRAGMENTATION: Self = Self(1 << 2);
+    /// Reserved for topic aliases, not implemented yet.
+    pub(crate) const ALIASES: Self = Self(1 << 3);
+    /// Application-defined control frames, see `Behaviour::send_control`.
+    pub const CONTROL: Self = Self(1 << 4);
+    /// Acknowledgments of subscriptions, see `Event::SubscriptionConfirmed`.
+    pub const SUBSCRIPTION_ACKS: Self = Self(1 << 5);
+    /// Subscriptions as a member of a group, see `Behaviour::subscribe_shared`.
+    pub const SHARED_SUBSCRIPTIONS: Self = Self(1 << 6);
+    /// Credits granted by receivers, see `Config::with_flow_control`.
+    pub const CREDITS: Self = Self(1 << 7);
+    /// Topics longer than `Topic::MAX_PLAIN_LENGTH`, see `Config::with_max_topic_length`.
+    pub const LONG_TOPICS: Self = Self(1 << 8);
+
+    /// Features the bits are set aside for, which cannot be announced yet.
+    #[cfg(feature = "behaviour")]
+    pub(crate) const RESERVED: Self =
+        Self(Self::COMPRESSION.0 | Self::FRAGMENTATION.0 | Self::ALIASES.0);
+
+    pub fn empty() -> Self {
+        Self(0)
+    }
+
+    pub fn from_bits(bits: u32) -> Self {
+        Self(bits)
+    }
+
+    pub fn bits(&self) -> u32 {
+        self.0
+    }
+
+    /// Returns `true` if all features of `other` are in the set.
+    pub fn contains(&self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Names of the features in the set, in snake case.
+    pub fn names(&self) -> Vec<&'static str> {
+        [
+            (Self::COMPRESSION, "compression"),
+            (Self::ACKS, "acks"),
+            (Self::FRAGMENTATION, "fragmentation"),
+            (Self::ALIASES, "aliases"),
+            (Self::CONTROL, "control"),
+            (Self::SUBSCRIPTION_ACKS, "subscription_acks"),
+            (Self::SHARED_SUBSCRIPTIONS, "shared_subscriptions"),
+            (Self::CREDITS, "credits"),
+            (Self::LONG_TOPICS, "long_topics"),
+        ]
+        .iter()
+        .filter(|(feature, _)| self.contains(*feature))
+        .map(|(_, name)| *name)
+        .collect()
+    }
+}
+
+impl BitOr for Capabilities {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
+impl BitAnd for Capabilities {
+    type Output = Self;
+
+    fn bitand(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+}
+
+/// Limits and features of a peer, sent as the first frame of each version 2 substream.
+///
+/// Fields added later are appended to the frame, and decoders ignore the fields they do not
+/// know.
+#[cfg(any(feature = "behaviour", feature = "wire-only"))]
 #[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Handshake {
+    /// Length of the largest frame the peer accepts.
+    pub max_frame_size: usize,
+    /// Optional features the peer supports. Empty if the peer does not announce any.
+    pub capabilities: Capabilities,
+}
+
+/// Optional fields carried along the payload of a broadcast.
+///
+/// Metadata travels in extended frames. Peers that only speak version 1 of the protocol receive
+/// the payload without it.
+#[derive(Clone, Debug, Default, Eq, Hash, PartialEq)]
+pub struct Metadata {
+    /// Format of the payload, e.g. a MIME type or a schema id, so that topics carrying payloads
+    /// of several formats can be decoded without sniffing them. At most 255 bytes long.
+    pub content_type: Option<String>,
+    /// Identifies the request a reply answers. Set by `Behaviour::publish_request` and copied
+    /// into the reply by `Behaviour::publish_reply`.
+    pub correlation_id: Option<u64>,
+    /// Topic on which replies to a request are expected.
+    pub reply_to: Option<Topic>,
+    /// Time at which the broadcast was published, in milliseconds since the Unix epoch, set
+    /// when the publisher enables `Config::with_timestamps`.
+    pub timestamp: Option<u64>,
+    /// Local receive time minus `timestamp` in milliseconds, i.e. the transit delay skewed by
+    /// the offset between the clocks of the peers. Measured on delivery, never sent.
+    pub skew: Option<i64>,
+    /// The peer that published the broadcast, set when it was forwarded by a subscriber rather
+    /// than received from the publisher itself.
+    pub origin: Option<PeerId>,
+    /// Number of times a forwarded broadcast was passed on before reaching us.
+    pub hops: Option<u8>,
+    /// Number of times the broadcast may be forwarded. Forwarders do not pass it on once `hops`
+    /// reached this limit.
+    pub hop_limit: Option<u8>,
+    /// Time after which forwarders no longer pass the broadcast on, in milliseconds since the
+    /// Unix epoch.
+    pub expires_at: Option<u64>,
+    /// Set on the broadcasts a publisher replays from its history to a peer that subscribed
+    /// late: the number of replayed broadcasts still to come, `0` on the last one.
+    pub replay_remaining: Option<u32>,
+    /// Set on the broadcasts of at-least-once topics, see `Config::with_at_least_once`: the id
+    /// the receiver acknowledges the broadcast with, in a `Message::Ack`.
+    pub delivery_id: Option<u64>,
+    /// Number of the broadcast among the ones its publisher published on the topic, see
+    /// `Config::with_sequence_numbers`. Kept when the broadcast is forwarded or replayed.
+    pub sequence: Option<u64>,
+    /// Trace the broadcast was published in, set by `Metadata::with_trace` or captured with
+    /// `Config::with_trace_context`. Kept when the broadcast is forwarded.
+    pub trace: Option<TraceContext>,
+}
+
+/// Identifies a span in a distributed trace, as in the W3C trace context, so that a broadcast
+/// can be followed from its publisher to its subscribers in a tracing backend.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct TraceContext {
+    pub trace_id: [u8; 16],
+    pub span_id: [u8; 8],
+}
+
+impl TraceContext {
+    pub fn new(trace_id: u128, span_id: u64) -> Self {
+        Self {
+            trace_id: trace_id.to_be_bytes(),
+            span_id: span_id.to_be_bytes(),
+        }
+    }
+
+    /// The trace id as 32 lowercase hex digits, as tracing backends display it.
+    pub fn trace_id_hex(&self) -> String {
+        hex(&self.trace_id)
+    }
+
+    /// The span id as 16 lowercase hex digits.
+    pub fn span_id_hex(&self) -> String {
+        hex(&self.span_id)
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Metadata field tags. Unknown tags are skipped when decoding.
+const CONTENT_TYPE: u8 = 0;
+const CORRELATION_ID: u8 = 1;
+const REPLY_TO: u8 = 2;
+const TIMESTAMP: u8 = 3;
+const ORIGIN: u8 = 4;
+const HOPS: u8 = 5;
+const HOP_LIMIT: u8 = 6;
+const EXPIRES_AT: u8 = 7;
+const REPLAY_REMAINING: u8 = 8;
+const DELIVERY_ID: u8 = 9;
+const SEQUENCE: u8 = 10;
+const TRACE: u8 = 11;
+
+impl Metadata {
+    pub fn with_content_type(mut self, content_type: impl Into<String>) -> Self {
+        let content_type = content_type.into();
+        assert!(content_type.len() <= 255, "content type too long");
+        self.content_type = Some(content_type);
+        self
+    }
+
+    pub fn with_correlation_id(mut self, correlation_id: u64) -> Self {
+        self.correlation_id = Some(correlation_id);
+        self
+    }
+
+    pub fn with_reply_to(mut self, reply_to: Topic) -> Self {
+        self.reply_to = Some(reply_to);
+        self
+    }
+
+    pub fn with_trace(mut self, trace: TraceContext) -> Self {
+        self.trace = Some(trace);
+        self
+    }
+
+    pub fn with_hop_limit(mut self, hop_limit: u8) -> Self {
+        self.hop_limit = Some(hop_limit);
+        self
+    }
+
+    pub fn with_expiry(mut self, expires_at: SystemTime) -> Self {
+        let expires_at = expires_at
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        self.expires_at = Some(expires_at as u64);
+        self
+    }
+
+    /// Returns `true` if forwarders should no longer pass the broadcast on at `now`, given in
+    /// milliseconds since the Unix epoch.
+    pub fn is_exhausted(&self, now: u64) -> bool {
+        let hops = self.hops.unwrap_or(0);
+        self.hop_limit.is_some_and(|limit| hops >= limit)
+            || self.expires_at.is_some_and(|expires_at| now >= expires_at)
+    }
+
+    /// Returns `true` if no field is set, in which case the broadcast is sent in a plain frame.
+    pub fn is_empty(&self) -> bool {
+        self.fields().is_empty()
+    }
+
+    /// The fields that are set, as tag and encoded value.
+    fn fields(&self) -> Vec<(u8, Cow<'_, [u8]>)> {
+        let mut fields = Vec::new();
+        if let Some(content_type) = &self.content_type {
+            fields.push((CONTENT_TYPE, Cow::Borrowed(content_type.as_bytes())));
+        }
+        if let Some(correlation_id) = self.correlation_id {
+            fields.push((
+                CORRELATION_ID,
+                Cow::Owned(correlation_id.to_be_bytes().to_vec()),
+            ));
+        }
+        if let Some(reply_to) = &self.reply_to {
+            fields.push((REPLY_TO, Cow::Borrowed(reply_to.as_ref())));
+        }
+        if let Some(timestamp) = self.timestamp {
+            fields.push((TIMESTAMP, Cow::Owned(timestamp.to_be_bytes().to_vec())));
+        }
+        if let Some(origin) = self.origin {
+            fields.push((ORIGIN, Cow::Owned(origin.to_bytes())));
+        }
+        if let Some(hops) = self.hops {
+            fields.push((HOPS, Cow::Owned(vec![hops])));
+        }
+        if let Some(hop_limit) = self.hop_limit {
+            fields.push((HOP_LIMIT, Cow::Owned(vec![hop_limit])));
+        }
+        if let Some(expires_at) = self.expires_at {
+            fields.push((EXPIRES_AT, Cow::Owned(expires_at.to_be_bytes().to_vec())));
+        }
+        if let Some(remaining) = self.replay_remaining {
+            fields.push((
+                REPLAY_REMAINING,
+                Cow::Owned(remaining.to_be_bytes().to_vec()),
+            ));
+        }
+        if let Some(delivery_id) = self.delivery_id {
+            fields.push((DELIVERY_ID, Cow::Owned(delivery_id.to_be_bytes().to_vec())));
+        }
+        if let Some(sequence) = self.sequence {
+            fields.push((SEQUENCE, Cow::Owned(sequence.to_be_bytes().to_vec())));
+        }
+        if let Some(trace) = &self.trace {
+            let mut value = trace.trace_id.to_vec();
+            value.extend_from_slice(&trace.span_id);
+            fields.push((TRACE, Cow::Owned(value)));
+        }
+        fields
+    }
+
+    /// Length of the fields once encoded, excluding their length prefix.
+    #[cfg(any(feature = "behaviour", feature = "wire-only"))]
+    fn encoded_len(&self) -> usize {
+        self.fields()
+            .iter()
+            .map(|(_, value)| 1 + varint_len(value.len()) + value.len())
+            .sum()
+    }
+
+    #[cfg(any(feature = "behaviour", feature = "wire-only"))]
+    fn encode(&self, buf: &mut Vec<u8>) {
+        put_varint(buf, self.encoded_len());
+        for (tag, value) in self.fields() {
+            buf.push(tag);
+            put_varint(buf, value.len());
+            buf.extend_from_slice(&value);
+        }
+    }
+
+    #[cfg(any(feature = "behaviour", feature = "wire-only"))]
+    fn decode(reader: &mut Reader) -> Result<Self> {
+        let len = reader.varint()?;
+        let mut fields = Reader {
+            bytes: reader.take(len)?,
+        };
+        let mut metadata = Self::default();
+        while !fields.is_empty() {
+            let tag = fields.u8()?;
+            let len = fields.varint()?;
+            let mut value = Reader {
+                bytes: fields.take(len)?,
+            };
+            match tag {
+                CONTENT_TYPE => {
+                    let content_type = value.rest();
+                    let content_type = std::str::from_utf8(&content_type)
+                        .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+                    metadata.content_type = Some(content_type.to_owned());
+                }
+                CORRELATION_ID => metadata.correlation_id = Some(value.u64()?),
+                TIMESTAMP => metadata.timestamp = Some(value.u64()?),
+                ORIGIN => {
+                    let origin = PeerId::from_bytes(&value.rest())
+                        .map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))?;
+                    metadata.origin = Some(origin);
+                }
+                HOPS => metadata.hops = Some(value.u8()?),
+                HOP_LIMIT => metadata.hop_limit = Some(value.u8()?),
+                EXPIRES_AT => metadata.expires_at = Some(value.u64()?),
+                REPLAY_REMAINING => metadata.replay_remaining = Some(value.u32()?),
+                DELIVERY_ID => metadata.delivery_id = Some(value.u64()?),
+                SEQUENCE => metadata.sequence = Some(value.u64()?),
+                TRACE => {
+                    metadata.trace = Some(TraceContext {
+                        trace_id: value.take(16)?[..].try_into().unwrap(),
+                        span_id: value.take(8)?[..].try_into().unwrap(),
+                    });
+                }
+                REPLY_TO => {
+                    let topic = value.rest();
+                    if topic.len() > Topic::MAX_TOPIC_LENGTH {
+                        return Err(Error::new(
+                            ErrorKind::InvalidData,
+                            "topic length out of range",
+                        ));
+                    }
+                    metadata.reply_to = Some(Topic::new(&topic));
+                }
+                _ => tracing::trace!(target: CODEC, "Skipping unknown metadata field {tag}"),
+            }
+        }
+        Ok(metadata)
+    }
+}
+
+#[cfg(any(feature = "behaviour", feature = "wire-only"))]
+fn varint_len(value: usize) -> usize {
+    let mut varint_buf = unsigned_varint::encode::usize_buffer();
+    unsigned_varint::encode::usize(value, &mut varint_buf).len()
+}
+
+#[cfg(any(feature = "behaviour", feature = "wire-only"))]
+fn put_varint(buf: &mut Vec<u8>, value: usize) {
+    let mut varint_buf = unsigned_varint::encode::usize_buffer();
+    buf.extend_from_slice(unsigned_varint::encode::usize(value, &mut varint_buf));
+}
+
+/// A frame of the protocol. New kinds of frames may be added, and new fields to broadcasts,
+/// without breaking the wire format.
+#[cfg(any(feature = "behaviour", feature = "wire-only"))]
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
 pub enum Message {
-    Subscribe(Topic),
-    Broadcast(Topic, Bytes),
-    Unsubscribe(Topic),
+    Subscribe {
+        topic: Topic,
+    },
+    /// A broadcast, sent in an extended frame if it carries metadata. Built with
+    /// `Message::broadcast` or `Message::broadcast_with_metadata`.
+    #[non_exhaustive]
+    Broadcast {
+        topic: Topic,
+        payload: Payload,
+        metadata: Arc<Metadata>,
+    },
+    Unsubscribe {
+        topic: Topic,
+    },
+    Shard {
+        topic: Topic,
+        shard: Box<Shard>,
+    },
+    /// Several messages sent as one frame. Batches do not nest.
+    Batch {
+        messages: Vec<Message>,
+    },
+    /// Sent once per substream, before any other message.
+    Handshake {
+        handshake: Handshake,
+    },
+    /// All topics the sender is subscribed to, replacing the receiver's view of them.
+    Snapshot {
+        topics: Vec<Topic>,
+    },
+    /// Leading part of a snapshot too large for a single frame, see `Message::snapshot`. The
+    /// receiver holds on to the topics until the closing `Message::Snapshot` arrives.
+    SnapshotPart {
+        topics: Vec<Topic>,
+    },
+    /// Asks the receiver for a snapshot of its subscriptions.
+    Query,
+    /// An application-defined payload scoped to the topic, neither forwarded nor checked for
+    /// duplicates.
+    Control {
+        topic: Topic,
+        payload: Bytes,
+    },
+    /// Confirms that the sender registered the receiver's subscription to the topic.
+    SubscribeAck {
+        topic: Topic,
+    },
+    /// Subscribes the sender to the topic as a member of the named group, whose members share
+    /// the broadcasts on the topic between them.
+    SubscribeShared {
+        topic: Topic,
+        group: Topic,
+    },
+    /// Acknowledges the broadcast sent with this `Metadata::delivery_id`.
+    Ack {
+        delivery_id: u64,
+    },
+    /// Grants the receiver as many more broadcasts on the topic, see `Config::with_flow_control`.
+    Credit {
+        topic: Topic,
+        credits: u32,
+    },
+}
+
+/// First byte of a frame. Its lower two bits tell the kind of the frame, and its upper six bits
+/// hold the length of the topic, or for extended frames, the extended kind.
+#[cfg(any(feature = "behaviour", feature = "wire-only"))]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum Header {
+    Subscribe { topic_len: u8 },
+    Broadcast { topic_len: u8 },
+    Unsubscribe { topic_len: u8 },
+    Extended { kind: u8 },
 }
 
+#[cfg(any(feature = "behaviour", feature = "wire-only"))]
+impl Header {
+    /// Bit of the extended kinds that receivers not knowing them skip instead of failing the
+    /// substream, for frames newer peers may send that are safe to miss. Kinds without it are
+    /// rejected when unknown.
+    pub const IGNORABLE: u8 = 1 << 5;
+
+    pub fn read(byte: u8) -> Self {
+        let upper = byte >> 2;
+        match byte & 0b11 {
+            0b00 => Header::Subscribe { topic_len: upper },
+            0b01 => Header::Broadcast { topic_len: upper },
+            0b10 => Header::Unsubscribe { topic_len: upper },
+            _ => Header::Extended { kind: upper },
+        }
+    }
+
+    pub fn write(self) -> u8 {
+        match self {
+            Header::Subscribe { topic_len } => topic_len << 2,
+            Header::Broadcast { topic_len } => topic_len << 2 | 0b01,
+            Header::Unsubscribe { topic_len } => topic_len << 2 | 0b10,
+            Header::Extended { kind } => kind << 2 | 0b11,
+        }
+    }
+
+    fn extended(kind: u8) -> u8 {
+        Header::Extended { kind }.write()
+    }
+}
+
+/// Extended frame kinds.
+#[cfg(any(feature = "behaviour", feature = "wire-only"))]
+const SHARD: u8 = 0;
+#[cfg(any(feature = "behaviour", feature = "wire-only"))]
+const BATCH: u8 = 1;
+#[cfg(any(feature = "behaviour", feature = "wire-only"))]
+const BROADCAST: u8 = 2;
+#[cfg(any(feature = "behaviour", feature = "wire-only"))]
+const HANDSHAKE: u8 = 3;
+#[cfg(any(feature = "behaviour", feature = "wire-only"))]
+const SNAPSHOT: u8 = 4;
+#[cfg(any(feature = "behaviour", feature = "wire-only"))]
+const QUERY: u8 = 5;
+#[cfg(any(feature = "behaviour", feature = "wire-only"))]
+const CONTROL: u8 = 6;
+#[cfg(any(feature = "behaviour", feature = "wire-only"))]
+const SUBSCRIBE_ACK: u8 = 7;
+#[cfg(any(feature = "behaviour", feature = "wire-only"))]
+const SUBSCRIBE_SHARED: u8 = 8;
+#[cfg(any(feature = "behaviour", feature = "wire-only"))]
+const ACK: u8 = 9;
+#[cfg(any(feature = "behaviour", feature = "wire-only"))]
+const CREDIT: u8 = 10;
+/// Subscriptions to topics too long for plain frames.
+#[cfg(any(feature = "behaviour", feature = "wire-only"))]
+const SUBSCRIBE: u8 = 11;
+#[cfg(any(feature = "behaviour", feature = "wire-only"))]
+const UNSUBSCRIBE: u8 = 12;
+#[cfg(any(feature = "behaviour", feature = "wire-only"))]
+const SNAPSHOT_PART: u8 = 13;
+
+/// Cursor over the body of an extended frame. The parts taken share the buffer of the frame.
+#[cfg(any(feature = "behaviour", feature = "wire-only"))]
+struct Reader {
+    bytes: Bytes,
+}
+
+#[cfg(any(feature = "behaviour", feature = "wire-only"))]
+impl Reader {
+    fn take(&mut self, len: usize) -> Result<Bytes> {
+        if self.bytes.len() < len {
+            return Err(Error::new(ErrorKind::InvalidData, "frame too short"));
+        }
+        Ok(self.bytes.split_to(len))
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_be_bytes(self.take(4)?[..].try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_be_bytes(self.take(8)?[..].try_into().unwrap()))
+    }
+
+    /// Reads a topic prefixed by its length.
+    fn topic(&mut self) -> Result<Topic> {
+        let len = self.u8()? as usize;
+        if len > Topic::MAX_TOPIC_LENGTH {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "topic length out of range",
+            ));
+        }
+        Ok(Topic::new(&self.take(len)?))
+    }
+
+    fn peer_id(&mut self) -> Result<PeerId> {
+        let len = self.u8()? as usize;
+        PeerId::from_bytes(&self.take(len)?)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))
+    }
+
+    fn varint(&mut self) -> Result<usize> {
+        let (value, rest) = unsigned_varint::decode::usize(&self.bytes)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))?;
+        let len = self.bytes.len() - rest.len();
+        self.bytes = self.bytes.slice(len..);
+        Ok(value)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    fn rest(&mut self) -> Bytes {
+        std::mem::take(&mut self.bytes)
+    }
+}
+
+#[cfg(any(feature = "behaviour", feature = "wire-only"))]
 impl Message {
-    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+    /// A broadcast without metadata.
+    pub fn broadcast(topic: Topic, payload: impl Into<Payload>) -> Self {
+        Message::Broadcast {
+            topic,
+            payload: payload.into(),
+            metadata: Arc::default(),
+        }
+    }
+
+    pub fn broadcast_with_metadata(
+        topic: Topic,
+        payload: impl Into<Payload>,
+        metadata: Metadata,
+    ) -> Self {
+        Message::Broadcast {
+            topic,
+            payload: payload.into(),
+            metadata: Arc::new(metadata),
+        }
+    }
+
+    /// Decodes a message whose payload, if any, shares the buffer instead of being copied out
+    /// of it. Frames of an unknown kind marked `Header::IGNORABLE` decode into an empty batch.
+    pub fn from_bytes(bytes: Bytes) -> Result<Self> {
+        Ok(Self::decode(bytes)?.unwrap_or(Message::Batch {
+            messages: Vec::new(),
+        }))
+    }
+
+    /// Returns `None` for the frames to ignore.
+    fn decode(bytes: Bytes) -> Result<Option<Self>> {
         if bytes.is_empty() {
             return Err(Error::new(ErrorKind::InvalidData, "empty message"));
         }
-        let topic_len = (bytes[0] >> 2) as usize;
+        let topic_len = match Header::read(bytes[0]) {
+            Header::Extended { kind } => return Self::from_extended(kind, bytes.slice(1..)),
+            Header::Subscribe { topic_len }
+            | Header::Broadcast { topic_len }
+            | Header::Unsubscribe { topic_len } => topic_len as usize,
+        };
         if bytes.len() < topic_len + 1 {
             return Err(Error::new(
                 ErrorKind::InvalidData,
                 "topic length out of range",
             ));
         }
-        let msg_len = bytes.len() - topic_len - 1;
         let topic = Topic::new(&bytes[1..topic_len + 1]);
-        Ok(match bytes[0] & 0b11 {
-            0b00 => Message::Subscribe(topic),
-            0b10 => Message::Unsubscribe(topic),
-            0b01 => {
-                let mut msg = Vec::with_capacity(msg_len);
-                msg.extend_from_slice(&bytes[(topic_len + 1)..]);
-                Message::Broadcast(topic, msg.into())
-            }
-            _ => return Err(Error::new(ErrorKind::InvalidData, "invalid header")),
-        })
+        Ok(Some(match Header::read(bytes[0]) {
+            Header::Subscribe { .. } => Message::Subscribe { topic },
+            Header::Unsubscribe { .. } => Message::Unsubscribe { topic },
+            Header::Broadcast { .. } => Message::broadcast(topic, bytes.slice(topic_len + 1..)),
+            Header::Extended { .. } => unreachable!("extended frames are decoded above"),
+        }))
+    }
+
+    /// Decodes a message, copying the bytes.
+    pub fn from_slice(bytes: &[u8]) -> Result<Self> {
+        Self::from_bytes(Bytes::copy_from_slice(bytes))
+    }
+
+    fn from_extended(kind: u8, body: Bytes) -> Result<Option<Self>> {
+        let mut reader = Reader { bytes: body };
+        let message = match kind {
+            SHARD => {
+                let topic = reader.topic()?;
+                let flags = reader.u8()?;
+                let origin = if flags & 1 != 0 {
+                    Some(reader.peer_id()?)
+                } else {
+                    None
+                };
+                let id = reader.u64()?;
+                let index = reader.u8()?;
+                let data_shards = reader.u8()?;
+                let parity_shards = reader.u8()?;
+                let payload_len = reader.u32()?;
+                let data = reader.rest();
+                Ok(Message::Shard {
+                    topic,
+                    shard: Box::new(Shard {
+                        origin,
+                        id,
+                        index,
+                        data_shards,
+                        parity_shards,
+                        payload_len,
+                        data,
+                    }),
+                })
+            }
+            BROADCAST => {
+                let topic = reader.topic()?;
+                let metadata = Metadata::decode(&mut reader)?;
+                let payload = reader.rest();
+                Ok(Message::broadcast_with_metadata(topic, payload, metadata))
+            }
+            BATCH => {
+                let mut messages = Vec::new();
+                while !reader.is_empty() {
+                    let len = reader.varint()?;
+                    let frame = reader.take(len)?;
+                    // Checked before decoding, so that nesting cannot exhaust the stack.
+                    if let Some(Header::Extended { kind: BATCH }) =
+                        frame.first().map(|b| Header::read(*b))
+                    {
+                        return Err(Error::new(ErrorKind::InvalidData, "nested batch"));
+                    }
+                    if let Some(message) = Self::decode(frame)? {
+                        messages.push(message);
+                    }
+                }
+                Ok(Message::Batch { messages })
+            }
+            HANDSHAKE => {
+                let max_frame_size = reader.varint()?;
+                let capabilities = if reader.is_empty() {
+                    Capabilities::empty()
+                } else {
+                    Capabilities::from_bits(reader.varint()? as u32)
+                };
+                Ok(Message::Handshake {
+                    handshake: Handshake {
+                        max_frame_size,
+                        capabilities,
+                    },
+                })
+            }
+            SNAPSHOT | SNAPSHOT_PART => {
+                let mut topics = Vec::new();
+                while !reader.is_empty() {
+                    topics.push(reader.topic()?);
+                }
+                Ok(if kind == SNAPSHOT {
+                    Message::Snapshot { topics }
+                } else {
+                    Message::SnapshotPart { topics }
+                })
+            }
+            QUERY => Ok(Message::Query),
+            CONTROL => {
+                let topic = reader.topic()?;
+                let payload = reader.rest();
+                Ok(Message::Control { topic, payload })
+            }
+            SUBSCRIBE_ACK => Ok(Message::SubscribeAck {
+                topic: reader.topic()?,
+            }),
+            SUBSCRIBE_SHARED => {
+                let topic = reader.topic()?;
+                let group = reader.topic()?;
+                Ok(Message::SubscribeShared { topic, group })
+            }
+            ACK => Ok(Message::Ack {
+                delivery_id: reader.u64()?,
+            }),
+            CREDIT => {
+                let topic = reader.topic()?;
+                Ok(Message::Credit {
+                    topic,
+                    credits: reader.u32()?,
+                })
+            }
+            SUBSCRIBE => Ok(Message::Subscribe {
+                topic: reader.topic()?,
+            }),
+            UNSUBSCRIBE => Ok(Message::Unsubscribe {
+                topic: reader.topic()?,
+            }),
+            kind if kind & Header::IGNORABLE != 0 => {
+                tracing::trace!(target: CODEC, "Ignoring frame of unknown kind {kind}");
+                return Ok(None);
+            }
+            _ => Err(Error::new(ErrorKind::InvalidData, "unknown frame kind")),
+        };
+        message.map(Some)
     }
 
     pub fn to_bytes(&self) -> Vec<u8> {
         match self {
-            Message::Subscribe(topic) => {
+            Message::Subscribe { topic } if topic.len() > Topic::MAX_PLAIN_LENGTH => {
+                let mut buf = Vec::with_capacity(self.len());
+                buf.push(Header::extended(SUBSCRIBE));
+                buf.push(topic.len() as u8);
+                buf.extend_from_slice(topic);
+                buf
+            }
+            Message::Unsubscribe { topic } if topic.len() > Topic::MAX_PLAIN_LENGTH => {
+                let mut buf = Vec::with_capacity(self.len());
+                buf.push(Header::extended(UNSUBSCRIBE));
+                buf.push(topic.len() as u8);
+                buf.extend_from_slice(topic);
+                buf
+            }
+            Message::Subscribe { topic } => {
                 let mut buf = Vec::with_capacity(topic.len() + 1);
-                buf.push((topic.len() as u8) << 2);
+                buf.push(
+                    Header::Subscribe {
+                        topic_len: topic.len() as u8,
+                    }
+                    .write(),
+                );
                 buf.extend_from_slice(topic);
                 buf
             }
-            Message::Unsubscribe(topic) => {
+            Message::Unsubscribe { topic } => {
                 let mut buf = Vec::with_capacity(topic.len() + 1);
-                buf.push((topic.len() as u8) << 2 | 0b10);
+                buf.push(
+                    Header::Unsubscribe {
+                        topic_len: topic.len() as u8,
+                    }
+                    .write(),
+                );
                 buf.extend_from_slice(topic);
                 buf
             }
-            Message::Broadcast(topic, msg) => {
+            Message::Broadcast {
+                topic,
+                payload: msg,
+                metadata,
+            } if metadata.is_empty() && topic.len() <= Topic::MAX_PLAIN_LENGTH => {
                 let mut buf = Vec::with_capacity(topic.len() + msg.len() + 1);
-                buf.push((topic.len() as u8) << 2 | 0b01);
+                buf.push(
+                    Header::Broadcast {
+                        topic_len: topic.len() as u8,
+                    }
+                    .write(),
+                );
                 buf.extend_from_slice(topic);
                 buf.extend_from_slice(msg);
                 buf
             }
+            Message::Broadcast {
+                topic,
+                payload: msg,
+                metadata,
+            } => {
+                let mut buf = Vec::with_capacity(self.len());
+                buf.push(Header::extended(BROADCAST));
+                buf.push(topic.len() as u8);
+                buf.extend_from_slice(topic);
+                metadata.encode(&mut buf);
+                buf.extend_from_slice(msg);
+                buf
+            }
+            Message::Shard { topic, shard } => {
+                let mut buf = Vec::with_capacity(self.len());
+                buf.push(Header::extended(SHARD));
+                buf.push(topic.len() as u8);
+                buf.extend_from_slice(topic);
+                match &shard.origin {
+                    Some(origin) => {
+                        let origin = origin.to_bytes();
+                        buf.push(1);
+                        buf.push(origin.len() as u8);
+                        buf.extend_from_slice(&origin);
+                    }
+                    None => buf.push(0),
+                }
+                buf.extend_from_slice(&shard.id.to_be_bytes());
+                buf.push(shard.index);
+                buf.push(shard.data_shards);
+                buf.push(shard.parity_shards);
+                buf.extend_from_slice(&shard.payload_len.to_be_bytes());
+                buf.extend_from_slice(&shard.data);
+                buf
+            }
+            Message::Batch { messages } => {
+                let mut buf = Vec::with_capacity(self.len());
+                buf.push(Header::extended(BATCH));
+                for message in messages {
+                    put_varint(&mut buf, message.len());
+                    buf.extend_from_slice(&message.to_bytes());
+                }
+                buf
+            }
+            Message::Handshake { handshake } => {
+                let mut buf = Vec::with_capacity(self.len());
+                buf.push(Header::extended(HANDSHAKE));
+                put_varint(&mut buf, handshake.max_frame_size);
+                put_varint(&mut buf, handshake.capabilities.bits() as usize);
+                buf
+            }
+            Message::Snapshot { topics } | Message::SnapshotPart { topics } => {
+                let kind = match self {
+                    Message::Snapshot { .. } => SNAPSHOT,
+                    _ => SNAPSHOT_PART,
+                };
+                let mut buf = Vec::with_capacity(self.len());
+                buf.push(Header::extended(kind));
+                for topic in topics {
+                    buf.push(topic.len() as u8);
+                    buf.extend_from_slice(topic);
+                }
+                buf
+            }
+            Message::Query => vec![Header::extended(QUERY)],
+            Message::Control { topic, payload } => {
+                let mut buf = Vec::with_capacity(self.len());
+                buf.push(Header::extended(CONTROL));
+                buf.push(topic.len() as u8);
+                buf.extend_from_slice(topic);
+                buf.extend_from_slice(payload);
+                buf
+            }
+            Message::SubscribeAck { topic } => {
+                let mut buf = Vec::with_capacity(self.len());
+                buf.push(Header::extended(SUBSCRIBE_ACK));
+                buf.push(topic.len() as u8);
+                buf.extend_from_slice(topic);
+                buf
+            }
+            Message::SubscribeShared { topic, group } => {
+                let mut buf = Vec::with_capacity(self.len());
+                buf.push(Header::extended(SUBSCRIBE_SHARED));
+                buf.push(topic.len() as u8);
+                buf.extend_from_slice(topic);
+                buf.push(group.len() as u8);
+                buf.extend_from_slice(group);
+                buf
+            }
+            Message::Ack { delivery_id: id } => {
+                let mut buf = Vec::with_capacity(self.len());
+                buf.push(Header::extended(ACK));
+                buf.extend_from_slice(&id.to_be_bytes());
+                buf
+            }
+            Message::Credit { topic, credits } => {
+                let mut buf = Vec::with_capacity(self.len());
+                buf.push(Header::extended(CREDIT));
+                buf.push(topic.len() as u8);
+                buf.extend_from_slice(topic);
+                buf.extend_from_slice(&credits.to_be_bytes());
+                buf
+            }
+        }
+    }
+
+    /// Packs the messages into as few frames as possible, none of them longer than `max_len`
+    /// unless a single message already is. Lone messages are not wrapped in a batch.
+    pub fn batch(messages: Vec<Message>, max_len: usize) -> Vec<Message> {
+        let mut frames = Vec::new();
+        let mut current = Vec::new();
+        let mut current_len = 1;
+        for message in messages {
+            let len = message.len();
+            let entry_len = varint_len(len) + len;
+            if !current.is_empty() && current_len + entry_len > max_len {
+                frames.push(Self::frame(std::mem::take(&mut current)));
+                current_len = 1;
+            }
+            current.push(message);
+            current_len += entry_len;
+        }
+        if !current.is_empty() {
+            frames.push(Self::frame(current));
+        }
+        frames
+    }
+
+    /// Splits a snapshot of the topics into frames no longer than `max_len`, unless a single
+    /// topic already is: leading `Message::SnapshotPart`s closed by a `Message::Snapshot`.
+    pub fn snapshot(topics: Vec<Topic>, max_len: usize) -> Vec<Message> {
+        let mut frames = Vec::new();
+        let mut current = Vec::new();
+        let mut current_len = 1;
+        for topic in topics {
+            let entry_len = 1 + topic.len();
+            if !current.is_empty() && current_len + entry_len > max_len {
+                frames.push(Message::SnapshotPart {
+                    topics: std::mem::take(&mut current),
+                });
+                current_len = 1;
+            }
+            current.push(topic);
+            current_len += entry_len;
+        }
+        frames.push(Message::Snapshot { topics: current });
+        frames
+    }
+
+    fn frame(mut messages: Vec<Message>) -> Self {
+        if messages.len() == 1 {
+            messages.pop().expect("one message")
+        } else {
+            Message::Batch { messages }
+        }
+    }
+
+    /// Returns `true` if the message is a broadcast, or a shard of one, on the topic.
+    pub fn is_broadcast_on(&self, topic: &Topic) -> bool {
+        match self {
+            Message::Broadcast { topic: other, .. } | Message::Shard { topic: other, .. } => {
+                other == topic
+            }
+            _ => false,
+        }
+    }
+
+    /// Removes the message, or the messages of a batch, for which `discard` returns `true`.
+    /// A removed message is replaced by an empty batch. Returns how many messages were removed.
+    pub fn discard(&mut self, mut discard: impl FnMut(&Message) -> bool) -> usize {
+        match self {
+            Message::Batch { messages } => {
+                let len = messages.len();
+                messages.retain(|message| !discard(message));
+                len - messages.len()
+            }
+            message if discard(message) => {
+                *message = Message::Batch {
+                    messages: Vec::new(),
+                };
+                1
+            }
+            _ => 0,
+        }
+    }
+
+    /// Returns `true` for a batch without messages, which is not worth sending.
+    pub fn is_empty(&self) -> bool {
+        matches!(self, Message::Batch { messages } if messages.is_empty())
+    }
+
+    /// Topic of the message, `None` for messages not scoped to a single topic.
+    pub fn topic(&self) -> Option<&Topic> {
+        match self {
+            Message::Subscribe { topic }
+            | Message::Unsubscribe { topic }
+            | Message::Broadcast { topic, .. }
+            | Message::Shard { topic, .. }
+            | Message::Control { topic, .. }
+            | Message::SubscribeAck { topic }
+            | Message::SubscribeShared { topic, .. }
+            | Message::Credit { topic, .. } => Some(topic),
+            Message::Batch { .. }
+            | Message::Handshake { .. }
+            | Message::Snapshot { .. }
+            | Message::SnapshotPart { .. }
+            | Message::Query
+            | Message::Ack { .. } => None,
+        }
+    }
+
+    /// Length of the longest topic the message names, including the group of a shared
+    /// subscription, the topic replies to a broadcast are expected on, and the topics named by
+    /// the messages of a batch.
+    pub fn longest_topic(&self) -> usize {
+        match self {
+            Message::Broadcast {
+                topic, metadata, ..
+            } => topic
+                .len()
+                .max(metadata.reply_to.map_or(0, |reply_to| reply_to.len())),
+            Message::SubscribeShared { topic, group } => topic.len().max(group.len()),
+            Message::Snapshot { topics } | Message::SnapshotPart { topics } => {
+                topics.iter().map(|topic| topic.len()).max().unwrap_or(0)
+            }
+            Message::Batch { messages } => messages
+                .iter()
+                .map(Message::longest_topic)
+                .max()
+                .unwrap_or(0),
+            message => message.topic().map_or(0, |topic| topic.len()),
+        }
+    }
+
+    /// Converts the message into one peers speaking version 1 of the protocol understand, which
+    /// drops the metadata of a broadcast. The message is only copied if it has to change.
+    /// Returns `None` if there is no such message.
+    pub fn downgrade(&self) -> Option<Cow<'_, Self>> {
+        if self
+            .topic()
+            .is_some_and(|topic| topic.len() > Topic::MAX_PLAIN_LENGTH)
+        {
+            return None;
+        }
+        match self {
+            Message::Subscribe { .. } | Message::Unsubscribe { .. } => Some(Cow::Borrowed(self)),
+            // Peers unaware of groups send every broadcast to each member.
+            Message::SubscribeShared { topic, .. } => {
+                Some(Cow::Owned(Message::Subscribe { topic: *topic }))
+            }
+            Message::Broadcast { metadata, .. } if metadata.is_empty() => Some(Cow::Borrowed(self)),
+            Message::Broadcast {
+                topic,
+                payload: msg,
+                ..
+            } => Some(Cow::Owned(Message::broadcast(*topic, msg.clone()))),
+            Message::Shard { .. }
+            | Message::Batch { .. }
+            | Message::Handshake { .. }
+            | Message::Snapshot { .. }
+            | Message::SnapshotPart { .. }
+            | Message::Query
+            | Message::Control { .. }
+            | Message::SubscribeAck { .. }
+            | Message::Ack { .. }
+            | Message::Credit { .. } => None,
         }
     }
 
     pub fn len(&self) -> usize {
         match self {
-            Message::Subscribe(topic) => 1 + topic.len(),
-            Message::Unsubscribe(topic) => 1 + topic.len(),
-            Message::Broadcast(topic, msg) => 1 + topic.len() + msg.len(),
+            Message::Subscribe { topic } | Message::Unsubscribe { topic }
+                if topic.len() > Topic::MAX_PLAIN_LENGTH =>
+            {
+                2 + topic.len()
+            }
+            Message::Subscribe { topic } => 1 + topic.len(),
+            Message::Unsubscribe { topic } => 1 + topic.len(),
+            Message::Broadcast {
+                topic,
+                payload: msg,
+                metadata,
+            } if metadata.is_empty() && topic.len() <= Topic::MAX_PLAIN_LENGTH => {
+                1 + topic.len() + msg.len()
+            }
+            Message::Broadcast {
+                topic,
+                payload: msg,
+                metadata,
+            } => {
+                let metadata_len = metadata.encoded_len();
+                2 + topic.len() + varint_len(metadata_len) + metadata_len + msg.len()
+            }
+            Message::Shard { topic, shard } => {
+                let origin_len = shard.origin.map_or(0, |origin| 1 + origin.to_bytes().len());
+                3 + topic.len() + origin_len + 8 + 3 + 4 + shard.data.len()
+            }
+            Message::Batch { messages } => {
+                1 + messages
+                    .iter()
+                    .map(|message| {
+                        let len = message.len();
+                        varint_len(len) + len
+                    })
+                    .sum::<usize>()
+            }
+            Message::Handshake { handshake } => {
+                1 + varint_len(handshake.max_frame_size)
+                    + varint_len(handshake.capabilities.bits() as usize)
+            }
+            Message::Snapshot { topics } | Message::SnapshotPart { topics } => {
+                1 + topics.iter().map(|topic| 1 + topic.len()).sum::<usize>()
+            }
+            Message::Query => 1,
+            Message::Control { topic, payload } => 2 + topic.len() + payload.len(),
+            Message::SubscribeAck { topic } => 2 + topic.len(),
+            Message::SubscribeShared { topic, group } => 3 + topic.len() + group.len(),
+            Message::Ack { .. } => 9,
+            Message::Credit { topic, .. } => 2 + topic.len() + 4,
         }
     }
 }
 
-#[cfg(test)]
+#[cfg(all(test, any(feature = "behaviour", feature = "wire-only")))]
 mod tests {
     use super::*;
 
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    fn arbitrary_topic(rng: &mut StdRng) -> Topic {
+        let len = rng.gen_range(0..=Topic::MAX_TOPIC_LENGTH);
+        Topic::new(&(0..len).map(|_| rng.gen()).collect::<Vec<u8>>())
+    }
+
+    /// Mostly small payloads, and now and then a large one.
+    fn arbitrary_payload(rng: &mut StdRng) -> Bytes {
+        let max_len = if rng.gen_bool(0.1) { 4096 } else { 16 };
+        let len = rng.gen_range(0..=max_len);
+        (0..len).map(|_| rng.gen()).collect()
+    }
+
+    fn arbitrary_metadata(rng: &mut StdRng) -> Metadata {
+        let mut field = || rng.gen_bool(0.3);
+        let (content_type, correlation_id, reply_to, timestamp) =
+            (field(), field(), field(), field());
+        let (origin, hops, hop_limit, expires_at) = (field(), field(), field(), field());
+        let (replay_remaining, delivery_id, sequence, trace) = (field(), field(), field(), field());
+        Metadata {
+            content_type: content_type.then(|| {
+                let len = rng.gen_range(0..=255);
+                (0..len).map(|_| rng.gen_range('a'..='z')).collect()
+            }),
+            correlation_id: correlation_id.then(|| rng.gen()),
+            reply_to: reply_to.then(|| arbitrary_topic(rng)),
+            timestamp: timestamp.then(|| rng.gen()),
+            origin: origin.then(PeerId::random),
+            hops: hops.then(|| rng.gen()),
+            hop_limit: hop_limit.then(|| rng.gen()),
+            expires_at: expires_at.then(|| rng.gen()),
+            replay_remaining: replay_remaining.then(|| rng.gen()),
+            delivery_id: delivery_id.then(|| rng.gen()),
+            sequence: sequence.then(|| rng.gen()),
+            trace: trace.then(|| TraceContext::new(rng.gen(), rng.gen())),
+            // Measured on delivery, never sent.
+            skew: None,
+        }
+    }
+
+    /// A message of any kind. Batches only hold other kinds, as they do not nest.
+    fn arbitrary_message(rng: &mut StdRng, in_batch: bool) -> Message {
+        let kinds = if in_batch { 14 } else { 15 };
+        match rng.gen_range(0..kinds) {
+            0 => Message::Subscribe {
+                topic: arbitrary_topic(rng),
+            },
+            1 => Message::Unsubscribe {
+                topic: arbitrary_topic(rng),
+            },
+            2 => Message::broadcast(arbitrary_topic(rng), arbitrary_payload(rng)),
+            3 => {
+                let (topic, payload) = (arbitrary_topic(rng), arbitrary_payload(rng));
+                Message::broadcast_with_metadata(topic, payload, arbitrary_metadata(rng))
+            }
+            4 => Message::Shard {
+                topic: arbitrary_topic(rng),
+                shard: Box::new(Shard {
+                    origin: rng.gen_bool(0.5).then(PeerId::random),
+                    id: rng.gen(),
+                    index: rng.gen(),
+                    data_shards: rng.gen(),
+                    parity_shards: rng.gen(),
+                    payload_len: rng.gen(),
+                    data: arbitrary_payload(rng),
+                }),
+            },
+            5 => Message::Handshake {
+                handshake: Handshake {
+                    max_frame_size: rng.gen::<u32>() as usize,
+                    capabilities: Capabilities::from_bits(rng.gen()),
+                },
+            },
+            6 => Message::Snapshot {
+                topics: (0..rng.gen_range(0..4))
+                    .map(|_| arbitrary_topic(rng))
+                    .collect(),
+            },
+            7 => Message::Query,
+            8 => Message::Control {
+                topic: arbitrary_topic(rng),
+                payload: arbitrary_payload(rng),
+            },
+            9 => Message::SubscribeAck {
+                topic: arbitrary_topic(rng),
+            },
+            10 => Message::SubscribeShared {
+                topic: arbitrary_topic(rng),
+                group: arbitrary_topic(rng),
+            },
+            11 => Message::Ack {
+                delivery_id: rng.gen(),
+            },
+            12 => Message::Credit {
+                topic: arbitrary_topic(rng),
+                credits: rng.gen(),
+            },
+            13 => Message::SnapshotPart {
+                topics: (0..rng.gen_range(0..4))
+                    .map(|_| arbitrary_topic(rng))
+                    .collect(),
+            },
+            _ => Message::Batch {
+                messages: (0..rng.gen_range(0..4))
+                    .map(|_| arbitrary_message(rng, true))
+                    .collect(),
+            },
+        }
+    }
+
     #[test]
     fn test_roundtrip() {
         let topic = Topic::new(b"topic");
         let msgs = [
-            Message::Broadcast(Topic::new(b""), Bytes::from_static(b"")),
-            Message::Subscribe(topic),
-            Message::Unsubscribe(topic),
-            Message::Broadcast(topic, Bytes::from_static(b"content")),
+            Message::broadcast(Topic::new(b""), Bytes::from_static(b"")),
+            Message::Subscribe { topic },
+            Message::Unsubscribe { topic },
+            Message::broadcast(topic, Bytes::from_static(b"content")),
+            Message::Broadcast {
+                topic,
+                payload: Bytes::from_static(b"{}").into(),
+                metadata: Metadata::default()
+                    .with_content_type("application/json")
+                    .into(),
+            },
+            Message::Broadcast {
+                topic,
+                payload: Bytes::from_static(b"request").into(),
+                metadata: Metadata::default()
+                    .with_correlation_id(7)
+                    .with_reply_to(Topic::new(b"replies"))
+                    .into(),
+            },
+            Message::Broadcast {
+                topic,
+                payload: Bytes::from_static(b"fresh").into(),
+                metadata: Metadata {
+                    timestamp: Some(1_700_000_000_000),
+                    origin: Some(PeerId::random()),
+                    hops: Some(2),
+                    hop_limit: Some(4),
+                    expires_at: Some(1_700_000_060_000),
+                    delivery_id: Some(7),
+                    sequence: Some(1_700_000_000_000_000),
+                    trace: Some(TraceContext::new(0x4bf9_2f35_77b3_4da6, 0x00f0_67aa)),
+                    ..Metadata::default()
+                }
+                .into(),
+            },
+            Message::Shard {
+                topic,
+                shard: Box::new(Shard {
+                    origin: Some(PeerId::random()),
+                    id: 42,
+                    index: 3,
+                    data_shards: 4,
+                    parity_shards: 2,
+                    payload_len: 1000,
+                    data: Bytes::from_static(b"shard"),
+                }),
+            },
+            Message::Batch { messages: vec![] },
+            Message::Handshake {
+                handshake: Handshake {
+                    max_frame_size: 4 * 1024 * 1024,
+                    capabilities: Capabilities::ACKS | Capabilities::ALIASES,
+                },
+            },
+            Message::Snapshot { topics: vec![] },
+            Message::Snapshot {
+                topics: vec![topic, Topic::new(b"other")],
+            },
+            Message::SnapshotPart {
+                topics: vec![topic],
+            },
+            Message::Query,
+            Message::SubscribeShared {
+                topic,
+                group: Topic::new(b"workers"),
+            },
+            Message::Ack {
+                delivery_id: u64::MAX,
+            },
+            Message::Credit {
+                topic,
+                credits: u32::MAX,
+            },
+            Message::Batch {
+                messages: vec![
+                    Message::Subscribe { topic },
+                    Message::Unsubscribe {
+                        topic: Topic::new(b"other"),
+                    },
+                ],
+            },
         ];
         for msg in &msgs {
-            let msg2 = Message::from_bytes(&msg.to_bytes()).unwrap();
+            let bytes = msg.to_bytes();
+            assert_eq!(bytes.len(), msg.len());
+            let msg2 = Message::from_slice(&bytes).unwrap();
             assert_eq!(msg, &msg2);
         }
     }
 
+    #[test]
+    fn test_arbitrary_roundtrip() {
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..2_000 {
+            let msg = arbitrary_message(&mut rng, false);
+            let bytes = msg.to_bytes();
+            assert_eq!(bytes.len(), msg.len());
+            assert_eq!(Message::from_slice(&bytes).unwrap(), msg);
+        }
+    }
+
+    #[test]
+    fn test_decode_never_panics() {
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..10_000 {
+            let len = rng.gen_range(0..64);
+            let noise: Vec<u8> = (0..len).map(|_| rng.gen()).collect();
+            let _ = Message::from_slice(&noise);
+
+            // Corrupted frames get further into the decoder than noise does.
+            let mut bytes = arbitrary_message(&mut rng, false).to_bytes();
+            for _ in 0..rng.gen_range(1..4) {
+                let index = rng.gen_range(0..bytes.len());
+                bytes[index] = rng.gen();
+            }
+            bytes.truncate(rng.gen_range(0..=bytes.len()));
+            let _ = Message::from_slice(&bytes);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "long-topics")]
+    fn test_long_topics() {
+        let long = Topic::new(&[b'a'; Topic::MAX_TOPIC_LENGTH]);
+        let msgs = [
+            Message::Subscribe { topic: long },
+            Message::Unsubscribe { topic: long },
+            Message::broadcast(long, Bytes::from_static(b"content")),
+        ];
+        for msg in &msgs {
+            let bytes = msg.to_bytes();
+            assert!(matches!(Header::read(bytes[0]), Header::Extended { .. }));
+            assert_eq!(bytes.len(), msg.len());
+            assert_eq!(&Message::from_slice(&bytes).unwrap(), msg);
+        }
+    }
+
+    #[test]
+    fn test_payloads_share_the_frame() {
+        let topic = Topic::new(b"topic");
+        let metadata = Metadata {
+            sequence: Some(1),
+            ..Metadata::default()
+        };
+        let payload = Bytes::from_static(b"payload");
+        for msg in [
+            Message::broadcast(topic, payload.clone()),
+            Message::broadcast_with_metadata(topic, payload.clone(), metadata),
+        ] {
+            let frame = Bytes::from(msg.to_bytes());
+            let Message::Broadcast { payload, .. } = Message::from_bytes(frame.clone()).unwrap()
+            else {
+                panic!("not a broadcast");
+            };
+            assert_eq!(payload, b"payload"[..]);
+            assert!(frame.as_ptr_range().contains(&payload.as_ptr()));
+        }
+    }
+
+    #[test]
+    fn test_inline_payloads() {
+        let small = [7u8; Payload::INLINE_CAPACITY];
+        let large = [7u8; Payload::INLINE_CAPACITY + 1];
+        assert!(Payload::from(&small[..]).is_inline());
+        assert!(Payload::from(small.to_vec()).is_inline());
+        assert!(Payload::compact(Bytes::copy_from_slice(&small)).is_inline());
+        assert!(!Payload::from(&large[..]).is_inline());
+        assert!(!Payload::compact(Bytes::copy_from_slice(&large)).is_inline());
+        assert!(!Payload::from(Bytes::copy_from_slice(&small)).is_inline());
+
+        // Payloads compare by content, however they are stored.
+        let inline = Message::broadcast(Topic::new(b"votes"), &small[..]);
+        let shared = Message::broadcast(Topic::new(b"votes"), Bytes::copy_from_slice(&small));
+        assert_eq!(inline, shared);
+        assert_eq!(
+            Message::from_bytes(inline.to_bytes().into()).unwrap(),
+            shared
+        );
+        let Message::Broadcast { payload, .. } = inline else {
+            unreachable!()
+        };
+        assert_eq!(Bytes::from(payload), small[..]);
+    }
+
+    #[test]
+    fn test_batch() {
+        let subscribe = |name: &[u8]| Message::Subscribe {
+            topic: Topic::new(name),
+        };
+        let messages = vec![subscribe(b"a"), subscribe(b"b"), subscribe(b"c")];
+
+        // Each entry takes one length byte, one header byte and one topic byte.
+        let frames = Message::batch(messages.clone(), 7);
+        assert_eq!(
+            frames,
+            [
+                Message::Batch {
+                    messages: vec![subscribe(b"a"), subscribe(b"b")]
+                },
+                subscribe(b"c"),
+            ]
+        );
+        assert!(frames.iter().all(|frame| frame.len() <= 7));
+        assert_eq!(Message::batch(messages.clone(), 1024).len(), 1);
+        assert!(Message::batch(vec![], 1024).is_empty());
+    }
+
+    #[test]
+    fn test_handshake_without_capabilities() {
+        let bytes = [Header::extended(HANDSHAKE), 0x80, 0x08];
+        assert_eq!(
+            Message::from_slice(&bytes).unwrap(),
+            Message::Handshake {
+                handshake: Handshake {
+                    max_frame_size: 1024,
+                    capabilities: Capabilities::empty(),
+                }
+            }
+        );
+
+        let both = Capabilities::COMPRESSION | Capabilities::ACKS;
+        assert!(both.contains(Capabilities::ACKS));
+        assert_eq!(both & Capabilities::ACKS, Capabilities::ACKS);
+        assert!(!(both & Capabilities::ALIASES).contains(Capabilities::ALIASES));
+    }
+
+    #[test]
+    fn test_unknown_frames() {
+        for byte in 0..=u8::MAX {
+            assert_eq!(Header::read(byte).write(), byte);
+        }
+
+        // Frames of unknown kinds are skipped if ignorable, and rejected otherwise.
+        let ignorable = [Header::extended(Header::IGNORABLE | 1), 0xff];
+        let empty = Message::Batch {
+            messages: Vec::new(),
+        };
+        assert_eq!(Message::from_slice(&ignorable).unwrap(), empty);
+        assert!(Message::from_slice(&[Header::extended(31)]).is_err());
+
+        let subscribe = Message::Subscribe {
+            topic: Topic::new(b"topic"),
+        };
+        let mut batch = vec![Header::extended(BATCH), 2];
+        batch.extend_from_slice(&ignorable);
+        batch.push(subscribe.len() as u8);
+        batch.extend_from_slice(&subscribe.to_bytes());
+        assert_eq!(
+            Message::from_slice(&batch).unwrap(),
+            Message::Batch {
+                messages: vec![subscribe]
+            }
+        );
+    }
+
+    #[test]
+    fn test_nested_batch() {
+        // Each level wraps the previous one in a batch with a single frame. Built back to front,
+        // as each level is prefixed.
+        let mut frame: Vec<u8> = Message::Query.to_bytes().iter().rev().copied().collect();
+        for _ in 0..100_000 {
+            let mut len = unsigned_varint::encode::usize_buffer();
+            let len = unsigned_varint::encode::usize(frame.len(), &mut len);
+            frame.extend(len.iter().rev());
+            frame.push(Header::extended(BATCH));
+        }
+        frame.reverse();
+        assert!(Message::from_slice(&frame).is_err());
+    }
+
+    #[test]
+    fn test_downgrade() {
+        let (topic, group) = (Topic::new(b"topic"), Topic::new(b"group"));
+        let subscribe = Message::Subscribe { topic };
+        assert_eq!(subscribe.downgrade(), Some(Cow::Borrowed(&subscribe)));
+        let shared = Message::SubscribeShared { topic, group };
+        assert!(matches!(shared.downgrade(), Some(Cow::Owned(m)) if m == subscribe));
+
+        // Only broadcasts carrying metadata are copied, to drop it.
+        let plain = Message::broadcast(topic, Bytes::from_static(b"msg"));
+        assert!(matches!(plain.downgrade(), Some(Cow::Borrowed(m)) if *m == plain));
+        let with_metadata = Message::Broadcast {
+            topic,
+            payload: Bytes::from_static(b"msg").into(),
+            metadata: Metadata::default().with_content_type("text/plain").into(),
+        };
+        assert!(matches!(with_metadata.downgrade(), Some(Cow::Owned(m)) if m == plain));
+
+        assert_eq!(Message::Query.downgrade(), None);
+        assert_eq!(Message::Ack { delivery_id: 1 }.downgrade(), None);
+    }
+
+    #[test]
+    fn test_content_hasher() {
+        let topic = Topic::new(b"topic");
+        let mut hasher = ContentHasher::new(&topic);
+        hasher.update(b"abc");
+        hasher.update(b"de");
+        assert_eq!(hasher.finish(), MessageId::from_content(&topic, b"abcde"));
+    }
+
     #[test]
     #[should_panic]
     fn test_invalid_message() {
         let out_of_range = [0b0000_0100];
-        Message::from_bytes(&out_of_range).unwrap();
+        Message::from_slice(&out_of_range).unwrap();
     }
 }
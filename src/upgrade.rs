@@ -0,0 +1,88 @@
+use std::{convert::Infallible, fmt, sync::Arc};
+
+use futures::future::{ready, Ready};
+use libp2p::core::{InboundUpgrade, OutboundUpgrade, UpgradeInfo};
+use libp2p::swarm::Stream;
+
+use crate::protocol::{Version, PROTOCOL_V1, PROTOCOL_V2};
+
+pub struct Protocol {
+    /// Held by inbound upgrades for as long as they are in flight, so that the handler can count
+    /// them. `None` for outbound upgrades and refused inbound ones.
+    _permit: Option<Arc<()>>,
+    pub(crate) refused: bool,
+}
+
+impl Protocol {
+    pub fn new() -> Self {
+        Self {
+            _permit: None,
+            refused: false,
+        }
+    }
+
+    /// Inbound upgrade holding the permit until it completes.
+    pub fn with_permit(permit: Arc<()>) -> Self {
+        Self {
+            _permit: Some(permit),
+            refused: false,
+        }
+    }
+
+    /// Inbound upgrade failing right after the protocol was negotiated.
+    pub fn refused() -> Self {
+        Self {
+            _permit: None,
+            refused: true,
+        }
+    }
+}
+
+/// An inbound substream was refused because too many were being upgraded at once.
+#[derive(Debug)]
+pub struct UpgradeRefused;
+
+impl fmt::Display for UpgradeRefused {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("too many inbound substream upgrades in flight")
+    }
+}
+
+impl std::error::Error for UpgradeRefused {}
+
+impl UpgradeInfo for Protocol {
+    type Info = &'static str;
+    type InfoIter = std::array::IntoIter<Self::Info, 2>;
+
+    fn protocol_info(&self) -> Self::InfoIter {
+        IntoIterator::into_iter([PROTOCOL_V2, PROTOCOL_V1])
+    }
+}
+
+impl InboundUpgrade<Stream> for Protocol {
+    type Output = (Stream, Version);
+    type Error = UpgradeRefused;
+    type Future = Ready<Result<Self::Output, Self::Error>>;
+
+    fn upgrade_inbound(self, socket: Stream, info: Self::Info) -> Self::Future {
+        if self.refused {
+            return ready(Err(UpgradeRefused));
+        }
+        ready(Ok((socket, version(info))))
+    }
+}
+
+impl OutboundUpgrade<Stream> for Protocol {
+    type Output = (Stream, Version);
+    type Error = Infallible;
+    type Future = Ready<Result<Self::Output, Self::Error>>;
+
+    fn upgrade_outbound(self, socket: Stream, info: Self::Info) -> Self::Future {
+        ready(Ok((socket, version(info))))
+    }
+}
+
+/// Version of the negotiated protocol, always one of ours.
+fn version(info: &str) -> Version {
+    Version::from_protocol(info).unwrap_or(Version::V1)
+}
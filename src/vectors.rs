@@ -0,0 +1,209 @@
+use std::sync::Arc;
+
+use bytes::Bytes;
+use libp2p_identity::PeerId;
+
+use crate::types::{Capabilities, Handshake, Message, Metadata, Shard, Topic};
+
+/// A message along with its canonical encoding, for checking that other implementations of the
+/// protocol, or later versions of this one, speak the same wire format.
+///
+/// `bytes` is the body of a frame. On the wire, each frame is prefixed by its length as an
+/// unsigned varint.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TestVector {
+    pub name: &'static str,
+    pub bytes: Vec<u8>,
+}
+
+/// Peer id with a fixed sha2-256 multihash, used wherever a vector carries one.
+fn peer_id() -> PeerId {
+    let mut bytes = vec![0x12, 0x20];
+    bytes.extend(0..32u8);
+    PeerId::from_bytes(&bytes).expect("valid multihash")
+}
+
+/// Canonical encodings of representative messages, covering every frame kind and metadata
+/// field. The frames of long topics are only covered with the `long-topics` feature.
+pub fn test_vectors() -> Vec<TestVector> {
+    let topic = Topic::new(b"topic");
+    let vectors = [
+        ("subscribe", Message::Subscribe { topic }),
+        ("unsubscribe", Message::Unsubscribe { topic }),
+        (
+            "broadcast",
+            Message::broadcast(topic, Bytes::from_static(b"payload")),
+        ),
+        (
+            "broadcast_with_metadata",
+            Message::Broadcast {
+                topic,
+                payload: Bytes::from_static(b"payload").into(),
+                metadata: Arc::new(Metadata {
+                    content_type: Some("text/plain".to_owned()),
+                    correlation_id: Some(7),
+                    reply_to: Some(Topic::new(b"replies")),
+                    timestamp: Some(1_700_000_000_000),
+                    origin: Some(peer_id()),
+                    hops: Some(2),
+                    hop_limit: Some(4),
+                    expires_at: Some(1_700_000_060_000),
+                    ..Metadata::default()
+                }),
+            },
+        ),
+        (
+            "shard",
+            Message::Shard {
+                topic,
+                shard: Box::new(Shard {
+                    origin: None,
+                    id: 42,
+                    index: 1,
+                    data_shards: 2,
+                    parity_shards: 1,
+                    payload_len: 5,
+                    data: Bytes::from_static(b"abc"),
+                }),
+            },
+        ),
+        (
+            "relayed_shard",
+            Message::Shard {
+                topic,
+                shard: Box::new(Shard {
+                    origin: Some(peer_id()),
+                    id: 42,
+                    index: 1,
+                    data_shards: 2,
+                    parity_shards: 1,
+                    payload_len: 5,
+                    data: Bytes::from_static(b"abc"),
+                }),
+            },
+        ),
+        (
+            "batch",
+            Message::Batch {
+                messages: vec![
+                    Message::Subscribe { topic },
+                    Message::broadcast(topic, Bytes::from_static(b"payload")),
+                ],
+            },
+        ),
+        (
+            "handshake",
+            Message::Handshake {
+                handshake: Handshake {
+                    max_frame_size: 4 * 1024 * 1024,
+                    capabilities: Capabilities::ACKS | Capabilities::ALIASES,
+                },
+            },
+        ),
+        (
+            "snapshot",
+            Message::Snapshot {
+                topics: vec![topic],
+            },
+        ),
+        ("query", Message::Query),
+        (
+            "control",
+            Message::Control {
+                topic,
+                payload: Bytes::from_static(b"payload"),
+            },
+        ),
+        ("subscribe_ack", Message::SubscribeAck { topic }),
+        (
+            "subscribe_shared",
+            Message::SubscribeShared {
+                topic,
+                group: Topic::new(b"group"),
+            },
+        ),
+        ("ack", Message::Ack { delivery_id: 42 }),
+        ("credit", Message::Credit { topic, credits: 16 }),
+    ];
+    #[cfg(feature = "long-topics")]
+    let vectors = {
+        let long_topic = Topic::new(&[b'a'; Topic::MAX_PLAIN_LENGTH + 1]);
+        let mut vectors = vectors.to_vec();
+        vectors.push(("long_subscribe", Message::Subscribe { topic: long_topic }));
+        vectors.push((
+            "long_unsubscribe",
+            Message::Unsubscribe { topic: long_topic },
+        ));
+        vectors
+    };
+    IntoIterator::into_iter(vectors)
+        .map(|(name, message)| TestVector {
+            name,
+            bytes: message.to_bytes(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+    }
+
+    #[test]
+    fn test_vectors_are_pinned() {
+        let pinned = [
+            ("subscribe", "14746f706963"),
+            ("unsubscribe", "16746f706963"),
+            ("broadcast", "15746f7069637061796c6f6164"),
+            (
+                "broadcast_with_metadata",
+                "0b05746f7069635d000a746578742f706c61696e0108000000000000000702077265706c6965730308\
+                 0000018bcfe5680004221220000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c\
+                 1d1e1f05010206010407080000018bcfe652607061796c6f6164",
+            ),
+            ("shard", "0305746f70696300000000000000002a01020100000005616263"),
+            (
+                "relayed_shard",
+                "0305746f70696301221220000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c\
+                 1d1e1f000000000000002a01020100000005616263",
+            ),
+            ("batch", "070614746f7069630d15746f7069637061796c6f6164"),
+            ("handshake", "0f808080020a"),
+            ("snapshot", "1305746f706963"),
+            ("query", "17"),
+            ("control", "1b05746f7069637061796c6f6164"),
+            ("subscribe_ack", "1f05746f706963"),
+            ("subscribe_shared", "2305746f7069630567726f7570"),
+            ("ack", "27000000000000002a"),
+            ("credit", "2b05746f70696300000010"),
+        ];
+        #[cfg(feature = "long-topics")]
+        let pinned = {
+            let mut pinned = pinned.to_vec();
+            pinned.push((
+                "long_subscribe",
+                "2f406161616161616161616161616161616161616161616161616161616161616161616161616161\
+                 6161616161616161616161616161616161616161616161616161",
+            ));
+            pinned.push((
+                "long_unsubscribe",
+                "33406161616161616161616161616161616161616161616161616161616161616161616161616161\
+                 6161616161616161616161616161616161616161616161616161",
+            ));
+            pinned
+        };
+        let vectors = test_vectors();
+        assert_eq!(vectors.len(), pinned.len());
+        for (vector, (name, bytes)) in vectors.iter().zip(pinned) {
+            assert_eq!(vector.name, name);
+            assert_eq!(hex(&vector.bytes), bytes, "encoding of {name} changed");
+            assert_eq!(
+                Message::from_slice(&vector.bytes).unwrap().to_bytes(),
+                vector.bytes
+            );
+        }
+    }
+}